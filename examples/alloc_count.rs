@@ -0,0 +1,85 @@
+//! Counts the allocations made by parsing a PSD file over and over, with and without reusing buffers, and the most memory that was in use at once.
+//!
+//!```sh
+//!cargo run --release --example alloc_count -- [file.psd]
+//!```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rawpsd::{ParseOptions, ScratchBuffers};
+
+struct Counter;
+
+static COUNT : AtomicU64 = AtomicU64::new(0);
+static BYTES : AtomicU64 = AtomicU64::new(0);
+static LIVE : AtomicU64 = AtomicU64::new(0);
+static PEAK : AtomicU64 = AtomicU64::new(0);
+
+fn grow(n : u64)
+{
+    let live = LIVE.fetch_add(n, Ordering::Relaxed) + n;
+    PEAK.fetch_max(live, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counter
+{
+    unsafe fn alloc(&self, layout : Layout) -> *mut u8
+    {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        grow(layout.size() as u64);
+        unsafe { System.alloc(layout) }
+    }
+    unsafe fn dealloc(&self, ptr : *mut u8, layout : Layout)
+    {
+        LIVE.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+    unsafe fn realloc(&self, ptr : *mut u8, layout : Layout, new_size : usize) -> *mut u8
+    {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        LIVE.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        grow(new_size as u64);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR : Counter = Counter;
+
+fn measure(name : &str, mut f : impl FnMut())
+{
+    // The first parse has nothing to reuse, so it's left out.
+    f();
+    let (count, bytes) = (COUNT.load(Ordering::Relaxed), BYTES.load(Ordering::Relaxed));
+    PEAK.store(LIVE.load(Ordering::Relaxed), Ordering::Relaxed);
+    let live = LIVE.load(Ordering::Relaxed);
+    for _ in 0..10
+    {
+        f();
+    }
+    let count = COUNT.load(Ordering::Relaxed) - count;
+    let bytes = BYTES.load(Ordering::Relaxed) - bytes;
+    let peak = PEAK.load(Ordering::Relaxed) - live;
+    println!("{}: {} allocations, {} bytes per parse, {} bytes peak", name, count / 10, bytes / 10, peak);
+}
+
+fn main()
+{
+    let path = std::env::args().nth(1).unwrap_or_else(|| "data/test.psd".to_string());
+    let data = std::fs::read(&path).expect("Failed to read file");
+
+    measure("parse_layer_records", ||
+    {
+        rawpsd::parse_layer_records(&data).expect("Failed to parse file");
+    });
+
+    let mut layers = vec!();
+    let mut scratch = ScratchBuffers::default();
+    measure("parse_layer_records_into", ||
+    {
+        rawpsd::parse_layer_records_into(&data, &ParseOptions::default(), &mut layers, &mut scratch).expect("Failed to parse file");
+    });
+}
@@ -0,0 +1,145 @@
+//! Times how long it takes to parse and decode every layer of a PSD file.
+//!
+//! Run it with and without the `rayon` feature to compare:
+//!
+//!```sh
+//!cargo run --release --example decode_bench -- [file.psd]
+//!cargo run --release --example decode_bench --features rayon -- [file.psd]
+//!```
+//!
+//! Without a file, it makes up a document with a few large RLE-compressed layers.
+//!
+//! On Linux, it also prints the process's peak RSS at the end, file data included.
+
+use std::time::Instant;
+
+fn push_u16(out : &mut Vec<u8>, x : u16) { out.extend_from_slice(&x.to_be_bytes()); }
+fn push_u32(out : &mut Vec<u8>, x : u32) { out.extend_from_slice(&x.to_be_bytes()); }
+
+/// PackBits-compresses one row.
+fn packbits(row : &[u8], out : &mut Vec<u8>)
+{
+    let mut i = 0;
+    while i < row.len()
+    {
+        let run = row[i..].iter().take(128).take_while(|x| **x == row[i]).count();
+        if run >= 3
+        {
+            out.push((1 - run as i32) as u8);
+            out.push(row[i]);
+            i += run;
+        }
+        else
+        {
+            let mut end = i;
+            while end < row.len() && end - i < 128 && !(end + 2 < row.len() && row[end] == row[end + 1] && row[end] == row[end + 2])
+            {
+                end += 1;
+            }
+            out.push((end - i - 1) as u8);
+            out.extend_from_slice(&row[i..end]);
+            i = end;
+        }
+    }
+}
+
+/// An RLE-compressed channel with a pattern that's neither all runs nor all literals.
+fn channel(w : u32, h : u32, seed : u32) -> Vec<u8>
+{
+    let mut counts = vec!();
+    let mut rows = vec!();
+    let mut row = vec![0; w as usize];
+    for y in 0..h
+    {
+        for (x, c) in row.iter_mut().enumerate()
+        {
+            let x = x as u32;
+            *c = if (x / 16 + y / 16 + seed).is_multiple_of(3) { (x ^ y).wrapping_mul(seed + 7) as u8 } else { (y / 4 + seed) as u8 };
+        }
+        let start = rows.len();
+        packbits(&row, &mut rows);
+        push_u16(&mut counts, (rows.len() - start) as u16);
+    }
+    let mut out = vec!();
+    push_u16(&mut out, 1);
+    out.extend(counts);
+    out.extend(rows);
+    out
+}
+
+fn synthesize(w : u32, h : u32, layer_count : u16) -> Vec<u8>
+{
+    let mut records = vec!();
+    let mut channel_data = vec!();
+    for i in 0..layer_count
+    {
+        let channels : Vec<(i16, Vec<u8>)> = [-1, 0, 1, 2].into_iter().map(|id| (id, channel(w, h, i as u32 * 4 + (id + 1) as u32))).collect();
+        for x in [0, 0, h, w]
+        {
+            push_u32(&mut records, x);
+        }
+        push_u16(&mut records, channels.len() as u16);
+        for (id, data) in &channels
+        {
+            push_u16(&mut records, *id as u16);
+            push_u32(&mut records, data.len() as u32);
+            channel_data.extend_from_slice(data);
+        }
+        records.extend_from_slice(b"8BIMnorm");
+        records.extend_from_slice(&[255, 0, 0, 0]);
+        // No mask, no blending ranges, and a four-byte padded name.
+        push_u32(&mut records, 12);
+        push_u32(&mut records, 0);
+        push_u32(&mut records, 0);
+        records.extend_from_slice(&[3, b'l', b'y', b'r']);
+    }
+
+    let mut info = vec!();
+    push_u16(&mut info, layer_count);
+    info.extend(records);
+    info.extend(channel_data);
+
+    let mut out = vec!();
+    out.extend_from_slice(b"8BPS");
+    push_u16(&mut out, 1);
+    out.extend_from_slice(&[0; 6]);
+    push_u16(&mut out, 3);
+    push_u32(&mut out, h);
+    push_u32(&mut out, w);
+    push_u16(&mut out, 8);
+    push_u16(&mut out, 3);
+    push_u32(&mut out, 0);
+    push_u32(&mut out, 0);
+    push_u32(&mut out, info.len() as u32 + 8);
+    push_u32(&mut out, info.len() as u32);
+    out.extend(info);
+    push_u32(&mut out, 0);
+    push_u16(&mut out, 0);
+    out.extend(std::iter::repeat_n(0, (w * h * 3) as usize));
+    out
+}
+
+fn main()
+{
+    let data = match std::env::args().nth(1)
+    {
+        Some(path) => std::fs::read(path).expect("Failed to read file"),
+        None => synthesize(2048, 2048, 8),
+    };
+    println!("rayon: {}", cfg!(feature = "rayon"));
+
+    let mut best = f64::MAX;
+    for _ in 0..5
+    {
+        let start = Instant::now();
+        let layers = rawpsd::parse_layer_records(&data).expect("Failed to parse file");
+        let time = start.elapsed().as_secs_f64();
+        best = best.min(time);
+        println!("{} layers in {:.1}ms", layers.len(), time * 1000.0);
+    }
+    println!("best: {:.1}ms", best * 1000.0);
+    if let Some(peak) = std::fs::read_to_string("/proc/self/status").ok().and_then(|x| x.lines().find_map(|x| x.strip_prefix("VmHWM:").map(|x| x.trim().to_string())))
+    {
+        println!("peak RSS: {}", peak);
+    }
+}
@@ -0,0 +1,50 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rawpsd::*;
+
+// Arbitrary bytes must never panic, only return Err. Results are ignored; only crashes matter here.
+//
+// Run with `cargo fuzz run parse`. Inputs that used to crash live in `regressions/parse`, and are also run by rawpsd's own tests; pass that directory as an extra corpus to start from them.
+fuzz_target!(|data : &[u8]|
+{
+    // Arbitrary bytes can claim huge but valid dimensions, so the limits keep the fuzzer from running out of memory on them.
+    let limits = ParseLimits { max_layer_pixels : Some(1 << 24), max_total_bytes : Some(1 << 26), ..Default::default() };
+    let limited = ParseOptions { limits, ..Default::default() };
+    if let Ok(metadata) = parse_psd_metadata(data)
+    {
+        let _ = parse_merged_image_opts(data, &metadata, &limited);
+    }
+    let _ = parse_layer_records_opts(data, &limited);
+    let mut options = limited.clone();
+    options.convert_16_bit = true;
+    options.expand_indexed = true;
+    if let Ok(layers) = parse_layer_records_opts(data, &options)
+    {
+        let _ = compute_layer_bounds(&layers, true, None);
+        for layer in &layers
+        {
+            let _ = layer.adjustment();
+        }
+    }
+    let _ = parse_layer_section_flags(data);
+    let _ = parse_document_blocks(data);
+    let _ = parse_image_resources(data);
+    let _ = parse_resolution_info(data);
+    let _ = parse_thumbnail(data);
+    let _ = parse_color_profile(data);
+    let _ = parse_exif(data);
+    let _ = parse_guides(data);
+    let _ = parse_alpha_channels(data);
+    let _ = parse_layer_state(data);
+    let _ = parse_paths(data);
+    let _ = parse_clipping_path_name(data);
+    let _ = parse_iptc(data);
+    let _ = parse_print_info(data);
+    let _ = parse_pixel_aspect_ratio(data);
+    let _ = parse_url_list(data);
+    let _ = parse_patterns_opts(data, &limited);
+    let _ = parse_linked_files(data);
+    let _ = parse_annotations(data);
+    let _ = parse_engine_data(data);
+});
@@ -0,0 +1,165 @@
+//! Photoshop's text engine data format. See [EngineValue].
+
+use super::*;
+
+/// A value in Photoshop's text engine data format. Returned from [parse_engine_data].
+///
+/// The text engine data format is a loose PostScript-like serialization, used for the 'EngineData' item of [TextLayerInfo::text_desc] and for the document-level 'Txt2' block. It holds the actual styling of text layers: paragraph and style runs, font sizes, tracking, fill colors, etc.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub enum EngineValue
+{
+    /// `<< /Key value ... >>`, in file order. Keys don't include the leading slash.
+    Dict(Vec<(String, EngineValue)>),
+    /// `[ value ... ]`
+    Array(Vec<EngineValue>),
+    /// `( ... )`. Decoded from UTF-16 if it starts with a byte order mark, which almost all of them do.
+    String(String),
+    /// A bare `/Name` outside of a key position. Doesn't include the leading slash.
+    Name(String),
+    /// A number without a decimal point.
+    Integer(i64),
+    /// A number with a decimal point.
+    Number(f64),
+    Bool(bool),
+    /// `null`, or anything else that rawpsd doesn't recognize.
+    Null,
+}
+
+impl EngineValue
+{
+    /// If this is a dict, get the value for the given key (without the leading slash).
+    pub fn get(&self, key : &str) -> Option<&EngineValue>
+    {
+        match self
+        {
+            EngineValue::Dict(x) => x.iter().find(|x| x.0 == key).map(|x| &x.1),
+            _ => None,
+        }
+    }
+}
+
+/// Parses text engine data (see [EngineValue]) into a tree of values. Trailing garbage after the top-level dict is ignored.
+pub fn parse_engine_data(data : &[u8]) -> Result<EngineValue, PsdError>
+{
+    fn skip_whitespace(data : &[u8], i : &mut usize)
+    {
+        while *i < data.len() && (data[*i].is_ascii_whitespace() || data[*i] == 0)
+        {
+            *i += 1;
+        }
+    }
+    fn read_token<'a>(data : &'a [u8], i : &mut usize) -> &'a [u8]
+    {
+        let start = *i;
+        while *i < data.len() && !data[*i].is_ascii_whitespace() && !b"<>[]()/".contains(&data[*i])
+        {
+            *i += 1;
+        }
+        &data[start..*i]
+    }
+    fn read_value(data : &[u8], i : &mut usize, depth : usize) -> Result<EngineValue, PsdError>
+    {
+        if depth > 256
+        {
+            return Err(PsdError::malformed("Text engine data nested too deeply", *i as u64));
+        }
+        skip_whitespace(data, i);
+        let rest = &data[*i..];
+        if rest.starts_with(b"<<")
+        {
+            *i += 2;
+            let mut ret = vec!();
+            loop
+            {
+                skip_whitespace(data, i);
+                if data[*i..].starts_with(b">>")
+                {
+                    *i += 2;
+                    return Ok(EngineValue::Dict(ret));
+                }
+                if data.get(*i) != Some(&b'/')
+                {
+                    return Err(PsdError::malformed("Expected a key in text engine data", *i as u64));
+                }
+                *i += 1;
+                let key = String::from_utf8_lossy(read_token(data, i)).to_string();
+                ret.push((key, read_value(data, i, depth + 1)?));
+            }
+        }
+        else if rest.starts_with(b"[")
+        {
+            *i += 1;
+            let mut ret = vec!();
+            loop
+            {
+                skip_whitespace(data, i);
+                match data.get(*i)
+                {
+                    Some(b']') => { *i += 1; return Ok(EngineValue::Array(ret)); }
+                    None => return Err(PsdError::malformed("Unterminated array in text engine data", *i as u64)),
+                    _ => ret.push(read_value(data, i, depth + 1)?),
+                }
+            }
+        }
+        else if rest.starts_with(b"(")
+        {
+            *i += 1;
+            let mut bytes = vec!();
+            loop
+            {
+                match data.get(*i)
+                {
+                    // Backslash escapes the next byte, whatever it is.
+                    Some(b'\\') =>
+                    {
+                        bytes.push(*data.get(*i + 1).ok_or_else(|| PsdError::malformed("Unterminated string in text engine data", *i as u64))?);
+                        *i += 2;
+                    }
+                    Some(b')') => { *i += 1; break; }
+                    Some(x) => { bytes.push(*x); *i += 1; }
+                    None => return Err(PsdError::malformed("Unterminated string in text engine data", *i as u64)),
+                }
+            }
+            if let Some(utf16) = bytes.strip_prefix(&[0xFE, 0xFF])
+            {
+                let utf16 : Vec<u16> = utf16.chunks_exact(2).map(|x| u16::from_be_bytes([x[0], x[1]])).collect();
+                Ok(EngineValue::String(String::from_utf16_lossy(&utf16)))
+            }
+            else
+            {
+                Ok(EngineValue::String(String::from_utf8_lossy(&bytes).to_string()))
+            }
+        }
+        else if rest.starts_with(b"/")
+        {
+            *i += 1;
+            Ok(EngineValue::Name(String::from_utf8_lossy(read_token(data, i)).to_string()))
+        }
+        else
+        {
+            let token = read_token(data, i);
+            if token.is_empty()
+            {
+                return Err(PsdError::malformed("Unexpected character in text engine data", *i as u64));
+            }
+            let token = String::from_utf8_lossy(token);
+            Ok(match token.as_ref()
+            {
+                "true" => EngineValue::Bool(true),
+                "false" => EngineValue::Bool(false),
+                _ if !token.contains('.') && token.parse::<i64>().is_ok() => EngineValue::Integer(token.parse().unwrap()),
+                _ => token.parse().map(EngineValue::Number).unwrap_or(EngineValue::Null),
+            })
+        }
+    }
+    
+    let mut i = 0;
+    skip_whitespace(data, &mut i);
+    if !data[i..].starts_with(b"<<")
+    {
+        return Err(PsdError::Malformed { message : "Text engine data doesn't start with a dict".to_string(), offset : Some(0) });
+    }
+    read_value(data, &mut i, 0)
+}
@@ -2,15 +2,23 @@
 //!
 //! Comparison with other crates:
 //! - `psd`: The `psd` crate's API makes it impossible to figure out the exact layer group hierarchy, so you can only use it on very simple PSDs.
-//! - `zune-psd`: Doesn't actually support the psd format, just gets the embedded thumbnail.
+//! - `zune-psd`: Doesn't actually support the psd format, just gets the embedded thumbnail. rawpsd can do that too; see [parse_thumbnail].
 //!
 //! rawpsd draws a compatibility support line at Photoshop CS6, the last non-subscription version of Photoshop. Features only supported by newer versions are unlikely to be supported.
 //!
-//! rawpsd currently only supports 8-bit RGB, CMYK, and Grayscale PSDs. This is the vast majority of PSD files that can be found in the wild. It does not yet support the large document PSB format variant.
+//! rawpsd currently only supports 8-bit RGB, CMYK, Grayscale, and Indexed PSDs. This is the vast majority of PSD files that can be found in the wild. 16-bit PSDs can be loaded by converting them to 8-bit; see `ParseOptions::convert_16_bit`. It does not yet support the large document PSB format variant.
+//!
+//! CMYK image data is left inverted, the way PSD files store it, with 0 meaning full ink. [cmyk_layer_to_rgba8] and [cmyk_layer_to_cmyka8] turn it into something more usual.
 //!
 //! rawpsd's docs do not document the entire PSD format, not even its capabilities. You will need to occasionally reference <https://www.adobe.com/devnet-apps/photoshop/fileformatashtml/> and manually poke at PSD files in a hex editor to take full advantage of rawpsd.
 //!
-//! You want [parse_layer_records] and [parse_psd_metadata].
+//! rawpsd's parsing functions don't panic, no matter what bytes they're given: malformed or truncated data produces a [PsdError] instead. There's a fuzz target in the repository's `fuzz` directory that checks this. They can still use a lot of memory on files that claim to be huge, though; to parse untrusted files, set [ParseOptions::limits].
+//!
+//! [write_psd] goes the other way, writing layers back out to a PSD file.
+//!
+//! With the `rayon` feature, the channels of each layer are decompressed in parallel. This needs std, so it turns off `no_std` support.
+//!
+//! You want [parse_layer_records] and [parse_psd_metadata]. The layers come out as a flat list, bottom to top, with groups marked by opener and closer records; [build_layer_tree] turns that into a tree.
 //!
 //! Example:
 //!
@@ -19,13 +27,10 @@
 //!
 //!if let Ok(layers) = parse_layer_records(&data)
 //!{
-//!    for mut layer in layers
+//!    for layer in layers
 //!    {
 //!        // Don't spew tons of image data bytes to stdout; we just want to see the metadata.
-//!        layer.image_data_rgba = vec!();
-//!        layer.image_data_k = vec!();
-//!        layer.image_data_mask = vec!();
-//!        println!("{:?}", layer);
+//!        println!("{:?}", layer.metadata_view());
 //!    }
 //!}
 //!```
@@ -34,7 +39,7 @@
 #![allow(clippy::manual_range_contains)] // bad idiom
 #![allow(clippy::field_reassign_with_default)] // bad idiom
 
-#![cfg_attr(not(any(test, feature = "serde_support", feature = "debug_spew")), no_std)]
+#![cfg_attr(not(any(test, feature = "serde_support", feature = "debug_spew", feature = "rayon")), no_std)]
 extern crate alloc;
 use alloc::string::{String, ToString};
 use alloc::vec;
@@ -42,63 +47,185 @@ use alloc::vec::Vec;
 use alloc::boxed::Box;
 use alloc::format;
 
+mod engine_data;
+pub use engine_data::{EngineValue, parse_engine_data};
+mod write;
+pub use write::write_psd;
+
 #[derive(Clone, Debug, Default)]
 struct SliceCursor<'a>
 {
     pub (crate) buf : &'a [u8],
     pub (crate) pos : usize,
+    /// Where `buf` starts in the file, for error reporting.
+    pub (crate) base : u64,
 }
 
 impl<'a> SliceCursor<'a>
 {
     pub (crate) fn new(buf : &'a [u8]) -> Self
     {
-        Self { buf, pos: 0 }
+        Self { buf, pos: 0, base: 0 }
+    }
+    /// Cursor over `buf`, which must be a subslice of `whole`, with error offsets relative to the start of `whole`.
+    pub (crate) fn within(whole : &[u8], buf : &'a [u8]) -> Self
+    {
+        let base = (buf.as_ptr() as usize).wrapping_sub(whole.as_ptr() as usize) as u64;
+        Self { buf, pos: 0, base }
     }
 
     pub (crate) fn position(&self) -> u64 { self.pos as u64 }
     pub (crate) fn set_position(&mut self, pos : u64) { self.pos = pos as usize }
+    /// Absolute position, for error reporting.
+    pub (crate) fn offset(&self) -> u64 { self.base + self.pos as u64 }
     
-    pub (crate) fn read_exact(&mut self, out : &mut [u8]) -> Result<(), String>
+    pub (crate) fn read_exact(&mut self, out : &mut [u8]) -> Result<(), PsdError>
     {
         let remaining = self.buf.len().saturating_sub(self.pos);
         if out.len() > remaining
         {
-            return Err("Unexpeted end of stream".to_string());
+            return Err(PsdError::UnexpectedEof { offset : self.offset() });
         }
         out.copy_from_slice(&self.buf[self.pos..self.pos + out.len()]);
         self.pos += out.len();
         Ok(())
     }
 
-    pub (crate) fn read_to_end(&mut self, out : &mut Vec<u8>) -> Result<usize, String>
+    pub (crate) fn read_slice(&mut self, n : u64) -> Result<&'a [u8], PsdError>
     {
         let remaining = self.buf.len().saturating_sub(self.pos);
-        out.reserve(remaining);
-        out.extend_from_slice(&self.buf[self.pos..]);
-        self.pos = self.buf.len();
-        Ok(remaining)
+        if n > remaining as u64
+        {
+            return Err(PsdError::UnexpectedEof { offset : self.offset() });
+        }
+        let ret = &self.buf[self.pos..self.pos + n as usize];
+        self.pos += n as usize;
+        Ok(ret)
     }
-    
-    pub fn take(&mut self, n : u64) -> Self
+    /// Reads the next `n` bytes as their own cursor.
+    pub (crate) fn sub(&mut self, n : u64) -> Result<Self, PsdError>
     {
-        Self { buf : &self.buf[self.pos..self.pos + n as usize], pos : 0 }
+        let base = self.offset();
+        Ok(Self { buf : self.read_slice(n)?, pos : 0, base })
     }
     
-    pub fn take_rest(&mut self) -> Self
+    pub fn take_rest(&mut self) -> Result<Self, PsdError>
     {
-        Self { buf : &self.buf[self.pos..], pos : 0 }
+        match self.buf.get(self.pos..)
+        {
+            Some(buf) => Ok(Self { buf, pos : 0, base : self.offset() }),
+            None => Err(PsdError::UnexpectedEof { offset : self.offset() }),
+        }
     }
      
 }
 
-use alloc::collections::BTreeMap;
+/// Something that went wrong while parsing a PSD file.
+///
+/// Offsets are byte offsets into the data that was passed to the parsing function. Functions that take a single buffer of image data, like [append_img_data], report offsets relative to that buffer.
+///
+/// For compatibility with code written against older versions of rawpsd, which reported errors as strings, this converts into a `String` with the same text as its `Display` impl.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PsdError
+{
+    /// The data ended in the middle of something.
+    UnexpectedEof { offset : u64 },
+    /// A magic signature, like "8BPS" or "8BIM", wasn't what it should have been.
+    BadSignature { what : &'static str, found : [u8; 4], offset : u64 },
+    /// The file or one of its parts uses a format version that rawpsd doesn't understand.
+    UnsupportedVersion { what : &'static str, version : u32, offset : u64 },
+    /// The document's bit depth isn't supported by the function that was called. See `ParseOptions::convert_16_bit`.
+    UnsupportedDepth(u16),
+    /// The document's color mode isn't supported by the function that was called.
+    UnsupportedColorMode(u16),
+    /// Image data uses an unknown compression mode, or uses ZIP compression without the `zip_support` feature.
+    UnsupportedCompression { mode : u16, offset : u64 },
+    /// The data didn't end where its length said it would.
+    Desync { expected : u64, actual : u64, context : &'static str },
+    /// The data is broken in some other way.
+    Malformed { message : String, offset : Option<u64> },
+    /// Something that would normally be a warning (see `LayerInfo::warnings`), turned into an error by `ParseOptions::strict`.
+    Strict(String),
+    /// Parsing would have gone over one of the limits in [ParseLimits]. The string is the name of the limit's field.
+    LimitExceeded(&'static str),
+}
+
+impl PsdError
+{
+    fn malformed(message : &str, offset : u64) -> Self
+    {
+        Self::Malformed { message : message.to_string(), offset : Some(offset) }
+    }
+    /// The byte offset where the error happened, if known.
+    pub fn offset(&self) -> Option<u64>
+    {
+        match self
+        {
+            Self::UnexpectedEof { offset } | Self::BadSignature { offset, .. } | Self::UnsupportedVersion { offset, .. } | Self::UnsupportedCompression { offset, .. } => Some(*offset),
+            Self::Desync { actual, .. } => Some(*actual),
+            Self::Malformed { offset, .. } => *offset,
+            _ => None,
+        }
+    }
+    /// Shifts any offsets in the error by `by`, for errors that came from a buffer that starts partway into the file.
+    fn offset_by(mut self, by : u64) -> Self
+    {
+        match &mut self
+        {
+            Self::UnexpectedEof { offset } | Self::BadSignature { offset, .. } | Self::UnsupportedVersion { offset, .. } | Self::UnsupportedCompression { offset, .. } | Self::Malformed { offset : Some(offset), .. } => *offset += by,
+            Self::Desync { expected, actual, .. } =>
+            {
+                *expected += by;
+                *actual += by;
+            }
+            _ => {}
+        }
+        self
+    }
+}
+
+impl core::fmt::Display for PsdError
+{
+    fn fmt(&self, f : &mut core::fmt::Formatter<'_>) -> core::fmt::Result
+    {
+        match self
+        {
+            Self::UnexpectedEof { offset } => write!(f, "Unexpected end of stream at 0x{:X}", offset),
+            Self::BadSignature { what, found, offset } => write!(f, "Invalid {} signature {:?} at 0x{:X}", what, String::from_utf8_lossy(found), offset),
+            Self::UnsupportedVersion { what, version, offset } => write!(f, "Unsupported {} version {} at 0x{:X}", what, version, offset),
+            Self::UnsupportedDepth(depth) => write!(f, "Unsupported bit depth {}. Only 8-bit PSDs are fully supported; 16-bit PSDs can be loaded with ParseOptions::convert_16_bit.", depth),
+            Self::UnsupportedColorMode(mode) => write!(f, "Unsupported color mode {}. Only RGB, CMYK, Grayscale, and Indexed PSDs are currently supported.", mode),
+            Self::UnsupportedCompression { mode, offset } => write!(f, "Unsupported compression format {} at 0x{:X}", mode, offset),
+            Self::Desync { expected, actual, context } => write!(f, "Desynchronized while reading {}: expected to end at 0x{:X}, ended at 0x{:X}", context, expected, actual),
+            Self::Malformed { message, offset : Some(offset) } => write!(f, "{} at 0x{:X}", message, offset),
+            Self::Malformed { message, offset : None } | Self::Strict(message) => write!(f, "{}", message),
+            Self::LimitExceeded(limit) => write!(f, "Exceeded ParseLimits::{}", limit),
+        }
+    }
+}
+
+impl core::error::Error for PsdError {}
+
+impl From<PsdError> for String
+{
+    fn from(err : PsdError) -> String
+    {
+        err.to_string()
+    }
+}
+
+#[cfg(feature = "serde_support")]
+use serde::{Serialize, Deserialize};
 
 /// PSD Class Descriptor object data. Only used by certain PSD features.
 ///
 /// Some PSD format features use a dynamic meta-object format instead of feature-specific data encoding; that information is what this type is responsible for holding.
+///
+/// To read one, use the `as_*` methods like [DescItem::as_long], which return `None` if the item is some other kind. The methods named after the kinds, like [DescItem::long()], panic instead, which is only a good idea if you already know what the data looks like.
 #[non_exhaustive]
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
 pub enum DescItem
 {
     #[allow(non_camel_case_types)]
@@ -111,6 +238,8 @@ pub enum DescItem
     bool(bool),
     TEXT(String),
     /// When rawpsd ran into an error while parsing the data that goes here: what kind of error was it?
+    ///
+    /// Usually this is an item type rawpsd doesn't know. Items don't say how long they are, so rawpsd can't skip over it, and reading stops there: this is the last item of its descriptor, and of every descriptor it's nested in. See [Descriptor::is_truncated].
     Err(String),
     /// Entire sub-object.
     Objc(Box<Descriptor>),
@@ -119,38 +248,276 @@ pub enum DescItem
     _enum(String, String),
     /// Variable-length list.
     VlLs(Vec<DescItem>),
+    #[allow(non_camel_case_types)]
+    /// Raw data, as-is. Text layers store their 'EngineData' this way.
+    tdta(Vec<u8>),
+    #[allow(non_camel_case_types)]
+    /// 64-bit integer.
+    comp(i64),
+    /// Global object. Same as [DescItem::Objc][variant@DescItem::Objc].
+    GlbO(Box<Descriptor>),
+    #[allow(non_camel_case_types)]
+    /// A class, from a 'type', 'GlbC', or 'Clss' item: the class's display name (usually empty), then its ID.
+    _type(String, String),
+    #[allow(non_camel_case_types)]
+    /// A reference to some other object, as a list of steps. Used for things like book colors in gradients.
+    obj(Vec<DescRef>),
+    #[allow(non_camel_case_types)]
+    /// A file alias, as-is. The format is platform-specific.
+    alis(Vec<u8>),
+    /// A list of floats that share a unit system, like [DescItem::UntF][variant@DescItem::UntF].
+    UnFl(String, Vec<f64>),
+    /// An object array: the number of objects, then a descriptor with one item per field, each holding that field's value for every object, usually as a [DescItem::UnFl]. Custom warps store their mesh points this way.
+    ObAr(u32, Box<Descriptor>),
     /// Dummy non-data data.
     #[default] Xxx
 }
 
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// One step of a reference ('obj ') descriptor item. See [DescItem::obj].
+///
+/// Each kind of step also stores a display name, which is almost always empty, so it's dropped, except for [DescRef::name] where the name is the point.
+pub enum DescRef
+{
+    #[allow(non_camel_case_types)]
+    /// A property of a class: the class ID, then the property's key.
+    prop(String, String),
+    /// A class, by ID.
+    Clss(String),
+    /// An enumerated reference: the class ID, then the enum's type and value.
+    Enmr(String, String, String),
+    #[allow(non_camel_case_types)]
+    /// An offset: the class ID, then the offset.
+    rele(String, i32),
+    /// An object, by identifier.
+    Idnt(i32),
+    #[allow(non_camel_case_types)]
+    /// An object, by index.
+    indx(i32),
+    #[allow(non_camel_case_types)]
+    /// An object, by name: the class ID, then the name.
+    name(String, String),
+}
+
 impl DescItem
 {
-    /// Get the given item if the enum is of that kind, otherwise panic.
+    /// Get the given item if the enum is of that kind, otherwise panic. Prefer [DescItem::as_long] when the data comes from an arbitrary file.
     pub fn long(&self) -> i32 { match self { DescItem::long(x) => *x, _ => panic!(), } }
-    /// Get the given item if the enum is of that kind, otherwise panic.
+    /// Get the given item if the enum is of that kind, otherwise panic. Prefer [DescItem::as_doub] when the data comes from an arbitrary file.
     pub fn doub(&self) -> f64 { match self { DescItem::doub(x) => *x, _ => panic!(), } }
-    /// Get the given item if the enum is of that kind, otherwise panic.
+    /// Get the given item if the enum is of that kind, otherwise panic. Prefer [DescItem::as_bool] when the data comes from an arbitrary file.
     pub fn bool(&self) -> bool { match self { DescItem::bool(x) => *x, _ => panic!(), } }
-    /// Get the given item if the enum is of that kind, otherwise panic.
+    /// Get the given item if the enum is of that kind, otherwise panic. Prefer [DescItem::as_enum] when the data comes from an arbitrary file.
     pub fn _enum(&self) -> (String, String) { match self { DescItem::_enum(y, x) => (y.clone(), x.clone()), _ => panic!(), } }
     #[allow(non_snake_case)]
-    /// Get the given item if the enum is of that kind, otherwise panic.
+    /// Get the given item if the enum is of that kind, otherwise panic. Prefer [DescItem::as_untf] when the data comes from an arbitrary file.
     pub fn UntF(&self) -> (String, f64) { match self { DescItem::UntF(y, x) => (y.clone(), *x), _ => panic!(), } }
     #[allow(non_snake_case)]
-    /// Get the given item if the enum is of that kind, otherwise panic.
+    /// Get the given item if the enum is of that kind, otherwise panic. Prefer [DescItem::as_objc] when the data comes from an arbitrary file.
     pub fn Objc(&self) -> Box<Descriptor> { match self { DescItem::Objc(x) => x.clone(), _ => panic!(), } }
     #[allow(non_snake_case)]
-    /// Get the given item if the enum is of that kind, otherwise panic.
+    /// Get the given item if the enum is of that kind, otherwise panic. Prefer [DescItem::as_text] when the data comes from an arbitrary file.
     pub fn TEXT(&self) -> String { match self { DescItem::TEXT(x) => x.clone(), _ => panic!(), } }
     #[allow(non_snake_case)]
-    /// Get the given item if the enum is of that kind, otherwise panic.
+    /// Get the given item if the enum is of that kind, otherwise panic. Prefer [DescItem::as_list] when the data comes from an arbitrary file.
     pub fn VlLs(&self) -> Vec<DescItem> { match self { DescItem::VlLs(x) => x.clone(), _ => panic!(), } }
+    /// Get the given item if the enum is of that kind, otherwise panic. Prefer [DescItem::as_tdta] when the data comes from an arbitrary file.
+    pub fn tdta(&self) -> Vec<u8> { match self { DescItem::tdta(x) => x.clone(), _ => panic!(), } }
+    
+    /// Get the given item if the enum is of that kind.
+    pub fn as_long(&self) -> Option<i32> { match self { DescItem::long(x) => Some(*x), _ => None, } }
+    /// Get the given item if the enum is of that kind.
+    pub fn as_doub(&self) -> Option<f64> { match self { DescItem::doub(x) => Some(*x), _ => None, } }
+    /// Get the given item if the enum is of that kind.
+    pub fn as_bool(&self) -> Option<bool> { match self { DescItem::bool(x) => Some(*x), _ => None, } }
+    /// Get the given item if the enum is of that kind: the enum's type, then its value.
+    pub fn as_enum(&self) -> Option<(&str, &str)> { match self { DescItem::_enum(y, x) => Some((y, x)), _ => None, } }
+    /// Get the given item if the enum is of that kind: the unit system, then the value.
+    pub fn as_untf(&self) -> Option<(&str, f64)> { match self { DescItem::UntF(y, x) => Some((y, *x)), _ => None, } }
+    /// Get the given item if the enum is of that kind. Also works on [DescItem::GlbO].
+    pub fn as_objc(&self) -> Option<&Descriptor> { match self { DescItem::Objc(x) | DescItem::GlbO(x) => Some(x), _ => None, } }
+    /// Get the given item if the enum is of that kind.
+    pub fn as_text(&self) -> Option<&str> { match self { DescItem::TEXT(x) => Some(x), _ => None, } }
+    /// Get the given item if the enum is of that kind.
+    pub fn as_list(&self) -> Option<&[DescItem]> { match self { DescItem::VlLs(x) => Some(x), _ => None, } }
+    /// Get the given item if the enum is of that kind.
+    pub fn as_tdta(&self) -> Option<&[u8]> { match self { DescItem::tdta(x) => Some(x), _ => None, } }
+    /// Get the given item if the enum is of that kind.
+    pub fn as_reference(&self) -> Option<&[DescRef]> { match self { DescItem::obj(x) => Some(x), _ => None, } }
+    /// Get the given item if the enum is of that kind: the unit system, then the values.
+    pub fn as_unfl(&self) -> Option<(&str, &[f64])> { match self { DescItem::UnFl(y, x) => Some((y, x)), _ => None, } }
+    /// Get the given item if the enum is of that kind: the number of objects, then the fields.
+    pub fn as_object_array(&self) -> Option<(u32, &Descriptor)> { match self { DescItem::ObAr(y, x) => Some((*y, x)), _ => None, } }
+    /// Get a number out of a `long`, `comp`, `doub`, or `UntF`, whichever it is. Photoshop isn't consistent about which of these it uses for a given value. The unit of a `UntF` is ignored.
+    pub fn as_f64(&self) -> Option<f64>
+    {
+        match self
+        {
+            DescItem::long(x) => Some(*x as f64),
+            DescItem::comp(x) => Some(*x as f64),
+            DescItem::doub(x) | DescItem::UntF(_, x) => Some(*x),
+            _ => None,
+        }
+    }
+    /// If this is an `Objc` or `GlbO`, get the item with the given key from it.
+    pub fn get(&self, key : &str) -> Option<&DescItem>
+    {
+        self.as_objc().and_then(|x| x.get(key))
+    }
+    /// Is this an [DescItem::Err], or does it end in one? See [Descriptor::is_truncated].
+    pub fn is_truncated(&self) -> bool
+    {
+        match self
+        {
+            DescItem::Err(_) => true,
+            DescItem::Objc(x) | DescItem::GlbO(x) | DescItem::ObAr(_, x) => x.is_truncated(),
+            DescItem::VlLs(x) => x.last().is_some_and(DescItem::is_truncated),
+            _ => false,
+        }
+    }
 }
 
-type Descriptor = (String, Vec<(String, DescItem)>);
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A class descriptor: the class ID, then the descriptor's items, as key-value pairs in file order. See [DescItem].
+///
+/// Keys are usually unique, but nothing stops a file from repeating them, so this is a list instead of a map. Use [Descriptor::get] and [Descriptor::get_path] to look things up.
+///
+/// This used to be a plain `(String, Vec<(String, DescItem)>)` tuple. The fields have the same names, so `.0` and `.1` still work, and there are `From` conversions both ways.
+pub struct Descriptor(pub String, pub Vec<(String, DescItem)>);
+
+impl Descriptor
+{
+    /// The descriptor's class ID, like "null" or "Lefx".
+    pub fn class(&self) -> &str
+    {
+        &self.0
+    }
+    /// The descriptor's items, in file order.
+    pub fn items(&self) -> &[(String, DescItem)]
+    {
+        &self.1
+    }
+    /// Get the first item with the given key.
+    pub fn get(&self, key : &str) -> Option<&DescItem>
+    {
+        self.1.iter().find(|x| x.0 == key).map(|x| &x.1)
+    }
+    /// Get every item with the given key, in file order.
+    pub fn get_all<'a>(&'a self, key : &'a str) -> impl Iterator<Item = &'a DescItem> + 'a
+    {
+        self.1.iter().filter(move |x| x.0 == key).map(|x| &x.1)
+    }
+    /// Follow a path of keys down through nested descriptors, like `&["Scl ", "Hrzn"]`. Path segments that are numbers index into lists, like `&["filterFXList", "0", "Fltr"]`.
+    ///
+    /// Returns `None` if any step of the path doesn't exist.
+    pub fn get_path(&self, path : &[&str]) -> Option<&DescItem>
+    {
+        let (first, rest) = path.split_first()?;
+        let mut item = self.get(first)?;
+        for segment in rest
+        {
+            item = match item
+            {
+                DescItem::VlLs(x) => x.get(segment.parse::<usize>().ok()?)?,
+                _ => item.get(segment)?,
+            };
+        }
+        Some(item)
+    }
+    /// Did reading stop early because of an item rawpsd couldn't read? If so, the last item, or the last item of some nested descriptor or list, is a [DescItem::Err] saying what went wrong, and anything after it in the file is missing.
+    pub fn is_truncated(&self) -> bool
+    {
+        self.1.last().is_some_and(|x| x.1.is_truncated())
+    }
+}
+
+impl From<(String, Vec<(String, DescItem)>)> for Descriptor
+{
+    fn from(x : (String, Vec<(String, DescItem)>)) -> Self
+    {
+        Descriptor(x.0, x.1)
+    }
+}
+
+impl From<Descriptor> for (String, Vec<(String, DescItem)>)
+{
+    fn from(x : Descriptor) -> Self
+    {
+        (x.0, x.1)
+    }
+}
+
+/// Converts a descriptor to JSON, for debugging or for handing to code that doesn't know about rawpsd's types. Needs the `json_support` feature.
+///
+/// The descriptor becomes a JSON object, with its class ID under the reserved key `"_class"` and its items under their own keys. Items map like this:
+/// - `long`, `comp`, and `doub` become numbers. Non-finite `doub`s become `null`, since JSON can't hold them.
+/// - `UntF` becomes `{"unit": "#Pxl", "value": 3.0}`, and `UnFl` becomes `{"unit": "#Pxl", "values": [3.0, ...]}`.
+/// - `_enum` becomes `{"enum": type, "value": value}`.
+/// - `bool` and `TEXT` become booleans and strings.
+/// - `Objc` and `GlbO` become nested objects, the same way as the descriptor itself.
+/// - `ObAr` becomes `{"count": n, "fields": {...}}`, where the fields are an object the same way as the descriptor itself.
+/// - `type`, `GlbC`, and `Clss` become `{"class": id, "name": name}`.
+/// - `obj ` becomes an array of reference steps, each an object with a `"ref"` key holding the step's kind, like `{"ref": "Enmr", "class": id, "enum": type, "value": value}`. See [DescRef] for what each kind holds; the other keys are `"key"`, `"offset"`, `"id"`, `"index"`, and `"name"`.
+/// - `VlLs` becomes an array.
+/// - `tdta` becomes `{"data": [bytes...]}`, and `alis` becomes `{"alias": [bytes...]}`.
+/// - `Err` becomes `{"error": message}`.
+///
+/// JSON objects can't hold duplicate keys, so if a key shows up more than once, the second one gets `#2` added to the end, the third one `#3`, and so on. Key order is only kept if serde_json's `preserve_order` feature is enabled.
+#[cfg(feature = "json_support")]
+pub fn descriptor_to_json(desc : &Descriptor) -> serde_json::Value
+{
+    use serde_json::{Map, Value};
+    let mut map = Map::new();
+    map.insert("_class".to_string(), Value::from(desc.0.clone()));
+    for (i, (key, item)) in desc.1.iter().enumerate()
+    {
+        let n = desc.1[..i].iter().filter(|x| x.0 == *key).count();
+        let key = if n == 0 { key.clone() } else { format!("{}#{}", key, n + 1) };
+        map.insert(key, desc_item_to_json(item));
+    }
+    Value::Object(map)
+}
+
+/// Converts a single descriptor item to JSON. See [descriptor_to_json] for how each kind of item maps over. Needs the `json_support` feature.
+#[cfg(feature = "json_support")]
+pub fn desc_item_to_json(item : &DescItem) -> serde_json::Value
+{
+    use serde_json::{json, Value};
+    match item
+    {
+        DescItem::long(x) => Value::from(*x),
+        DescItem::doub(x) => Value::from(*x),
+        DescItem::UntF(unit, x) => json!({ "unit" : unit, "value" : x }),
+        DescItem::bool(x) => Value::from(*x),
+        DescItem::TEXT(x) => Value::from(x.clone()),
+        DescItem::Err(x) => json!({ "error" : x }),
+        DescItem::Objc(x) => descriptor_to_json(x),
+        DescItem::_enum(ty, x) => json!({ "enum" : ty, "value" : x }),
+        DescItem::VlLs(x) => Value::Array(x.iter().map(desc_item_to_json).collect()),
+        DescItem::tdta(x) => json!({ "data" : x }),
+        DescItem::comp(x) => Value::from(*x),
+        DescItem::GlbO(x) => descriptor_to_json(x),
+        DescItem::_type(name, id) => json!({ "class" : id, "name" : name }),
+        DescItem::obj(x) => Value::Array(x.iter().map(|x| match x
+        {
+            DescRef::prop(class, key) => json!({ "ref" : "prop", "class" : class, "key" : key }),
+            DescRef::Clss(class) => json!({ "ref" : "Clss", "class" : class }),
+            DescRef::Enmr(class, ty, value) => json!({ "ref" : "Enmr", "class" : class, "enum" : ty, "value" : value }),
+            DescRef::rele(class, offset) => json!({ "ref" : "rele", "class" : class, "offset" : offset }),
+            DescRef::Idnt(id) => json!({ "ref" : "Idnt", "id" : id }),
+            DescRef::indx(index) => json!({ "ref" : "indx", "index" : index }),
+            DescRef::name(class, name) => json!({ "ref" : "name", "class" : class, "name" : name }),
+        }).collect()),
+        DescItem::alis(x) => json!({ "alias" : x }),
+        DescItem::UnFl(unit, x) => json!({ "unit" : unit, "values" : x }),
+        DescItem::ObAr(n, x) => json!({ "count" : n, "fields" : descriptor_to_json(x) }),
+        DescItem::Xxx => Value::Null,
+    }
+}
 
-#[cfg(feature = "serde_support")]
-use serde::{Serialize, Deserialize};
 #[cfg(feature = "serde_support")]
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 /// Metadata about where a mask attached to an object physically is and how to interpret it.
@@ -168,7 +535,9 @@ pub struct MaskInfo {
     pub w : u32,
     /// Mask image data height.
     pub h : u32,
-    /// Default color of the mask outside of its explicit image area. Must be 0 or 255.
+    /// The mask's rectangle as stored in the file: top, left, bottom, right. Only differs from [MaskInfo::x], [MaskInfo::y], [MaskInfo::w], and [MaskInfo::h] if the rectangle is inside-out, in which case the width and height are clamped to zero.
+    pub rect_raw : (i32, i32, i32, i32),
+    /// Default color of the mask outside of its explicit image area. Should be 0 or 255, but some writers emit other values, which are passed through as-is. See [MaskInfo::effective_default_color].
     pub default_color : u8,
     /// Is the mask flagged as moving along with the layer it's attached to? Does not affect the encoding of the x/y coordinates. I think.
     pub relative : bool,
@@ -176,6 +545,18 @@ pub struct MaskInfo {
     pub disabled : bool,
     /// Is the mask marked as inverted, i.e. the default color and image data should be treated as being the opposite color (white <-> black)?
     pub invert : bool,
+    /// Is the mask marked as having come from rendering other data, rather than being painted by the user?
+    pub from_render : bool,
+    /// The mask's flags byte, as-is. Bit 4 says the mask has density or feather parameters.
+    pub flags_raw : u8,
+    /// Density of the layer's user mask (0 to 255), if it's been changed in the properties panel. Lower density makes the mask's hidden parts partially visible.
+    pub user_density : Option<u8>,
+    /// Feather radius of the layer's user mask, in pixels, if it's been changed in the properties panel.
+    pub user_feather : Option<f64>,
+    /// Same as [MaskInfo::user_density], but for the layer's vector mask.
+    pub vector_density : Option<u8>,
+    /// Same as [MaskInfo::user_feather], but for the layer's vector mask.
+    pub vector_feather : Option<f64>,
 }
 
 #[cfg(not(feature = "serde_support"))]
@@ -195,7 +576,9 @@ pub struct MaskInfo {
     pub w : u32,
     /// Mask image data height.
     pub h : u32,
-    /// Default color of the mask outside of its explicit image area. Must be 0 or 255.
+    /// The mask's rectangle as stored in the file: top, left, bottom, right. Only differs from [MaskInfo::x], [MaskInfo::y], [MaskInfo::w], and [MaskInfo::h] if the rectangle is inside-out, in which case the width and height are clamped to zero.
+    pub rect_raw : (i32, i32, i32, i32),
+    /// Default color of the mask outside of its explicit image area. Should be 0 or 255, but some writers emit other values, which are passed through as-is. See [MaskInfo::effective_default_color].
     pub default_color : u8,
     /// Is the mask flagged as moving along with the layer it's attached to? Does not affect the encoding of the x/y coordinates. I think.
     pub relative : bool,
@@ -203,6 +586,101 @@ pub struct MaskInfo {
     pub disabled : bool,
     /// Is the mask marked as inverted, i.e. the default color and image data should be treated as being the opposite color (white <-> black)?
     pub invert : bool,
+    /// Is the mask marked as having come from rendering other data, rather than being painted by the user?
+    pub from_render : bool,
+    /// The mask's flags byte, as-is. Bit 4 says the mask has density or feather parameters.
+    pub flags_raw : u8,
+    /// Density of the layer's user mask (0 to 255), if it's been changed in the properties panel. Lower density makes the mask's hidden parts partially visible.
+    pub user_density : Option<u8>,
+    /// Feather radius of the layer's user mask, in pixels, if it's been changed in the properties panel.
+    pub user_feather : Option<f64>,
+    /// Same as [MaskInfo::user_density], but for the layer's vector mask.
+    pub vector_density : Option<u8>,
+    /// Same as [MaskInfo::user_feather], but for the layer's vector mask.
+    pub vector_feather : Option<f64>,
+}
+
+impl MaskInfo
+{
+    /// The default color as Photoshop interprets it: values of 128 or higher are treated as 255, lower values as 0.
+    pub fn effective_default_color(&self) -> u8
+    {
+        if self.default_color >= 128 { 255 } else { 0 }
+    }
+}
+
+/// Options that control how [parse_layer_records_opts] behaves.
+///
+/// This struct is safe to initialize with `{ ..., ..Default::default() }`; new options will always default to the old behavior.
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    /// Treat things that would otherwise only be reported in [LayerInfo::warnings] as hard errors.
+    pub strict : bool,
+    /// Allow loading 16-bit PSDs by converting their image data to 8 bits per channel. The conversion is lossy. [PsdMetadata::depth] still says 16.
+    ///
+    /// Without this, 16-bit PSDs are rejected.
+    pub convert_16_bit : bool,
+    /// For indexed color PSDs, look each pixel up in [PsdMetadata::palette] and store the resulting RGB color in [LayerInfo::image_data_rgba].
+    ///
+    /// Without this, the palette indexes are left in the first channel of [LayerInfo::image_data_rgba], and the second and third channels are filler.
+    pub expand_indexed : bool,
+    /// Limits on how much work and memory parsing is allowed to use. Unlimited by default.
+    pub limits : ParseLimits,
+    /// Don't decode the layers' color channels. [LayerInfo::image_data_rgba] and [LayerInfo::image_data_k] are left empty, but everything else, including the layers' sizes and channel counts, is still filled in.
+    ///
+    /// For when only the layer structure is needed. Skipping the decompression makes parsing much faster on big files.
+    pub skip_image_data : bool,
+    /// Same as [ParseOptions::skip_image_data], but for [LayerInfo::image_data_mask], [LayerInfo::image_data_real_mask], and [SmartFilters::image_data_mask]. The mask infos are still filled in.
+    pub skip_masks : bool,
+    /// Store each color channel in its own plane in [LayerInfo::image_data_channels], instead of interleaving them into [LayerInfo::image_data_rgba].
+    ///
+    /// Only the channels that the layer actually has are stored, and nothing is prefilled with 255, so single-channel layers (like in grayscale documents) take a quarter of the memory. [LayerInfo::image_data_rgba] and [LayerInfo::image_data_k] are left empty, and [ParseOptions::expand_indexed] does nothing.
+    ///
+    /// For grayscale documents ([PsdMetadata::color_mode] 1), this is the way to get each layer as a plain one-byte-per-pixel image, plus a separate alpha plane if it has one: see [LayerInfo::plane].
+    pub planar_channels : bool,
+    /// Call [LayerInfo::shrink_channels] on each layer as soon as it's parsed, so that [LayerInfo::image_data_rgba] only holds the channels that came from the file.
+    pub shrink_channels : bool,
+    /// Decode the layers' channels that aren't color, transparency, or mask channels, like spot color channels and saved alpha channels, into [LayerInfo::extra_channels]. Without this, they're skipped.
+    pub extra_channels : bool,
+    /// Keep a copy of each of the layer's additional info blocks that rawpsd doesn't understand in [LayerInfo::extra_blocks].
+    pub keep_unknown_blocks : bool,
+    /// Same as [ParseOptions::keep_unknown_blocks], but for the blocks that rawpsd does understand. For checking rawpsd's parsing against the raw data.
+    pub keep_known_blocks : bool,
+}
+
+/// Resource limits for [parse_layer_records_opts], [parse_merged_image_opts], and [parse_patterns_opts], for parsing untrusted files. See [ParseOptions::limits].
+///
+/// Exceeding a limit makes parsing fail with [PsdError::LimitExceeded] before the memory in question is allocated. `None` means unlimited, which is the default for all of them.
+///
+/// This struct is safe to initialize with `{ ..., ..Default::default() }`; new limits will always default to unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ParseLimits {
+    /// Maximum width times height of any one layer or mask. Also applies to the whole document for [parse_merged_image_opts], and to each pattern channel for [parse_patterns_opts].
+    pub max_layer_pixels : Option<u64>,
+    /// Maximum number of bytes of decoded image data, across all layers and masks. For [parse_merged_image_opts] and [parse_patterns_opts], it's counted separately for each call.
+    pub max_total_bytes : Option<u64>,
+    /// Maximum number of layer records, including group openers and closers.
+    pub max_layer_count : Option<u32>,
+    /// Maximum nesting depth of descriptors. Descriptors are never allowed to nest more than 256 levels deep, regardless of this.
+    pub max_descriptor_depth : Option<u32>,
+}
+
+impl ParseLimits
+{
+    fn check_pixels(&self, w : u32, h : u32) -> Result<(), PsdError>
+    {
+        if let Some(max) = self.max_layer_pixels && w as u64 * h as u64 > max
+        {
+            return Err(PsdError::LimitExceeded("max_layer_pixels"));
+        }
+        Ok(())
+    }
+}
+/// Takes `n` bytes out of what's left of [ParseLimits::max_total_bytes].
+fn spend_bytes(left : &mut u64, n : u64) -> Result<(), PsdError>
+{
+    *left = left.checked_sub(n).ok_or(PsdError::LimitExceeded("max_total_bytes"))?;
+    Ok(())
 }
 
 /// Dummy struct to keep the main docs from being bloated. See [LayerInfo::blend_mode].
@@ -274,923 +752,9584 @@ pub struct BlendModeDocs { _no_init : core::marker::PhantomData<()>, }
 
 #[non_exhaustive]
 #[derive(Clone, Debug, Default)]
-/// Describes a single layer stack entry.
-///
-/// This data is very unorganized, and you should not use it directly in your application. You should move it out into your own types.
-///
-/// Returned from [parse_layer_records].
-pub struct LayerInfo {
-    /// Name of the layer.
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single smart filter applied to a smart object layer. See [SmartFilters].
+pub struct SmartFilter {
+    /// Class ID of the filter's parameter descriptor, e.g. "GsnB" for Gaussian Blur. This is what identifies the kind of filter.
+    pub key : String,
+    /// Display name of the filter, as shown in the layers panel.
     pub name : String,
-    /// Normal opacity of the layer.
-    pub opacity : f32,
-    /// Photoshop has separate "opacity" and "fill" sliders.
-    ///
-    /// Fill opacity behaves differently for certain blend modes and when layer effects are involved.
-    pub fill_opacity : f32,
-    /// Blend mode stored as a string. See [BlendModeDocs].
-    pub blend_mode : String,
-    /// Global X position of the layer, based on the top left of the canvas. Can be negative. Ignored for groups.
-    pub x : i32,
-    /// Global Y position of the layer, based on the top left of the canvas. Can be negative. Ignored for groups.
-    pub y : i32,
-    /// Layer image data width.
-    pub w : u32,
-    /// Layer image data height.
-    pub h : u32,
-    /// Number of channels in the image data.
-    pub image_channel_count : u16,
-    /// Four channels worth of image data. Can be RGBA or CMYA, sometimes with fewer channels. This is non-planar: a single full RGBA pixel is 4 consecutive bytes.
-    pub image_data_rgba : Vec<u8>,
-    /// The K channel of CMYK image data, if present.
-    pub image_data_k : Vec<u8>,
-    /// Whether the second channel of the RGBA data came from the PSD file (true) or was synthesized (false).
-    ///
-    /// If the PSD is malformed and has multiple channels of the same type, this flag might be incorrect.
-    /// But on well-formed PSDs, it's always correct.
-    pub image_data_has_g : bool,
-    /// Whether the third channel of the RGBA data came from the PSD file (true) or was synthesized (false).
-    ///
-    /// If the PSD is malformed and has multiple channels of the same type, this flag might be incorrect.
-    /// But on well-formed PSDs, it's always correct.
-    pub image_data_has_b : bool,
-    /// Whether the fourth channel of the RGBA data came from the PSD file (true) or was synthesized (false).
-    ///
-    /// If the PSD is malformed and has multiple channels of the same type, this flag might be incorrect.
-    /// But on well-formed PSDs, it's always correct.
-    pub image_data_has_a : bool,
-    /// Number of channels in the mask image data. They are stored planar (all of ch1, then all of ch2, etc), not interleaved like RGBA.
-    pub mask_channel_count : u16,
-    /// Where is the mask, and how do you interpret it?
-    pub mask_info : MaskInfo,
-    // TODO
-    //pub global_mask_opacity : u16,
-    //pub global_mask_kind : u16,
-    /// Actual mask data. Again, this is planar, unlike RGBA.
+    /// Is this filter enabled?
+    pub enabled : bool,
+    /// The filter's parameters. Not every filter has parameters.
+    pub params : Option<Descriptor>,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// The smart filter stack on a smart object layer.
+///
+/// rawpsd doesn't render smart filters. This is just what the file says the filters are.
+pub struct SmartFilters {
+    /// Filters in the order they're stored in the file, which is the order they're applied in.
+    pub filters : Vec<SmartFilter>,
+    /// Master switch for the whole filter stack.
+    pub enabled : bool,
+    /// Where the filter mask is. Photoshop stores a single mask for the whole filter stack, not one per filter. Only the position and size fields are meaningful.
+    pub mask_info : Option<MaskInfo>,
+    /// Filter mask image data, if the file has any. Planar, like [LayerInfo::image_data_mask]. Moved into [LayerPixels] by [LayerInfo::take_pixels].
     pub image_data_mask : Vec<u8>,
-    /// If this is a group opener, is the group expanded?
-    pub group_expanded : bool,
-    /// Is this a group opener?
-    pub group_opener : bool,
-    /// Is this a group closer?
-    pub group_closer : bool,
-    /// PSD layers have a "transparency shapes layer" flag. This is the inverse of that flag, i.e. "true" means "the transparency-shapes-layer flag is disabled". This flag state is funny and does weird things to some blend modes and layer effects.
-    pub funny_flag : bool,
-    /// Does this layer have the "clipping mask" flag enabled?
-    pub is_clipped : bool,
-    /// Is this layer alpha locked?
-    pub is_alpha_locked : bool,
-    /// Is this layer visible?
-    pub is_visible : bool,
-    /// Is this an adjustment layer, and if so, what kind? Blank if not an adjustment layer.
-    pub adjustment_type : String,
-    /// Pile of raw, flattened adjustment layer metadata. Search `// Read adjustment data.` in the [source code](https://docs.rs/crate/rawpsd/latest/source/src/lib.rs) and read down from there to see how each adjustment's data is flattened.
-    pub adjustment_info : Vec<f32>,
-    /// Some adjustments use class descriptors instead of "hardcoded" data. Those adjustments get their data here.
-    pub adjustment_desc : Option<Descriptor>,
-    /// What effects, if any, does this layer have attached to it?
-    pub effects_desc : Option<Descriptor>,
+    /// Color the filter mask is displayed with, from the layer's 'FMsk' block, if it has one.
+    pub mask_color : Option<EffectColor>,
+    /// Opacity the filter mask is displayed with, from the layer's 'FMsk' block, as stored. Zero if the layer doesn't have one.
+    pub mask_opacity : u16,
 }
 
-fn read_u8(cursor: &mut SliceCursor) -> Result<u8, String>
-{
-    let mut buf = [0; 1];
-    cursor.read_exact(&mut buf).map_err(|x| x.to_string())?;
-    Ok(buf[0])
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single entry of a layer's metadata setting block ('shmd'). This is where animation frame data, layer comp data, and some plugin data lives.
+pub struct MetadataSetting {
+    /// Key of the entry, e.g. "mlst" for animation frame visibility overrides or "cmls" for layer comps.
+    pub key : String,
+    /// Whether this entry gets copied when the layer is duplicated.
+    pub copy_on_duplicate : bool,
+    /// The entry's data, as-is.
+    pub data : Vec<u8>,
+    /// If the data is a versioned class descriptor, which most entries are, the decoded descriptor.
+    pub desc : Option<Descriptor>,
 }
 
-fn read_u16(cursor: &mut SliceCursor) -> Result<u16, String>
-{
-    let mut buf = [0; 2];
-    cursor.read_exact(&mut buf).map_err(|x| x.to_string())?;
-    Ok(u16::from_be_bytes(buf))
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A layer's vector mask, from its 'vmsk' or 'vsms' block. Shape layers store their outline this way.
+pub struct VectorMaskInfo {
+    /// Is the mask inverted?
+    pub invert : bool,
+    /// Is the mask unlinked from the layer, i.e. does it stay put when the layer is moved?
+    pub not_linked : bool,
+    /// Is the mask disabled?
+    pub disabled : bool,
+    /// The mask's outline. Same format as [PathInfo::subpaths].
+    pub subpaths : Vec<Subpath>,
+    /// From the initial fill rule record, if any: whether the mask starts out with all pixels filled instead of none.
+    pub initial_fill : Option<bool>,
 }
 
-fn read_u32(cursor: &mut SliceCursor) -> Result<u32, String>
-{
-    let mut buf = [0; 4];
-    cursor.read_exact(&mut buf).map_err(|x| x.to_string())?;
-    Ok(u32::from_be_bytes(buf))
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A text layer's type tool data, from its 'TySh' block.
+///
+/// The layer's pixel data is still there too, as a rasterized proxy of the text.
+pub struct TextLayerInfo {
+    /// Transform from text space to document space, as (xx, xy, yx, yy, tx, ty).
+    pub transform : [f64; 6],
+    /// Text data. The 'Txt ' item holds the plain text, and 'EngineData' holds the styling, as a [tdta][DescItem::tdta()] blob.
+    pub text_desc : Descriptor,
+    /// Warp settings. The 'warpStyle' item is "warpNone" if the text isn't warped. Empty if it has an unknown version, or if [TextLayerInfo::text_desc] [is truncated][Descriptor::is_truncated].
+    pub warp_desc : Descriptor,
+    /// (left, top, right, bottom) bounds of the text, in text space. All zero if either descriptor is truncated or unreadable.
+    pub bounds : [f64; 4],
 }
 
-fn read_b4(cursor: &mut SliceCursor) -> Result<[u8; 4], String>
-{
-    let mut buf = [0; 4];
-    cursor.read_exact(&mut buf).map_err(|x| x.to_string())?;
-    Ok(buf)
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Placement of a smart object layer's content, from its 'SoLd' block, or its older 'PlLd' block if it doesn't have one.
+///
+/// The layer's pixel data is a rasterized proxy of the placed content, and might be stale.
+pub struct PlacedLayerInfo {
+    /// Unique ID of the placed content. Matches the ID of the embedded or linked file.
+    pub unique_id : String,
+    /// Page of the placed content that's shown, for multi-page documents like PDFs. 1-based.
+    pub page_number : u32,
+    pub total_pages : u32,
+    /// Anti-aliasing policy. 0 is none, 1 is low quality, 2 is high quality.
+    pub anti_alias : u32,
+    /// What kind of content is placed. 0 is unknown, 1 is vector, 2 is raster, 3 is an image stack.
+    pub placed_type : u32,
+    /// Where the corners of the placed content end up in document space, as (x, y) pairs in the order top left, top right, bottom right, bottom left.
+    pub transform : [f64; 8],
+    /// Warp settings. The 'warpStyle' item is "warpNone" if the content isn't warped.
+    pub warp_desc : Option<Descriptor>,
+    /// The whole 'SoLd' descriptor, if the layer has one. Things like the placed content's size, resolution, and smart filters are in here.
+    pub desc : Option<Descriptor>,
+    /// The transform from the 'PlLd' block, if the layer has one. Usually the same as [PlacedLayerInfo::transform], but not always.
+    pub plld_transform : Option<[f64; 8]>,
 }
 
-fn read_i32(cursor: &mut SliceCursor) -> Result<i32, String>
-{
-    let mut buf = [0; 4];
-    cursor.read_exact(&mut buf).map_err(|x| x.to_string())?;
-    Ok(i32::from_be_bytes(buf))
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A color as stored in legacy layer effects and annotations: a color space ID and four 16-bit components. Unused components are zero.
+pub struct EffectColor {
+    /// Color space of `color`. See <https://www.adobe.com/devnet-apps/photoshop/fileformatashtml/#50577409_17587>
+    pub color_space : u16,
+    pub color : [u16; 4],
 }
 
-fn read_f64(cursor: &mut SliceCursor) -> Result<f64, String>
-{
-    let mut buf = [0; 8];
-    cursor.read_exact(&mut buf).map_err(|x| x.to_string())?;
-    Ok(f64::from_be_bytes(buf))
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A legacy drop shadow or inner shadow ('dsdw' or 'isdw' in 'lrFX').
+pub struct LegacyShadow {
+    /// 0 or 2. Version 2 adds `native_color`.
+    pub version : u32,
+    /// Blur size in pixels.
+    pub blur : u32,
+    /// Intensity as a percent.
+    pub intensity : u32,
+    /// Light angle in degrees.
+    pub angle : i32,
+    /// Distance in pixels.
+    pub distance : u32,
+    pub color : EffectColor,
+    /// Blend mode, in the same format as [LayerInfo::blend_mode].
+    pub blend_mode : String,
+    pub enabled : bool,
+    /// Whether the angle follows the document's global light angle instead of `angle`.
+    pub use_global_angle : bool,
+    /// Opacity as a percent.
+    pub opacity : u8,
+    /// Only in version 2.
+    pub native_color : Option<EffectColor>,
 }
 
-/// Parses just the frontmost metadata at the start of a PSD file.
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A legacy outer glow or inner glow ('oglw' or 'iglw' in 'lrFX').
+pub struct LegacyGlow {
+    /// 0 or 2. Version 2 adds `invert` (inner glows only) and `native_color`.
+    pub version : u32,
+    /// Blur size in pixels.
+    pub blur : u32,
+    /// Intensity as a percent.
+    pub intensity : u32,
+    pub color : EffectColor,
+    /// Blend mode, in the same format as [LayerInfo::blend_mode].
+    pub blend_mode : String,
+    pub enabled : bool,
+    /// Opacity as a percent.
+    pub opacity : u8,
+    /// Inner glows only: whether the glow comes from the center instead of the edges. Always false in version 0.
+    pub invert : bool,
+    /// Only in version 2.
+    pub native_color : Option<EffectColor>,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A legacy bevel ('bevl' in 'lrFX').
+pub struct LegacyBevel {
+    /// 0 or 2. Version 2 adds `real_highlight_color` and `real_shadow_color`.
+    pub version : u32,
+    /// Light angle in degrees.
+    pub angle : i32,
+    /// Depth in pixels.
+    pub strength : u32,
+    /// Blur size in pixels.
+    pub blur : u32,
+    pub highlight_blend_mode : String,
+    pub shadow_blend_mode : String,
+    pub highlight_color : EffectColor,
+    pub shadow_color : EffectColor,
+    /// 1 = outer bevel, 2 = inner bevel, 3 = emboss, 4 = pillow emboss, 5 = stroke emboss.
+    pub style : u8,
+    /// Highlight opacity as a percent.
+    pub highlight_opacity : u8,
+    /// Shadow opacity as a percent.
+    pub shadow_opacity : u8,
+    pub enabled : bool,
+    /// Whether the angle follows the document's global light angle instead of `angle`.
+    pub use_global_angle : bool,
+    /// 0 = up, 1 = down.
+    pub direction : u8,
+    /// Only in version 2.
+    pub real_highlight_color : Option<EffectColor>,
+    /// Only in version 2.
+    pub real_shadow_color : Option<EffectColor>,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A legacy solid fill ('sofi' in 'lrFX').
+pub struct LegacySolidFill {
+    pub version : u32,
+    /// Blend mode, in the same format as [LayerInfo::blend_mode].
+    pub blend_mode : String,
+    pub color : EffectColor,
+    /// Opacity as a percent.
+    pub opacity : u8,
+    pub enabled : bool,
+    pub native_color : EffectColor,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Legacy binary layer effects ('lrFX'), as written by old versions of Photoshop and some third-party tools. Newer files use [LayerInfo::effects_desc] instead, usually alongside this.
 ///
-/// You will need to use both this and [parse_layer_records].
-pub fn parse_psd_metadata(data : &[u8]) -> Result<PsdMetadata, String>
-{
-    let mut cursor = SliceCursor::new(data);
+/// Each effect is `None` if the block doesn't have it.
+pub struct LegacyEffects {
+    /// From the common state record ('cmnS').
+    pub visible : bool,
+    pub drop_shadow : Option<LegacyShadow>,
+    pub inner_shadow : Option<LegacyShadow>,
+    pub outer_glow : Option<LegacyGlow>,
+    pub inner_glow : Option<LegacyGlow>,
+    pub bevel : Option<LegacyBevel>,
+    pub solid_fill : Option<LegacySolidFill>,
+}
 
-    let signature = read_b4(&mut cursor)?;
-    if signature != [0x38, 0x42, 0x50, 0x53]
+fn read_color(c : &mut SliceCursor) -> Result<EffectColor, PsdError>
+{
+    Ok(EffectColor { color_space : read_u16(c)?, color : [read_u16(c)?, read_u16(c)?, read_u16(c)?, read_u16(c)?] })
+}
+fn read_legacy_effects(cursor : &mut SliceCursor) -> Result<LegacyEffects, PsdError>
+{
+    fn read_blend_mode(c : &mut SliceCursor) -> Result<String, PsdError>
     {
-        return Err("Invalid PSD signature".to_string());
+        let _sig = read_b4(c)?;
+        Ok(String::from_utf8_lossy(&read_b4(c)?).to_string())
     }
-
-    let version = read_u16(&mut cursor)?;
-    if version != 1
+    fn read_bool(c : &mut SliceCursor) -> Result<bool, PsdError>
     {
-        return Err("Unsupported PSD version".to_string());
+        Ok(read_u8(c)? != 0)
     }
-
-    cursor.set_position(cursor.position() + 6);
-
-    let channel_count = read_u16(&mut cursor)?;
-    let height = read_u32(&mut cursor)?;
-    let width = read_u32(&mut cursor)?;
-    let depth = read_u16(&mut cursor)?;
-    let color_mode = read_u16(&mut cursor)?;
-
-    Ok(PsdMetadata
+    fn read_shadow(c : &mut SliceCursor) -> Result<LegacyShadow, PsdError>
     {
-        width,
-        height,
-        channel_count,
-        depth,
-        color_mode,
-    })
-}
-/// Decompress a packbits image data buffer into a vec, appending to the vec.
-///
-/// On success, returns `Ok(size)`.
-///
-/// Panics if there isn't enough data.
-///
-/// PSD files generally use compression on their image data. This decompresses it into a vec, bytewise.
-pub fn append_img_data(cursor : &[u8], output : &mut Vec<u8>, size : u64, h : u64) -> Result<usize, String>
-{
-    let mut _cursor = SliceCursor::new(cursor);
-    let cursor = &mut _cursor;
-    //println!("starting at: {:X}\t", cursor.position());
-    let mode = read_u16(cursor)?;
-    if mode == 0
+        let version = read_u32(c)?;
+        Ok(LegacyShadow
+        {
+            version,
+            blur : read_u32(c)?,
+            intensity : read_u32(c)?,
+            angle : read_i32(c)?,
+            distance : read_u32(c)?,
+            color : read_color(c)?,
+            blend_mode : read_blend_mode(c)?,
+            enabled : read_bool(c)?,
+            use_global_angle : read_bool(c)?,
+            opacity : read_u8(c)?,
+            native_color : if version >= 2 { Some(read_color(c)?) } else { None },
+        })
+    }
+    fn read_glow(c : &mut SliceCursor, inner : bool) -> Result<LegacyGlow, PsdError>
+    {
+        let version = read_u32(c)?;
+        Ok(LegacyGlow
+        {
+            version,
+            blur : read_u32(c)?,
+            intensity : read_u32(c)?,
+            color : read_color(c)?,
+            blend_mode : read_blend_mode(c)?,
+            enabled : read_bool(c)?,
+            opacity : read_u8(c)?,
+            invert : if inner && version >= 2 { read_bool(c)? } else { false },
+            native_color : if version >= 2 { Some(read_color(c)?) } else { None },
+        })
+    }
+    fn read_bevel(c : &mut SliceCursor) -> Result<LegacyBevel, PsdError>
     {
-        cursor.take(size).read_to_end(output).map_err(|x| x.to_string())?;
+        let version = read_u32(c)?;
+        Ok(LegacyBevel
+        {
+            version,
+            angle : read_i32(c)?,
+            strength : read_u32(c)?,
+            blur : read_u32(c)?,
+            highlight_blend_mode : read_blend_mode(c)?,
+            shadow_blend_mode : read_blend_mode(c)?,
+            highlight_color : read_color(c)?,
+            shadow_color : read_color(c)?,
+            style : read_u8(c)?,
+            highlight_opacity : read_u8(c)?,
+            shadow_opacity : read_u8(c)?,
+            enabled : read_bool(c)?,
+            use_global_angle : read_bool(c)?,
+            direction : read_u8(c)?,
+            real_highlight_color : if version >= 2 { Some(read_color(c)?) } else { None },
+            real_shadow_color : if version >= 2 { Some(read_color(c)?) } else { None },
+        })
     }
-    else if mode == 1
+    
+    let mut ret = LegacyEffects { visible : true, ..Default::default() };
+    let _version = read_u16(cursor)?;
+    let count = read_u16(cursor)?;
+    for _ in 0..count
     {
-        let mut c2 = cursor.clone();
-        c2.set_position(c2.position() + h * 2);
-        for _ in 0..h
+        let sig = read_b4(cursor)?;
+        if sig != *b"8BIM"
+        {
+            return Err(PsdError::BadSignature { what : "legacy effect", found : sig, offset : cursor.offset() - 4 });
+        }
+        let key = read_b4(cursor)?;
+        let len = read_u32(cursor)? as u64;
+        // Read from a sub-cursor so that versions with extra trailing data don't throw us off.
+        let mut c = cursor.sub(len)?;
+        match &key
         {
-            //println!("at: {:X} - {:X}\t", cursor.position(), c2.position());
-            let len = read_u16(cursor)?;
-            let start = c2.position();
-            // FIXME: ignore overflow and pad out underflow?
-            while c2.position() < start + len as u64
+            b"cmnS" =>
             {
-                let n = read_u8(&mut c2)? as i8;
-                if n >= 0
-                {
-                    c2.take(n as u64 + 1).read_to_end(output).map_err(|x| x.to_string())?;
-                }
-                else if n != -128
+                let _version = read_u32(&mut c)?;
+                ret.visible = read_bool(&mut c)?;
+            }
+            b"dsdw" => ret.drop_shadow = Some(read_shadow(&mut c)?),
+            b"isdw" => ret.inner_shadow = Some(read_shadow(&mut c)?),
+            b"oglw" => ret.outer_glow = Some(read_glow(&mut c, false)?),
+            b"iglw" => ret.inner_glow = Some(read_glow(&mut c, true)?),
+            b"bevl" => ret.bevel = Some(read_bevel(&mut c)?),
+            b"sofi" =>
+            {
+                ret.solid_fill = Some(LegacySolidFill
                 {
-                    output.extend(core::iter::repeat_n(read_u8(&mut c2)?, (1 - n as i64) as usize));
-                }
+                    version : read_u32(&mut c)?,
+                    blend_mode : read_blend_mode(&mut c)?,
+                    color : read_color(&mut c)?,
+                    opacity : read_u8(&mut c)?,
+                    enabled : read_bool(&mut c)?,
+                    native_color : read_color(&mut c)?,
+                });
             }
+            _ => {}
         }
-        cursor.set_position(c2.position());
-    }
-    else
-    {
-        return Err("unsupported compression format".to_string());
     }
-    Ok(cursor.position() as usize)
+    Ok(ret)
 }
-/// Decompress a packbits image data buffer into a slice, writing into the slice in-place. `stride` can be used to control how far apart to write each byte.
-///
-/// On success, returns `Ok(size)`.
+
+/// Translates legacy layer effects into a descriptor shaped like the one in [LayerInfo::effects_desc], so that code that handles modern effects can handle legacy effects too.
 ///
-/// Panics if the slice isn't big enough or there isn't enough data.
+/// The result has a 'masterFXSwitch' item plus 'DrSh', 'IrSh', 'OrGl', 'IrGl', 'ebbl', and 'SoFi' sub-objects for whichever effects are present, using the same keys and unit types as 'lfx2'. Settings that legacy effects don't have (contours, noise, etc.) are left out rather than guessed. Legacy values map over as-is: intensity becomes 'Ckmt' (spread/choke), capped at 100 like 'Ckmt' is, and bevel strength becomes 'srgR' (depth). RGB, CMYK, and grayscale colors are converted to 'RGBC', 'CMYC', and 'Grsc' objects; colors in other color spaces are left out.
 ///
-/// PSD files generally use compression on their image data. This decompresses it into a slice, bytewise.
-pub fn copy_img_data(cursor : &[u8], output : &mut [u8], stride : usize, size : u64, h : u64) -> Result<usize, String>
+/// The 'Ckmt' and 'srgR' mappings are approximate: they haven't been checked against a file that Photoshop saved with both 'lrFX' and 'lfx2', so Photoshop's own conversion may scale them differently.
+pub fn lrfx_to_lfx2(fx : &LegacyEffects) -> Descriptor
 {
-    let mut _cursor = SliceCursor::new(cursor);
-    let cursor = &mut _cursor;
-    //println!("pos... 0x{:X}", cursor.position());
-    let pos = cursor.position();
-    let mode = read_u16(cursor)?;
-    //println!("size... 0x{:X}", size as usize - 2);
-    if mode == 0
+    fn untf(unit : &str, x : f64) -> DescItem { DescItem::UntF(unit.to_string(), x) }
+    fn blend_mode(key : &str) -> DescItem
+    {
+        let mode = match key
+        {
+            "norm" => "Nrml", "diss" => "Dslv", "dark" => "Drkn", "mul " => "Mltp", "idiv" => "CBrn", "lbrn" => "linearBurn",
+            "dkCl" => "darkerColor", "lite" => "Lghn", "scrn" => "Scrn", "div " => "CDdg", "lddg" => "linearDodge",
+            "lgCl" => "lighterColor", "over" => "Ovrl", "sLit" => "SftL", "hLit" => "HrdL", "vLit" => "vividLight",
+            "lLit" => "linearLight", "pLit" => "pinLight", "hMix" => "hardMix", "diff" => "Dfrn", "smud" => "Xclu",
+            "fsub" => "blendSubtraction", "fdiv" => "blendDivide", "hue " => "H   ", "sat " => "Strt", "colr" => "Clr ",
+            "lum " => "Lmns", x => x,
+        };
+        DescItem::_enum("BlnM".to_string(), mode.to_string())
+    }
+    fn color(c : &EffectColor) -> Option<DescItem>
+    {
+        let [a, b, c_, d] = c.color.map(|x| x as f64);
+        let (class, items) : (&str, Vec<(&str, f64)>) = match c.color_space
+        {
+            0 => ("RGBC", vec!(("Rd  ", a / 257.0), ("Grn ", b / 257.0), ("Bl  ", c_ / 257.0))),
+            // Stored inverted: 0 is full ink.
+            2 => ("CMYC", vec!(("Cyn ", 100.0 - a / 655.35), ("Mgnt", 100.0 - b / 655.35), ("Ylw ", 100.0 - c_ / 655.35), ("Blck", 100.0 - d / 655.35))),
+            // 0 to 10000.
+            8 => ("Grsc", vec!(("Gry ", a / 100.0))),
+            _ => return None,
+        };
+        Some(DescItem::Objc(Box::new(Descriptor(class.to_string(), items.into_iter().map(|(k, v)| (k.to_string(), DescItem::doub(v))).collect()))))
+    }
+    fn push_color(items : &mut Vec<(String, DescItem)>, key : &str, c : &EffectColor)
     {
-        for i in 0..size as usize - 2
+        if let Some(c) = color(c)
         {
-            output[i*stride] = read_u8(cursor)?;
+            items.push((key.to_string(), c));
         }
     }
-    else if mode == 1
+    fn shadow(x : &LegacyShadow, drop : bool) -> Descriptor
     {
-        let mut c2 = cursor.clone();
-        c2.set_position(c2.position() + h * 2);
-        let mut i = 0;
-        let mut j = 2;
-        for _ in 0..h
-        {
-            let _i2 = i;
-            //print!("at: {:X} - {:X}\t", cursor.position(), c2.position());
-            let len = read_u16(cursor)?;
-            j += 2;
-            let start = c2.position();
-            // FIXME: ignore overflow and pad out underflow?
-            while c2.position() - start < len as u64
-            {
-                let n = read_u8(&mut c2)? as i8;
-                j += 1;
-                if n >= 0
-                {
-                    for _ in 0..n as u64 + 1
-                    {
-                        let c = read_u8(&mut c2)?;
-                        if i*stride < output.len()
-                        {
-                            output[i*stride] = c;
-                        }
-                        i += 1;
-                        j += 1;
-                    }
-                }
-                else if n != -128
-                {
-                    let c = read_u8(&mut c2)?;
-                    for _ in 0..1 - n as i64
-                    {
-                        if i*stride < output.len()
-                        {
-                            output[i*stride] = c;
-                        }
-                        i += 1;
-                    }
-                    j += 1;
-                }
-            }
-            //println!("effective w: {}", i - _i2);
-            c2.set_position(start + len as u64);
+        let mut items = vec!();
+        items.push(("enab".to_string(), DescItem::bool(x.enabled)));
+        items.push(("Md  ".to_string(), blend_mode(&x.blend_mode)));
+        push_color(&mut items, "Clr ", &x.color);
+        items.push(("Opct".to_string(), untf("#Prc", x.opacity as f64)));
+        items.push(("uglg".to_string(), DescItem::bool(x.use_global_angle)));
+        items.push(("lagl".to_string(), untf("#Ang", x.angle as f64)));
+        items.push(("Dstn".to_string(), untf("#Pxl", x.distance as f64)));
+        items.push(("Ckmt".to_string(), untf("#Pxl", x.intensity.min(100) as f64)));
+        items.push(("blur".to_string(), untf("#Pxl", x.blur as f64)));
+        if drop
+        {
+            items.push(("layerConceals".to_string(), DescItem::bool(true)));
         }
-        if j != size
+        Descriptor((if drop { "DrSh" } else { "IrSh" }).to_string(), items)
+    }
+    fn glow(x : &LegacyGlow, inner : bool) -> Descriptor
+    {
+        let mut items = vec!();
+        items.push(("enab".to_string(), DescItem::bool(x.enabled)));
+        items.push(("Md  ".to_string(), blend_mode(&x.blend_mode)));
+        push_color(&mut items, "Clr ", &x.color);
+        items.push(("Opct".to_string(), untf("#Prc", x.opacity as f64)));
+        items.push(("Ckmt".to_string(), untf("#Pxl", x.intensity.min(100) as f64)));
+        items.push(("blur".to_string(), untf("#Pxl", x.blur as f64)));
+        if inner
         {
-            return Err("Desynchronized while reading image data".to_string());
+            items.push(("glwS".to_string(), DescItem::_enum("IGSr".to_string(), (if x.invert { "SrcC" } else { "SrcE" }).to_string())));
         }
+        Descriptor((if inner { "IrGl" } else { "OrGl" }).to_string(), items)
     }
-    else
+    
+    let mut ret = vec!();
+    ret.push(("Scl ".to_string(), untf("#Prc", 100.0)));
+    ret.push(("masterFXSwitch".to_string(), DescItem::bool(fx.visible)));
+    if let Some(x) = &fx.drop_shadow
     {
-        return Err(format!("unsupported compression format {} at 0x{:X}", mode, pos));
+        ret.push(("DrSh".to_string(), DescItem::Objc(Box::new(shadow(x, true)))));
     }
-    Ok(size as usize)
-}
-/// Parses the layer records out of a PSD file, producing a bottom-to-top list.
-///
-/// PSD data is compressed and poorly-ordered, so it's very rare to benefit from streaming loading, even for performance. Therefore, to keep things simple, the input is a slice instead of a streaming trait.
-///
-/// PSD doesn't store its layer data in a tree; instead, it uses start-of-group and end-of-group nodes in a list to indicate tree structure.
-///
-/// On failure, returns all the layers that have been parsed *so far, from the bottom* (PSD files are stored bottom-up), and a string describing the error. The incomplete list of parsed layers is unlikely to be useful for any application, but it may be useful for debugging.
-///
-/// You will need to use both this and [parse_psd_metadata].
-pub fn parse_layer_records(data : &[u8]) -> Result<Vec<LayerInfo>, (Vec<LayerInfo>, String)>
-{
-    let mut layers = Vec::new();
-    let ret = parse_layer_records_impl(data, &mut layers);
-    match ret
+    if let Some(x) = &fx.inner_shadow
     {
-        Ok(_) => Ok(layers),
-        Err(err) => Err((layers, err)),
+        ret.push(("IrSh".to_string(), DescItem::Objc(Box::new(shadow(x, false)))));
     }
-}
-fn parse_layer_records_impl(data : &[u8], layers : &mut Vec<LayerInfo>) -> Result<(), String>
-{
-    let metadata = parse_psd_metadata(data)?;
-    if metadata.depth != 8
+    if let Some(x) = &fx.outer_glow
     {
-        return Err("Only PSDs in 8-bit RGB, CMYK, or Grayscale mode are currently supported.".to_string());
+        ret.push(("OrGl".to_string(), DescItem::Objc(Box::new(glow(x, false)))));
     }
-    // TODO
-    if metadata.color_mode != 1 && metadata.color_mode != 3 && metadata.color_mode != 4
+    if let Some(x) = &fx.inner_glow
     {
-        return Err("Only PSDs in 8-bit RGB, CMYK, or Grayscale mode are currently supported.".to_string());
+        ret.push(("IrGl".to_string(), DescItem::Objc(Box::new(glow(x, true)))));
     }
-    
-    let mut cursor = SliceCursor::new(data);
-    cursor.set_position(26);
-
-    let color_mode_length = read_u32(&mut cursor)? as u64;
-    cursor.set_position(cursor.position() + color_mode_length);
-
-    let image_resources_length = read_u32(&mut cursor)? as u64;
-    cursor.set_position(cursor.position() + image_resources_length);
-
-    let layer_mask_info_length = read_u32(&mut cursor)? as u64;
-    let _layer_mask_info_end = cursor.position() + layer_mask_info_length;
-
-    let layer_info_length = read_u32(&mut cursor)? as u64;
-    let _layer_info_end = cursor.position() + layer_info_length;
-    
-    let layer_count = read_u16(&mut cursor)? as i16;
-    let layer_count = layer_count.abs(); // If negative, transparency info exists
-    
-    #[cfg(feature = "debug_spew")]
-    println!("starting at {:X}", cursor.position());
-    
-    let mut idata_c = SliceCursor::new(data);
-    idata_c.set_position(cursor.position());
-    
-    for _i in 0..layer_count
+    if let Some(x) = &fx.bevel
     {
-        //println!("{}", _i);
-        read_i32(&mut idata_c)?;
-        read_i32(&mut idata_c)?;
-        read_i32(&mut idata_c)?;
-        read_i32(&mut idata_c)?;
-        let image_channel_count = read_u16(&mut idata_c)? as u64;
-        idata_c.set_position(idata_c.position() + 6*image_channel_count + 4 + 4 + 4);
-        let idat_len = read_u32(&mut idata_c)? as u64;
-        idata_c.set_position(idata_c.position() + idat_len);
+        let style = match x.style { 1 => "OtrB", 2 => "InrB", 3 => "Embs", 4 => "PlEb", _ => "strokeEmboss" };
+        let mut items = vec!();
+        items.push(("enab".to_string(), DescItem::bool(x.enabled)));
+        items.push(("hglM".to_string(), blend_mode(&x.highlight_blend_mode)));
+        push_color(&mut items, "hglC", &x.highlight_color);
+        items.push(("hglO".to_string(), untf("#Prc", x.highlight_opacity as f64)));
+        items.push(("sdwM".to_string(), blend_mode(&x.shadow_blend_mode)));
+        push_color(&mut items, "sdwC", &x.shadow_color);
+        items.push(("sdwO".to_string(), untf("#Prc", x.shadow_opacity as f64)));
+        items.push(("bvlS".to_string(), DescItem::_enum("BESl".to_string(), style.to_string())));
+        items.push(("uglg".to_string(), DescItem::bool(x.use_global_angle)));
+        items.push(("lagl".to_string(), untf("#Ang", x.angle as f64)));
+        items.push(("srgR".to_string(), untf("#Prc", x.strength as f64)));
+        items.push(("blur".to_string(), untf("#Pxl", x.blur as f64)));
+        items.push(("bvlD".to_string(), DescItem::_enum("BESs".to_string(), (if x.direction == 0 { "In  " } else { "Out " }).to_string())));
+        ret.push(("ebbl".to_string(), DescItem::Objc(Box::new(Descriptor("ebbl".to_string(), items)))));
     }
-
-    for _ in 0..layer_count
+    if let Some(x) = &fx.solid_fill
     {
-        let top = read_i32(&mut cursor)?;
-        let left = read_i32(&mut cursor)?;
-        let bottom = read_i32(&mut cursor)?;
-        let right = read_i32(&mut cursor)?;
-
-        let x = left;
-        let y = top;
-        let w = (right - left) as u32;
-        let h = (bottom - top) as u32;
-        
-        let image_channel_count = read_u16(&mut cursor)?;
-        //println!("chan count {}", image_channel_count);
-        
-        let channel_info_start = cursor.position();
-        
-        cursor.set_position(channel_info_start);
-        let mut image_data_rgba : Vec<u8> = vec![255u8; (w * h * 4) as usize];
-        let mut image_data_k : Vec<u8> = vec!();
-        let mut image_data_mask : Vec<u8> = vec!();
-        
-        let mut _rgba_count = 0;
-        let mut has_g = false;
-        let mut has_b = false;
-        let mut has_a = false;
-        let mut aux_count = 0;
-        
-        let mut cdat_cursor = cursor.clone();
-        
-        let mut has_neg2 = false;
-        let mut has_neg3 = false;
-        for _ in 0..image_channel_count
-        {
-            let channel_id = read_u16(&mut cursor)? as i16;
-            let _channel_length = read_u32(&mut cursor)? as usize;
-            has_neg2 = has_neg2 || channel_id == -2;
-            has_neg3 = has_neg3 || channel_id == -3;
-        }
-        
-        let blend_mode_signature = read_b4(&mut cursor)?;
-        if blend_mode_signature != [0x38, 0x42, 0x49, 0x4D]
-        {
-            return Err("Invalid blend mode magic signature".to_string());
-        }
-
-        let blend_mode_key = read_b4(&mut cursor)?;
-        let blend_mode = String::from_utf8_lossy(&blend_mode_key).to_string();
-
-        let opacity = read_u8(&mut cursor)? as f32 / 255.0;
-        #[cfg(feature = "debug_spew")]
-        println!("opacity: {}", opacity * 100.0);
-        let clipping = read_u8(&mut cursor)?;
-        let flags = read_u8(&mut cursor)?;
-        let _filler = read_u8(&mut cursor)?;
+        let mut items = vec!();
+        items.push(("enab".to_string(), DescItem::bool(x.enabled)));
+        items.push(("Md  ".to_string(), blend_mode(&x.blend_mode)));
+        items.push(("Opct".to_string(), untf("#Prc", x.opacity as f64)));
+        push_color(&mut items, "Clr ", &x.color);
+        ret.push(("SoFi".to_string(), DescItem::Objc(Box::new(Descriptor("SoFi".to_string(), items)))));
+    }
+    Descriptor("null".to_string(), ret)
+}
 
-        let exdat_len = read_u32(&mut cursor)? as u64;
-        let exdat_start = cursor.position();
-        
-        let maskdat_len = read_u32(&mut cursor)? as u64;
-        let maskdat_start = cursor.position();
-        
-        // FIXME: support maskdat_len == 0 case
-        let mtop = read_i32(&mut cursor)?;
-        let mleft = read_i32(&mut cursor)?;
-        let mbottom = read_i32(&mut cursor)?;
-        let mright = read_i32(&mut cursor)?;
-        let mut mask_info = MaskInfo::default();
-        mask_info.x = mleft;
-        mask_info.y = mtop;
-        mask_info.w = (mright - mleft) as u32;
-        mask_info.h = (mbottom - mtop) as u32;
-        mask_info.default_color = read_u8(&mut cursor)?;
-        let mflags = read_u8(&mut cursor)?;
-        mask_info.relative = (mflags & 1) != 0;
-        mask_info.disabled = (mflags & 2) != 0;
-        mask_info.invert = (mflags & 4) != 0;
-        
-        cursor.set_position(maskdat_start + maskdat_len);
-        
-        for _ in 0..image_channel_count
-        {
-            let channel_id = read_u16(&mut cdat_cursor)? as i16;
-            has_g |= channel_id == 1;
-            has_b |= channel_id == 2;
-            has_a |= channel_id == -1;
-            let channel_length = read_u32(&mut cdat_cursor)? as usize;
-            #[cfg(feature = "debug_spew")]
-            println!("channel... {} {} at 0x{:X}", channel_id, channel_length, idata_c.position());
-            if channel_id >= -1 && channel_id <= 2
-            {
-                _rgba_count += 1;
-                let pos = if channel_id >= 0 { channel_id } else { 3 } as usize;
-                #[cfg(feature = "debug_spew")]
-                println!("{} {} {} {}", w, h, pos, channel_length);
-                if channel_length > 2
-                {
-                    let progress = copy_img_data(idata_c.take_rest().buf, &mut image_data_rgba[pos..], 4, channel_length as u64, h as u64)?;
-                    idata_c.pos += progress;
-                }
-                else
-                {
-                    idata_c.set_position(idata_c.position() + 2);
-                }
-            }
-            else if channel_id == 3 // CMYK's K
-            {
-                if channel_length > 2
-                {
-                    let progress = append_img_data(idata_c.take_rest().buf, &mut image_data_k, channel_length as u64, h as u64)?;
-                    idata_c.pos += progress;
-                }
-                else
-                {
-                    idata_c.set_position(idata_c.position() + 2);
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Describes a single layer stack entry.
+///
+/// This data is very unorganized, and you should not use it directly in your application. You should move it out into your own types.
+///
+/// With the `serde_support` feature, this can be serialized, pixel data and all. To cache just the metadata, parse with [ParseOptions::skip_image_data] and [ParseOptions::skip_masks], or call [LayerInfo::take_pixels] first; empty buffers take almost no space. The serde format of this struct is not guaranteed to be stable between minor versions or patch versions; if you need to ensure compatibility between different builds of your code, pin `rawpsd` to a specific exact version.
+///
+/// Returned from [parse_layer_records].
+pub struct LayerInfo {
+    /// Name of the layer. This is the unicode name ('luni') if there is one, otherwise the same as [LayerInfo::name_ascii].
+    pub name : String,
+    /// The layer record's legacy Pascal-string name. Usually the same as [LayerInfo::name], but can be truncated or mangled by codepage conversion.
+    pub name_ascii : String,
+    /// Where the layer's name came from ('lnsr'), as a four-character key. "layr" and "bgnd" mean it was auto-generated, and anything else means the user set it. Blank if the layer doesn't say.
+    pub name_source : String,
+    /// Unique, stable ID of the layer ('lyid'). Survives renames and reordering, and is what [LayerStateInfo::selected_ids] refers to. `None` if the layer doesn't have one, which only happens in very old files.
+    pub layer_id : Option<u32>,
+    /// Normal opacity of the layer.
+    pub opacity : f32,
+    /// Photoshop has separate "opacity" and "fill" sliders.
+    ///
+    /// Fill opacity behaves differently for certain blend modes and when layer effects are involved.
+    pub fill_opacity : f32,
+    /// Blend mode stored as a string. See [BlendModeDocs].
+    pub blend_mode : String,
+    /// Global X position of the layer, based on the top left of the canvas. Can be negative. Ignored for groups.
+    pub x : i32,
+    /// Global Y position of the layer, based on the top left of the canvas. Can be negative. Ignored for groups.
+    pub y : i32,
+    /// Layer image data width.
+    pub w : u32,
+    /// Layer image data height.
+    pub h : u32,
+    /// The layer's rectangle as stored in the file: top, left, bottom, right. Only differs from [LayerInfo::x], [LayerInfo::y], [LayerInfo::w], and [LayerInfo::h] if the rectangle is inside-out (right < left or bottom < top), in which case the width and height are clamped to zero and the layer is treated as empty, like Photoshop does.
+    pub rect_raw : (i32, i32, i32, i32),
+    /// Number of channels in the image data.
+    pub image_channel_count : u16,
+    /// Where each of the layer's channels is stored in the file, in file order. These are filled in even with [ParseOptions::skip_image_data], so that channels can be decoded later with [decode_channel].
+    ///
+    /// This is the layer's channel table as-is: every channel the file lists is here, with its ID and its length in bytes, including channels with IDs that rawpsd doesn't decode (like spot colors) and channels whose IDs are repeated. When there are repeats, the last one is the one that ends up in the image data.
+    pub channel_spans : Vec<ChannelSpan>,
+    /// Four channels worth of image data. Can be RGBA or CMYA, sometimes with fewer channels. This is non-planar: a single full RGBA pixel is 4 consecutive bytes. For indexed color PSDs, see [ParseOptions::expand_indexed].
+    ///
+    /// CMYK values are stored the way Photoshop stores them, inverted: 0 is full ink and 255 is no ink. Missing channels are filled with 255, which is no ink for C, M, and Y, and fully opaque for alpha. The K channel is in [LayerInfo::image_data_k]. See [cmyk_layer_to_rgba8] and [cmyk_layer_to_cmyka8] for turning this into something more usual.
+    ///
+    /// After [LayerInfo::shrink_channels], only the channels that came from the file are left, in the same order; see [LayerInfo::pixel_stride].
+    pub image_data_rgba : Vec<u8>,
+    /// The K channel of CMYK image data, if present, w*h bytes. Inverted like the other CMYK channels: 0 is full ink. Empty if the layer has no K channel, which means no black ink.
+    pub image_data_k : Vec<u8>,
+    /// With [ParseOptions::planar_channels], the color channels (-1 to 3) that have data, in file order, keyed by channel ID. Each one is w*h bytes.
+    pub image_data_channels : Vec<(i16, Vec<u8>)>,
+    /// With [ParseOptions::extra_channels], the channels with IDs other than -3 to 3 that have data, in file order, keyed by channel ID. Each one is w*h bytes.
+    ///
+    /// These are usually spot color channels and saved alpha channels. Their IDs usually count up from right after the document's color channels (4 and up for CMYK), in the same order as [parse_alpha_channels], which has their names and colors.
+    pub extra_channels : Vec<(i16, Vec<u8>)>,
+    /// Whether [LayerInfo::shrink_channels] has dropped the synthesized channels from [LayerInfo::image_data_rgba].
+    pub image_data_shrunk : bool,
+    /// Whether the first channel of the RGBA data came from the PSD file (true) or was synthesized (false).
+    ///
+    /// If the PSD is malformed and has multiple channels of the same type, this flag might be incorrect.
+    /// But on well-formed PSDs, it's always correct.
+    pub image_data_has_r : bool,
+    /// Whether the second channel of the RGBA data came from the PSD file (true) or was synthesized (false). With [ParseOptions::expand_indexed], the palette fills in the second and third channels, so this is the same as [LayerInfo::image_data_has_r].
+    ///
+    /// If the PSD is malformed and has multiple channels of the same type, this flag might be incorrect.
+    /// But on well-formed PSDs, it's always correct.
+    pub image_data_has_g : bool,
+    /// Whether the third channel of the RGBA data came from the PSD file (true) or was synthesized (false). See [LayerInfo::image_data_has_g] for indexed color PSDs.
+    ///
+    /// If the PSD is malformed and has multiple channels of the same type, this flag might be incorrect.
+    /// But on well-formed PSDs, it's always correct.
+    pub image_data_has_b : bool,
+    /// Whether the fourth channel of the RGBA data came from the PSD file (true) or was synthesized (false).
+    ///
+    /// If the PSD is malformed and has multiple channels of the same type, this flag might be incorrect.
+    /// But on well-formed PSDs, it's always correct.
+    pub image_data_has_a : bool,
+    /// Number of channels in the mask image data. They are stored planar (all of ch1, then all of ch2, etc), not interleaved like RGBA.
+    pub mask_channel_count : u16,
+    /// Where is the mask, and how do you interpret it?
+    pub mask_info : MaskInfo,
+    /// Layers that have both a raster mask and a vector mask store the raster mask separately, as the "real" user mask, with its own position and flags. [LayerInfo::mask_info] describes the rasterized vector mask in that case.
+    pub real_mask_info : Option<MaskInfo>,
+    /// The layer's vector mask, if it has one.
+    pub vector_mask : Option<VectorMaskInfo>,
+    // TODO
+    //pub global_mask_opacity : u16,
+    //pub global_mask_kind : u16,
+    /// Actual mask data. Again, this is planar, unlike RGBA.
+    pub image_data_mask : Vec<u8>,
+    /// Image data of the real user mask, if the layer has one. See [LayerInfo::real_mask_info].
+    pub image_data_real_mask : Vec<u8>,
+    /// If this is a group opener, is the group expanded?
+    pub group_expanded : bool,
+    /// Is this a group opener?
+    pub group_opener : bool,
+    /// Is this a group closer?
+    pub group_closer : bool,
+    /// The group's blend mode, from the section divider block ('lsct'), if it has one. Blank otherwise. This is the authoritative blend mode for groups; the layer record's own [LayerInfo::blend_mode] can be "norm" or "pass" even when this isn't.
+    pub group_blend_mode : String,
+    /// Section divider sub-type, from 'lsct'. 0 = normal group, 1 = scene group (affects the animation timeline).
+    pub group_sub_type : u32,
+    /// PSD layers have a "transparency shapes layer" flag. This is the inverse of that flag, i.e. "true" means "the transparency-shapes-layer flag is disabled". This flag state is funny and does weird things to some blend modes and layer effects.
+    pub funny_flag : bool,
+    /// Knockout setting ('knko'). 0 = none, 1 = shallow (punches through to the bottom of the layer's group), 2 = deep (punches through to the background layer, or transparency if there isn't one).
+    pub knockout : u8,
+    /// "Blend interior effects as group" ('infx'). If true, interior effects (inner glow, satin, color/gradient/pattern overlays) are composited onto the layer's content before the layer's blend mode is applied, instead of each effect being blended with the layers below on its own.
+    pub blend_interior_effects : bool,
+    /// "Blend clipped layers as group" ('clbl'). If true, layers clipped to this one are composited together with it first, and the result is blended with the layers below using this layer's blend mode. If false, each clipped layer is blended with the layers below on its own. Defaults to true when the block is absent, like Photoshop.
+    ///
+    /// `LayerInfo::default()` sets this to false, so a default-constructed layer doesn't match a parsed layer without the block; set it to true by hand to match.
+    pub blend_clipped_elements : bool,
+    /// Channels excluded from blending ('brst'), as channel indices. For RGB, 0 = red, 1 = green, 2 = blue. Excluded channels are left as they are in the layers below. Empty if every channel blends.
+    pub blend_restricted_channels : Vec<u32>,
+    /// Is this layer's mask flagged as acting as a global mask ('lmgm')? Global masks apply after layer effects instead of before them, which changes how the mask participates in compositing.
+    pub layer_mask_as_global_mask : bool,
+    /// Same as [LayerInfo::layer_mask_as_global_mask], but for the user mask ('lumm').
+    pub user_mask_as_global_mask : bool,
+    /// Does this layer have the "clipping mask" flag enabled?
+    pub is_clipped : bool,
+    /// Is this layer alpha locked? Also known as "lock transparent pixels".
+    ///
+    /// This is stored in two places: the layer record's flags, and the 'lspf' protection flags. They can disagree in weird files; if 'lspf' is present, it wins.
+    pub is_alpha_locked : bool,
+    /// Are this layer's pixels locked against painting ('lspf')?
+    pub lock_composite : bool,
+    /// Is this layer locked against moving ('lspf')?
+    pub lock_position : bool,
+    /// Is this layer fully locked ('lspf')? Photoshop sets this on its own, separately from the other lock flags.
+    pub lock_all : bool,
+    /// Is this layer visible?
+    pub is_visible : bool,
+    /// The layer record's flags byte, as-is. Bit 0 is [LayerInfo::is_alpha_locked], bit 1 is the inverse of [LayerInfo::is_visible], bit 3 says whether bit 4 is useful, and bit 4 says the layer's pixel data doesn't affect the document's appearance, e.g. for adjustment and fill layers.
+    pub flags_raw : u8,
+    /// Color label of the layer in the layers panel ('lclr'). 0 = none, 1 = red, 2 = orange, 3 = yellow, 4 = green, 5 = blue, 6 = violet, 7 = gray.
+    pub sheet_color : u16,
+    /// Is this an adjustment layer or fill layer, and if so, what kind? Blank if neither. Fill layers are "SoCo" for solid color, "GdFl" for gradients, and "PtFl" for patterns.
+    pub adjustment_type : String,
+    /// Pile of raw, flattened adjustment layer metadata. Search `// Read adjustment data.` in the [source code](https://docs.rs/crate/rawpsd/latest/source/src/lib.rs) and read down from there to see how each adjustment's data is flattened.
+    ///
+    /// [LayerInfo::adjustment] converts this into a typed form.
+    pub adjustment_info : Vec<f32>,
+    /// Some adjustments use class descriptors instead of "hardcoded" data. Those adjustments get their data here.
+    pub adjustment_desc : Option<Descriptor>,
+    /// For pattern fill layers, the ID of the pattern they're filled with, taken from [LayerInfo::adjustment_desc]. Look it up by [Pattern::id] in the list returned by [parse_patterns]. The fill's scale and phase are in the descriptor.
+    pub fill_pattern_id : Option<String>,
+    /// What effects, if any, does this layer have attached to it?
+    pub effects_desc : Option<Descriptor>,
+    /// For shape layers, what the shape is filled with ('vscg'), as a key ("SoCo" for solid color, "GdFl" for gradient, "PtFl" for pattern) and a descriptor holding the fill's settings. The outline is in [LayerInfo::vector_mask].
+    pub vector_fill_desc : Option<(String, Descriptor)>,
+    /// For shape layers, how the shape is stroked ('vstk'), if it has stroke settings.
+    pub vector_stroke_desc : Option<Descriptor>,
+    /// If this is a text layer, its text and styling.
+    pub text_info : Option<TextLayerInfo>,
+    /// Are this layer's effects turned on? Taken from the 'masterFXSwitch' flag in [LayerInfo::effects_desc], or from [LegacyEffects::visible] if the layer only has legacy effects; `None` if the layer doesn't have effects. The descriptor is still the source of truth; this is just for convenience.
+    ///
+    /// Individual effects also have their own 'enab' flags, which this doesn't look at.
+    pub effects_enabled : Option<bool>,
+    /// Legacy binary layer effects ('lrFX'), if the layer has any. Files that have these usually have [LayerInfo::effects_desc] too, which should be preferred.
+    pub legacy_effects : Option<LegacyEffects>,
+    /// Reference point of the layer's effects ('fxrp'), as (x, y) in document coordinates. Pattern overlays and other anchored effects are aligned to this point, which doesn't follow the layer when it's moved.
+    pub reference_point : Option<(f64, f64)>,
+    /// If this is a smart object layer, where its content is placed.
+    pub placed_layer : Option<PlacedLayerInfo>,
+    /// Smart filters on this layer, if it's a smart object layer that has any.
+    pub smart_filters : Option<SmartFilters>,
+    /// Entries of the layer's metadata setting block ('shmd'), in file order. Entries with unknown keys are kept too.
+    pub metadata_settings : Vec<MetadataSetting>,
+    /// The layer's additional info blocks, as their key and their data, in file order. Only filled in with [ParseOptions::keep_unknown_blocks] or [ParseOptions::keep_known_blocks].
+    pub extra_blocks : Vec<(String, Vec<u8>)>,
+    /// Things about this layer that were technically wrong, but not wrong enough to stop parsing. Only ever empty in [strict][ParseOptions::strict] mode, because they become errors instead.
+    pub warnings : Vec<String>,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Where one channel of a layer's image data is stored in the file, for decoding it later with [decode_channel]. See [LayerInfo::channel_spans].
+///
+/// Offsets are relative to the start of the whole file, so they stay valid for as long as the file's bytes do.
+pub struct ChannelSpan {
+    /// Channel ID. 0, 1, and 2 are the color channels (3 is K for CMYK), -1 is transparency, -2 is the user mask, -3 is the real user mask.
+    pub id : i16,
+    /// Compression mode: 0 for uncompressed, 1 for packbits, 2 for ZIP, 3 for ZIP with prediction.
+    pub compression : u16,
+    /// Where the channel's data starts, at its compression mode.
+    pub offset : u64,
+    /// Length of the channel's data, including the compression mode.
+    pub length : u64,
+    /// Width of the channel's image. For mask channels, this is the mask's width, not the layer's.
+    pub w : u32,
+    /// Height of the channel's image, which is also the number of packbits rows.
+    pub h : u32,
+    /// Bits per sample. 16-bit channels are converted to 8 bits by [decode_channel].
+    pub depth : u16,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// The pixel data of a layer, moved out of a [LayerInfo] by [LayerInfo::take_pixels]. See the fields of the same names on [LayerInfo].
+pub struct LayerPixels {
+    pub image_data_rgba : Vec<u8>,
+    pub image_data_k : Vec<u8>,
+    pub image_data_channels : Vec<(i16, Vec<u8>)>,
+    pub extra_channels : Vec<(i16, Vec<u8>)>,
+    pub image_data_mask : Vec<u8>,
+    pub image_data_real_mask : Vec<u8>,
+    /// [SmartFilters::image_data_mask], if the layer has smart filters.
+    pub smart_filter_mask : Vec<u8>,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde_support", derive(Serialize))]
+/// Borrowed view of everything in a [LayerInfo] except for its pixel data. Returned from [LayerInfo::metadata_view].
+///
+/// Useful for printing or serializing layer metadata without dragging megabytes of pixel data along.
+pub struct LayerMetadataView<'a> {
+    pub name : &'a str,
+    pub name_ascii : &'a str,
+    pub name_source : &'a str,
+    pub layer_id : Option<u32>,
+    pub opacity : f32,
+    pub fill_opacity : f32,
+    pub blend_mode : &'a str,
+    pub x : i32,
+    pub y : i32,
+    pub w : u32,
+    pub h : u32,
+    pub rect_raw : (i32, i32, i32, i32),
+    pub image_channel_count : u16,
+    pub channel_spans : &'a [ChannelSpan],
+    pub image_data_shrunk : bool,
+    pub image_data_has_r : bool,
+    pub image_data_has_g : bool,
+    pub image_data_has_b : bool,
+    pub image_data_has_a : bool,
+    pub mask_channel_count : u16,
+    pub mask_info : &'a MaskInfo,
+    pub real_mask_info : &'a Option<MaskInfo>,
+    pub vector_mask : &'a Option<VectorMaskInfo>,
+    pub group_expanded : bool,
+    pub group_opener : bool,
+    pub group_closer : bool,
+    pub group_blend_mode : &'a str,
+    pub group_sub_type : u32,
+    pub funny_flag : bool,
+    pub knockout : u8,
+    pub blend_interior_effects : bool,
+    pub blend_clipped_elements : bool,
+    pub blend_restricted_channels : &'a [u32],
+    pub layer_mask_as_global_mask : bool,
+    pub user_mask_as_global_mask : bool,
+    pub is_clipped : bool,
+    pub is_alpha_locked : bool,
+    pub lock_composite : bool,
+    pub lock_position : bool,
+    pub lock_all : bool,
+    pub is_visible : bool,
+    pub flags_raw : u8,
+    pub sheet_color : u16,
+    pub adjustment_type : &'a str,
+    pub adjustment_info : &'a [f32],
+    pub adjustment_desc : &'a Option<Descriptor>,
+    pub fill_pattern_id : &'a Option<String>,
+    pub effects_desc : &'a Option<Descriptor>,
+    pub vector_fill_desc : &'a Option<(String, Descriptor)>,
+    pub vector_stroke_desc : &'a Option<Descriptor>,
+    pub text_info : &'a Option<TextLayerInfo>,
+    pub effects_enabled : Option<bool>,
+    pub legacy_effects : &'a Option<LegacyEffects>,
+    pub reference_point : Option<(f64, f64)>,
+    pub placed_layer : &'a Option<PlacedLayerInfo>,
+    pub smart_filters : Option<SmartFiltersView<'a>>,
+    pub metadata_settings : &'a [MetadataSetting],
+    pub extra_blocks : &'a [(String, Vec<u8>)],
+    pub warnings : &'a [String],
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde_support", derive(Serialize))]
+/// Borrowed view of everything in a [SmartFilters] except for the filter mask's pixel data. Part of [LayerMetadataView].
+pub struct SmartFiltersView<'a> {
+    pub filters : &'a [SmartFilter],
+    pub enabled : bool,
+    pub mask_info : &'a Option<MaskInfo>,
+    pub mask_color : &'a Option<EffectColor>,
+    pub mask_opacity : u16,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A hue, saturation, and lightness shift, as used by [HueSaturation].
+pub struct HslShift {
+    pub hue : f32,
+    pub saturation : f32,
+    pub lightness : f32,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// One of the six color ranges of a [HueSaturation] adjustment.
+pub struct HueSaturationRange {
+    /// The hues where the falloff starts, the range starts, the range ends, and the falloff ends, in degrees.
+    pub range : [f32; 4],
+    pub shift : HslShift,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Hue/saturation adjustment settings ('hue2').
+pub struct HueSaturation {
+    /// If true, the layer colorizes using [HueSaturation::colorization] instead of shifting colors using [HueSaturation::master].
+    pub colorize : bool,
+    pub colorization : HslShift,
+    pub master : HslShift,
+    /// Reds, yellows, greens, cyans, blues, and magentas, in that order.
+    pub ranges : [HueSaturationRange; 6],
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single channel's levels settings ('levl'). Everything except `gamma` is in the 0.0 to 1.0 range.
+pub struct LevelsRecord {
+    pub input_floor : f32,
+    pub input_ceil : f32,
+    pub output_floor : f32,
+    pub output_ceil : f32,
+    pub gamma : f32,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A color stop of a [GradientMap]. Location and midpoint are in the 0.0 to 1.0 range.
+pub struct GradientColorStop {
+    pub location : f32,
+    pub midpoint : f32,
+    /// Color space of `color`. See <https://www.adobe.com/devnet-apps/photoshop/fileformatashtml/#50577409_17587>
+    pub color_space : u16,
+    /// Color components, in the 0.0 to 1.0 range.
+    pub color : [f32; 4],
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A transparency stop of a [GradientMap]. Everything is in the 0.0 to 1.0 range.
+pub struct GradientTransparencyStop {
+    pub location : f32,
+    pub midpoint : f32,
+    pub opacity : f32,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Gradient map adjustment settings ('grdm').
+pub struct GradientMap {
+    pub reverse : bool,
+    pub dither : bool,
+    /// If true, this is a noise gradient, and the stops don't mean anything. The rest of the fields describe the noise.
+    pub noise : bool,
+    pub color_stops : Vec<GradientColorStop>,
+    pub transparency_stops : Vec<GradientTransparencyStop>,
+    pub smoothness : f32,
+    pub random_seed : f32,
+    pub show_transparency : bool,
+    pub vector_color : bool,
+    pub roughness : f32,
+    pub color_model : u16,
+    pub min_color : [f32; 4],
+    pub max_color : [f32; 4],
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// The filter color of a photo filter adjustment ('phfl').
+pub enum PhotoFilterColor {
+    /// XYZ color, as stored. Written by newer versions of Photoshop.
+    Xyz([f32; 3]),
+    /// A color space and four color components in the 0.0 to 1.0 range. Written by older versions of Photoshop.
+    Color { color_space : u16, color : [f32; 4] },
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// An adjustment layer's or fill layer's settings, in typed form. Returned from [LayerInfo::adjustment].
+///
+/// This holds the same information as [LayerInfo::adjustment_info] and [LayerInfo::adjustment_desc], with names instead of indexes.
+pub enum Adjustment {
+    /// 'post'
+    Posterize { levels : u16 },
+    /// 'nvrt'
+    Invert,
+    /// 'brit', or 'CgEd' in newer files.
+    BrightnessContrast { brightness : f32, contrast : f32, mean : f32, lab_only : bool, legacy : bool, auto : bool },
+    /// 'thrs'
+    Threshold { level : u16 },
+    /// 'hue2'
+    HueSaturation(HueSaturation),
+    /// 'levl'. One record per channel, by channel index: 0 is the composite, then the document's channels in order.
+    Levels(Vec<LevelsRecord>),
+    /// 'curv'. One list of (x, y) points per channel, by channel index: 0 is the composite, then the document's channels in order.
+    Curves(Vec<Vec<(f32, f32)>>),
+    /// 'selc'. Each plate is cyan, magenta, yellow, and black adjustments in the -1.0 to 1.0 range. The first plate is unused, then reds, yellows, greens, cyans, blues, magentas, whites, neutrals, and blacks.
+    SelectiveColor { absolute : bool, plates : [[f32; 4]; 10] },
+    /// 'mixr'. One row per output channel, then the monochrome row. Each row is four source channel amounts and a constant, where 1.0 is 100%.
+    ChannelMixer { monochrome : bool, rows : Vec<[f32; 5]> },
+    /// 'phfl'. Density is in the 0.0 to 1.0 range.
+    PhotoFilter { color : PhotoFilterColor, density : f32, preserve_luminosity : bool },
+    /// 'blnc'. Each entry is cyan-red, magenta-green, and yellow-blue, in the -1.0 to 1.0 range.
+    ColorBalance { shadows : [f32; 3], midtones : [f32; 3], highlights : [f32; 3], preserve_luminosity : bool },
+    /// 'grdm'
+    GradientMap(GradientMap),
+    /// 'blwh'
+    BlackAndWhite { desc : Descriptor },
+    /// 'SoCo'
+    SolidColor { desc : Descriptor },
+    /// 'GdFl'
+    GradientFill { desc : Descriptor },
+    /// 'PtFl'. See [LayerInfo::fill_pattern_id].
+    PatternFill { pattern_id : Option<String>, desc : Descriptor },
+}
+
+impl Adjustment
+{
+    /// Flattens this back into the layout used by [LayerInfo::adjustment_info]. Descriptor-based adjustments don't use [LayerInfo::adjustment_info], so they flatten to nothing.
+    pub fn to_adjustment_info(&self) -> Vec<f32>
+    {
+        let flag = |x : bool| x as u8 as f32;
+        let mut data = vec!();
+        match self
+        {
+            Adjustment::Posterize { levels } => data.push(*levels as f32),
+            Adjustment::Invert => {}
+            Adjustment::BrightnessContrast { brightness, contrast, mean, lab_only, legacy, auto } =>
+                data.extend([*brightness, *contrast, *mean, flag(*lab_only), flag(*legacy), flag(*auto)]),
+            Adjustment::Threshold { level } => data.push(*level as f32),
+            Adjustment::HueSaturation(x) =>
+            {
+                data.push(flag(x.colorize));
+                for shift in [x.colorization, x.master]
+                {
+                    data.extend([shift.hue, shift.saturation, shift.lightness]);
+                }
+                for range in &x.ranges
+                {
+                    data.extend(range.range);
+                    data.extend([range.shift.hue, range.shift.saturation, range.shift.lightness]);
                 }
             }
-            else
+            Adjustment::Levels(records) =>
             {
-                #[cfg(feature = "debug_spew")]
-                println!("mask... {} {} {}", mask_info.w, mask_info.h, channel_length);
-                aux_count += 1;
-                if aux_count > 1
+                for x in records
                 {
-                    idata_c.set_position(idata_c.position() + channel_length as u64);
+                    data.extend([x.input_floor, x.input_ceil, x.output_floor, x.output_ceil, x.gamma]);
                 }
-                else if channel_length > 2
+            }
+            Adjustment::Curves(curves) =>
+            {
+                for curve in curves
                 {
-                    #[cfg(feature = "debug_spew")]
-                    println!("adding mask data...");
-                    let progress = append_img_data(idata_c.take_rest().buf, &mut image_data_mask, channel_length as u64, mask_info.h as u64)?;
-                    idata_c.pos += progress;
+                    data.push(curve.len() as f32);
+                    for (x, y) in curve
+                    {
+                        data.extend([*x, *y]);
+                    }
                 }
-                else
+            }
+            Adjustment::SelectiveColor { absolute, plates } =>
+            {
+                data.push(flag(*absolute));
+                data.extend(plates.as_flattened());
+            }
+            Adjustment::ChannelMixer { monochrome, rows } =>
+            {
+                data.push(flag(*monochrome));
+                data.extend(rows.as_flattened());
+            }
+            Adjustment::PhotoFilter { color, density, preserve_luminosity } =>
+            {
+                match color
                 {
-                    idata_c.set_position(idata_c.position() + 2);
+                    PhotoFilterColor::Xyz(xyz) => data.extend([1.0, 0.0, xyz[0], xyz[1], xyz[2], 0.0]),
+                    PhotoFilterColor::Color { color_space, color } =>
+                    {
+                        data.extend([0.0, *color_space as f32]);
+                        data.extend(color);
+                    }
+                }
+                data.extend([*density, flag(*preserve_luminosity)]);
+            }
+            Adjustment::ColorBalance { shadows, midtones, highlights, preserve_luminosity } =>
+            {
+                for x in [shadows, midtones, highlights]
+                {
+                    data.extend(x);
+                }
+                data.push(flag(*preserve_luminosity));
+            }
+            Adjustment::GradientMap(x) =>
+            {
+                data.extend([flag(x.reverse), flag(x.dither), flag(x.noise)]);
+                data.push(x.color_stops.len() as f32);
+                for stop in &x.color_stops
+                {
+                    data.extend([stop.location, stop.midpoint, stop.color_space as f32]);
+                    data.extend(stop.color);
+                }
+                data.push(x.transparency_stops.len() as f32);
+                for stop in &x.transparency_stops
+                {
+                    data.extend([stop.location, stop.midpoint, stop.opacity]);
                 }
+                data.extend([x.smoothness, x.random_seed, flag(x.show_transparency), flag(x.vector_color), x.roughness, x.color_model as f32]);
+                data.extend(x.min_color);
+                data.extend(x.max_color);
             }
+            Adjustment::BlackAndWhite { .. } | Adjustment::SolidColor { .. } | Adjustment::GradientFill { .. } | Adjustment::PatternFill { .. } => {}
         }
+        data
+    }
+}
+
+impl LayerInfo
+{
+    /// Converts [LayerInfo::adjustment_info] or [LayerInfo::adjustment_desc] into an [Adjustment], depending on [LayerInfo::adjustment_type].
+    ///
+    /// Returns `None` if this isn't an adjustment layer or fill layer, if it's a kind rawpsd doesn't parse, or if its data is malformed.
+    pub fn adjustment(&self) -> Option<Adjustment>
+    {
+        let mut rest = &self.adjustment_info[..];
+        let mut take = |n : usize|
+        {
+            let (x, new_rest) = rest.split_at_checked(n)?;
+            rest = new_rest;
+            Some(x)
+        };
+        let desc = || self.adjustment_desc.clone();
         
-        let blendat_len = read_u32(&mut cursor)? as u64;
-        cursor.set_position(cursor.position() + blendat_len);
-        
-        let mut name_len = read_u8(&mut cursor)?;
-        let orig_namelen = name_len;
-        while (name_len + 1) % 4 != 0
+        Some(match self.adjustment_type.as_str()
         {
-            name_len += 1;
+            "post" => Adjustment::Posterize { levels : take(1)?[0] as u16 },
+            "nvrt" => Adjustment::Invert,
+            "brit" =>
+            {
+                let x = take(6)?;
+                Adjustment::BrightnessContrast { brightness : x[0], contrast : x[1], mean : x[2], lab_only : x[3] != 0.0, legacy : x[4] != 0.0, auto : x[5] != 0.0 }
+            }
+            "thrs" => Adjustment::Threshold { level : take(1)?[0] as u16 },
+            "hue2" =>
+            {
+                let x = take(7 + 6 * 7)?;
+                let shift = |x : &[f32]| HslShift { hue : x[0], saturation : x[1], lightness : x[2] };
+                let mut ranges = [HueSaturationRange::default(); 6];
+                for (range, x) in ranges.iter_mut().zip(x[7..].chunks_exact(7))
+                {
+                    range.range = [x[0], x[1], x[2], x[3]];
+                    range.shift = shift(&x[4..]);
+                }
+                Adjustment::HueSaturation(HueSaturation { colorize : x[0] != 0.0, colorization : shift(&x[1..]), master : shift(&x[4..]), ranges })
+            }
+            "levl" =>
+            {
+                if !self.adjustment_info.len().is_multiple_of(5)
+                {
+                    return None;
+                }
+                Adjustment::Levels(self.adjustment_info.chunks_exact(5).map(|x| LevelsRecord {
+                    input_floor : x[0],
+                    input_ceil : x[1],
+                    output_floor : x[2],
+                    output_ceil : x[3],
+                    gamma : x[4],
+                }).collect())
+            }
+            "curv" =>
+            {
+                let mut curves = vec!();
+                while let Some(n) = take(1)
+                {
+                    let points = take((n[0] as usize).saturating_mul(2))?;
+                    curves.push(points.chunks_exact(2).map(|x| (x[0], x[1])).collect());
+                }
+                Adjustment::Curves(curves)
+            }
+            "selc" =>
+            {
+                let x = take(41)?;
+                let mut plates = [[0.0; 4]; 10];
+                plates.as_flattened_mut().copy_from_slice(&x[1..]);
+                Adjustment::SelectiveColor { absolute : x[0] != 0.0, plates }
+            }
+            "mixr" =>
+            {
+                let monochrome = take(1)?[0] != 0.0;
+                if !rest.len().is_multiple_of(5)
+                {
+                    return None;
+                }
+                Adjustment::ChannelMixer { monochrome, rows : rest.chunks_exact(5).map(|x| [x[0], x[1], x[2], x[3], x[4]]).collect() }
+            }
+            "phfl" =>
+            {
+                let x = take(8)?;
+                let color = if x[0] != 0.0
+                {
+                    PhotoFilterColor::Xyz([x[2], x[3], x[4]])
+                }
+                else
+                {
+                    PhotoFilterColor::Color { color_space : x[1] as u16, color : [x[2], x[3], x[4], x[5]] }
+                };
+                Adjustment::PhotoFilter { color, density : x[6], preserve_luminosity : x[7] != 0.0 }
+            }
+            "blnc" =>
+            {
+                let x = take(10)?;
+                Adjustment::ColorBalance {
+                    shadows : [x[0], x[1], x[2]],
+                    midtones : [x[3], x[4], x[5]],
+                    highlights : [x[6], x[7], x[8]],
+                    preserve_luminosity : x[9] != 0.0,
+                }
+            }
+            "grdm" =>
+            {
+                let mut ret = GradientMap::default();
+                let x = take(3)?;
+                (ret.reverse, ret.dither, ret.noise) = (x[0] != 0.0, x[1] != 0.0, x[2] != 0.0);
+                for _ in 0..take(1)?[0] as usize
+                {
+                    let x = take(7)?;
+                    ret.color_stops.push(GradientColorStop { location : x[0], midpoint : x[1], color_space : x[2] as u16, color : [x[3], x[4], x[5], x[6]] });
+                }
+                for _ in 0..take(1)?[0] as usize
+                {
+                    let x = take(3)?;
+                    ret.transparency_stops.push(GradientTransparencyStop { location : x[0], midpoint : x[1], opacity : x[2] });
+                }
+                let x = take(14)?;
+                ret.smoothness = x[0];
+                ret.random_seed = x[1];
+                ret.show_transparency = x[2] != 0.0;
+                ret.vector_color = x[3] != 0.0;
+                ret.roughness = x[4];
+                ret.color_model = x[5] as u16;
+                ret.min_color = [x[6], x[7], x[8], x[9]];
+                ret.max_color = [x[10], x[11], x[12], x[13]];
+                Adjustment::GradientMap(ret)
+            }
+            "blwh" => Adjustment::BlackAndWhite { desc : desc()? },
+            "SoCo" => Adjustment::SolidColor { desc : desc()? },
+            "GdFl" => Adjustment::GradientFill { desc : desc()? },
+            "PtFl" => Adjustment::PatternFill { pattern_id : self.fill_pattern_id.clone(), desc : desc()? },
+            _ => return None,
+        })
+    }
+    /// Get one of the planes in [LayerInfo::image_data_channels] by channel ID: 0 to 3 for color channels, -1 for alpha. For grayscale documents, `plane(0)` is the gray channel. `None` if the layer has no data for that channel, or if it wasn't parsed with [ParseOptions::planar_channels].
+    pub fn plane(&self, id : i16) -> Option<&[u8]>
+    {
+        self.image_data_channels.iter().rev().find(|x| x.0 == id).map(|x| &x.1[..])
+    }
+    /// Crops [LayerInfo::image_data_rgba] to the canvas. Returns the part of the layer that's on the canvas, as (x, y, w, h) in canvas coordinates, and its pixels, tightly packed with [LayerInfo::pixel_stride] bytes per pixel.
+    ///
+    /// If the layer is entirely off the canvas, or empty, this returns `(0, 0, 0, 0, vec!())`. If the layer's pixel data isn't there (like with [ParseOptions::skip_image_data] or [ParseOptions::planar_channels]), the rectangle is still returned, but the buffer is empty.
+    pub fn crop_to_canvas(&self, canvas : &PsdMetadata) -> (i32, i32, u32, u32, Vec<u8>)
+    {
+        crop_plane(&self.image_data_rgba, (self.x, self.y, self.w, self.h), self.pixel_stride(), canvas)
+    }
+    /// Same as [LayerInfo::crop_to_canvas], but for [LayerInfo::image_data_mask], against the mask's own rectangle from [LayerInfo::mask_info]. One byte per pixel.
+    pub fn crop_mask_to_canvas(&self, canvas : &PsdMetadata) -> (i32, i32, u32, u32, Vec<u8>)
+    {
+        let info = &self.mask_info;
+        crop_plane(&self.image_data_mask, (info.x, info.y, info.w, info.h), 1, canvas)
+    }
+    /// Number of bytes per pixel in [LayerInfo::image_data_rgba]: 4, or after [LayerInfo::shrink_channels], one for each channel that came from the file.
+    pub fn pixel_stride(&self) -> usize
+    {
+        if !self.image_data_shrunk
+        {
+            return 4;
         }
-        let mut name = vec![0; name_len as usize];
-        cursor.read_exact(&mut name[..]).map_err(|x| x.to_string())?;
-        let name = String::from_utf8_lossy(&name[..orig_namelen as usize]).to_string();
-
-        let mut layer = LayerInfo {
-            name,
-            opacity,
-            fill_opacity : 1.0,
+        [self.image_data_has_r, self.image_data_has_g, self.image_data_has_b, self.image_data_has_a].into_iter().filter(|x| *x).count()
+    }
+    /// Rewrites [LayerInfo::image_data_rgba] to only hold the channels that came from the file, according to the `image_data_has_*` flags, still interleaved and in the same order. For example, a grayscale layer with transparency goes from 4 bytes per pixel to 2. Does nothing if it's already been done.
+    ///
+    /// Sets [LayerInfo::image_data_shrunk]. Use [LayerInfo::pixel_stride] to find out how many bytes are left per pixel. See also [ParseOptions::shrink_channels].
+    pub fn shrink_channels(&mut self)
+    {
+        if self.image_data_shrunk
+        {
+            return;
+        }
+        let keep = [self.image_data_has_r, self.image_data_has_g, self.image_data_has_b, self.image_data_has_a];
+        let mut n = 0;
+        for i in 0..self.image_data_rgba.len() / 4
+        {
+            for (c, keep) in keep.iter().enumerate()
+            {
+                if *keep
+                {
+                    self.image_data_rgba[n] = self.image_data_rgba[i * 4 + c];
+                    n += 1;
+                }
+            }
+        }
+        self.image_data_rgba.truncate(n);
+        self.image_data_rgba.shrink_to_fit();
+        self.image_data_shrunk = true;
+    }
+    /// Moves the pixel data out of this layer, leaving empty buffers behind. Everything else is left alone.
+    pub fn take_pixels(&mut self) -> LayerPixels
+    {
+        LayerPixels {
+            image_data_rgba : core::mem::take(&mut self.image_data_rgba),
+            image_data_k : core::mem::take(&mut self.image_data_k),
+            image_data_channels : core::mem::take(&mut self.image_data_channels),
+            extra_channels : core::mem::take(&mut self.extra_channels),
+            image_data_mask : core::mem::take(&mut self.image_data_mask),
+            image_data_real_mask : core::mem::take(&mut self.image_data_real_mask),
+            smart_filter_mask : self.smart_filters.as_mut().map(|x| core::mem::take(&mut x.image_data_mask)).unwrap_or_default(),
+        }
+    }
+    /// Borrows everything except the pixel data. See [LayerMetadataView].
+    pub fn metadata_view(&self) -> LayerMetadataView<'_>
+    {
+        LayerMetadataView {
+            name : &self.name,
+            name_ascii : &self.name_ascii,
+            name_source : &self.name_source,
+            layer_id : self.layer_id,
+            opacity : self.opacity,
+            fill_opacity : self.fill_opacity,
+            blend_mode : &self.blend_mode,
+            x : self.x,
+            y : self.y,
+            w : self.w,
+            h : self.h,
+            rect_raw : self.rect_raw,
+            image_channel_count : self.image_channel_count,
+            channel_spans : &self.channel_spans,
+            image_data_shrunk : self.image_data_shrunk,
+            image_data_has_r : self.image_data_has_r,
+            image_data_has_g : self.image_data_has_g,
+            image_data_has_b : self.image_data_has_b,
+            image_data_has_a : self.image_data_has_a,
+            mask_channel_count : self.mask_channel_count,
+            mask_info : &self.mask_info,
+            real_mask_info : &self.real_mask_info,
+            vector_mask : &self.vector_mask,
+            group_expanded : self.group_expanded,
+            group_opener : self.group_opener,
+            group_closer : self.group_closer,
+            group_blend_mode : &self.group_blend_mode,
+            group_sub_type : self.group_sub_type,
+            funny_flag : self.funny_flag,
+            knockout : self.knockout,
+            blend_interior_effects : self.blend_interior_effects,
+            blend_clipped_elements : self.blend_clipped_elements,
+            blend_restricted_channels : &self.blend_restricted_channels,
+            layer_mask_as_global_mask : self.layer_mask_as_global_mask,
+            user_mask_as_global_mask : self.user_mask_as_global_mask,
+            is_clipped : self.is_clipped,
+            is_alpha_locked : self.is_alpha_locked,
+            lock_composite : self.lock_composite,
+            lock_position : self.lock_position,
+            lock_all : self.lock_all,
+            is_visible : self.is_visible,
+            flags_raw : self.flags_raw,
+            sheet_color : self.sheet_color,
+            adjustment_type : &self.adjustment_type,
+            adjustment_info : &self.adjustment_info,
+            adjustment_desc : &self.adjustment_desc,
+            fill_pattern_id : &self.fill_pattern_id,
+            effects_desc : &self.effects_desc,
+            vector_fill_desc : &self.vector_fill_desc,
+            vector_stroke_desc : &self.vector_stroke_desc,
+            text_info : &self.text_info,
+            effects_enabled : self.effects_enabled,
+            legacy_effects : &self.legacy_effects,
+            reference_point : self.reference_point,
+            placed_layer : &self.placed_layer,
+            smart_filters : self.smart_filters.as_ref().map(|x| SmartFiltersView {
+                filters : &x.filters,
+                enabled : x.enabled,
+                mask_info : &x.mask_info,
+                mask_color : &x.mask_color,
+                mask_opacity : x.mask_opacity,
+            }),
+            metadata_settings : &self.metadata_settings,
+            extra_blocks : &self.extra_blocks,
+            warnings : &self.warnings,
+        }
+    }
+}
+
+fn read_u8(cursor: &mut SliceCursor) -> Result<u8, PsdError>
+{
+    let mut buf = [0; 1];
+    cursor.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(cursor: &mut SliceCursor) -> Result<u16, PsdError>
+{
+    let mut buf = [0; 2];
+    cursor.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(cursor: &mut SliceCursor) -> Result<u32, PsdError>
+{
+    let mut buf = [0; 4];
+    cursor.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_b4(cursor: &mut SliceCursor) -> Result<[u8; 4], PsdError>
+{
+    let mut buf = [0; 4];
+    cursor.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_i32(cursor: &mut SliceCursor) -> Result<i32, PsdError>
+{
+    let mut buf = [0; 4];
+    cursor.read_exact(&mut buf)?;
+    Ok(i32::from_be_bytes(buf))
+}
+
+fn read_f64(cursor: &mut SliceCursor) -> Result<f64, PsdError>
+{
+    let mut buf = [0; 8];
+    cursor.read_exact(&mut buf)?;
+    Ok(f64::from_be_bytes(buf))
+}
+
+/// Reads a length-prefixed UTF-16 string, the way 'luni' and most other unicode strings in PSD files are stored. Does not trim trailing nulls.
+fn read_unicode_string(cursor : &mut SliceCursor) -> Result<String, PsdError>
+{
+    let len = read_u32(cursor)? as u64;
+    // Bounds-check the whole string up front so that bogus lengths fail instead of allocating.
+    let text = cursor.read_slice(len * 2)?.chunks_exact(2).map(|x| u16::from_be_bytes([x[0], x[1]])).collect::<Vec<_>>();
+    Ok(String::from_utf16_lossy(&text))
+}
+/// Width and height of a rectangle, clamped to zero if it's inside-out.
+fn rect_size(top : i32, left : i32, bottom : i32, right : i32) -> (u32, u32)
+{
+    let w = right as i64 - left as i64;
+    let h = bottom as i64 - top as i64;
+    (w.max(0) as u32, h.max(0) as u32)
+}
+fn rect_inside_out(rect : (i32, i32, i32, i32)) -> bool
+{
+    rect.3 < rect.1 || rect.2 < rect.0
+}
+/// Fills `ret` with `w * h * bytes` copies of `fill`, reusing its allocation if it's big enough. Returns `None` if the dimensions are too large to allocate.
+fn try_pixel_buffer_in(mut ret : Vec<u8>, w : u64, h : u64, bytes : u64, fill : u8) -> Option<Vec<u8>>
+{
+    let len = w.checked_mul(h)?.checked_mul(bytes)?;
+    let len = usize::try_from(len).ok()?;
+    ret.clear();
+    ret.try_reserve_exact(len).ok()?;
+    ret.resize(len, fill);
+    Some(ret)
+}
+/// Allocates a `w * h * bytes` buffer filled with `fill`, or fails if the dimensions are too large to allocate.
+fn pixel_buffer(w : u64, h : u64, bytes : u64, fill : u8) -> Result<Vec<u8>, PsdError>
+{
+    pixel_buffer_in(vec!(), w, h, bytes, fill)
+}
+/// Same as [pixel_buffer], but reuses `buf`'s allocation if it's big enough. See [try_pixel_buffer_in].
+fn pixel_buffer_in(buf : Vec<u8>, w : u64, h : u64, bytes : u64, fill : u8) -> Result<Vec<u8>, PsdError>
+{
+    try_pixel_buffer_in(buf, w, h, bytes, fill).ok_or_else(|| PsdError::Malformed { message : format!("Image dimensions {}x{} are too large", w, h), offset : None })
+}
+/// Takes the smallest buffer out of `pool` that can hold `len` bytes without reallocating, emptied. If there isn't one, returns a new empty buffer.
+fn take_buffer(pool : &mut Vec<Vec<u8>>, len : u64) -> Vec<u8>
+{
+    let best = pool.iter().enumerate().filter(|(_, x)| x.capacity() as u64 >= len).min_by_key(|(_, x)| x.capacity()).map(|(i, _)| i);
+    let mut ret = best.map(|i| pool.swap_remove(i)).unwrap_or_default();
+    ret.clear();
+    ret
+}
+#[cfg(test)]
+fn read_descriptor(c : &mut SliceCursor) -> Result<Descriptor, PsdError>
+{
+    read_descriptor_limited(c, 256)
+}
+/// Reads a descriptor without its version header, nested no deeper than the given limit, or 256 at most. See [ParseLimits::max_descriptor_depth].
+fn read_descriptor_limited(c : &mut SliceCursor, max_depth : usize) -> Result<Descriptor, PsdError>
+{
+    read_descriptor_impl(c, 0, max_depth.min(256))
+}
+/// Reads a descriptor with its version header in front. Descriptors are always version 16; for anything else, this returns `None` without reading further, since there's no telling what the data is. Blocks that hold a descriptor get skipped in that case, like unknown blocks are.
+fn read_versioned_descriptor(c : &mut SliceCursor, max_depth : usize) -> Result<Option<Descriptor>, PsdError>
+{
+    if read_u32(c)? != 16
+    {
+        return Ok(None);
+    }
+    read_descriptor_limited(c, max_depth).map(Some)
+}
+fn read_descriptor_impl(c : &mut SliceCursor, depth : usize, max_depth : usize) -> Result<Descriptor, PsdError>
+{
+    if depth > max_depth
+    {
+        return Err(if max_depth < 256 { PsdError::LimitExceeded("max_descriptor_depth") } else { PsdError::malformed("Descriptor nested too deeply", c.offset()) });
+    }
+    // skip name. usually/often blank
+    let n = read_u32(c)? as u64;
+    c.set_position(c.position() + n * 2);
+
+    let mut idlen = read_u32(c)?;
+    if idlen == 0 { idlen = 4; }
+    let id = String::from_utf8_lossy(c.read_slice(idlen as u64)?).to_string();
+
+    let mut data = vec!();
+
+    let itemcount = read_u32(c)?;
+
+    for _ in 0..itemcount
+    {
+        let mut namelen = read_u32(c)?;
+        if namelen == 0 { namelen = 4; }
+        let name = String::from_utf8_lossy(c.read_slice(namelen as u64)?).to_string();
+
+        /// Reads an ID that's either length-prefixed, or four bytes long if the length is zero.
+        fn read_id(c : &mut SliceCursor) -> Result<String, PsdError>
+        {
+            let mut len = read_u32(c)?;
+            if len == 0 { len = 4; }
+            Ok(String::from_utf8_lossy(c.read_slice(len as u64)?).to_string())
+        }
+        /// Returns an error message instead if the reference type is unknown, since there's no way to skip over it.
+        fn read_ref(c : &mut SliceCursor) -> Result<Result<DescRef, String>, PsdError>
+        {
+            let start = c.offset();
+            let typ = read_b4(c)?;
+            if &typ == b"Idnt" || &typ == b"indx"
+            {
+                let n = read_i32(c)?;
+                return Ok(Ok(if &typ == b"Idnt" { DescRef::Idnt(n) } else { DescRef::indx(n) }));
+            }
+            if ![b"prop", b"Clss", b"Enmr", b"rele", b"name"].contains(&&typ)
+            {
+                return Ok(Err(format!("Unknown descriptor reference type '{}' at 0x{:X}", String::from_utf8_lossy(&typ), start)));
+            }
+            let name = read_unicode_string(c)?.trim_end_matches('\0').to_string();
+            let class = read_id(c)?;
+            Ok(Ok(match &typ
+            {
+                b"prop" => DescRef::prop(class, read_id(c)?),
+                b"Clss" => DescRef::Clss(class),
+                b"Enmr" => DescRef::Enmr(class, read_id(c)?, read_id(c)?),
+                b"rele" => DescRef::rele(class, read_i32(c)?),
+                _ => DescRef::name(class, name),
+            }))
+        }
+        fn read_key(c : &mut SliceCursor, depth : usize, max_depth : usize) -> Result<DescItem, PsdError>
+        {
+            let start = c.offset();
+            let id = read_b4(c)?;
+            let id = String::from_utf8_lossy(&id).to_string();
+
+            Ok(match id.as_str()
+            {
+                "long" => DescItem::long(read_i32(c)?),
+                "doub" => DescItem::doub(read_f64(c)?),
+                "Objc" => DescItem::Objc(Box::new(read_descriptor_impl(c, depth + 1, max_depth)?)),
+                "bool" => DescItem::bool(read_u8(c)? != 0),
+                "TEXT" =>
+                {
+                    DescItem::TEXT(read_unicode_string(c)?.trim_end_matches('\0').to_string())
+                }
+                "UntF" =>
+                {
+                    let typ = read_b4(c)?;
+                    let typ = String::from_utf8_lossy(&typ).to_string();
+
+                    DescItem::UntF(typ, read_f64(c)?)
+                }
+                "enum" =>
+                {
+                    let name1 = read_id(c)?;
+                    let name2 = read_id(c)?;
+                    DescItem::_enum(name1, name2)
+                }
+                "tdta" =>
+                {
+                    let len = read_u32(c)? as u64;
+                    DescItem::tdta(c.read_slice(len)?.to_vec())
+                }
+                "VlLs" =>
+                {
+                    let len = read_u32(c)?;
+                    let mut ret = vec!();
+                    for _ in 0..len
+                    {
+                        let item = read_key(c, depth + 1, max_depth)?;
+                        let truncated = item.is_truncated();
+                        ret.push(item);
+                        if truncated
+                        {
+                            break;
+                        }
+                    }
+                    DescItem::VlLs(ret)
+                }
+                "comp" => DescItem::comp(((read_u32(c)? as u64) << 32 | read_u32(c)? as u64) as i64),
+                "GlbO" => DescItem::GlbO(Box::new(read_descriptor_impl(c, depth + 1, max_depth)?)),
+                "type" | "GlbC" | "Clss" =>
+                {
+                    let name = read_unicode_string(c)?.trim_end_matches('\0').to_string();
+                    DescItem::_type(name, read_id(c)?)
+                }
+                "obj " =>
+                {
+                    let len = read_u32(c)?;
+                    let mut ret = vec!();
+                    for _ in 0..len
+                    {
+                        match read_ref(c)?
+                        {
+                            Ok(x) => ret.push(x),
+                            Err(message) => return Ok(DescItem::Err(message)),
+                        }
+                    }
+                    DescItem::obj(ret)
+                }
+                "alis" =>
+                {
+                    let len = read_u32(c)? as u64;
+                    DescItem::alis(c.read_slice(len)?.to_vec())
+                }
+                "UnFl" =>
+                {
+                    let typ = read_b4(c)?;
+                    let typ = String::from_utf8_lossy(&typ).to_string();
+                    let len = read_u32(c)? as u64;
+                    let data = c.read_slice(len * 8)?;
+                    DescItem::UnFl(typ, data.chunks_exact(8).map(|x| f64::from_be_bytes(x.try_into().unwrap())).collect())
+                }
+                "ObAr" =>
+                {
+                    let len = read_u32(c)?;
+                    DescItem::ObAr(len, Box::new(read_descriptor_impl(c, depth + 1, max_depth)?))
+                }
+                _ =>
+                {
+                    #[cfg(feature = "debug_spew")]
+                    println!("!!! errant descriptor subobject type... {}", id);
+                    DescItem::Err(format!("Unknown descriptor item type '{}' at 0x{:X}", id, start))
+                }
+            })
+        }
+
+        // Unknown items can't be skipped, so stop here and let the caller resynchronize.
+        let item = read_key(c, depth, max_depth)?;
+        let truncated = item.is_truncated();
+        data.push((name, item));
+        if truncated
+        {
+            break;
+        }
+    }
+
+    Ok(Descriptor(id, data))
+}
+
+/// Parses just the frontmost metadata at the start of a PSD file.
+///
+/// You will need to use both this and [parse_layer_records].
+pub fn parse_psd_metadata(data : &[u8]) -> Result<PsdMetadata, PsdError>
+{
+    let mut cursor = SliceCursor::new(data);
+
+    let signature = read_b4(&mut cursor)?;
+    if signature != [0x38, 0x42, 0x50, 0x53]
+    {
+        return Err(PsdError::BadSignature { what : "PSD", found : signature, offset : 0 });
+    }
+
+    let version = read_u16(&mut cursor)?;
+    if version != 1
+    {
+        return Err(PsdError::UnsupportedVersion { what : "PSD", version : version as u32, offset : 4 });
+    }
+
+    cursor.set_position(cursor.position() + 6);
+
+    let channel_count = read_u16(&mut cursor)?;
+    let height = read_u32(&mut cursor)?;
+    let width = read_u32(&mut cursor)?;
+    let depth = read_u16(&mut cursor)?;
+    let color_mode = read_u16(&mut cursor)?;
+    
+    // Only indexed color documents need anything past the header, so header-only data still parses for everything else.
+    let mut palette = None;
+    if color_mode == 2 && read_u32(&mut cursor)? >= 768
+    {
+        // Stored planar: all the reds, then all the greens, then all the blues.
+        let planes = cursor.read_slice(768)?;
+        palette = Some((0..256).map(|i| [planes[i], planes[i + 256], planes[i + 512]]).collect());
+    }
+
+    Ok(PsdMetadata
+    {
+        width,
+        height,
+        channel_count,
+        depth,
+        color_mode,
+        palette,
+    })
+}
+/// Byte offsets of the top-level sections of a PSD file. Each offset points just past the section's length field.
+#[derive(Clone, Debug, Default)]
+struct SectionOffsets
+{
+    psb : bool,
+    color_mode_data : u64,
+    image_resources : u64,
+    layer_and_mask_info : u64,
+    layer_info_end : u64,
+    layer_and_mask_info_end : u64,
+    image_data : u64,
+}
+
+fn read_section_len(cursor : &mut SliceCursor, long : bool) -> Result<u64, PsdError>
+{
+    if long
+    {
+        Ok(((read_u32(cursor)? as u64) << 32) | read_u32(cursor)? as u64)
+    }
+    else
+    {
+        Ok(read_u32(cursor)? as u64)
+    }
+}
+
+fn find_sections(data : &[u8]) -> Result<SectionOffsets, PsdError>
+{
+    let mut cursor = SliceCursor::new(data);
+    let signature = read_b4(&mut cursor)?;
+    if signature != [0x38, 0x42, 0x50, 0x53]
+    {
+        return Err(PsdError::BadSignature { what : "PSD", found : signature, offset : 0 });
+    }
+    let psb = read_u16(&mut cursor)? == 2;
+    cursor.set_position(26);
+    
+    let mut ret = SectionOffsets { psb, ..Default::default() };
+    
+    let color_mode_length = read_u32(&mut cursor)? as u64;
+    ret.color_mode_data = cursor.position();
+    cursor.set_position(cursor.position() + color_mode_length);
+    
+    let image_resources_length = read_u32(&mut cursor)? as u64;
+    ret.image_resources = cursor.position();
+    cursor.set_position(cursor.position() + image_resources_length);
+    
+    let layer_mask_info_length = read_section_len(&mut cursor, psb)?;
+    ret.layer_and_mask_info = cursor.position();
+    ret.layer_and_mask_info_end = cursor.position().saturating_add(layer_mask_info_length);
+    
+    if layer_mask_info_length != 0
+    {
+        let layer_info_length = read_section_len(&mut cursor, psb)?;
+        ret.layer_info_end = cursor.position().saturating_add(layer_info_length);
+    }
+    else
+    {
+        ret.layer_info_end = ret.layer_and_mask_info_end;
+    }
+    ret.image_data = ret.layer_and_mask_info_end;
+    
+    if ret.layer_and_mask_info_end > data.len() as u64 || ret.layer_info_end > ret.layer_and_mask_info_end
+    {
+        return Err(PsdError::Malformed { message : "Section lengths run past the end of the file".to_string(), offset : None });
+    }
+    
+    Ok(ret)
+}
+
+/// Keys of tagged blocks that have 8-byte lengths in PSB files.
+const LONG_LENGTH_KEYS : [&[u8; 4]; 13] = [b"LMsk", b"Lr16", b"Lr32", b"Layr", b"Mt16", b"Mt32", b"Mtrn", b"Alph", b"FMsk", b"lnk2", b"FEid", b"FXid", b"PxSD"];
+
+/// Parses the document-level tagged blocks ("additional layer information") that live after the layer records and global layer mask info, returning them as (key, data) pairs in file order.
+///
+/// These are things like patterns ('Patt'), text engine data ('Txt2'), and embedded linked files ('lnkD', 'lnk2', 'lnk3'). rawpsd doesn't interpret them here; see the feature-specific functions for that.
+///
+/// Unlike the tagged blocks attached to individual layers, each of these blocks is padded out to a multiple of 4 bytes.
+pub fn parse_document_blocks(data : &[u8]) -> Result<Vec<(String, Vec<u8>)>, PsdError>
+{
+    Ok(document_block_spans(data)?.into_iter().map(|(key, start, len)| (key, data[start as usize..(start + len) as usize].to_vec())).collect())
+}
+/// Same as [parse_document_blocks], but returns the (start, length) of each block's data instead of copying it.
+fn document_block_spans(data : &[u8]) -> Result<Vec<(String, u64, u64)>, PsdError>
+{
+    let sections = find_sections(data)?;
+    let mut cursor = SliceCursor::new(data);
+    cursor.set_position(sections.layer_info_end);
+    
+    let mut ret = vec!();
+    if sections.layer_info_end + 4 > sections.layer_and_mask_info_end
+    {
+        return Ok(ret);
+    }
+    
+    let global_mask_length = read_u32(&mut cursor)? as u64;
+    cursor.set_position(cursor.position() + global_mask_length);
+    
+    let end = sections.layer_and_mask_info_end;
+    while cursor.position() + 12 <= end
+    {
+        let sig = read_b4(&mut cursor)?;
+        if sig != *b"8BIM" && sig != *b"8B64"
+        {
+            // Some writers pad the end of the section out with zeroes.
+            if data[cursor.position() as usize - 4..end as usize].iter().all(|x| *x == 0)
+            {
+                break;
+            }
+            return Err(PsdError::BadSignature { what : "document-level tagged block", found : sig, offset : cursor.position() - 4 });
+        }
+        let key = read_b4(&mut cursor)?;
+        let long = sig == *b"8B64" || (sections.psb && LONG_LENGTH_KEYS.contains(&&key));
+        let len = read_section_len(&mut cursor, long)?;
+        let start = cursor.position();
+        let key = String::from_utf8_lossy(&key).to_string();
+        if start.saturating_add(len) > end
+        {
+            return Err(PsdError::Malformed { message : format!("Document-level tagged block '{}' runs past the end of its section", key), offset : Some(start) });
+        }
+        ret.push((key, start, len));
+        
+        cursor.set_position((start + len.div_ceil(4) * 4).min(end));
+    }
+    
+    Ok(ret)
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single raw image resource block.
+///
+/// Returned from [parse_image_resources].
+pub struct ImageResource {
+    /// Signature of the block. Almost always "8BIM", but some third-party tools write other signatures, like "MeSa" or "PHUT".
+    pub signature : String,
+    /// Resource ID. See <https://www.adobe.com/devnet-apps/photoshop/fileformatashtml/#50577409_38034> for what they all mean.
+    pub id : u16,
+    /// Name of the resource. Almost always blank.
+    pub name : String,
+    /// The resource's data, as-is.
+    pub data : Vec<u8>,
+}
+
+/// Parses the image resources section (the document-wide metadata between the color mode data and the layer and mask info) into a list of raw resource blocks, in file order.
+///
+/// rawpsd doesn't interpret them here. If you only need a single resource, [get_image_resource] is cheaper, because it doesn't copy anything.
+pub fn parse_image_resources(data : &[u8]) -> Result<Vec<ImageResource>, PsdError>
+{
+    Ok(image_resource_spans(data)?.into_iter().map(|(mut resource, start, len)|
+    {
+        resource.data = data[start as usize..(start + len) as usize].to_vec();
+        resource
+    }).collect())
+}
+/// Finds the data of the first image resource with the given ID, without copying it. Returns `Ok(None)` if there is no such resource.
+pub fn get_image_resource(data : &[u8], id : u16) -> Result<Option<&[u8]>, PsdError>
+{
+    Ok(image_resource_spans(data)?.into_iter().find(|x| x.0.id == id).map(|(_, start, len)| &data[start as usize..(start + len) as usize]))
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Document resolution, from image resource 1005.
+///
+/// Returned from [parse_resolution_info].
+pub struct ResolutionInfo {
+    /// Horizontal resolution in pixels per inch. Always stored in pixels per inch, even if `h_res_unit` says to display it in pixels per centimeter.
+    pub h_res : f64,
+    /// Unit to display the horizontal resolution in. 1 = pixels per inch, 2 = pixels per centimeter.
+    pub h_res_unit : u16,
+    /// Unit to display the width in. 1 = inches, 2 = centimeters, 3 = points, 4 = picas, 5 = columns.
+    pub width_unit : u16,
+    /// Vertical resolution in pixels per inch. Always stored in pixels per inch, even if `v_res_unit` says to display it in pixels per centimeter.
+    pub v_res : f64,
+    /// Unit to display the vertical resolution in. 1 = pixels per inch, 2 = pixels per centimeter.
+    pub v_res_unit : u16,
+    /// Unit to display the height in. 1 = inches, 2 = centimeters, 3 = points, 4 = picas, 5 = columns.
+    pub height_unit : u16,
+}
+
+/// Parses the document resolution out of image resource 1005. Returns `Ok(None)` if the file doesn't have one.
+pub fn parse_resolution_info(data : &[u8]) -> Result<Option<ResolutionInfo>, PsdError>
+{
+    let Some(resource) = get_image_resource(data, 1005)? else { return Ok(None) };
+    let mut cursor = SliceCursor::within(data, resource);
+    // 16.16 fixed point.
+    let read_fixed = |cursor : &mut SliceCursor| -> Result<f64, PsdError> { Ok(read_u32(cursor)? as f64 / 65536.0) };
+    Ok(Some(ResolutionInfo
+    {
+        h_res : read_fixed(&mut cursor)?,
+        h_res_unit : read_u16(&mut cursor)?,
+        width_unit : read_u16(&mut cursor)?,
+        v_res : read_fixed(&mut cursor)?,
+        v_res_unit : read_u16(&mut cursor)?,
+        height_unit : read_u16(&mut cursor)?,
+    }))
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// The thumbnail Photoshop embeds in image resource 1036, or 1033 in very old files.
+///
+/// Returned from [parse_thumbnail].
+pub struct Thumbnail {
+    /// Which resource the thumbnail came from: 1036, or 1033 for Photoshop 4.0 files. 1033 thumbnails store their color channels in BGR order instead of RGB.
+    pub resource_id : u16,
+    /// 1 = JPEG (`data` is a JFIF file), 0 = raw (`data` is uncompressed 24-bit pixels, with each row padded out to `width_bytes`).
+    pub format : u32,
+    /// Thumbnail width in pixels.
+    pub width : u32,
+    /// Thumbnail height in pixels.
+    pub height : u32,
+    /// Length of a row of uncompressed pixels in bytes, including padding to a multiple of 4.
+    pub width_bytes : u32,
+    /// Bits per pixel. Always 24 in practice.
+    pub bits_per_pixel : u16,
+    /// The thumbnail's image data, as-is. Not decoded.
+    pub data : Vec<u8>,
+}
+
+/// Extracts the embedded thumbnail out of image resource 1036, falling back to the older 1033. Returns `Ok(None)` if the file doesn't have one.
+///
+/// The thumbnail is not decoded; JPEG thumbnails are returned as JFIF files, and raw thumbnails as uncompressed pixels. Check [Thumbnail::format].
+pub fn parse_thumbnail(data : &[u8]) -> Result<Option<Thumbnail>, PsdError>
+{
+    let (resource_id, resource) = match get_image_resource(data, 1036)?
+    {
+        Some(x) => (1036, x),
+        None => match get_image_resource(data, 1033)?
+        {
+            Some(x) => (1033, x),
+            None => return Ok(None),
+        }
+    };
+    let mut cursor = SliceCursor::within(data, resource);
+    let format = read_u32(&mut cursor)?;
+    let width = read_u32(&mut cursor)?;
+    let height = read_u32(&mut cursor)?;
+    let width_bytes = read_u32(&mut cursor)?;
+    let _total_size = read_u32(&mut cursor)?;
+    let compressed_size = read_u32(&mut cursor)? as u64;
+    let bits_per_pixel = read_u16(&mut cursor)?;
+    let _planes = read_u16(&mut cursor)?;
+    
+    // Raw thumbnails don't always fill in the compressed size, so just take everything.
+    let rest = cursor.take_rest()?.buf;
+    let data = if format == 1 && compressed_size != 0 && compressed_size <= rest.len() as u64
+    {
+        &rest[..compressed_size as usize]
+    }
+    else
+    {
+        rest
+    };
+    
+    Ok(Some(Thumbnail { resource_id, format, width, height, width_bytes, bits_per_pixel, data : data.to_vec() }))
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// The document's color profile, from image resources 1039 and 1041.
+///
+/// Returned from [parse_color_profile].
+pub struct ColorProfile {
+    /// The embedded ICC profile, as-is. `None` if the file doesn't have one.
+    pub icc : Option<Vec<u8>>,
+    /// Whether the document is explicitly marked as having no color profile. If true, the document's colors should not be color managed, even if `icc` is present.
+    pub untagged : bool,
+}
+
+/// Extracts the document's ICC profile and "profile untagged" flag.
+///
+/// ICC profiles can be hundreds of kilobytes, so this is the only function that copies them out; [parse_layer_records] and [parse_psd_metadata] don't touch them.
+pub fn parse_color_profile(data : &[u8]) -> Result<ColorProfile, PsdError>
+{
+    let icc = get_image_resource(data, 1039)?.map(|x| x.to_vec());
+    let untagged = get_image_resource(data, 1041)?.is_some_and(|x| x.first().is_some_and(|x| *x != 0));
+    Ok(ColorProfile { icc, untagged })
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// The document's EXIF data, from image resource 1058 or 1059.
+///
+/// Returned from [parse_exif].
+pub struct ExifData {
+    /// Which resource the EXIF data came from: 1058 ("EXIF data 1"), or 1059 ("EXIF data 3") if there's no 1058.
+    pub resource_id : u16,
+    /// The EXIF data, as-is. Normally a TIFF-format blob, ready to hand to an EXIF library.
+    pub data : Vec<u8>,
+    /// Whether `data` starts with a valid TIFF header. If false, `data` is returned anyway, but other EXIF libraries may not accept it.
+    pub valid_tiff_header : bool,
+}
+
+impl ExifData {
+    /// Reads the orientation tag (0x0112) out of the first IFD. Returns `None` if it's not there or the data is malformed.
+    ///
+    /// 1 = upright, 3 = rotated 180 degrees, 6 = rotated 90 degrees clockwise, 8 = rotated 90 degrees counterclockwise; 2, 4, 5, and 7 are the mirrored versions of those.
+    pub fn orientation(&self) -> Option<u16>
+    {
+        let data = self.data.strip_prefix(b"Exif\0\0").unwrap_or(&self.data);
+        let le = match data.get(..4)?
+        {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        let u16_at = |i : usize| -> Option<u16>
+        {
+            let b = [*data.get(i)?, *data.get(i + 1)?];
+            Some(if le { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) })
+        };
+        let u32_at = |i : usize| -> Option<u32>
+        {
+            let b = [*data.get(i)?, *data.get(i + 1)?, *data.get(i + 2)?, *data.get(i + 3)?];
+            Some(if le { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) })
+        };
+        
+        let ifd = u32_at(4)? as usize;
+        let count = u16_at(ifd)? as usize;
+        (0..count).map(|i| ifd + 2 + i * 12).find(|x| u16_at(*x) == Some(0x0112)).and_then(|x| u16_at(x + 8))
+    }
+}
+
+/// Extracts the document's EXIF data out of image resource 1058, falling back to 1059. Returns `Ok(None)` if the file doesn't have any.
+pub fn parse_exif(data : &[u8]) -> Result<Option<ExifData>, PsdError>
+{
+    let (resource_id, resource) = match get_image_resource(data, 1058)?
+    {
+        Some(x) => (1058, x),
+        None => match get_image_resource(data, 1059)?
+        {
+            Some(x) => (1059, x),
+            None => return Ok(None),
+        }
+    };
+    let valid_tiff_header = resource.starts_with(b"II*\0") || resource.starts_with(b"MM\0*");
+    Ok(Some(ExifData { resource_id, data : resource.to_vec(), valid_tiff_header }))
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single guide line.
+pub struct Guide {
+    /// Position of the guide in document pixels. Guides can be placed at fractional positions.
+    pub position : f64,
+    /// Whether this is a horizontal guide (a line at a given y coordinate). If false, it's a vertical guide (a line at a given x coordinate).
+    pub horizontal : bool,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// The document's guides and grid spacing, from image resource 1032.
+///
+/// Returned from [parse_guides].
+pub struct GuidesInfo {
+    /// Version of the resource. Always 1.
+    pub version : u32,
+    /// Horizontal grid spacing in document pixels.
+    pub grid_h : f64,
+    /// Vertical grid spacing in document pixels.
+    pub grid_v : f64,
+    /// The guides, in file order.
+    pub guides : Vec<Guide>,
+}
+
+/// Parses the document's guides and grid spacing out of image resource 1032. Returns `Ok(None)` if the file doesn't have them.
+///
+/// Positions are stored in 1/32 pixel units; they're converted to pixels here.
+pub fn parse_guides(data : &[u8]) -> Result<Option<GuidesInfo>, PsdError>
+{
+    let Some(resource) = get_image_resource(data, 1032)? else { return Ok(None) };
+    let mut cursor = SliceCursor::within(data, resource);
+    let version = read_u32(&mut cursor)?;
+    let grid_h = read_u32(&mut cursor)? as f64 / 32.0;
+    let grid_v = read_u32(&mut cursor)? as f64 / 32.0;
+    let count = read_u32(&mut cursor)?;
+    let mut guides = vec!();
+    for _ in 0..count
+    {
+        let position = read_i32(&mut cursor)? as f64 / 32.0;
+        let horizontal = read_u8(&mut cursor)? != 0;
+        guides.push(Guide { position, horizontal });
+    }
+    Ok(Some(GuidesInfo { version, grid_h, grid_v, guides }))
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// How an alpha or spot channel is displayed, from image resource 1077.
+pub struct ChannelDisplayInfo {
+    /// Color space of `color`. See <https://www.adobe.com/devnet-apps/photoshop/fileformatashtml/#50577409_17587>
+    pub color_space : u16,
+    /// Display color, in `color_space`'s terms. Unused components are zero.
+    pub color : [u16; 4],
+    /// Display opacity, 0 to 100.
+    pub opacity : u16,
+    /// 0 = alpha channel where color indicates selected areas, 1 = alpha channel where color indicates protected areas, 2 = spot color channel.
+    pub kind : u8,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Metadata for a single document-level alpha or spot channel, gathered from several image resources.
+///
+/// Each field is `None` if the corresponding resource is missing or doesn't have an entry for this channel.
+///
+/// Returned from [parse_alpha_channels].
+pub struct AlphaChannelInfo {
+    /// Pascal-string name, from resource 1006.
+    pub pascal_name : Option<String>,
+    /// Unicode name, from resource 1045.
+    pub unicode_name : Option<String>,
+    /// Unique identifier, from resource 1053.
+    pub identifier : Option<u32>,
+    /// Display color and kind, from resource 1077.
+    pub display : Option<ChannelDisplayInfo>,
+}
+
+/// Parses the document's alpha and spot channel metadata out of image resources 1006, 1045, 1053, and 1077, returning one entry per channel.
+///
+/// These resources can disagree on how many channels there are in malformed files, so each one is read to its end, and the returned list is as long as the longest of them.
+pub fn parse_alpha_channels(data : &[u8]) -> Result<Vec<AlphaChannelInfo>, PsdError>
+{
+    let mut pascal_names = vec!();
+    if let Some(resource) = get_image_resource(data, 1006)?
+    {
+        let mut cursor = SliceCursor::within(data, resource);
+        while cursor.pos < resource.len()
+        {
+            let len = read_u8(&mut cursor)? as u64;
+            pascal_names.push(String::from_utf8_lossy(cursor.read_slice(len)?).to_string());
+        }
+    }
+    
+    let mut unicode_names = vec!();
+    if let Some(resource) = get_image_resource(data, 1045)?
+    {
+        let mut cursor = SliceCursor::within(data, resource);
+        while cursor.pos + 4 <= resource.len()
+        {
+            unicode_names.push(read_unicode_string(&mut cursor)?.trim_end_matches('\0').to_string());
+        }
+    }
+    
+    let mut identifiers = vec!();
+    if let Some(resource) = get_image_resource(data, 1053)?
+    {
+        let mut cursor = SliceCursor::within(data, resource);
+        while cursor.pos + 4 <= resource.len()
+        {
+            identifiers.push(read_u32(&mut cursor)?);
+        }
+    }
+    
+    let mut display = vec!();
+    if let Some(resource) = get_image_resource(data, 1077)?
+    {
+        let mut cursor = SliceCursor::within(data, resource);
+        let _version = read_u32(&mut cursor)?;
+        while cursor.pos + 13 <= resource.len()
+        {
+            display.push(ChannelDisplayInfo
+            {
+                color_space : read_u16(&mut cursor)?,
+                color : [read_u16(&mut cursor)?, read_u16(&mut cursor)?, read_u16(&mut cursor)?, read_u16(&mut cursor)?],
+                opacity : read_u16(&mut cursor)?,
+                kind : read_u8(&mut cursor)?,
+            });
+        }
+    }
+    
+    let count = pascal_names.len().max(unicode_names.len()).max(identifiers.len()).max(display.len());
+    Ok((0..count).map(|i| AlphaChannelInfo
+    {
+        pascal_name : pascal_names.get(i).cloned(),
+        unicode_name : unicode_names.get(i).cloned(),
+        identifier : identifiers.get(i).copied(),
+        display : display.get(i).cloned(),
+    }).collect())
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Layer selection and grouping state, from image resources 1024, 1026, 1069, and 1072.
+///
+/// Each field is `None` if the corresponding resource is missing.
+///
+/// Returned from [parse_layer_state].
+pub struct LayerStateInfo {
+    /// Index of the target (active) layer, from resource 1024. This is an index into the list returned by [parse_layer_records], including group openers and closers.
+    pub target_layer : Option<u16>,
+    /// Layer IDs of all selected layers, from resource 1069.
+    pub selected_ids : Option<Vec<u32>>,
+    /// Linked-layer group ID of each layer, from resource 1026, in the same order as [parse_layer_records]. Layers with the same nonzero ID are linked together.
+    pub group_ids : Option<Vec<u16>>,
+    /// Linked-layer group IDs that are enabled, from resource 1072, one byte per layer in the same order as [parse_layer_records].
+    pub group_enabled_ids : Option<Vec<u8>>,
+}
+
+/// Parses the document's layer selection and grouping state out of image resources 1024, 1026, 1069, and 1072.
+///
+/// The per-layer lists are read to the end of their resource rather than trusting the document's layer count.
+pub fn parse_layer_state(data : &[u8]) -> Result<LayerStateInfo, PsdError>
+{
+    let mut ret = LayerStateInfo::default();
+    if let Some(resource) = get_image_resource(data, 1024)?
+    {
+        ret.target_layer = Some(read_u16(&mut SliceCursor::within(data, resource))?);
+    }
+    if let Some(resource) = get_image_resource(data, 1069)?
+    {
+        let mut cursor = SliceCursor::within(data, resource);
+        let count = read_u16(&mut cursor)?;
+        let mut ids = vec!();
+        for _ in 0..count
+        {
+            ids.push(read_u32(&mut cursor)?);
+        }
+        ret.selected_ids = Some(ids);
+    }
+    if let Some(resource) = get_image_resource(data, 1026)?
+    {
+        ret.group_ids = Some(resource.chunks_exact(2).map(|x| u16::from_be_bytes([x[0], x[1]])).collect());
+    }
+    if let Some(resource) = get_image_resource(data, 1072)?
+    {
+        ret.group_enabled_ids = Some(resource.to_vec());
+    }
+    Ok(ret)
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single bezier knot of a path.
+///
+/// Points are (x, y), relative to the document: (0.0, 0.0) is the top left corner of the canvas, and (1.0, 1.0) is the bottom right corner. Knots can be outside of the canvas.
+pub struct PathKnot {
+    /// Control point for the curve coming into this knot.
+    pub control_in : (f64, f64),
+    /// The knot itself.
+    pub anchor : (f64, f64),
+    /// Control point for the curve going out of this knot.
+    pub control_out : (f64, f64),
+    /// Whether the control points are linked, i.e. moving one moves the other.
+    pub linked : bool,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single subpath of a path.
+pub struct Subpath {
+    /// Whether the subpath is closed, i.e. the last knot connects back to the first.
+    pub closed : bool,
+    /// The subpath's knots, in order.
+    pub knots : Vec<PathKnot>,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A saved path, from an image resource in the range 2000 to 2997.
+///
+/// Returned from [parse_paths].
+pub struct PathInfo {
+    /// The path's resource ID.
+    pub resource_id : u16,
+    /// The path's name, from the resource block's name.
+    pub name : String,
+    /// The path's subpaths, in order.
+    pub subpaths : Vec<Subpath>,
+    /// From the initial fill rule record, if any: whether the path starts out with all pixels filled instead of none.
+    pub initial_fill : Option<bool>,
+    /// From the clipboard record, if any: (top, left, bottom, right, resolution) of the path when it was copied.
+    pub clipboard : Option<[f64; 5]>,
+}
+
+/// Parses the document's saved paths out of image resources 2000 to 2997, in file order.
+///
+/// Use [parse_clipping_path_name] to find out which of these, if any, is the clipping path.
+pub fn parse_paths(data : &[u8]) -> Result<Vec<PathInfo>, PsdError>
+{
+    let mut ret = vec!();
+    for (resource, start, len) in image_resource_spans(data)?
+    {
+        if resource.id < 2000 || resource.id > 2997
+        {
+            continue;
+        }
+        let mut path = read_path_records(&data[start as usize..(start + len) as usize]).map_err(|x| x.offset_by(start))?;
+        path.resource_id = resource.id;
+        path.name = resource.name;
+        ret.push(path);
+    }
+    Ok(ret)
+}
+/// Reads a list of 26-byte path records, as used by path resources and vector masks. The resource ID and name are left blank.
+fn read_path_records(data : &[u8]) -> Result<PathInfo, PsdError>
+{
+    // 8.24 fixed point.
+    fn read_fixed(cursor : &mut SliceCursor) -> Result<f64, PsdError>
+    {
+        Ok(read_i32(cursor)? as f64 / 16777216.0)
+    }
+    fn read_point(cursor : &mut SliceCursor) -> Result<(f64, f64), PsdError>
+    {
+        // Stored as (vertical, horizontal).
+        let y = read_fixed(cursor)?;
+        let x = read_fixed(cursor)?;
+        Ok((x, y))
+    }
+    
+    let mut path = PathInfo::default();
+    for record in data.chunks_exact(26)
+    {
+        let mut cursor = SliceCursor::within(data, record);
+        match read_u16(&mut cursor)?
+        {
+            // Subpath length records. The knot count isn't needed; the knot records that follow say which subpath kind they belong to.
+            selector @ (0 | 3) => path.subpaths.push(Subpath { closed : selector == 0, knots : vec!() }),
+            selector @ (1 | 2 | 4 | 5) =>
+            {
+                let knot = PathKnot
+                {
+                    control_in : read_point(&mut cursor)?,
+                    anchor : read_point(&mut cursor)?,
+                    control_out : read_point(&mut cursor)?,
+                    linked : selector == 1 || selector == 4,
+                };
+                match path.subpaths.last_mut()
+                {
+                    Some(subpath) => subpath.knots.push(knot),
+                    None => return Err(PsdError::malformed("Path knot record without a subpath length record", cursor.base)),
+                }
+            }
+            // Path fill rule record. Contains nothing.
+            6 => {}
+            7 => path.clipboard = Some([read_fixed(&mut cursor)?, read_fixed(&mut cursor)?, read_fixed(&mut cursor)?, read_fixed(&mut cursor)?, read_fixed(&mut cursor)?]),
+            8 => path.initial_fill = Some(read_u16(&mut cursor)? == 1),
+            _ => return Err(PsdError::malformed("Unknown path record type", cursor.base)),
+        }
+    }
+    Ok(path)
+}
+
+/// Finds the name of the clipping path, from image resource 2999. Returns `Ok(None)` if the document doesn't have a clipping path.
+///
+/// The name matches [PathInfo::name] of one of the paths returned by [parse_paths].
+pub fn parse_clipping_path_name(data : &[u8]) -> Result<Option<String>, PsdError>
+{
+    let Some(resource) = get_image_resource(data, 2999)? else { return Ok(None) };
+    let mut cursor = SliceCursor::within(data, resource);
+    let len = read_u8(&mut cursor)? as u64;
+    Ok(Some(String::from_utf8_lossy(cursor.read_slice(len)?).to_string()))
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// The document's IPTC-NAA metadata, from image resource 1028.
+///
+/// Returned from [parse_iptc].
+pub struct IptcData {
+    /// The raw IIM data, as-is.
+    pub data : Vec<u8>,
+    /// The datasets in `data`, as (record number, dataset number, value) in file order. Captions, keywords, credits, etc. are in record 2. Values are not interpreted.
+    ///
+    /// If `data` is malformed, this only contains the datasets before the malformed part.
+    pub datasets : Vec<(u8, u8, Vec<u8>)>,
+}
+
+/// Extracts the document's IPTC-NAA metadata out of image resource 1028, and splits it into datasets. Returns `Ok(None)` if the file doesn't have any.
+pub fn parse_iptc(data : &[u8]) -> Result<Option<IptcData>, PsdError>
+{
+    let Some(resource) = get_image_resource(data, 1028)? else { return Ok(None) };
+    
+    fn read_dataset(cursor : &mut SliceCursor) -> Result<(u8, u8, Vec<u8>), PsdError>
+    {
+        let record = read_u8(cursor)?;
+        let dataset = read_u8(cursor)?;
+        let mut len = read_u16(cursor)? as u64;
+        // Extended dataset: the low bits are the length of the actual length field.
+        if len & 0x8000 != 0
+        {
+            let len_len = len & 0x7FFF;
+            if len_len > 8
+            {
+                return Err(PsdError::malformed("IPTC dataset length too long", cursor.offset() - 2));
+            }
+            len = cursor.read_slice(len_len)?.iter().fold(0, |a, b| (a << 8) | *b as u64);
+        }
+        Ok((record, dataset, cursor.read_slice(len)?.to_vec()))
+    }
+    
+    let mut cursor = SliceCursor::within(data, resource);
+    let mut datasets = vec!();
+    // Each dataset starts with a 0x1C tag marker. Anything else is padding or garbage.
+    while cursor.pos < resource.len() && resource[cursor.pos] == 0x1C
+    {
+        cursor.pos += 1;
+        match read_dataset(&mut cursor)
+        {
+            Ok(x) => datasets.push(x),
+            Err(_) => break,
+        }
+    }
+    Ok(Some(IptcData { data : resource.to_vec(), datasets }))
+}
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Print flags, from image resource 1011. Each field is one of the checkboxes in the print dialog.
+pub struct PrintFlags {
+    pub labels : bool,
+    pub crop_marks : bool,
+    pub color_bars : bool,
+    pub registration_marks : bool,
+    pub negative : bool,
+    pub flip : bool,
+    pub interpolate : bool,
+    pub caption : bool,
+    pub print_flags : bool,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// Print scale, from image resource 1062.
+pub struct PrintScale {
+    /// 0 = centered, 1 = size to fit, 2 = user defined.
+    pub style : u16,
+    /// Horizontal location of the image on the page.
+    pub x : f32,
+    /// Vertical location of the image on the page.
+    pub y : f32,
+    /// Scale of the image on the page.
+    pub scale : f32,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// The document's print setup, from image resources 1011, 1062, 1082, and 1083.
+///
+/// Each field is `None` if the corresponding resource is missing.
+///
+/// Returned from [parse_print_info].
+pub struct PrintInfo {
+    /// From resource 1011.
+    pub flags : Option<PrintFlags>,
+    /// From resource 1062.
+    pub scale : Option<PrintScale>,
+    /// Print information descriptor, from resource 1082. Holds things like the printer name, color handling, and bleed settings.
+    pub print_info : Option<Descriptor>,
+    /// Print style descriptor, from resource 1083. Holds things like crop marks and background color.
+    pub print_style : Option<Descriptor>,
+}
+
+/// Parses the document's print setup out of image resources 1011, 1062, 1082, and 1083.
+pub fn parse_print_info(data : &[u8]) -> Result<PrintInfo, PsdError>
+{
+    let mut ret = PrintInfo::default();
+    if let Some(resource) = get_image_resource(data, 1011)?
+    {
+        let mut cursor = SliceCursor::within(data, resource);
+        let mut flag = || -> Result<bool, PsdError> { Ok(read_u8(&mut cursor)? != 0) };
+        ret.flags = Some(PrintFlags
+        {
+            labels : flag()?,
+            crop_marks : flag()?,
+            color_bars : flag()?,
+            registration_marks : flag()?,
+            negative : flag()?,
+            flip : flag()?,
+            interpolate : flag()?,
+            caption : flag()?,
+            print_flags : flag()?,
+        });
+    }
+    if let Some(resource) = get_image_resource(data, 1062)?
+    {
+        let mut cursor = SliceCursor::within(data, resource);
+        ret.scale = Some(PrintScale
+        {
+            style : read_u16(&mut cursor)?,
+            x : f32::from_bits(read_u32(&mut cursor)?),
+            y : f32::from_bits(read_u32(&mut cursor)?),
+            scale : f32::from_bits(read_u32(&mut cursor)?),
+        });
+    }
+    for (id, field) in [(1082, &mut ret.print_info), (1083, &mut ret.print_style)]
+    {
+        if let Some(resource) = get_image_resource(data, id)?
+        {
+            let mut cursor = SliceCursor::within(data, resource);
+            *field = read_versioned_descriptor(&mut cursor, 256)?;
+        }
+    }
+    Ok(ret)
+}
+/// Parses the document's pixel aspect ratio (width / height of a single pixel) out of image resource 1064. Returns `Ok(None)` if the file doesn't have one, which means pixels are square.
+pub fn parse_pixel_aspect_ratio(data : &[u8]) -> Result<Option<f64>, PsdError>
+{
+    let Some(resource) = get_image_resource(data, 1064)? else { return Ok(None) };
+    let mut cursor = SliceCursor::within(data, resource);
+    let _version = read_u32(&mut cursor)?;
+    Ok(Some(read_f64(&mut cursor)?))
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single image map URL, from image resource 1054.
+///
+/// Returned from [parse_url_list].
+pub struct UrlEntry {
+    /// Four-character tag, stored as an integer.
+    pub tag : u32,
+    /// ID of the URL.
+    pub id : u32,
+    /// The URL itself.
+    pub url : String,
+}
+
+/// Parses the document's image map URL list out of image resource 1054. Returns an empty list if the file doesn't have one.
+pub fn parse_url_list(data : &[u8]) -> Result<Vec<UrlEntry>, PsdError>
+{
+    let Some(resource) = get_image_resource(data, 1054)? else { return Ok(vec!()) };
+    let mut cursor = SliceCursor::within(data, resource);
+    let count = read_u32(&mut cursor)?;
+    let mut ret = vec!();
+    for _ in 0..count
+    {
+        let tag = read_u32(&mut cursor)?;
+        let id = read_u32(&mut cursor)?;
+        let url = read_unicode_string(&mut cursor)?.trim_end_matches('\0').to_string();
+        ret.push(UrlEntry { tag, id, url });
+    }
+    Ok(ret)
+}
+/// Same as [parse_image_resources], but returns each resource with empty data alongside the (start, length) of its data instead of copying it.
+fn image_resource_spans(data : &[u8]) -> Result<Vec<(ImageResource, u64, u64)>, PsdError>
+{
+    let sections = find_sections(data)?;
+    let mut cursor = SliceCursor::new(data);
+    cursor.set_position(sections.image_resources - 4);
+    let end = sections.image_resources + read_u32(&mut cursor)? as u64;
+    
+    let mut ret = vec!();
+    while cursor.position() + 12 <= end
+    {
+        let sig = read_b4(&mut cursor)?;
+        let id = read_u16(&mut cursor)?;
+        
+        // Pascal string, padded so that the length byte and the string together are an even number of bytes.
+        let name_len = read_u8(&mut cursor)? as u64;
+        let name = String::from_utf8_lossy(cursor.read_slice(name_len)?).to_string();
+        if name_len.is_multiple_of(2)
+        {
+            cursor.set_position(cursor.position() + 1);
+        }
+        
+        let len = read_u32(&mut cursor)? as u64;
+        let start = cursor.position();
+        if start + len > end
+        {
+            return Err(PsdError::malformed("Image resource runs past the end of its section", start));
+        }
+        ret.push((ImageResource { signature : String::from_utf8_lossy(&sig).to_string(), id, name, data : vec!() }, start, len));
+        
+        // Data is padded to an even length.
+        cursor.set_position(start + len + len % 2);
+    }
+    
+    Ok(ret)
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single channel of a pattern.
+pub struct PatternChannel {
+    /// Bit depth of the channel.
+    pub depth : u16,
+    /// (top, left, bottom, right) of the channel. Usually the same as the pattern's.
+    pub rect : (u32, u32, u32, u32),
+    /// Decompressed channel data.
+    pub data : Vec<u8>,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A single pattern, from a document-level 'Patt', 'Pat2', or 'Pat3' block.
+///
+/// Returned from [parse_patterns].
+pub struct Pattern {
+    /// Name of the pattern.
+    pub name : String,
+    /// Unique ID of the pattern. Pattern fills and pattern overlays refer to patterns by this ID.
+    pub id : String,
+    /// Color mode of the pattern. Same values as [PsdMetadata::color_mode].
+    pub color_mode : u32,
+    /// Pattern origin point, as (vertical, horizontal).
+    pub point : (u16, u16),
+    /// (top, left, bottom, right) of the pattern.
+    pub rect : (u32, u32, u32, u32),
+    /// For indexed color patterns, the 256-entry RGB palette. `None` for everything else.
+    pub palette : Option<Vec<[u8; 3]>>,
+    /// The pattern's channels, in color mode order, followed by two more entries for the user mask and sheet mask. Channels that aren't written to the file are `None`.
+    pub channels : Vec<Option<PatternChannel>>,
+}
+
+impl Pattern {
+    /// Width of the pattern in pixels.
+    pub fn width(&self) -> u32 { self.rect.3.saturating_sub(self.rect.1) }
+    /// Height of the pattern in pixels.
+    pub fn height(&self) -> u32 { self.rect.2.saturating_sub(self.rect.0) }
+}
+
+/// Parses and decodes all patterns stored in the document-level 'Patt', 'Pat2', and 'Pat3' blocks, in file order.
+pub fn parse_patterns(data : &[u8]) -> Result<Vec<Pattern>, PsdError>
+{
+    parse_patterns_opts(data, &ParseOptions::default())
+}
+/// Same as [parse_patterns], but with [ParseOptions::limits] applied to the patterns' channels. The other options don't do anything here.
+pub fn parse_patterns_opts(data : &[u8], options : &ParseOptions) -> Result<Vec<Pattern>, PsdError>
+{
+    let mut bytes_left = options.limits.max_total_bytes.unwrap_or(u64::MAX);
+    let mut ret = vec!();
+    for (key, start, len) in document_block_spans(data)?
+    {
+        if key != "Patt" && key != "Pat2" && key != "Pat3"
+        {
+            continue;
+        }
+        let block = &data[start as usize..(start + len) as usize];
+        let mut cursor = SliceCursor::within(data, block);
+        while cursor.pos + 4 <= block.len()
+        {
+            let len = read_u32(&mut cursor)? as u64;
+            let start = cursor.position();
+            let pattern = cursor.read_slice(len)?;
+            ret.push(parse_pattern(pattern, &options.limits, &mut bytes_left).map_err(|x| x.offset_by(start + cursor.base))?);
+            // Each pattern is padded out to a multiple of 4 bytes.
+            cursor.set_position(start + len.div_ceil(4) * 4);
+        }
+    }
+    Ok(ret)
+}
+fn parse_pattern(data : &[u8], limits : &ParseLimits, bytes_left : &mut u64) -> Result<Pattern, PsdError>
+{
+    fn read_rect(cursor : &mut SliceCursor) -> Result<(u32, u32, u32, u32), PsdError>
+    {
+        Ok((read_u32(cursor)?, read_u32(cursor)?, read_u32(cursor)?, read_u32(cursor)?))
+    }
+    
+    let mut cursor = SliceCursor::new(data);
+    let mut ret = Pattern::default();
+    let _version = read_u32(&mut cursor)?;
+    ret.color_mode = read_u32(&mut cursor)?;
+    ret.point = (read_u16(&mut cursor)?, read_u16(&mut cursor)?);
+    ret.name = read_unicode_string(&mut cursor)?.trim_end_matches('\0').to_string();
+    let id_len = read_u8(&mut cursor)? as u64;
+    ret.id = String::from_utf8_lossy(cursor.read_slice(id_len)?).to_string();
+    if ret.color_mode == 2
+    {
+        let palette = cursor.read_slice(768)?;
+        ret.palette = Some(palette.chunks_exact(3).map(|x| [x[0], x[1], x[2]]).collect());
+    }
+    
+    // "Virtual memory array list".
+    let _version = read_u32(&mut cursor)?;
+    let _len = read_u32(&mut cursor)?;
+    ret.rect = read_rect(&mut cursor)?;
+    let channel_count = read_u32(&mut cursor)?;
+    // Plus the user mask and sheet mask.
+    for _ in 0..channel_count as u64 + 2
+    {
+        if read_u32(&mut cursor)? == 0
+        {
+            ret.channels.push(None);
+            continue;
+        }
+        let len = read_u32(&mut cursor)? as u64;
+        if len == 0
+        {
+            ret.channels.push(None);
+            continue;
+        }
+        let mut channel = cursor.sub(len)?;
+        let _depth = read_u32(&mut channel)?;
+        let rect = read_rect(&mut channel)?;
+        let depth = read_u16(&mut channel)?;
+        let mode = read_u8(&mut channel)? as u16;
+        limits.check_pixels(rect.3.saturating_sub(rect.1), rect.2.saturating_sub(rect.0))?;
+        
+        // Same compression scheme as layer channels, just with a one-byte compression mode.
+        let mut buf = mode.to_be_bytes().to_vec();
+        let channel = channel.take_rest()?;
+        buf.extend_from_slice(channel.buf);
+        let mut data = vec!();
+        append_img_data_impl(&buf, &mut data, buf.len() as u64, rect.2.saturating_sub(rect.0) as u64, DecodeOpts { depth, limit : (*bytes_left).min(usize::MAX as u64) as usize, ..Default::default() }).map_err(|x| x.offset_by(channel.base - 2))?;
+        spend_bytes(bytes_left, data.len() as u64)?;
+        ret.channels.push(Some(PatternChannel { depth, rect, data }));
+    }
+    Ok(ret)
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A file placed into the document as a smart object, from a document-level 'lnkD', 'lnk2', or 'lnk3' block.
+///
+/// Returned from [parse_linked_files].
+pub struct LinkedFile {
+    /// "liFD" for files embedded in the document, "liFE" for external files, and "liFA" for aliases.
+    pub kind : String,
+    pub version : u32,
+    /// Unique ID of the file. Smart object layers refer to their content by this ID, in [PlacedLayerInfo::unique_id].
+    pub id : String,
+    /// Original file name.
+    pub filename : String,
+    /// Mac file type code, e.g. "8BPB" for PSB files or "png " for PNG files. Often blank.
+    pub file_type : String,
+    /// Mac creator code. Often blank.
+    pub creator : String,
+    /// Descriptor with settings for opening the file, if it has one.
+    pub open_desc : Option<Descriptor>,
+    /// For external files, a descriptor saying where the file is.
+    pub link_desc : Option<Descriptor>,
+    /// The file's contents, as-is. Always there for embedded files, and sometimes there for external files, as a cached copy.
+    pub data : Option<Vec<u8>>,
+}
+
+/// Parses all linked files stored in the document-level 'lnkD', 'lnk2', and 'lnk3' blocks, in file order.
+pub fn parse_linked_files(data : &[u8]) -> Result<Vec<LinkedFile>, PsdError>
+{
+    let mut ret = vec!();
+    for (key, start, len) in document_block_spans(data)?
+    {
+        if key != "lnkD" && key != "lnk2" && key != "lnk3"
+        {
+            continue;
+        }
+        let block = &data[start as usize..(start + len) as usize];
+        let mut cursor = SliceCursor::within(data, block);
+        while cursor.pos + 8 <= block.len()
+        {
+            // Each entry has an 8-byte length, even in PSD files.
+            let len = read_section_len(&mut cursor, true)?;
+            let start = cursor.position();
+            let entry = cursor.read_slice(len)?;
+            ret.push(parse_linked_file(entry).map_err(|x| x.offset_by(start + cursor.base))?);
+            // Each entry is padded out to a multiple of 4 bytes.
+            cursor.set_position(start + len.div_ceil(4) * 4);
+        }
+    }
+    Ok(ret)
+}
+fn parse_linked_file(data : &[u8]) -> Result<LinkedFile, PsdError>
+{
+    fn read_b4_string(cursor : &mut SliceCursor) -> Result<String, PsdError>
+    {
+        Ok(String::from_utf8_lossy(&read_b4(cursor)?).to_string())
+    }
+    
+    let mut cursor = SliceCursor::new(data);
+    let mut ret = LinkedFile::default();
+    ret.kind = read_b4_string(&mut cursor)?;
+    ret.version = read_u32(&mut cursor)?;
+    let id_len = read_u8(&mut cursor)? as u64;
+    ret.id = String::from_utf8_lossy(cursor.read_slice(id_len)?).to_string();
+    ret.filename = read_unicode_string(&mut cursor)?.trim_end_matches('\0').to_string();
+    ret.file_type = read_b4_string(&mut cursor)?;
+    ret.creator = read_b4_string(&mut cursor)?;
+    let data_len = read_section_len(&mut cursor, true)?;
+    if read_u8(&mut cursor)? != 0
+    {
+        ret.open_desc = read_versioned_descriptor(&mut cursor, 256)?;
+        // Nothing after a truncated or unreadable descriptor can be found, so keep what's there.
+        if ret.open_desc.as_ref().is_none_or(Descriptor::is_truncated)
+        {
+            return Ok(ret);
+        }
+    }
+    match ret.kind.as_str()
+    {
+        "liFD" => ret.data = Some(cursor.read_slice(data_len)?.to_vec()),
+        "liFE" =>
+        {
+            ret.link_desc = read_versioned_descriptor(&mut cursor, 256)?;
+            if ret.link_desc.as_ref().is_none_or(Descriptor::is_truncated)
+            {
+                return Ok(ret);
+            }
+            if ret.version > 3
+            {
+                // Modification date, as year, month, day, hour, minute, and seconds.
+                cursor.set_position(cursor.position() + 4 + 4 + 8);
+            }
+            // Version 1 has no cached copy. Version 2 puts it at the very end, after the fields that later versions added here, which version 2 doesn't have, so it's in the same place either way.
+            let file_len = read_section_len(&mut cursor, true)?;
+            if ret.version >= 2
+            {
+                ret.data = Some(cursor.read_slice(file_len)?.to_vec()).filter(|x| !x.is_empty());
+            }
+        }
+        _ => {}
+    }
+    Ok(ret)
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A text or audio note, from the document-level 'Anno' block.
+///
+/// Returned from [parse_annotations].
+pub struct Annotation {
+    /// "txtA" for text notes and "sndA" for audio notes.
+    pub kind : String,
+    /// Whether the note's popup is open.
+    pub open : bool,
+    pub flags : u8,
+    /// (top, left, bottom, right) of the note's icon, in document coordinates.
+    pub icon_rect : (i32, i32, i32, i32),
+    /// (top, left, bottom, right) of the note's popup, in document coordinates.
+    pub popup_rect : (i32, i32, i32, i32),
+    pub color : EffectColor,
+    pub author : String,
+    pub name : String,
+    /// Modification date, as written by whatever made the note. Not in any particular format.
+    pub modified : String,
+    /// For text notes, the text, decoded from UTF-16 if it starts with a byte order mark and as Latin-1 if it doesn't.
+    pub text : Option<String>,
+    /// The note's data, as-is. For audio notes, this is the sound data.
+    pub data : Vec<u8>,
+}
+
+/// Parses all annotations stored in the document-level 'Anno' block, in file order.
+pub fn parse_annotations(data : &[u8]) -> Result<Vec<Annotation>, PsdError>
+{
+    fn read_rect(cursor : &mut SliceCursor) -> Result<(i32, i32, i32, i32), PsdError>
+    {
+        Ok((read_i32(cursor)?, read_i32(cursor)?, read_i32(cursor)?, read_i32(cursor)?))
+    }
+    fn read_pascal_string(cursor : &mut SliceCursor) -> Result<String, PsdError>
+    {
+        let len = read_u8(cursor)? as u64;
+        // Padded out to an even length, including the length byte.
+        let s = String::from_utf8_lossy(cursor.read_slice(len)?).to_string();
+        if len.is_multiple_of(2)
+        {
+            read_u8(cursor)?;
+        }
+        Ok(s)
+    }
+    
+    let mut ret = vec!();
+    for (key, start, len) in document_block_spans(data)?
+    {
+        if key != "Anno"
+        {
+            continue;
+        }
+        let mut cursor = SliceCursor::within(data, &data[start as usize..(start + len) as usize]);
+        let _major_version = read_u16(&mut cursor)?;
+        let _minor_version = read_u16(&mut cursor)?;
+        let count = read_u32(&mut cursor)?;
+        for _ in 0..count
+        {
+            let start = cursor.position();
+            // Includes the length field itself.
+            let len = read_u32(&mut cursor)? as u64;
+            let mut note = Annotation::default();
+            note.kind = String::from_utf8_lossy(&read_b4(&mut cursor)?).to_string();
+            note.open = read_u8(&mut cursor)? != 0;
+            note.flags = read_u8(&mut cursor)?;
+            let _optional_blocks = read_u16(&mut cursor)?;
+            note.icon_rect = read_rect(&mut cursor)?;
+            note.popup_rect = read_rect(&mut cursor)?;
+            note.color = read_color(&mut cursor)?;
+            note.author = read_pascal_string(&mut cursor)?;
+            note.name = read_pascal_string(&mut cursor)?;
+            note.modified = read_pascal_string(&mut cursor)?;
+            let _len = read_u32(&mut cursor)?;
+            let _marker = read_b4(&mut cursor)?;
+            let data_len = read_u32(&mut cursor)? as u64;
+            note.data = cursor.read_slice(data_len)?.to_vec();
+            if note.kind == "txtA"
+            {
+                note.text = Some(if let Some(text) = note.data.strip_prefix(&[0xFE, 0xFF])
+                {
+                    let text : Vec<u16> = text.chunks_exact(2).map(|x| u16::from_be_bytes([x[0], x[1]])).collect();
+                    String::from_utf16_lossy(&text)
+                }
+                else
+                {
+                    note.data.iter().map(|x| *x as char).collect()
+                });
+            }
+            ret.push(note);
+            cursor.set_position(start + len.max(4));
+        }
+    }
+    Ok(ret)
+}
+
+/// Decompress a packbits or ZIP image data buffer into a vec, appending to the vec.
+///
+/// On success, returns `Ok(size)`.
+///
+/// Returns an error if there isn't enough data.
+///
+/// This doesn't know the image's width, so packbits rows are decoded as-is, even if some are shorter or longer than the others. [parse_layer_records] pads short rows out with zeros and cuts long rows off at the layer's width instead.
+///
+/// PSD files generally use compression on their image data. This decompresses it into a vec, bytewise.
+pub fn append_img_data(cursor : &[u8], output : &mut Vec<u8>, size : u64, h : u64) -> Result<usize, PsdError>
+{
+    append_img_data_impl(cursor, output, size, h, DecodeOpts::default())
+}
+/// Could `size` bytes of compressed data plausibly decode to a `w * h` image? Deflate can expand data by at most a factor of 1032, and packbits by less.
+fn plausible_size(w : u64, h : u64, size : u64) -> bool
+{
+    w.saturating_mul(h) <= size.saturating_mul(1032)
+}
+/// Decompresses packbits rows one at a time into a reused buffer, handing the ones in `rows` to `f`. `cursor` has to be right after the compression mode, at the row length table.
+///
+/// Rows that aren't in `rows` are skipped using the row length table, without decompressing them. If `row_len` is set, short rows are padded out with zeros and long rows are cut off.
+///
+/// Returns the size of the channel's data according to the row length table, including the compression mode.
+fn unpack_rows<E : From<PsdError>>(cursor : &mut SliceCursor, h : u64, rows : core::ops::Range<u64>, row_len : Option<u64>, mut f : impl FnMut(u64, &[u8]) -> Result<(), E>) -> Result<u64, E>
+{
+    let mut data = cursor.clone();
+    data.set_position(cursor.position().saturating_add(h.saturating_mul(2)));
+    let mut total = h.saturating_mul(2).saturating_add(2);
+    let max = row_len.map_or(usize::MAX, |x| x as usize);
+    let mut row = vec!();
+    for i in 0..h
+    {
+        let len = read_u16(cursor)? as u64;
+        total = total.saturating_add(len);
+        let start = data.position();
+        if rows.contains(&i)
+        {
+            row.clear();
+            // Zero-length rows are valid, and just don't decode anything.
+            while data.position() - start < len
+            {
+                let n = read_u8(&mut data)? as i8;
+                let room = max - row.len();
+                if n >= 0
+                {
+                    let bytes = data.read_slice(n as u64 + 1)?;
+                    row.extend_from_slice(&bytes[..bytes.len().min(room)]);
+                }
+                else if n != -128
+                {
+                    row.extend(core::iter::repeat_n(read_u8(&mut data)?, ((1 - n as i64) as usize).min(room)));
+                }
+            }
+            if row_len.is_some()
+            {
+                row.resize(max, 0);
+            }
+            f(i, &row)?;
+        }
+        // Rows always start where the row length table says they do, even if a run overshoots the end of the previous one.
+        data.set_position(start + len);
+    }
+    Ok(total)
+}
+/// How to decode a channel, beyond what the public decoding functions get told.
+#[derive(Clone, Copy)]
+struct DecodeOpts
+{
+    /// Number of bytes each packbits row should decode to. Short rows are padded out with zeros and long rows are cut off, so that one bad row can't shift the rest of the channel. `None` decodes rows as-is.
+    row_len : Option<u64>,
+    /// Only used for ZIP prediction, which works on whole samples.
+    depth : u16,
+    /// Caps how large the output may grow, for [ParseLimits::max_total_bytes].
+    limit : usize,
+}
+impl Default for DecodeOpts
+{
+    fn default() -> Self
+    {
+        Self { row_len : None, depth : 8, limit : usize::MAX }
+    }
+}
+fn append_img_data_impl(cursor : &[u8], output : &mut Vec<u8>, size : u64, h : u64, opts : DecodeOpts) -> Result<usize, PsdError>
+{
+    let DecodeOpts { row_len, depth, limit } = opts;
+    let mut _cursor = SliceCursor::new(cursor);
+    let cursor = &mut _cursor;
+    //println!("starting at: {:X}\t", cursor.position());
+    let mode = read_u16(cursor)?;
+    if mode == 0
+    {
+        // `size` includes the compression mode.
+        let data = cursor.read_slice(size.saturating_sub(2))?;
+        if output.len().saturating_add(data.len()) > limit
+        {
+            return Err(PsdError::LimitExceeded("max_total_bytes"));
+        }
+        output.extend_from_slice(data);
+    }
+    else if mode == 1 && h == 0
+    {
+        // No rows, so nothing to decode. Some writers still pad the channel out, so skip whatever's there.
+        cursor.set_position(size.max(2));
+    }
+    else if mode == 1
+    {
+        let end = unpack_rows(cursor, h, 0..h, row_len, |_, row|
+        {
+            if output.len().saturating_add(row.len()) > limit
+            {
+                return Err(PsdError::LimitExceeded("max_total_bytes"));
+            }
+            output.extend_from_slice(row);
+            Ok(())
+        })?;
+        cursor.set_position(end);
+    }
+    else if mode == 2 || mode == 3
+    {
+        output.extend(inflate_img_data(cursor.read_slice(size.saturating_sub(2))?, mode == 3, h, depth, limit.saturating_sub(output.len()))?);
+    }
+    else
+    {
+        return Err(PsdError::UnsupportedCompression { mode, offset : 0 });
+    }
+    Ok(cursor.position() as usize)
+}
+/// Decompress ZIP-compressed image data (compression modes 2 and 3), undoing the per-row delta prediction if `predicted` is set.
+///
+/// Fails with [PsdError::LimitExceeded] instead of decompressing more than `limit` bytes.
+#[cfg(feature = "zip_support")]
+fn inflate_img_data(data : &[u8], predicted : bool, h : u64, depth : u16, limit : usize) -> Result<Vec<u8>, PsdError>
+{
+    let mut ret = miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(data, limit).map_err(|x| match x.status
+    {
+        miniz_oxide::inflate::TINFLStatus::HasMoreOutput => PsdError::LimitExceeded("max_total_bytes"),
+        _ => PsdError::Malformed { message : format!("Failed to decompress ZIP image data: {}", x), offset : None },
+    })?;
+    if predicted && h != 0
+    {
+        let row_len = ret.len() / h as usize;
+        for row in ret.chunks_exact_mut(row_len.max(1))
+        {
+            if depth == 16
+            {
+                let mut prev = 0u16;
+                for sample in row.chunks_exact_mut(2)
+                {
+                    prev = prev.wrapping_add(u16::from_be_bytes([sample[0], sample[1]]));
+                    sample.copy_from_slice(&prev.to_be_bytes());
+                }
+            }
+            else
+            {
+                for i in 1..row.len()
+                {
+                    row[i] = row[i].wrapping_add(row[i - 1]);
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+#[cfg(not(feature = "zip_support"))]
+fn inflate_img_data(_data : &[u8], _predicted : bool, _h : u64, _depth : u16, _limit : usize) -> Result<Vec<u8>, PsdError>
+{
+    Err(PsdError::Malformed { message : "ZIP-compressed image data requires the zip_support feature".to_string(), offset : None })
+}
+/// Decompress a packbits or ZIP image data buffer into a slice, writing into the slice in-place. `stride` can be used to control how far apart to write each byte.
+///
+/// On success, returns `Ok(size)`.
+///
+/// If the slice isn't big enough, the data that doesn't fit is decoded and skipped. Returns an error if there isn't enough data.
+///
+/// Like [append_img_data], packbits rows are decoded as-is.
+///
+/// PSD files generally use compression on their image data. This decompresses it into a slice, bytewise.
+pub fn copy_img_data(cursor : &[u8], output : &mut [u8], stride : usize, size : u64, h : u64) -> Result<usize, PsdError>
+{
+    copy_img_data_impl(cursor, output, stride, size, h, DecodeOpts::default()).map(|x| x.0)
+}
+/// Returns the amount of input consumed and the number of samples decoded, which can be different from the number of samples written if the output is too small.
+///
+/// [DecodeOpts::limit] only bounds ZIP decompression, since nothing else here allocates.
+fn copy_img_data_impl(cursor : &[u8], output : &mut [u8], stride : usize, size : u64, h : u64, opts : DecodeOpts) -> Result<(usize, usize), PsdError>
+{
+    let DecodeOpts { row_len, depth, limit } = opts;
+    let mut _cursor = SliceCursor::new(cursor);
+    let cursor = &mut _cursor;
+    //println!("pos... 0x{:X}", cursor.position());
+    let pos = cursor.position();
+    let mode = read_u16(cursor)?;
+    //println!("size... 0x{:X}", size as usize - 2);
+    let mut i : usize = 0;
+    if mode == 0
+    {
+        for _ in 0..(size as usize).saturating_sub(2)
+        {
+            let c = read_u8(cursor)?;
+            if let Some(x) = output.get_mut(i.saturating_mul(stride))
+            {
+                *x = c;
+            }
+            i += 1;
+        }
+    }
+    else if mode == 1 && h == 0
+    {
+        // No rows, so nothing to decode. Some writers still pad the channel out, which is fine.
+    }
+    else if mode == 1
+    {
+        let j = unpack_rows(cursor, h, 0..h, row_len, |_, row| -> Result<(), PsdError>
+        {
+            for &c in row
+            {
+                if let Some(x) = output.get_mut(i.saturating_mul(stride))
+                {
+                    *x = c;
+                }
+                i += 1;
+            }
+            Ok(())
+        })?;
+        if j != size
+        {
+            return Err(PsdError::Desync { expected : size, actual : j, context : "image data" });
+        }
+    }
+    else if mode == 2 || mode == 3
+    {
+        for c in inflate_img_data(cursor.read_slice(size.saturating_sub(2))?, mode == 3, h, depth, limit)?
+        {
+            if let Some(x) = output.get_mut(i.saturating_mul(stride))
+            {
+                *x = c;
+            }
+            i += 1;
+        }
+    }
+    else
+    {
+        return Err(PsdError::UnsupportedCompression { mode, offset : pos });
+    }
+    Ok((size as usize, i))
+}
+/// Decompress a packbits or ZIP image data buffer one row at a time, calling `f` with each row's index and data, in order, exactly `h` times.
+///
+/// Every row handed to `f` is exactly `w` bytes long: short rows are padded out with zeros and long rows are cut off, the same as [parse_layer_records] does. The row buffer is reused, so with packbits or uncompressed data, only one row is ever held in memory. ZIP-compressed data has to be decompressed in full first.
+///
+/// Errors from `f` stop decoding and are returned as-is. On success, returns `Ok(size)`. Returns an error if there isn't enough data.
+///
+/// This is for processing huge layers without holding whole channels in memory, e.g. to compute histograms or bounding boxes.
+pub fn decode_img_data_rows<E : From<PsdError>>(cursor : &[u8], size : u64, w : u64, h : u64, mut f : impl FnMut(u64, &[u8]) -> Result<(), E>) -> Result<usize, E>
+{
+    /// Cuts uncompressed data up into rows of `w` bytes.
+    fn split_rows<E>(data : &[u8], w : u64, h : u64, f : &mut impl FnMut(u64, &[u8]) -> Result<(), E>) -> Result<(), E>
+    {
+        let mut row = vec!();
+        for i in 0..h
+        {
+            let start = i.saturating_mul(w).min(data.len() as u64) as usize;
+            let end = (start as u64).saturating_add(w).min(data.len() as u64) as usize;
+            if (end - start) as u64 == w
+            {
+                f(i, &data[start..end])?;
+            }
+            else
+            {
+                row.clear();
+                row.extend_from_slice(&data[start..end]);
+                row.resize(w as usize, 0);
+                f(i, &row)?;
+            }
+        }
+        Ok(())
+    }
+    let mut _cursor = SliceCursor::new(cursor);
+    let cursor = &mut _cursor;
+    let pos = cursor.position();
+    let mode = read_u16(cursor)?;
+    if mode == 0
+    {
+        split_rows(cursor.read_slice(size.saturating_sub(2))?, w, h, &mut f)?;
+    }
+    else if mode == 1
+    {
+        let total = unpack_rows(cursor, h, 0..h, Some(w), &mut f)?;
+        if h != 0 && total != size
+        {
+            return Err(PsdError::Desync { expected : size, actual : total, context : "image data" }.into());
+        }
+    }
+    else if mode == 2 || mode == 3
+    {
+        split_rows(&inflate_img_data(cursor.read_slice(size.saturating_sub(2))?, mode == 3, h, 8, usize::MAX)?, w, h, &mut f)?;
+    }
+    else
+    {
+        return Err(PsdError::UnsupportedCompression { mode, offset : pos }.into());
+    }
+    Ok(size as usize)
+}
+/// Where [copy_img_data_rect] puts each decoded sample, and which ones it decodes at all.
+///
+/// Sample `col` of row `row` goes to `output[offset + (row - first_row) * pitch + (col - first_col) * stride]`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CopyRect {
+    /// Index in the output of the first sample of the first row.
+    pub offset : usize,
+    /// Distance between neighboring samples of a row in the output. Defaults to 1.
+    pub stride : usize,
+    /// Distance between the start of one row and the next in the output. Has to be set; defaults to 0.
+    pub pitch : usize,
+    /// First row to decode. Earlier rows are skipped without decompressing them, if the compression mode allows it.
+    pub first_row : u64,
+    /// Number of rows to decode. `None` means through the last row.
+    pub row_count : Option<u64>,
+    /// First sample of each row to keep. Earlier samples are decoded and thrown away.
+    pub first_col : u64,
+    /// Number of samples of each row to keep. `None` means through the end of the row.
+    pub col_count : Option<u64>,
+}
+
+impl Default for CopyRect
+{
+    fn default() -> Self
+    {
+        Self { offset : 0, stride : 1, pitch : 0, first_row : 0, row_count : None, first_col : 0, col_count : None }
+    }
+}
+
+/// Like [copy_img_data], but decodes only some of the rows and columns of the channel, into any rectangle of a larger output. See [CopyRect].
+///
+/// This makes it possible to decode the visible part of a huge layer straight into a canvas without allocating the whole layer. With packbits compression, rows before [CopyRect::first_row] are skipped using the row length table, so they cost almost nothing. ZIP-compressed data has to be decompressed in full, and uncompressed data is assumed to have rows of equal length.
+///
+/// Samples that would land outside the output are skipped. Packbits rows are decoded as-is, like [copy_img_data].
+///
+/// On success, returns `Ok(size)`. Returns an error if there isn't enough data.
+pub fn copy_img_data_rect(cursor : &[u8], output : &mut [u8], size : u64, h : u64, rect : &CopyRect) -> Result<usize, PsdError>
+{
+    let mut _cursor = SliceCursor::new(cursor);
+    let cursor = &mut _cursor;
+    let pos = cursor.position();
+    let mode = read_u16(cursor)?;
+    let end_row = rect.row_count.map_or(h, |x| rect.first_row.saturating_add(x).min(h));
+    let end_col = rect.col_count.map_or(u64::MAX, |x| rect.first_col.saturating_add(x));
+    let mut put = |row : u64, col : u64, c : u8|
+    {
+        if row >= rect.first_row && row < end_row && col >= rect.first_col && col < end_col
+        {
+            let i = rect.offset
+                .saturating_add(((row - rect.first_row) as usize).saturating_mul(rect.pitch))
+                .saturating_add(((col - rect.first_col) as usize).saturating_mul(rect.stride));
+            if let Some(x) = output.get_mut(i)
+            {
+                *x = c;
+            }
+        }
+    };
+    // Uncompressed and ZIP data don't say where their rows start, so assume they're all the same length.
+    let put_rows = |data : &[u8], put : &mut dyn FnMut(u64, u64, u8)|
+    {
+        let row_len = if h == 0 { 0 } else { data.len() / h as usize };
+        for (row, samples) in data.chunks_exact(row_len.max(1)).enumerate().skip(rect.first_row as usize).take(end_row.saturating_sub(rect.first_row) as usize)
+        {
+            for (col, c) in samples.iter().enumerate()
+            {
+                put(row as u64, col as u64, *c);
+            }
+        }
+    };
+    if mode == 0
+    {
+        put_rows(cursor.read_slice(size.saturating_sub(2))?, &mut put);
+    }
+    else if mode == 1
+    {
+        let total = unpack_rows(cursor, h, rect.first_row..end_row, None, |row, data| -> Result<(), PsdError>
+        {
+            for (col, c) in data.iter().enumerate()
+            {
+                put(row, col as u64, *c);
+            }
+            Ok(())
+        })?;
+        if h != 0 && total != size
+        {
+            return Err(PsdError::Desync { expected : size, actual : total, context : "image data" });
+        }
+    }
+    else if mode == 2 || mode == 3
+    {
+        put_rows(&inflate_img_data(cursor.read_slice(size.saturating_sub(2))?, mode == 3, h, 8, usize::MAX)?, &mut put);
+    }
+    else
+    {
+        return Err(PsdError::UnsupportedCompression { mode, offset : pos });
+    }
+    Ok(size as usize)
+}
+/// A layer channel decoded ahead of time, along with what it was decoded with. See [predecode_channels].
+struct Predecoded
+{
+    offset : u64,
+    limit : usize,
+    ret : Option<Result<(usize, Vec<u8>), PsdError>>,
+}
+/// Decodes a layer's channels in parallel, the same way [LayerRecordsIter] would decode them one at a time. Unless `planar` is set, color channels are decoded as if they were being copied into [LayerInfo::image_data_rgba], with missing samples left at 255.
+#[cfg(feature = "rayon")]
+fn predecode_channels(data : &[u8], spans : Vec<ChannelSpan>, sixteen_bit : bool, planar : bool, limit : usize) -> Vec<Predecoded>
+{
+    use rayon::prelude::*;
+    spans.into_par_iter().map(|span|
+    {
+        let mut cursor = SliceCursor::new(data);
+        cursor.set_position(span.offset);
+        let ret = cursor.take_rest().and_then(|rest|
+        {
+            let (w, h) = (span.w as u64, span.h as u64);
+            let mut out = vec!();
+            let progress = if sixteen_bit
+            {
+                decode_16_bit_channel(rest.buf, &mut out, span.length, w, h, limit)
+            }
+            else if !planar && span.id >= -1 && span.id <= 2
+            {
+                out = pixel_buffer(w, h, 1, 255)?;
+                copy_img_data_impl(rest.buf, &mut out, 1, span.length, h, DecodeOpts { row_len : Some(w), depth : 8, limit }).map(|x| x.0)
+            }
+            else
+            {
+                decode_planar_channel(rest.buf, &mut out, span.length, w, h, limit)
+            };
+            Ok((progress.map_err(|x| x.offset_by(span.offset))?, out))
+        });
+        Predecoded { offset : span.offset, limit, ret : Some(ret) }
+    }).collect()
+}
+/// Takes the result of decoding the channel at `offset` out of `predecoded`, if it was decoded with the same limit.
+fn take_predecoded(predecoded : &mut [Predecoded], offset : u64, limit : usize) -> Option<Result<(usize, Vec<u8>), PsdError>>
+{
+    predecoded.iter_mut().find(|x| x.offset == offset && x.limit == limit).and_then(|x| x.ret.take())
+}
+/// Decodes one channel of a layer from the file it was parsed from, given its [ChannelSpan]. This is how to decode layers lazily, e.g. after parsing with [ParseOptions::skip_image_data], or to decode layers in parallel.
+///
+/// Returns one byte per pixel, `span.w * span.h` bytes in total if the data matches the channel's size. 16-bit channels are converted to 8 bits. Empty channels (with no data past the compression mode) return an empty vec.
+pub fn decode_channel(data : &[u8], span : &ChannelSpan) -> Result<Vec<u8>, PsdError>
+{
+    let decode = match span.depth
+    {
+        8 => decode_planar_channel,
+        16 => decode_16_bit_channel,
+        _ => return Err(PsdError::UnsupportedDepth(span.depth)),
+    };
+    let mut cursor = SliceCursor::new(data);
+    cursor.set_position(span.offset);
+    let channel = cursor.sub(span.length)?;
+    let mut ret = vec!();
+    if span.length > 2
+    {
+        decode(channel.buf, &mut ret, span.length, span.w as u64, span.h as u64, usize::MAX).map_err(|x| x.offset_by(span.offset))?;
+    }
+    Ok(ret)
+}
+/// Decompresses a planar channel. Packbits data is decompressed into a buffer pre-sized to the channel's declared rectangle, so that big masks don't have to be grown piece by piece.
+///
+/// Uncompressed and ZIP data, and packbits data that couldn't possibly fill the declared rectangle, are decoded however long they really are, like [append_img_data]. Each channel is only decoded once either way. `output`'s old contents are replaced, but its allocation is reused if it's big enough.
+fn decode_planar_channel(data : &[u8], output : &mut Vec<u8>, size : u64, w : u64, h : u64, limit : usize) -> Result<usize, PsdError>
+{
+    decode_planar_channel_impl(data, output, size, w, h, 8, limit)
+}
+fn decode_planar_channel_impl(data : &[u8], output : &mut Vec<u8>, size : u64, w : u64, h : u64, depth : u16, limit : usize) -> Result<usize, PsdError>
+{
+    // Declared rectangles can be bogus, so only pre-size the buffer if the data could possibly fill it.
+    let plausible = plausible_size(w, h, size);
+    let opts = DecodeOpts { row_len : plausible.then_some(w), depth, limit };
+    output.clear();
+    // Packbits rows get padded or cut off to `w`, so packbits data always fills the buffer exactly. Uncompressed and ZIP data are kept at whatever size they really are, so they're appended instead.
+    let packbits = data.get(..2) == Some(&[0, 1][..]);
+    if !packbits || !plausible || w.saturating_mul(h) > limit as u64
+    {
+        return append_img_data_impl(data, output, size, h, opts);
+    }
+    let Some(buf) = try_pixel_buffer_in(core::mem::take(output), w, h, 1, 0) else { return append_img_data_impl(data, output, size, h, opts) };
+    *output = buf;
+    let (progress, _) = copy_img_data_impl(data, output, 1, size, h, opts)?;
+    Ok(progress)
+}
+/// Decompresses a 16-bit planar channel and converts it to 8 bits per sample.
+///
+/// Packbits works on bytes, not samples, so it's decompressed as if it were an 8-bit channel twice as wide first.
+fn decode_16_bit_channel(data : &[u8], output : &mut Vec<u8>, size : u64, w : u64, h : u64, limit : usize) -> Result<usize, PsdError>
+{
+    let mut wide = vec!();
+    let progress = decode_planar_channel_impl(data, &mut wide, size, w * 2, h, 16, limit.saturating_mul(2))?;
+    output.clear();
+    output.extend(wide.chunks_exact(2).map(|x| ((u16::from_be_bytes([x[0], x[1]]) as u32 * 255 + 32767) / 65535) as u8));
+    Ok(progress)
+}
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// What the layer count at the start of the layer info section says.
+///
+/// Returned from [parse_layer_section_flags].
+pub struct LayerSectionFlags {
+    /// The layer count, as stored. Negative if [LayerSectionFlags::merged_alpha] is set.
+    pub raw_layer_count : i16,
+    /// Is the merged image's first alpha channel the transparency of the merged result? If not, it's a saved selection or other extra channel, like the rest of the alpha channels.
+    pub merged_alpha : bool,
+}
+
+/// Reads the layer count from the start of the layer info section, including the sign bit that [parse_layer_records] doesn't report.
+///
+/// Returns `Ok(None)` if the file doesn't have a layer info section.
+pub fn parse_layer_section_flags(data : &[u8]) -> Result<Option<LayerSectionFlags>, PsdError>
+{
+    let sections = find_sections(data)?;
+    let mut start = sections.layer_and_mask_info + if sections.psb { 8 } else { 4 };
+    // 16-bit and 32-bit documents usually have an empty layer info section, with the real one stored in a document-level block instead.
+    if sections.layer_info_end < start + 2
+    {
+        let Some((_, block_start, _)) = document_block_spans(data)?.into_iter().find(|x| x.0 == "Lr16" || x.0 == "Lr32") else { return Ok(None) };
+        start = block_start;
+    }
+    let mut cursor = SliceCursor::new(data);
+    cursor.set_position(start);
+    let raw_layer_count = read_u16(&mut cursor)? as i16;
+    Ok(Some(LayerSectionFlags { raw_layer_count, merged_alpha : raw_layer_count < 0 }))
+}
+
+/// Parses the layer records out of a PSD file, producing a bottom-to-top list.
+///
+/// PSD data is compressed and poorly-ordered, so it's very rare to benefit from streaming loading, even for performance. Therefore, to keep things simple, the input is a slice instead of a streaming trait.
+///
+/// PSD doesn't store its layer data in a tree; instead, it uses start-of-group and end-of-group nodes in a list to indicate tree structure.
+///
+/// On failure, returns all the layers that have been parsed *so far, from the bottom* (PSD files are stored bottom-up), and the error. The incomplete list of parsed layers is unlikely to be useful for any application, but it may be useful for debugging.
+///
+/// To avoid holding every layer's pixels in memory at once, use [parse_layer_records_iter] instead.
+///
+/// You will need to use both this and [parse_psd_metadata].
+pub fn parse_layer_records(data : &[u8]) -> Result<Vec<LayerInfo>, (Vec<LayerInfo>, PsdError)>
+{
+    parse_layer_records_opts(data, &ParseOptions::default())
+}
+/// Same as [parse_layer_records], but with control over parsing behavior. See [ParseOptions].
+pub fn parse_layer_records_opts(data : &[u8], options : &ParseOptions) -> Result<Vec<LayerInfo>, (Vec<LayerInfo>, PsdError)>
+{
+    let mut layers = Vec::new();
+    let mut iter = match parse_layer_records_iter_opts(data, options)
+    {
+        Ok(iter) => iter,
+        Err(err) => return Err((layers, err)),
+    };
+    for layer in &mut iter
+    {
+        match layer
+        {
+            Ok(layer) => layers.push(layer),
+            Err(err) => return Err((layers, err)),
+        }
+    }
+    iter.attach_late_filter_effects(&mut layers);
+    Ok(layers)
+}
+#[derive(Clone, Debug, Default)]
+/// Image buffers left over from earlier parses, for [parse_layer_records_into] to decode into instead of allocating new ones.
+///
+/// Buffers that don't get reused stay here until the next parse, so drop this (or replace it with a new one) to give the memory back.
+///
+/// Only pixel buffers are reused. Layer names, descriptors and the other small values are still allocated fresh on every parse, since they get moved into the returned layers, so the number of allocations only drops a little: `examples/alloc_count` counts 19 allocations per parse of `data/test.psd` without this and 17 with it, and 155 and 151 for `data/test2.psd`. What gets saved is the size of the allocations, not their number.
+pub struct ScratchBuffers {
+    buffers : Vec<Vec<u8>>,
+}
+
+impl ScratchBuffers
+{
+    /// Takes the pixel data out of some layers for later parses to reuse. [parse_layer_records_into] does this on its own to the layers it's given, so this is only needed for layers that were moved out of there.
+    pub fn recycle(&mut self, layers : impl IntoIterator<Item = LayerInfo>)
+    {
+        for mut layer in layers
+        {
+            let pixels = layer.take_pixels();
+            let planes = pixels.image_data_channels.into_iter().chain(pixels.extra_channels).map(|x| x.1);
+            let buffers = [pixels.image_data_rgba, pixels.image_data_k, pixels.image_data_mask, pixels.image_data_real_mask, pixels.smart_filter_mask].into_iter().chain(planes);
+            self.buffers.extend(buffers.filter(|x| x.capacity() > 0));
+        }
+    }
+}
+
+/// Same as [parse_layer_records_opts], but parses into an existing list of layers, reusing its memory and the memory of the layers' image data.
+///
+/// Meant for parsing the same file over and over, like when watching it for changes: the results are the same as a fresh parse, but the big buffers get reused instead of being allocated again each time. On failure, `layers` holds the layers that were parsed before the error.
+pub fn parse_layer_records_into(data : &[u8], options : &ParseOptions, layers : &mut Vec<LayerInfo>, scratch : &mut ScratchBuffers) -> Result<(), PsdError>
+{
+    scratch.recycle(layers.drain(..));
+    let mut iter = parse_layer_records_iter_opts(data, options)?;
+    iter.pool = core::mem::take(&mut scratch.buffers);
+    let mut ret = Ok(());
+    for layer in &mut iter
+    {
+        match layer
+        {
+            Ok(layer) => layers.push(layer),
+            Err(err) =>
+            {
+                ret = Err(err);
+                break;
+            }
+        }
+    }
+    if ret.is_ok()
+    {
+        iter.attach_late_filter_effects(layers);
+    }
+    scratch.buffers = core::mem::take(&mut iter.pool);
+    ret
+}
+/// Same as [parse_layer_records], but returns an iterator that parses one layer at a time, bottom-to-top. Each layer's image data is decoded when the layer is reached, so only the current layer's pixels need to be in memory at once.
+///
+/// Fails right away if the file's header or layer info section is unusable. Errors while parsing a layer are yielded by the iterator, which stops after the first one.
+pub fn parse_layer_records_iter(data : &[u8]) -> Result<LayerRecordsIter<'_>, PsdError>
+{
+    parse_layer_records_iter_opts(data, &ParseOptions::default())
+}
+/// Same as [parse_layer_records_iter], but with control over parsing behavior. See [ParseOptions].
+pub fn parse_layer_records_iter_opts<'a>(data : &'a [u8], options : &ParseOptions) -> Result<LayerRecordsIter<'a>, PsdError>
+{
+    let metadata = parse_psd_metadata(data)?;
+    let sixteen_bit = metadata.depth == 16 && options.convert_16_bit;
+    if metadata.depth != 8 && !sixteen_bit
+    {
+        return Err(PsdError::UnsupportedDepth(metadata.depth));
+    }
+    // TODO
+    if metadata.color_mode != 1 && metadata.color_mode != 2 && metadata.color_mode != 3 && metadata.color_mode != 4
+    {
+        return Err(PsdError::UnsupportedColorMode(metadata.color_mode));
+    }
+    
+    let mut cursor = SliceCursor::new(data);
+    cursor.set_position(26);
+
+    let color_mode_length = read_u32(&mut cursor)? as u64;
+    cursor.set_position(cursor.position() + color_mode_length);
+
+    let image_resources_length = read_u32(&mut cursor)? as u64;
+    cursor.set_position(cursor.position() + image_resources_length);
+
+    let layer_mask_info_length = read_u32(&mut cursor)? as u64;
+    let _layer_mask_info_end = cursor.position() + layer_mask_info_length;
+
+    let layer_info_length = read_u32(&mut cursor)? as u64;
+    let _layer_info_end = cursor.position() + layer_info_length;
+    
+    let mut has_layers = true;
+    // 16-bit documents usually have an empty layer info section, with the real one stored in a document-level 'Lr16' block instead.
+    if sixteen_bit && (layer_info_length == 0 || read_u16(&mut cursor.clone())? == 0)
+    {
+        if let Some((_, start, _)) = document_block_spans(data)?.into_iter().find(|x| x.0 == "Lr16")
+        {
+            cursor.set_position(start);
+        }
+        else if layer_info_length == 0
+        {
+            has_layers = false;
+        }
+    }
+    
+    let mut layer_count = 0;
+    if has_layers
+    {
+        layer_count = read_u16(&mut cursor)? as i16;
+    }
+    let layer_count = layer_count.unsigned_abs(); // If negative, transparency info exists. See parse_layer_section_flags.
+    if let Some(max) = options.limits.max_layer_count && layer_count as u32 > max
+    {
+        return Err(PsdError::LimitExceeded("max_layer_count"));
+    }
+    
+    #[cfg(feature = "debug_spew")]
+    println!("starting at {:X}", cursor.position());
+    
+    let mut idata_c = SliceCursor::new(data);
+    idata_c.set_position(cursor.position());
+    
+    for _i in 0..layer_count
+    {
+        //println!("{}", _i);
+        read_i32(&mut idata_c)?;
+        read_i32(&mut idata_c)?;
+        read_i32(&mut idata_c)?;
+        read_i32(&mut idata_c)?;
+        let image_channel_count = read_u16(&mut idata_c)? as u64;
+        idata_c.set_position(idata_c.position() + 6*image_channel_count + 4 + 4 + 4);
+        let idat_len = read_u32(&mut idata_c)? as u64;
+        idata_c.set_position(idata_c.position() + idat_len);
+    }
+    
+    Ok(LayerRecordsIter {
+        data,
+        options : options.clone(),
+        metadata,
+        sixteen_bit,
+        cursor,
+        idata_c,
+        remaining : layer_count,
+        index : 0,
+        max_depth : options.limits.max_descriptor_depth.map_or(256, |x| x as usize),
+        bytes_left : options.limits.max_total_bytes.unwrap_or(u64::MAX),
+        filter_effects : vec!(),
+        document_effects_loaded : false,
+        late_filter_effects : vec!(),
+        pool : vec!(),
+        failed : false,
+    })
+}
+
+/// Iterator over the layer records of a PSD file, bottom-to-top. Returned from [parse_layer_records_iter].
+///
+/// Layer records and their image data are stored in two separate lists, so this keeps track of where it is in both.
+pub struct LayerRecordsIter<'a> {
+    data : &'a [u8],
+    options : ParseOptions,
+    metadata : PsdMetadata,
+    sixteen_bit : bool,
+    /// Position in the layer records.
+    cursor : SliceCursor<'a>,
+    /// Position in the layers' image data, which comes after all of the layer records.
+    idata_c : SliceCursor<'a>,
+    remaining : u16,
+    index : usize,
+    max_depth : usize,
+    bytes_left : u64,
+    /// Smart filter masks live in a separate block from the smart filters themselves, keyed by the smart object's ID. Usually it's a document-level block, which is only read once a smart object shows up.
+    filter_effects : Vec<(String, MaskInfo, Vec<u8>)>,
+    document_effects_loaded : bool,
+    /// Smart objects whose filter masks weren't found by the time they were yielded, by layer index. They might be in a later layer's 'FXid' block.
+    late_filter_effects : Vec<(usize, String)>,
+    /// Old image buffers to decode into instead of allocating new ones. See [ScratchBuffers].
+    pool : Vec<Vec<u8>>,
+    failed : bool,
+}
+
+impl LayerRecordsIter<'_>
+{
+    fn next_layer(&mut self) -> Result<LayerInfo, PsdError>
+    {
+        let options = &self.options;
+        let metadata = &self.metadata;
+        let sixteen_bit = self.sixteen_bit;
+        let max_depth = self.max_depth;
+        let mut cursor = self.cursor.clone();
+        let mut idata_c = self.idata_c.clone();
+        let mut bytes_left = self.bytes_left;
+        let filter_effects = &mut self.filter_effects;
+        let pool = &mut self.pool;
+        let mut smart_object_id = None;
+        
+        let top = read_i32(&mut cursor)?;
+        let left = read_i32(&mut cursor)?;
+        let bottom = read_i32(&mut cursor)?;
+        let right = read_i32(&mut cursor)?;
+
+        let x = left;
+        let y = top;
+        let (w, h) = rect_size(top, left, bottom, right);
+        options.limits.check_pixels(w, h)?;
+        
+        let image_channel_count = read_u16(&mut cursor)?;
+        //println!("chan count {}", image_channel_count);
+        
+        let channel_info_start = cursor.position();
+        
+        cursor.set_position(channel_info_start);
+        let mut image_data_rgba : Vec<u8> = if options.skip_image_data || options.planar_channels
+        {
+            vec!()
+        }
+        else
+        {
+            spend_bytes(&mut bytes_left, w as u64 * h as u64 * 4)?;
+            pixel_buffer_in(take_buffer(pool, w as u64 * h as u64 * 4), w as u64, h as u64, 4, 255)?
+        };
+        let mut image_data_k : Vec<u8> = vec!();
+        let mut image_data_channels = vec!();
+        let mut extra_channels = vec!();
+        let mut image_data_mask : Vec<u8> = vec!();
+        let mut image_data_real_mask : Vec<u8> = vec!();
+        
+        let mut _rgba_count = 0;
+        let mut has_r = false;
+        let mut has_g = false;
+        let mut has_b = false;
+        let mut has_a = false;
+        let mut aux_count = 0;
+        let mut channel_spans = vec!();
+        
+        let mut cdat_cursor = cursor.clone();
+        
+        let mut has_neg2 = false;
+        let mut has_neg3 = false;
+        for _ in 0..image_channel_count
+        {
+            let channel_id = read_u16(&mut cursor)? as i16;
+            let _channel_length = read_u32(&mut cursor)? as usize;
+            has_neg2 = has_neg2 || channel_id == -2;
+            has_neg3 = has_neg3 || channel_id == -3;
+        }
+        
+        let blend_mode_signature = read_b4(&mut cursor)?;
+        if blend_mode_signature != [0x38, 0x42, 0x49, 0x4D]
+        {
+            return Err(PsdError::BadSignature { what : "blend mode", found : blend_mode_signature, offset : cursor.position() - 4 });
+        }
+
+        let blend_mode_key = read_b4(&mut cursor)?;
+        let blend_mode = String::from_utf8_lossy(&blend_mode_key).to_string();
+
+        let opacity = read_u8(&mut cursor)? as f32 / 255.0;
+        #[cfg(feature = "debug_spew")]
+        println!("opacity: {}", opacity * 100.0);
+        let clipping = read_u8(&mut cursor)?;
+        let flags = read_u8(&mut cursor)?;
+        let _filler = read_u8(&mut cursor)?;
+
+        let exdat_len = read_u32(&mut cursor)? as u64;
+        let exdat_start = cursor.position();
+        
+        let maskdat_len = read_u32(&mut cursor)? as u64;
+        let maskdat_start = cursor.position();
+        
+        fn read_mask_rect(cursor : &mut SliceCursor, mask_info : &mut MaskInfo) -> Result<(), PsdError>
+        {
+            let mtop = read_i32(cursor)?;
+            let mleft = read_i32(cursor)?;
+            let mbottom = read_i32(cursor)?;
+            let mright = read_i32(cursor)?;
+            mask_info.x = mleft;
+            mask_info.y = mtop;
+            (mask_info.w, mask_info.h) = rect_size(mtop, mleft, mbottom, mright);
+            mask_info.rect_raw = (mtop, mleft, mbottom, mright);
+            Ok(())
+        }
+        fn apply_mask_flags(mask_info : &mut MaskInfo, mflags : u8)
+        {
+            mask_info.relative = (mflags & 1) != 0;
+            mask_info.disabled = (mflags & 2) != 0;
+            mask_info.invert = (mflags & 4) != 0;
+            mask_info.from_render = (mflags & 8) != 0;
+            mask_info.flags_raw = mflags;
+        }
+        let limit = |bytes_left : u64| bytes_left.min(usize::MAX as u64) as usize;
+        
+        // Layers without a mask have an empty mask data section.
+        let mut mask_info = MaskInfo::default();
+        let mut real_mask_info = None;
+        let mut mflags = 0;
+        if maskdat_len >= 20
+        {
+            read_mask_rect(&mut cursor, &mut mask_info)?;
+            options.limits.check_pixels(mask_info.w, mask_info.h)?;
+            mask_info.default_color = read_u8(&mut cursor)?;
+            mflags = read_u8(&mut cursor)?;
+            apply_mask_flags(&mut mask_info, mflags);
+        }
+        // Mask parameters. Each one is only there if its bit is set.
+        if (mflags & 16) != 0 && cursor.position() < maskdat_start + maskdat_len
+        {
+            let params = read_u8(&mut cursor)?;
+            if (params & 1) != 0
+            {
+                mask_info.user_density = Some(read_u8(&mut cursor)?);
+            }
+            if (params & 2) != 0
+            {
+                mask_info.user_feather = Some(read_f64(&mut cursor)?);
+            }
+            if (params & 4) != 0
+            {
+                mask_info.vector_density = Some(read_u8(&mut cursor)?);
+            }
+            if (params & 8) != 0
+            {
+                mask_info.vector_feather = Some(read_f64(&mut cursor)?);
+            }
+        }
+        // The real user mask comes last, in the opposite order. Records without one are padded out to at least 20 bytes, which is never enough to hold one.
+        if cursor.position() + 18 <= maskdat_start + maskdat_len
+        {
+            let mut real = MaskInfo::default();
+            apply_mask_flags(&mut real, read_u8(&mut cursor)?);
+            real.default_color = read_u8(&mut cursor)?;
+            read_mask_rect(&mut cursor, &mut real)?;
+            options.limits.check_pixels(real.w, real.h)?;
+            real_mask_info = Some(real);
+        }
+        
+        let mut warnings = vec!();
+        if maskdat_len != 0 && mask_info.default_color != 0 && mask_info.default_color != 255
+        {
+            let warning = format!("Nonstandard mask default color {}", mask_info.default_color);
+            if options.strict
+            {
+                return Err(PsdError::Strict(warning));
+            }
+            warnings.push(warning);
+        }
+        let rects = [("layer", (top, left, bottom, right)), ("mask", mask_info.rect_raw)].into_iter().chain(real_mask_info.as_ref().map(|x| ("real mask", x.rect_raw)));
+        for (what, rect) in rects
+        {
+            if rect_inside_out(rect)
+            {
+                let warning = format!("Inside-out {} rectangle {:?}, treated as empty", what, rect);
+                if options.strict
+                {
+                    return Err(PsdError::Strict(warning));
+                }
+                warnings.push(warning);
+            }
+        }
+        
+        cursor.set_position(maskdat_start + maskdat_len);
+        
+        // With the rayon feature, every channel that's going to be decoded gets decoded up front in parallel, assuming that the channels before it are well-formed.
+        // The loop below falls back to decoding in line if that assumption turns out wrong, so the results are the same either way.
+        #[cfg(feature = "rayon")]
+        let mut predecoded = {
+            let mut spans = vec!();
+            let mut c = cdat_cursor.clone();
+            let mut offset = idata_c.position();
+            for _ in 0..image_channel_count
+            {
+                let id = read_u16(&mut c)? as i16;
+                let length = read_u32(&mut c)? as u64;
+                let (info, skip) = match (id, &real_mask_info)
+                {
+                    (-1..=3, _) => (None, options.skip_image_data),
+                    (-3, Some(real)) => (Some(real), options.skip_masks || rect_inside_out(real.rect_raw)),
+                    (-2 | -3, _) => (Some(&mask_info), options.skip_masks || rect_inside_out(mask_info.rect_raw)),
+                    _ => (None, options.skip_image_data || !options.extra_channels),
+                };
+                if !skip && length > 2
+                {
+                    let (w, h) = info.map_or((w, h), |x| (x.w, x.h));
+                    spans.push(ChannelSpan { id, compression : 0, offset, length, w, h, depth : metadata.depth });
+                }
+                offset += if skip || length > 2 { length } else { 2 };
+            }
+            predecode_channels(idata_c.buf, spans, sixteen_bit, options.planar_channels, limit(bytes_left))
+        };
+        #[cfg(not(feature = "rayon"))]
+        let mut predecoded = vec!();
+        
+        for _ in 0..image_channel_count
+        {
+            let channel_id = read_u16(&mut cdat_cursor)? as i16;
+            has_r |= channel_id == 0;
+            has_g |= channel_id == 1;
+            has_b |= channel_id == 2;
+            has_a |= channel_id == -1;
+            let channel_length = read_u32(&mut cdat_cursor)? as usize;
+            #[cfg(feature = "debug_spew")]
+            println!("channel... {} {} at 0x{:X}", channel_id, channel_length, idata_c.position());
+            let (span_w, span_h) = match (channel_id, &real_mask_info)
+            {
+                (-3, Some(real)) => (real.w, real.h),
+                (-2 | -3, _) => (mask_info.w, mask_info.h),
+                _ => (w, h),
+            };
+            channel_spans.push(ChannelSpan {
+                id : channel_id,
+                compression : if channel_length >= 2 { read_u16(&mut idata_c.clone()).unwrap_or(0) } else { 0 },
+                offset : idata_c.position(),
+                length : channel_length as u64,
+                w : span_w,
+                h : span_h,
+                depth : metadata.depth,
+            });
+            if channel_id >= -1 && channel_id <= 2
+            {
+                _rgba_count += 1;
+                let pos = if channel_id >= 0 { channel_id } else { 3 } as usize;
+                #[cfg(feature = "debug_spew")]
+                println!("{} {} {} {}", w, h, pos, channel_length);
+                if options.skip_image_data
+                {
+                    idata_c.set_position(idata_c.position() + channel_length as u64);
+                }
+                else if options.planar_channels && channel_length > 2
+                {
+                    let decode = if sixteen_bit { decode_16_bit_channel } else { decode_planar_channel };
+                    let mut plane = vec!();
+                    let progress = match take_predecoded(&mut predecoded, idata_c.position(), limit(bytes_left))
+                    {
+                        Some(ret) => ret.map(|(progress, data)| { plane = data; progress })?,
+                        None =>
+                        {
+                            plane = take_buffer(pool, w as u64 * h as u64);
+                            decode(idata_c.take_rest()?.buf, &mut plane, channel_length as u64, w as u64, h as u64, limit(bytes_left)).map_err(|x| x.offset_by(idata_c.position()))?
+                        }
+                    };
+                    spend_bytes(&mut bytes_left, plane.len() as u64)?;
+                    image_data_channels.push((channel_id, plane));
+                    idata_c.pos += progress;
+                }
+                else if channel_length > 2 && sixteen_bit
+                {
+                    let (progress, temp) = match take_predecoded(&mut predecoded, idata_c.position(), limit(bytes_left))
+                    {
+                        Some(ret) => ret?,
+                        None =>
+                        {
+                            let mut temp = take_buffer(pool, w as u64 * h as u64);
+                            let progress = decode_16_bit_channel(idata_c.take_rest()?.buf, &mut temp, channel_length as u64, w as u64, h as u64, limit(bytes_left)).map_err(|x| x.offset_by(idata_c.position()))?;
+                            (progress, temp)
+                        }
+                    };
+                    for (i, c) in temp.iter().enumerate()
+                    {
+                        if let Some(x) = image_data_rgba.get_mut(pos + i*4)
+                        {
+                            *x = *c;
+                        }
+                    }
+                    pool.push(temp);
+                    idata_c.pos += progress;
+                }
+                else if channel_length > 2
+                {
+                    let progress = match take_predecoded(&mut predecoded, idata_c.position(), limit(bytes_left))
+                    {
+                        Some(ret) =>
+                        {
+                            let (progress, samples) = ret?;
+                            for (i, c) in samples.into_iter().enumerate()
+                            {
+                                if let Some(x) = image_data_rgba.get_mut(pos + i*4)
+                                {
+                                    *x = c;
+                                }
+                            }
+                            progress
+                        }
+                        None => copy_img_data_impl(idata_c.take_rest()?.buf, image_data_rgba.get_mut(pos..).unwrap_or_default(), 4, channel_length as u64, h as u64, DecodeOpts { row_len : Some(w as u64), depth : 8, limit : limit(bytes_left) }).map_err(|x| x.offset_by(idata_c.position()))?.0,
+                    };
+                    idata_c.pos += progress;
+                }
+                else
+                {
+                    idata_c.set_position(idata_c.position() + 2);
+                }
+            }
+            else if channel_id == 3 // CMYK's K
+            {
+                if options.skip_image_data
+                {
+                    idata_c.set_position(idata_c.position() + channel_length as u64);
+                }
+                else if channel_length > 2
+                {
+                    let decode = if sixteen_bit { decode_16_bit_channel } else { decode_planar_channel };
+                    let progress = match take_predecoded(&mut predecoded, idata_c.position(), limit(bytes_left))
+                    {
+                        Some(ret) => ret.map(|(progress, data)| { image_data_k = data; progress })?,
+                        None =>
+                        {
+                            image_data_k = take_buffer(pool, w as u64 * h as u64);
+                            decode(idata_c.take_rest()?.buf, &mut image_data_k, channel_length as u64, w as u64, h as u64, limit(bytes_left)).map_err(|x| x.offset_by(idata_c.position()))?
+                        }
+                    };
+                    spend_bytes(&mut bytes_left, image_data_k.len() as u64)?;
+                    if options.planar_channels
+                    {
+                        image_data_channels.push((3, core::mem::take(&mut image_data_k)));
+                    }
+                    idata_c.pos += progress;
+                }
+                else
+                {
+                    idata_c.set_position(idata_c.position() + 2);
+                }
+            }
+            else if channel_id != -2 && channel_id != -3 // spot and alpha channels
+            {
+                aux_count += 1;
+                if options.extra_channels && !options.skip_image_data && channel_length > 2
+                {
+                    let decode = if sixteen_bit { decode_16_bit_channel } else { decode_planar_channel };
+                    let mut plane = vec!();
+                    let progress = match take_predecoded(&mut predecoded, idata_c.position(), limit(bytes_left))
+                    {
+                        Some(ret) => ret.map(|(progress, data)| { plane = data; progress })?,
+                        None =>
+                        {
+                            plane = take_buffer(pool, w as u64 * h as u64);
+                            decode(idata_c.take_rest()?.buf, &mut plane, channel_length as u64, w as u64, h as u64, limit(bytes_left)).map_err(|x| x.offset_by(idata_c.position()))?
+                        }
+                    };
+                    spend_bytes(&mut bytes_left, plane.len() as u64)?;
+                    extra_channels.push((channel_id, plane));
+                    idata_c.pos += progress;
+                }
+                else
+                {
+                    idata_c.set_position(idata_c.position() + channel_length as u64);
+                }
+            }
+            else
+            {
+                #[cfg(feature = "debug_spew")]
+                println!("mask... {} {} {}", mask_info.w, mask_info.h, channel_length);
+                aux_count += 1;
+                // Each mask channel goes with its own mask info. Without a real user mask record, -3 is treated like -2.
+                let (info, out) = match (channel_id, &real_mask_info)
+                {
+                    (-3, Some(real)) => (real, &mut image_data_real_mask),
+                    _ => (&mask_info, &mut image_data_mask),
+                };
+                // Inside-out masks are treated as empty, so their data is skipped.
+                if options.skip_masks || !out.is_empty() || rect_inside_out(info.rect_raw)
+                {
+                    idata_c.set_position(idata_c.position() + channel_length as u64);
+                }
+                else if channel_length > 2
+                {
+                    #[cfg(feature = "debug_spew")]
+                    println!("adding mask data...");
+                    let decode = if sixteen_bit { decode_16_bit_channel } else { decode_planar_channel };
+                    let progress = match take_predecoded(&mut predecoded, idata_c.position(), limit(bytes_left))
+                    {
+                        Some(ret) => ret.map(|(progress, data)| { *out = data; progress })?,
+                        None =>
+                        {
+                            *out = take_buffer(pool, info.w as u64 * info.h as u64);
+                            decode(idata_c.take_rest()?.buf, out, channel_length as u64, info.w as u64, info.h as u64, limit(bytes_left)).map_err(|x| x.offset_by(idata_c.position()))?
+                        }
+                    };
+                    // Compressed data can decode to more than the mask's rectangle holds. The rest isn't part of the mask.
+                    out.truncate((info.w as u64 * info.h as u64).min(usize::MAX as u64) as usize);
+                    spend_bytes(&mut bytes_left, out.len() as u64)?;
+                    idata_c.pos += progress;
+                }
+                else
+                {
+                    idata_c.set_position(idata_c.position() + 2);
+                }
+            }
+        }
+        
+        if options.expand_indexed && let Some(palette) = &metadata.palette
+        {
+            for pixel in image_data_rgba.chunks_exact_mut(4)
+            {
+                let color = palette[pixel[0] as usize];
+                pixel[..3].copy_from_slice(&color);
+            }
+            has_g = has_r;
+            has_b = has_r;
+        }
+        
+        let blendat_len = read_u32(&mut cursor)? as u64;
+        cursor.set_position(cursor.position() + blendat_len);
+        
+        let mut name_len = read_u8(&mut cursor)? as u64;
+        let orig_namelen = name_len;
+        while !(name_len + 1).is_multiple_of(4)
+        {
+            name_len += 1;
+        }
+        let name = cursor.read_slice(name_len)?;
+        let name = String::from_utf8_lossy(&name[..orig_namelen as usize]).to_string();
+
+        let mut layer = LayerInfo {
+            name : name.clone(),
+            name_ascii : name,
+            name_source : "".to_string(),
+            layer_id : None,
+            opacity,
+            fill_opacity : 1.0,
             blend_mode,
             x,
             y,
             w,
             h,
+            rect_raw : (top, left, bottom, right),
             image_channel_count,
+            channel_spans,
             image_data_rgba,
             image_data_k,
+            image_data_channels,
+            extra_channels,
+            image_data_shrunk : false,
+            image_data_has_r : has_r,
             image_data_has_g : has_g,
             image_data_has_b : has_b,
             image_data_has_a : has_a,
             mask_channel_count : aux_count,
             mask_info,
+            real_mask_info,
+            vector_mask : None,
             image_data_mask,
+            image_data_real_mask,
             group_expanded : false,
             group_opener : false,
             group_closer : false,
+            group_blend_mode : "".to_string(),
+            group_sub_type : 0,
             funny_flag : false,
+            knockout : 0,
+            blend_interior_effects : false,
+            blend_clipped_elements : true,
+            blend_restricted_channels : vec!(),
+            layer_mask_as_global_mask : false,
+            user_mask_as_global_mask : false,
             is_clipped : clipping != 0,
             is_alpha_locked : (flags & 1) != 0,
+            lock_composite : false,
+            lock_position : false,
+            lock_all : false,
             is_visible : (flags & 2) == 0,
+            flags_raw : flags,
+            sheet_color : 0,
             adjustment_type : "".to_string(),
             adjustment_info : vec!(),
             adjustment_desc : None,
+            fill_pattern_id : None,
             effects_desc : None,
+            vector_fill_desc : None,
+            vector_stroke_desc : None,
+            text_info : None,
+            effects_enabled : None,
+            legacy_effects : None,
+            reference_point : None,
+            placed_layer : None,
+            smart_filters : None,
+            metadata_settings : vec!(),
+            extra_blocks : vec!(),
+            warnings,
+        };
+        
+        //println!("--- {:X}", cursor.position());
+        
+        // 'lsdk' is the same as 'lsct', but 'lsct' wins if a layer has both.
+        let mut has_lsct = false;
+        let mut has_cged = false;
+        // 'FMsk' can come before or after the smart filters it belongs to.
+        let mut filter_mask = None;
+        while cursor.position() < exdat_start + exdat_len
+        {
+            let sig = read_b4(&mut cursor)?;
+            if sig != [0x38, 0x42, 0x49, 0x4D]
+            {
+                return Err(PsdError::BadSignature { what : "extended data", found : sig, offset : cursor.position() - 4 });
+            }
+            
+            let name = read_b4(&mut cursor)?;
+            let name = String::from_utf8_lossy(&name).to_string();
+            
+            let len = read_u32(&mut cursor)? as u64;
+            //println!("?? {}", len);
+            let start = cursor.position();
+            
+            #[cfg(feature = "debug_spew")]
+            println!("reading metadata.... {}", name.as_str());
+            
+            let mut known = true;
+            // This comment must stay here: it is a ctrl+f anchor.
+            // Read adjustment data.
+            match name.as_str()
+            {
+                "lsdk" if has_lsct => {}
+                "lsct" | "lsdk" =>
+                {
+                    has_lsct |= name == "lsct";
+                    layer.group_blend_mode = "".to_string();
+                    layer.group_sub_type = 0;
+                    let kind = read_u32(&mut cursor)? as u64;
+                    layer.group_expanded = kind == 1;
+                    layer.group_opener = kind == 1 || kind == 2;
+                    layer.group_closer = kind == 3;
+                    if kind == 1 || kind == 2
+                    {
+                        #[cfg(feature = "debug_spew")]
+                        println!("group opener!");
+                    }
+                    if kind == 3
+                    {
+                        #[cfg(feature = "debug_spew")]
+                        println!("group closer!");
+                    }
+                    if len >= 12 && read_b4(&mut cursor)? == *b"8BIM"
+                    {
+                        layer.group_blend_mode = String::from_utf8_lossy(&read_b4(&mut cursor)?).to_string();
+                        if len >= 16
+                        {
+                            layer.group_sub_type = read_u32(&mut cursor)?;
+                        }
+                    }
+                }
+                "luni" =>
+                {
+                    layer.name = read_unicode_string(&mut cursor)?;
+                }
+                "lyid" =>
+                {
+                    layer.layer_id = Some(read_u32(&mut cursor)?);
+                }
+                "lclr" =>
+                {
+                    // The other 6 bytes are unused, but not always zero.
+                    layer.sheet_color = read_u16(&mut cursor)?;
+                }
+                "lspf" =>
+                {
+                    let flags = read_u32(&mut cursor)?;
+                    layer.is_alpha_locked = (flags & 1) != 0;
+                    layer.lock_composite = (flags & 2) != 0;
+                    layer.lock_position = (flags & 4) != 0;
+                    layer.lock_all = (flags & 0x8000_0000) != 0;
+                }
+                "lnsr" =>
+                {
+                    layer.name_source = String::from_utf8_lossy(&read_b4(&mut cursor)?).to_string();
+                }
+                "shmd" =>
+                {
+                    let count = read_u32(&mut cursor)?;
+                    for _ in 0..count
+                    {
+                        let sig = read_b4(&mut cursor)?;
+                        // Without a good signature, there's no telling where the entry ends, so the rest of them get dropped.
+                        if sig != *b"8BIM"
+                        {
+                            let warning = format!("Bad metadata setting signature {:?}, skipping the rest", String::from_utf8_lossy(&sig));
+                            if options.strict
+                            {
+                                return Err(PsdError::Strict(warning));
+                            }
+                            layer.warnings.push(warning);
+                            break;
+                        }
+                        let key = String::from_utf8_lossy(&read_b4(&mut cursor)?).to_string();
+                        let copy_on_duplicate = read_u8(&mut cursor)? != 0;
+                        cursor.set_position(cursor.position() + 3);
+                        let len = read_u32(&mut cursor)? as u64;
+                        let data = cursor.read_slice(len)?;
+                        
+                        let mut c = SliceCursor::new(data);
+                        let desc = read_versioned_descriptor(&mut c, max_depth).ok().flatten();
+                        layer.metadata_settings.push(MetadataSetting { key, copy_on_duplicate, data : data.to_vec(), desc });
+                    }
+                }
+                "fxrp" =>
+                {
+                    layer.reference_point = Some((read_f64(&mut cursor)?, read_f64(&mut cursor)?));
+                }
+                "tsly" =>
+                {
+                    let thing = read_u8(&mut cursor)?;
+                    layer.funny_flag = thing == 0;
+                    #[cfg(feature = "debug_spew")]
+                    println!("{}", layer.funny_flag);
+                }
+                "knko" =>
+                {
+                    layer.knockout = read_u8(&mut cursor)?;
+                }
+                "infx" =>
+                {
+                    layer.blend_interior_effects = read_u8(&mut cursor)? != 0;
+                }
+                "clbl" =>
+                {
+                    layer.blend_clipped_elements = read_u8(&mut cursor)? != 0;
+                }
+                "brst" =>
+                {
+                    // No count; the block is just as long as the list.
+                    for _ in 0..len / 4
+                    {
+                        layer.blend_restricted_channels.push(read_u32(&mut cursor)?);
+                    }
+                }
+                "vmsk" | "vsms" =>
+                {
+                    let _version = read_u32(&mut cursor)?;
+                    let flags = read_u32(&mut cursor)?;
+                    let path_start = cursor.position();
+                    let path = read_path_records(cursor.read_slice(len.saturating_sub(8))?).map_err(|x| x.offset_by(path_start))?;
+                    layer.vector_mask = Some(VectorMaskInfo
+                    {
+                        invert : (flags & 1) != 0,
+                        not_linked : (flags & 2) != 0,
+                        disabled : (flags & 4) != 0,
+                        subpaths : path.subpaths,
+                        initial_fill : path.initial_fill,
+                    });
+                }
+                "vscg" =>
+                {
+                    let key = String::from_utf8_lossy(&read_b4(&mut cursor)?).to_string();
+                    layer.vector_fill_desc = read_versioned_descriptor(&mut cursor, max_depth)?.map(|desc| (key, desc));
+                }
+                "vstk" =>
+                {
+                    layer.vector_stroke_desc = read_versioned_descriptor(&mut cursor, max_depth)?;
+                }
+                "TySh" =>
+                {
+                    let _version = read_u16(&mut cursor)?;
+                    let mut transform = [0.0; 6];
+                    for x in transform.iter_mut()
+                    {
+                        *x = read_f64(&mut cursor)?;
+                    }
+                    let _text_version = read_u16(&mut cursor)?;
+                    if let Some(text_desc) = read_versioned_descriptor(&mut cursor, max_depth)?
+                    {
+                        // If a descriptor stopped early or couldn't be read, there's no finding what comes after it, so that's left empty.
+                        let mut warp_desc = None;
+                        let mut bounds = [0.0; 4];
+                        if !text_desc.is_truncated()
+                        {
+                            let _warp_version = read_u16(&mut cursor)?;
+                            warp_desc = read_versioned_descriptor(&mut cursor, max_depth)?;
+                        }
+                        if warp_desc.as_ref().is_some_and(|x| !x.is_truncated())
+                        {
+                            // The spec says these are 4-byte integers, but Photoshop writes doubles.
+                            let doubles = start + len >= cursor.position() + 32;
+                            for x in bounds.iter_mut()
+                            {
+                                *x = if doubles { read_f64(&mut cursor)? } else { read_i32(&mut cursor)? as f64 };
+                            }
+                        }
+                        layer.text_info = Some(TextLayerInfo { transform, text_desc, warp_desc : warp_desc.unwrap_or_default(), bounds });
+                    }
+                }
+                "lrFX" =>
+                {
+                    let fx = read_legacy_effects(&mut cursor)?;
+                    // 'lfx2' wins if the layer has both, whichever comes first.
+                    layer.effects_enabled.get_or_insert(fx.visible);
+                    layer.legacy_effects = Some(fx);
+                }
+                "lmgm" =>
+                {
+                    layer.layer_mask_as_global_mask = read_u8(&mut cursor)? != 0;
+                }
+                "lumm" =>
+                {
+                    layer.user_mask_as_global_mask = read_u8(&mut cursor)? != 0;
+                }
+                "iOpa" =>
+                {
+                    layer.fill_opacity = read_u8(&mut cursor)? as f32 / 255.0;
+                }
+                "lfx2" =>
+                {
+                    // The object effects version comes before the descriptor's own version.
+                    if read_u32(&mut cursor)? == 0
+                        && let Some(desc) = read_versioned_descriptor(&mut cursor, max_depth)?
+                    {
+                        layer.effects_enabled = Some(!matches!(desc.get("masterFXSwitch"), Some(DescItem::bool(false))));
+                        layer.effects_desc = Some(desc);
+                    }
+                }
+                "SoLd" =>
+                {
+                    let _key = read_b4(&mut cursor)?;
+                    let _version = read_u32(&mut cursor)?;
+                    // Smart object descriptors are big and varied, so don't let a broken one kill the whole parse.
+                    if let Ok(Some(desc)) = read_versioned_descriptor(&mut cursor, max_depth)
+                    {
+                        layer.smart_filters = read_smart_filters(&desc);
+                        if let Some(DescItem::TEXT(id)) = desc.get("Idnt")
+                        {
+                            smart_object_id = Some(id.clone());
+                        }
+                        let plld_transform = layer.placed_layer.take().and_then(|x| x.plld_transform);
+                        layer.placed_layer = Some(read_placed_layer(desc, plld_transform));
+                    }
+                }
+                "PlLd" =>
+                {
+                    let sig = read_b4(&mut cursor)?;
+                    if &sig != b"plcL"
+                    {
+                        return Err(PsdError::BadSignature { what : "placed layer", found : sig, offset : cursor.position() - 4 });
+                    }
+                    let _version = read_u32(&mut cursor)?;
+                    let id_len = read_u8(&mut cursor)?;
+                    let unique_id = String::from_utf8_lossy(cursor.read_slice(id_len as u64)?).to_string();
+                    let page_number = read_u32(&mut cursor)?;
+                    let total_pages = read_u32(&mut cursor)?;
+                    let anti_alias = read_u32(&mut cursor)?;
+                    let placed_type = read_u32(&mut cursor)?;
+                    let mut transform = [0.0; 8];
+                    for x in transform.iter_mut()
+                    {
+                        *x = read_f64(&mut cursor)?;
+                    }
+                    // SoLd has everything PlLd has and more, so only fill in the transform if it was already read.
+                    if let Some(placed) = layer.placed_layer.as_mut()
+                    {
+                        placed.plld_transform = Some(transform);
+                    }
+                    else
+                    {
+                        let _warp_version = read_u32(&mut cursor)?;
+                        let warp_desc = read_versioned_descriptor(&mut cursor, max_depth).ok().flatten();
+                        layer.placed_layer = Some(PlacedLayerInfo {
+                            unique_id,
+                            page_number,
+                            total_pages,
+                            anti_alias,
+                            placed_type,
+                            transform,
+                            warp_desc,
+                            desc : None,
+                            plld_transform : Some(transform),
+                        });
+                    }
+                }
+                "FMsk" =>
+                {
+                    filter_mask = Some((read_color(&mut cursor)?, read_u16(&mut cursor)?));
+                }
+                "FXid" | "FEid" =>
+                {
+                    filter_effects.extend(parse_filter_effects(cursor.read_slice(len)?, options, &mut bytes_left).map_err(|x| x.offset_by(start))?);
+                }
+                // adjustment layers
+                "post" =>
+                {
+                    let mut data = vec!();
+                    data.push(read_u16(&mut cursor)? as f32); // number of levels
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "nvrt" =>
+                {
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = vec!();
+                }
+                // Newer files have both 'brit' and 'CgEd'. 'CgEd' wins.
+                "brit" if has_cged => {}
+                "brit" =>
+                {
+                    let mut data = vec!();
+                    data.push(read_u16(&mut cursor)? as f32); // brightness
+                    data.push(read_u16(&mut cursor)? as f32); // contrast
+                    data.push(read_u16(&mut cursor)? as f32); // "Mean value for brightness and contrast"
+                    data.push(read_u8(&mut cursor)? as f32); // "Lab color only"
+                    data.push(1.0); // legacy mode
+                    data.push(0.0); // auto
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "thrs" =>
+                {
+                    let mut data = vec!();
+                    data.push(read_u16(&mut cursor)? as f32);
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "hue2" =>
+                {
+                    let mut data = vec!();
+                    
+                    let version = read_u16(&mut cursor)?;
+                    if version != 2
+                    {
+                        // Version 1 is from before Photoshop 5 and lays out its values differently.
+                        let warning = format!("Unsupported hue/saturation adjustment version {}", version);
+                        if options.strict
+                        {
+                            return Err(PsdError::Strict(warning));
+                        }
+                        layer.warnings.push(warning);
+                    }
+                    else
+                    {
+                        data.push(read_u8(&mut cursor)? as f32); // if 1, is absolute/colorization (rather than relative)
+                        read_u8(&mut cursor)?;
+                        
+                        // "colorization"
+                        data.push(read_u16(&mut cursor)? as i16 as f32); // hue
+                        data.push(read_u16(&mut cursor)? as i16 as f32); // sat
+                        data.push(read_u16(&mut cursor)? as i16 as f32); // lightness (-1 to +1)
+                        
+                        // "master"
+                        data.push(read_u16(&mut cursor)? as i16 as f32); // hue
+                        data.push(read_u16(&mut cursor)? as i16 as f32); // sat
+                        data.push(read_u16(&mut cursor)? as i16 as f32); // lightness (-1 to +1)
+                        
+                        // Reds, yellows, greens, cyans, blues, magentas.
+                        for _ in 0..6
+                        {
+                            // Range, as the hues where the falloff starts, the range starts, the range ends, and the falloff ends.
+                            for _ in 0..4
+                            {
+                                data.push(read_u16(&mut cursor)? as i16 as f32);
+                            }
+                            data.push(read_u16(&mut cursor)? as i16 as f32); // hue
+                            data.push(read_u16(&mut cursor)? as i16 as f32); // sat
+                            data.push(read_u16(&mut cursor)? as i16 as f32); // lightness
+                        }
+                    }
+                    
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "levl" =>
+                {
+                    fn read_record(cursor : &mut SliceCursor, data : &mut Vec<f32>) -> Result<(), PsdError>
+                    {
+                        data.push(read_u16(cursor)? as f32 / 255.0); // in floor
+                        data.push(read_u16(cursor)? as f32 / 255.0); // in ceil
+                        data.push(read_u16(cursor)? as f32 / 255.0); // out floor
+                        data.push(read_u16(cursor)? as f32 / 255.0); // out ceil
+                        data.push(read_u16(cursor)? as f32 / 100.0); // gamma
+                        Ok(())
+                    }
+                    
+                    // One record per channel, by channel index: 0 is the composite, then the document's channels in order.
+                    // There are 28 records, or more if the file has the extension below.
+                    let mut data = vec!();
+                    
+                    let version = read_u16(&mut cursor)?;
+                    if version != 2
+                    {
+                        let warning = format!("Unsupported levels adjustment version {}", version);
+                        if options.strict
+                        {
+                            return Err(PsdError::Strict(warning));
+                        }
+                        layer.warnings.push(warning);
+                    }
+                    else
+                    {
+                        for _ in 0..28
+                        {
+                            read_record(&mut cursor, &mut data)?;
+                        }
+                        // Newer versions of Photoshop write a 29th record, then more records for documents with lots of channels.
+                        if cursor.position() + 14 <= start + len
+                        {
+                            let mut record = vec!();
+                            read_record(&mut cursor, &mut record)?;
+                            if &read_b4(&mut cursor)? == b"Lvls"
+                            {
+                                let _version = read_u16(&mut cursor)?;
+                                let count = read_u16(&mut cursor)?;
+                                data.extend(record);
+                                for _ in 29..count
+                                {
+                                    read_record(&mut cursor, &mut data)?;
+                                }
+                            }
+                        }
+                    }
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "curv" =>
+                {
+                    fn read_points(cursor : &mut SliceCursor) -> Result<Vec<f32>, PsdError>
+                    {
+                        let mut points = vec!();
+                        for _ in 0..read_u16(cursor)?
+                        {
+                            let y = read_u16(cursor)? as f32 / 255.0;
+                            points.push(read_u16(cursor)? as f32 / 255.0); // x
+                            points.push(y); // y
+                        }
+                        Ok(points)
+                    }
+                    
+                    // One curve per channel, by channel index: 0 is the composite curve, then the document's channels in order.
+                    // Channels without a curve have no points. There are always at least 32 curves.
+                    let mut curves = vec![vec!(); 32];
+                    let mut data = vec!();
+                    
+                    read_u8(&mut cursor)?;
+                    let version = read_u16(&mut cursor)?;
+                    if version != 1
+                    {
+                        let warning = format!("Unsupported curves adjustment version {}", version);
+                        if options.strict
+                        {
+                            return Err(PsdError::Strict(warning));
+                        }
+                        layer.warnings.push(warning);
+                    }
+                    else
+                    {
+                        let enabled = read_u32(&mut cursor)?;
+                        
+                        for (i, curve) in curves.iter_mut().enumerate()
+                        {
+                            if (enabled & (1u32 << i)) != 0
+                            {
+                                *curve = read_points(&mut cursor)?;
+                            }
+                        }
+                        
+                        // Newer versions of Photoshop write the curves a second time, keyed by channel index instead of by bitmask. These win.
+                        if cursor.position() + 4 <= start + len && &read_b4(&mut cursor)? == b"Crv "
+                        {
+                            let _version = read_u16(&mut cursor)?;
+                            for _ in 0..read_u32(&mut cursor)?
+                            {
+                                let i = read_u16(&mut cursor)? as usize;
+                                if i >= curves.len()
+                                {
+                                    curves.resize(i + 1, vec!());
+                                }
+                                curves[i] = read_points(&mut cursor)?;
+                            }
+                        }
+                        
+                        for curve in curves
+                        {
+                            data.push((curve.len() / 2) as f32); // number of points
+                            data.extend(curve); // (x, y) pairs
+                        }
+                    }
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "selc" =>
+                {
+                    let mut data = vec!();
+                    
+                    let version = read_u16(&mut cursor)?;
+                    if version != 1
+                    {
+                        let warning = format!("Unsupported selective color adjustment version {}", version);
+                        if options.strict
+                        {
+                            return Err(PsdError::Strict(warning));
+                        }
+                        layer.warnings.push(warning);
+                    }
+                    else
+                    {
+                        data.push(read_u16(&mut cursor)? as f32); // 0 = relative, 1 = absolute
+                        // The first plate is unused, then reds, yellows, greens, cyans, blues, magentas, whites, neutrals, and blacks.
+                        for _ in 0..10
+                        {
+                            for _ in 0..4
+                            {
+                                data.push(read_u16(&mut cursor)? as i16 as f32 / 100.0); // cyan, magenta, yellow, black
+                            }
+                        }
+                    }
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "mixr" =>
+                {
+                    let mut data = vec!();
+                    
+                    let version = read_u16(&mut cursor)?;
+                    if version != 1
+                    {
+                        let warning = format!("Unsupported channel mixer adjustment version {}", version);
+                        if options.strict
+                        {
+                            return Err(PsdError::Strict(warning));
+                        }
+                        layer.warnings.push(warning);
+                    }
+                    else
+                    {
+                        data.push(read_u16(&mut cursor)? as f32); // monochrome
+                        // One row per output channel, then the monochrome row. That's four rows for RGB and five for CMYK.
+                        while cursor.position() + 10 <= start + len
+                        {
+                            for _ in 0..4
+                            {
+                                data.push(read_u16(&mut cursor)? as i16 as f32 / 100.0); // source channel amounts
+                            }
+                            data.push(read_u16(&mut cursor)? as i16 as f32 / 100.0); // constant
+                        }
+                    }
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "phfl" =>
+                {
+                    let mut data = vec!();
+                    
+                    match read_u16(&mut cursor)?
+                    {
+                        2 =>
+                        {
+                            data.push(0.0); // is XYZ color
+                            data.push(read_u16(&mut cursor)? as f32); // color space
+                            for _ in 0..4
+                            {
+                                data.push(read_u16(&mut cursor)? as f32 / 65535.0); // color components
+                            }
+                        }
+                        3 =>
+                        {
+                            data.push(1.0); // is XYZ color
+                            data.push(0.0); // color space (unused)
+                            for _ in 0..3
+                            {
+                                data.push(read_i32(&mut cursor)? as f32); // X, Y, Z, as stored
+                            }
+                            data.push(0.0);
+                        }
+                        version =>
+                        {
+                            let warning = format!("Unsupported photo filter adjustment version {}", version);
+                            if options.strict
+                            {
+                                return Err(PsdError::Strict(warning));
+                            }
+                            layer.warnings.push(warning);
+                        }
+                    }
+                    if !data.is_empty()
+                    {
+                        data.push(read_u32(&mut cursor)? as f32 / 100.0); // density
+                        data.push(read_u8(&mut cursor)? as f32); // preserve luminosity
+                    }
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "blnc" =>
+                {
+                    let mut data = vec!();
+                    
+                    // Shadows, then midtones, then highlights.
+                    for _ in 0..3
+                    {
+                        data.push(read_u16(&mut cursor)? as i16 as f32 / 100.0); // cyan-red
+                        data.push(read_u16(&mut cursor)? as i16 as f32 / 100.0); // magenta-green
+                        data.push(read_u16(&mut cursor)? as i16 as f32 / 100.0); // yellow-blue
+                    }
+                    data.push(read_u8(&mut cursor)? as f32); // preserve luminosity
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "grdm" =>
+                {
+                    let mut data = vec!();
+                    
+                    let version = read_u16(&mut cursor)?;
+                    if version != 1
+                    {
+                        let warning = format!("Unsupported gradient map adjustment version {}", version);
+                        if options.strict
+                        {
+                            return Err(PsdError::Strict(warning));
+                        }
+                        layer.warnings.push(warning);
+                    }
+                    else
+                    {
+                        data.push(read_u8(&mut cursor)? as f32); // reverse
+                        data.push(read_u8(&mut cursor)? as f32); // dither
+                        data.push(0.0); // is noise gradient, filled in below
+                        let _name = read_unicode_string(&mut cursor)?;
+                        
+                        let n = read_u16(&mut cursor)?;
+                        data.push(n as f32); // number of color stops
+                        for _ in 0..n
+                        {
+                            data.push(read_u32(&mut cursor)? as f32 / 4096.0); // location
+                            data.push(read_u32(&mut cursor)? as f32 / 100.0); // midpoint
+                            data.push(read_u16(&mut cursor)? as f32); // color space
+                            for _ in 0..4
+                            {
+                                data.push(read_u16(&mut cursor)? as f32 / 65535.0); // color components
+                            }
+                            read_u16(&mut cursor)?;
+                        }
+                        let n = read_u16(&mut cursor)?;
+                        data.push(n as f32); // number of transparency stops
+                        for _ in 0..n
+                        {
+                            data.push(read_u32(&mut cursor)? as f32 / 4096.0); // location
+                            data.push(read_u32(&mut cursor)? as f32 / 100.0); // midpoint
+                            data.push(read_u16(&mut cursor)? as f32 / 255.0); // opacity
+                        }
+                        
+                        // Noise gradient settings. Smooth gradients have these too, but they don't mean anything there.
+                        let _expansion_count = read_u16(&mut cursor)?;
+                        data.push(read_u16(&mut cursor)? as f32 / 4096.0); // smoothness
+                        let _len = read_u16(&mut cursor)?;
+                        data[2] = read_u16(&mut cursor)? as f32; // is noise gradient
+                        data.push(read_u32(&mut cursor)? as f32); // random seed
+                        data.push(read_u16(&mut cursor)? as f32); // "showing transparency"
+                        data.push(read_u16(&mut cursor)? as f32); // "using vector color"
+                        data.push(read_u32(&mut cursor)? as f32 / 4096.0); // roughness
+                        data.push(read_u16(&mut cursor)? as f32); // color model
+                        for _ in 0..8
+                        {
+                            data.push(read_u16(&mut cursor)? as f32 / 100.0); // min color components, then max color components
+                        }
+                    }
+                    
+                    layer.adjustment_type = name.clone();
+                    layer.adjustment_info = data;
+                }
+                "blwh" | "SoCo" | "GdFl" | "PtFl" =>
+                {
+                    if let Some(desc) = read_versioned_descriptor(&mut cursor, max_depth)?
+                    {
+                        if let Some(DescItem::Objc(pattern)) = desc.get("Ptrn")
+                            && let Some(DescItem::TEXT(id)) = pattern.get("Idnt")
+                        {
+                            layer.fill_pattern_id = Some(id.clone());
+                        }
+                        layer.adjustment_type = name.clone();
+                        layer.adjustment_desc = Some(desc);
+                    }
+                }
+                "CgEd" =>
+                {
+                    // Same layout as 'brit'. If the descriptor can't be read, a 'brit' block, if there is one, is used instead.
+                    if let Some(desc) = read_versioned_descriptor(&mut cursor, max_depth)?
+                    {
+                        has_cged = true;
+                        layer.adjustment_type = "brit".to_string();
+                        #[cfg(feature = "debug_spew")]
+                        println!("{:?}", desc);
+                        //("null", [("Vrsn", long(1)), ("Brgh", long(9)), ("Cntr", long(30)), ("means", long(127)), ("Lab ", bool(false)), ("useLegacy", bool(true)), ("Auto", bool(true))])
+                        let get = |key : &str| match desc.get(key)
+                        {
+                            Some(DescItem::long(x)) => Ok(*x as f32),
+                            Some(DescItem::bool(x)) => Ok(*x as u8 as f32),
+                            _ => Err(PsdError::malformed("Malformed brightness/contrast descriptor", start)),
+                        };
+                        let mut data = vec!();
+                        data.push(get("Brgh")?);
+                        data.push(get("Cntr")?);
+                        data.push(get("means")?);
+                        data.push(get("Lab ")?);
+                        data.push(get("useLegacy")?);
+                        data.push(matches!(desc.get("Auto"), Some(DescItem::bool(true))) as u8 as f32);
+                        #[cfg(feature = "debug_spew")]
+                        println!("??????????? {:?}", data);
+                        layer.adjustment_info = data;
+                    }
+                }
+                _ => known = false,
+            }
+            if (known && options.keep_known_blocks) || (!known && options.keep_unknown_blocks)
+            {
+                cursor.set_position(start);
+                layer.extra_blocks.push((name, cursor.read_slice(len)?.to_vec()));
+            }
+            cursor.set_position(start + len);
+        }
+        //println!("{:X} {:X}", cursor.position(), exdat_start + exdat_len);
+        if cursor.position() != exdat_start + exdat_len
+        {
+            return Err(PsdError::Desync { expected : exdat_start + exdat_len, actual : cursor.position(), context : "extra data" });
+        }
+        if let (Some(filters), Some((color, opacity))) = (layer.smart_filters.as_mut(), filter_mask)
+        {
+            filters.mask_color = Some(color);
+            filters.mask_opacity = opacity;
+        }
+        
+        if options.shrink_channels
+        {
+            layer.shrink_channels();
+        }
+        
+        #[cfg(feature = "debug_spew")]
+        println!("added layer with name {}", layer.name);
+        self.cursor = cursor;
+        self.idata_c = idata_c;
+        self.bytes_left = bytes_left;
+        if let Some(id) = smart_object_id
+        {
+            self.attach_filter_effects(&mut layer, id)?;
+        }
+        Ok(layer)
+    }
+    
+    fn attach_filter_effects(&mut self, layer : &mut LayerInfo, id : String) -> Result<(), PsdError>
+    {
+        if !self.document_effects_loaded
+        {
+            self.document_effects_loaded = true;
+            for (key, start, len) in document_block_spans(self.data)?
+            {
+                if key == "FXid" || key == "FEid"
+                {
+                    self.filter_effects.extend(parse_filter_effects(&self.data[start as usize..(start + len) as usize], &self.options, &mut self.bytes_left).map_err(|x| x.offset_by(start))?);
+                }
+            }
+        }
+        if let Some(filters) = layer.smart_filters.as_mut()
+        {
+            if let Some(effect) = self.filter_effects.iter_mut().find(|x| x.0 == id)
+            {
+                filters.mask_info = Some(core::mem::take(&mut effect.1));
+                filters.image_data_mask = core::mem::take(&mut effect.2);
+            }
+            else
+            {
+                self.late_filter_effects.push((self.index, id));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Hooks up smart filter masks that only turned up after their smart object layer was yielded. `layers` has to be everything this iterator yielded, in order.
+    fn attach_late_filter_effects(&mut self, layers : &mut [LayerInfo])
+    {
+        for (i, id) in core::mem::take(&mut self.late_filter_effects)
+        {
+            if let (Some(filters), Some(effect)) = (layers.get_mut(i).and_then(|x| x.smart_filters.as_mut()), self.filter_effects.iter_mut().find(|x| x.0 == id))
+            {
+                filters.mask_info = Some(core::mem::take(&mut effect.1));
+                filters.image_data_mask = core::mem::take(&mut effect.2);
+            }
+        }
+    }
+}
+
+impl Iterator for LayerRecordsIter<'_>
+{
+    type Item = Result<LayerInfo, PsdError>;
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        if self.failed || self.remaining == 0
+        {
+            return None;
+        }
+        let ret = self.next_layer();
+        self.failed = ret.is_err();
+        self.remaining -= 1;
+        self.index += 1;
+        Some(ret)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>)
+    {
+        if self.failed { (0, Some(0)) } else { (self.remaining.min(1) as usize, Some(self.remaining as usize)) }
+    }
+}
+
+impl core::iter::FusedIterator for LayerRecordsIter<'_> {}
+
+/// Pulls the smart filter list out of a smart object ('SoLd') descriptor.
+fn read_smart_filters(desc : &Descriptor) -> Option<SmartFilters>
+{
+    let Some(DescItem::Objc(fx)) = desc.get("filterFX") else { return None };
+    let mut ret = SmartFilters::default();
+    ret.enabled = !matches!(fx.get("enab"), Some(DescItem::bool(false)));
+    if let Some(DescItem::VlLs(list)) = fx.get("filterFXList")
+    {
+        for item in list
+        {
+            let DescItem::Objc(item) = item else { continue };
+            let mut filter = SmartFilter::default();
+            if let Some(DescItem::TEXT(name)) = item.get("Nm  ")
+            {
+                filter.name = name.clone();
+            }
+            filter.enabled = !matches!(item.get("enab"), Some(DescItem::bool(false)));
+            if let Some(DescItem::Objc(params)) = item.get("Fltr")
+            {
+                filter.key = params.0.clone();
+                filter.params = Some((**params).clone());
+            }
+            ret.filters.push(filter);
+        }
+    }
+    Some(ret)
+}
+
+/// Pulls the placement info out of a smart object ('SoLd') descriptor.
+fn read_placed_layer(desc : Descriptor, plld_transform : Option<[f64; 8]>) -> PlacedLayerInfo
+{
+    let get_u32 = |key| match desc.get(key) { Some(DescItem::long(x)) => *x as u32, _ => 0 };
+    let mut ret = PlacedLayerInfo {
+        page_number : get_u32("PgNm"),
+        total_pages : get_u32("totalPages"),
+        anti_alias : get_u32("Annt"),
+        placed_type : get_u32("Type"),
+        plld_transform,
+        ..Default::default()
+    };
+    if let Some(DescItem::TEXT(id)) = desc.get("Idnt").or(desc.get("placed"))
+    {
+        ret.unique_id = id.clone();
+    }
+    if let Some(DescItem::VlLs(list)) = desc.get("Trnf")
+    {
+        for (x, item) in ret.transform.iter_mut().zip(list)
+        {
+            if let DescItem::doub(v) = item
+            {
+                *x = *v;
+            }
+        }
+    }
+    if let Some(DescItem::Objc(warp)) = desc.get("warp")
+    {
+        ret.warp_desc = Some((**warp).clone());
+    }
+    ret.desc = Some(desc);
+    ret
+}
+
+/// Parses the contents of a 'FXid' or 'FEid' filter effects block into (smart object ID, filter mask position, filter mask data) triples.
+fn parse_filter_effects(data : &[u8], options : &ParseOptions, bytes_left : &mut u64) -> Result<Vec<(String, MaskInfo, Vec<u8>)>, PsdError>
+{
+    let mut cursor = SliceCursor::new(data);
+    let _version = read_u32(&mut cursor)?;
+    let len = read_section_len(&mut cursor, true)?;
+    let mut cursor = cursor.sub(len)?;
+    
+    let mut ret = vec!();
+    while cursor.position() < len
+    {
+        let id_len = read_u8(&mut cursor)?;
+        let mut id = vec![0; id_len as usize];
+        cursor.read_exact(&mut id)?;
+        let id = String::from_utf8_lossy(&id).to_string();
+        let _version = read_u32(&mut cursor)?;
+        let item_len = read_section_len(&mut cursor, true)?;
+        let item_start = cursor.position();
+        
+        let _rect = (read_i32(&mut cursor)?, read_i32(&mut cursor)?, read_i32(&mut cursor)?, read_i32(&mut cursor)?);
+        let _depth = read_u32(&mut cursor)?;
+        let max_channels = read_u32(&mut cursor)?;
+        // The cached filter output channels, plus the user mask and sheet mask.
+        for _ in 0..max_channels as u64 + 2
+        {
+            if read_u32(&mut cursor)? == 0
+            {
+                continue;
+            }
+            let channel_len = read_section_len(&mut cursor, true)?;
+            cursor.set_position(cursor.position().saturating_add(channel_len));
+        }
+        
+        let mut mask_info = MaskInfo::default();
+        let mut mask = vec!();
+        if read_u8(&mut cursor)? != 0
+        {
+            let top = read_i32(&mut cursor)?;
+            let left = read_i32(&mut cursor)?;
+            let bottom = read_i32(&mut cursor)?;
+            let right = read_i32(&mut cursor)?;
+            mask_info.x = left;
+            mask_info.y = top;
+            (mask_info.w, mask_info.h) = rect_size(top, left, bottom, right);
+            mask_info.rect_raw = (top, left, bottom, right);
+            options.limits.check_pixels(mask_info.w, mask_info.h)?;
+            let mode = read_u16(&mut cursor)?;
+            let mask_len = read_section_len(&mut cursor, true)?;
+            // The compression mode and length are stored in the opposite order from normal channel data, so stitch it back together.
+            let mut buf = mode.to_be_bytes().to_vec();
+            let mask_start = cursor.offset();
+            buf.extend_from_slice(cursor.read_slice(mask_len)?);
+            if !options.skip_masks
+            {
+                append_img_data_impl(&buf, &mut mask, mask_len + 2, mask_info.h as u64, DecodeOpts { row_len : plausible_size(mask_info.w as u64, mask_info.h as u64, mask_len).then_some(mask_info.w as u64), depth : 8, limit : (*bytes_left).min(usize::MAX as u64) as usize }).map_err(|x| x.offset_by(mask_start - 2))?;
+                mask.truncate((mask_info.w as u64 * mask_info.h as u64).min(usize::MAX as u64) as usize);
+                spend_bytes(bytes_left, mask.len() as u64)?;
+            }
+        }
+        ret.push((id, mask_info, mask));
+        
+        cursor.set_position(item_start.saturating_add(item_len));
+    }
+    Ok(ret)
+}
+
+/// Decodes the merged composite image stored at the end of the PSD file. This is the whole document flattened into a single image, as Photoshop last saw it.
+///
+/// The result is laid out like [LayerInfo::image_data_rgba]: 4 interleaved bytes per pixel, RGBA for RGB documents, CMYA for CMYK documents, and Y or palette indexes in the first channel for grayscale and indexed documents, with missing channels filled with 255. For CMYK documents, the K channel is stored after that as a separate plane, like [LayerInfo::image_data_k], so the buffer is `width * height * 5` bytes long instead of `width * height * 4`.
+///
+/// The first channel after the document's color channels is treated as alpha. Any other channels (spot colors, saved selections) are ignored.
+///
+/// 16-bit documents are converted to 8 bits per channel.
+///
+/// PSDs saved without "maximize compatibility" might not have a useful composite image.
+pub fn parse_merged_image(data : &[u8], metadata : &PsdMetadata) -> Result<Vec<u8>, PsdError>
+{
+    parse_merged_image_opts(data, metadata, &ParseOptions::default())
+}
+/// Same as [parse_merged_image], but with [ParseOptions::limits] applied to the document's size and its decoded image data. The other options don't do anything here.
+pub fn parse_merged_image_opts(data : &[u8], metadata : &PsdMetadata, options : &ParseOptions) -> Result<Vec<u8>, PsdError>
+{
+    if metadata.depth != 8 && metadata.depth != 16
+    {
+        return Err(PsdError::UnsupportedDepth(metadata.depth));
+    }
+    let color_channels = match metadata.color_mode
+    {
+        1 | 2 => 1,
+        3 => 3,
+        4 => 4,
+        _ => return Err(PsdError::UnsupportedColorMode(metadata.color_mode)),
+    };
+    let sections = find_sections(data)?;
+    options.limits.check_pixels(metadata.width, metadata.height)?;
+    let mut bytes_left = options.limits.max_total_bytes.unwrap_or(u64::MAX);
+    
+    let w = metadata.width as usize;
+    let h = metadata.height as usize;
+    let channel_count = metadata.channel_count as usize;
+    let bytes = metadata.depth as usize / 8;
+    
+    // Every channel is stored one after the other, and the RLE row length table covers all of them, so this is just one big channel with height*channel_count rows.
+    let image_data = &data[sections.image_data as usize..];
+    let mut planes = vec!();
+    append_img_data_impl(image_data, &mut planes, image_data.len() as u64, h as u64 * channel_count as u64, DecodeOpts { row_len : plausible_size((w * bytes) as u64, h as u64 * channel_count as u64, image_data.len() as u64).then_some((w * bytes) as u64), depth : metadata.depth, limit : bytes_left.min(usize::MAX as u64) as usize }).map_err(|x| x.offset_by(sections.image_data))?;
+    spend_bytes(&mut bytes_left, planes.len() as u64)?;
+    if bytes == 2
+    {
+        planes = planes.chunks_exact(2).map(|x| ((u16::from_be_bytes([x[0], x[1]]) as u32 * 255 + 32767) / 65535) as u8).collect();
+    }
+    
+    let stride = if color_channels == 4 { 5 } else { 4 };
+    spend_bytes(&mut bytes_left, w as u64 * h as u64 * stride)?;
+    let mut ret = pixel_buffer(w as u64, h as u64, stride, 255)?;
+    let pixels = w * h;
+    // Missing data reads as zero.
+    let sample = |i : usize, j : usize| planes.get(pixels * i + j).copied().unwrap_or(0);
+    for i in 0..color_channels.min(3).min(channel_count)
+    {
+        for j in 0..pixels
+        {
+            ret[j * 4 + i] = sample(i, j);
+        }
+    }
+    if channel_count > color_channels
+    {
+        for j in 0..pixels
+        {
+            ret[j * 4 + 3] = sample(color_channels, j);
+        }
+    }
+    if color_channels == 4 && channel_count >= 4
+    {
+        for j in 0..pixels
+        {
+            ret[pixels * 4 + j] = sample(3, j);
+        }
+    }
+    Ok(ret)
+}
+
+/// Crops an image with the given rectangle, in canvas coordinates, to the canvas. See [LayerInfo::crop_to_canvas].
+fn crop_plane(data : &[u8], rect : (i32, i32, u32, u32), stride : usize, canvas : &PsdMetadata) -> (i32, i32, u32, u32, Vec<u8>)
+{
+    let (x, y, w, h) = (rect.0 as i64, rect.1 as i64, rect.2 as i64, rect.3 as i64);
+    let (x1, y1) = (x.max(0), y.max(0));
+    let (x2, y2) = ((x + w).min(canvas.width as i64), (y + h).min(canvas.height as i64));
+    if x2 <= x1 || y2 <= y1
+    {
+        return (0, 0, 0, 0, vec!());
+    }
+    let ret = (x1 as i32, y1 as i32, (x2 - x1) as u32, (y2 - y1) as u32);
+    if (data.len() as u64) < (w * h) as u64 * stride as u64
+    {
+        return (ret.0, ret.1, ret.2, ret.3, vec!());
+    }
+    let row_len = (x2 - x1) as usize * stride;
+    let mut out = Vec::with_capacity(row_len * (y2 - y1) as usize);
+    for row in y1 - y..y2 - y
+    {
+        let start = (row * w + x1 - x) as usize * stride;
+        out.extend_from_slice(&data[start..start + row_len]);
+    }
+    (ret.0, ret.1, ret.2, ret.3, out)
+}
+
+/// Reads one sample of a layer's color or alpha channel, by channel ID (-1 to 3), wherever it's stored: in [LayerInfo::image_data_rgba] (shrunk or not), [LayerInfo::image_data_k], or [LayerInfo::image_data_channels]. `None` if the layer doesn't have that channel.
+fn layer_sample(layer : &LayerInfo, id : i16, i : usize) -> Option<u8>
+{
+    if !layer.image_data_channels.is_empty()
+    {
+        return layer.plane(id).and_then(|x| x.get(i).copied());
+    }
+    if id == 3
+    {
+        return layer.image_data_k.get(i).copied();
+    }
+    let c = if id < 0 { 3 } else { id as usize };
+    let has = [layer.image_data_has_r, layer.image_data_has_g, layer.image_data_has_b, layer.image_data_has_a];
+    if !layer.image_data_shrunk
+    {
+        return layer.image_data_rgba.get(i * 4 + c).copied();
+    }
+    if !has[c]
+    {
+        return None;
+    }
+    let offset = has[..c].iter().filter(|x| **x).count();
+    layer.image_data_rgba.get(i * layer.pixel_stride() + offset).copied()
+}
+
+/// Converts a layer of a CMYK document to plain RGBA, 4 bytes per pixel, for previews. This is the naive conversion, without any color management, so colors will be off compared to Photoshop, especially saturated ones.
+///
+/// Works with whatever layout the layer's pixel data is in: the default, [shrunk][LayerInfo::shrink_channels], or [planar][ParseOptions::planar_channels]. Missing C, M, Y, or K channels count as no ink, and a missing alpha channel counts as fully opaque. Returns an empty vec if the layer's pixel data was skipped or taken.
+pub fn cmyk_layer_to_rgba8(layer : &LayerInfo) -> Vec<u8>
+{
+    let mut out = cmyk_layer_to_cmyka8(layer);
+    let mut n = 0;
+    for i in 0..out.len() / 5
+    {
+        // Back to no-ink-is-255 for the multiplication.
+        let [c, m, y, k, a] : [u8; 5] = out[i * 5..i * 5 + 5].try_into().unwrap();
+        let k = 255 - k as u32;
+        for x in [c, m, y]
+        {
+            out[n] = (((255 - x) as u32 * k + 127) / 255) as u8;
+            n += 1;
+        }
+        out[n] = a;
+        n += 1;
+    }
+    out.truncate(n);
+    out
+}
+
+/// Converts a layer of a CMYK document to CMYKA, 5 bytes per pixel, with the inks un-inverted so that 0 is no ink and 255 is full ink. Alpha is left as-is.
+///
+/// Works with whatever layout the layer's pixel data is in, like [cmyk_layer_to_rgba8]. Missing C, M, Y, or K channels are filled with 0 (no ink), and a missing alpha channel is filled with 255. Returns an empty vec if the layer's pixel data was skipped or taken.
+pub fn cmyk_layer_to_cmyka8(layer : &LayerInfo) -> Vec<u8>
+{
+    let n = layer.w as usize * layer.h as usize;
+    if layer.image_data_rgba.is_empty() && layer.image_data_channels.is_empty()
+    {
+        return vec!();
+    }
+    let mut out = Vec::with_capacity(n * 5);
+    for i in 0..n
+    {
+        for id in [0, 1, 2, 3]
+        {
+            out.push(255 - layer_sample(layer, id, i).unwrap_or(255));
+        }
+        out.push(layer_sample(layer, -1, i).unwrap_or(255));
+    }
+    out
+}
+
+/// Computes a bounding box for every entry in a bottom-to-top layer list, as returned by [parse_layer_records].
+///
+/// Returns a list with one entry per layer, in the same order. Each entry is `(x, y, w, h)`, in the same coordinate system as [LayerInfo::x] etc.
+///
+/// For group openers, the bounds are the union of the bounds of every layer inside the group, including nested groups and clipped layers. Empty groups get `None`. For group closers, this is always `None`. For other layers, the bounds are the layer's own rectangle, or `None` if it's empty.
+///
+/// If `include_masks` is true, mask rectangles are added to the union as well. If `canvas` is given, every box is cropped to the canvas, and boxes that end up empty become `None`; otherwise, off-canvas area is included.
+pub fn compute_layer_bounds(layers : &[LayerInfo], include_masks : bool, canvas : Option<&PsdMetadata>) -> Vec<Option<(i32, i32, u32, u32)>>
+{
+    type Rect = Option<(i64, i64, i64, i64)>;
+    fn union(a : Rect, b : Rect) -> Rect
+    {
+        match (a, b)
+        {
+            (Some(a), Some(b)) => Some((a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3))),
+            (a, None) => a,
+            (None, b) => b,
+        }
+    }
+    fn rect(x : i32, y : i32, w : u32, h : u32) -> Rect
+    {
+        if w == 0 || h == 0 { return None; }
+        Some((x as i64, y as i64, x as i64 + w as i64, y as i64 + h as i64))
+    }
+    
+    let mut ret = vec![None; layers.len()];
+    let mut stack : Vec<Rect> = vec!();
+    let mut current : Rect = None;
+    for (i, layer) in layers.iter().enumerate()
+    {
+        let mut own = None;
+        if include_masks && layer.mask_channel_count > 0
+        {
+            own = rect(layer.mask_info.x, layer.mask_info.y, layer.mask_info.w, layer.mask_info.h);
+        }
+        
+        // Bottom-to-top, so closers come before the contents of their groups, and openers come after.
+        if layer.group_closer
+        {
+            stack.push(current.take());
+            continue;
+        }
+        let bounds = if layer.group_opener
+        {
+            let inner = union(current.take(), own);
+            current = stack.pop().unwrap_or(None);
+            inner
+        }
+        else
+        {
+            union(rect(layer.x, layer.y, layer.w, layer.h), own)
+        };
+        current = union(current, bounds);
+        
+        ret[i] = bounds.and_then(|(mut x1, mut y1, mut x2, mut y2)|
+        {
+            if let Some(canvas) = canvas
+            {
+                x1 = x1.max(0);
+                y1 = y1.max(0);
+                x2 = x2.min(canvas.width as i64);
+                y2 = y2.min(canvas.height as i64);
+            }
+            if x2 <= x1 || y2 <= y1 { return None; }
+            Some((x1 as i32, y1 as i32, (x2 - x1) as u32, (y2 - y1) as u32))
+        });
+    }
+    ret
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// One entry of the layer tree built by [build_layer_tree].
+pub enum LayerNode
+{
+    /// Anything that isn't a group: normal layers, adjustment layers, text layers, etc.
+    Layer(LayerInfo),
+    /// A group. `info` is the group opener's record, which has the group's name, blend mode, and so on. `children` are the group's contents, top to bottom. The group closer's record is dropped.
+    Group { info : LayerInfo, children : Vec<LayerNode> },
+}
+
+impl LayerNode
+{
+    /// The layer's record, or the group opener's record for groups.
+    pub fn info(&self) -> &LayerInfo
+    {
+        match self
+        {
+            LayerNode::Layer(info) | LayerNode::Group { info, .. } => info,
+        }
+    }
+}
+
+/// Turns the flat, bottom-to-top layer list returned by [parse_layer_records] into a tree, top to bottom, like in Photoshop's layers panel.
+///
+/// In the flat list, each group is a closer record, then the group's contents, then an opener record. If an opener doesn't have a closer, or a closer doesn't have an opener, this fails with a [PsdError::Malformed] saying which record it was, counting from 0 in the flat list.
+pub fn build_layer_tree(layers : Vec<LayerInfo>) -> Result<Vec<LayerNode>, PsdError>
+{
+    // The contents of each open group so far, bottom to top, along with the index of its closer.
+    let mut stack : Vec<(usize, Vec<LayerNode>)> = vec!();
+    let mut current = vec!();
+    for (i, layer) in layers.into_iter().enumerate()
+    {
+        if layer.group_closer
+        {
+            stack.push((i, core::mem::take(&mut current)));
+        }
+        else if layer.group_opener
+        {
+            let Some((_, parent)) = stack.pop() else
+            {
+                return Err(PsdError::Malformed { message : format!("Layer record {} opens a group that was never closed", i), offset : None });
+            };
+            let mut children = core::mem::replace(&mut current, parent);
+            children.reverse();
+            current.push(LayerNode::Group { info : layer, children });
+        }
+        else
+        {
+            current.push(LayerNode::Layer(layer));
+        }
+    }
+    if let Some((i, _)) = stack.pop()
+    {
+        return Err(PsdError::Malformed { message : format!("Layer record {} closes a group that was never opened", i), offset : None });
+    }
+    current.reverse();
+    Ok(current)
+}
+
+/// Matches up group closers with their openers in a bottom-to-top layer list. Returns the index of each closer's opener, and whether each opener has a closer.
+fn match_groups(layers : &[LayerInfo]) -> (Vec<Option<usize>>, Vec<bool>)
+{
+    let mut opener_of = vec![None; layers.len()];
+    let mut has_closer = vec![false; layers.len()];
+    let mut closers = vec!();
+    for (i, layer) in layers.iter().enumerate()
+    {
+        if layer.group_closer
+        {
+            closers.push(i);
+        }
+        else if layer.group_opener && let Some(closer) = closers.pop()
+        {
+            opener_of[closer] = Some(i);
+            has_closer[i] = true;
+        }
+    }
+    (opener_of, has_closer)
+}
+
+/// Walks a flat layer list, as returned by [parse_layer_records], along with the names of the groups each layer is in, outermost first. Useful for things like exporting layers as "Character/Face/Blush". Nothing is cloned.
+///
+/// Layers come out in the same order as the list, bottom to top. Group closers are skipped. Group openers are included, with the groups they're in, not counting themselves; filter on [LayerInfo::group_opener] to skip them too.
+///
+/// Unlike [build_layer_tree], this doesn't fail on unbalanced lists: closers without openers and openers without closers don't count as groups.
+pub fn iter_with_paths(layers : &[LayerInfo]) -> impl Iterator<Item = (Vec<&str>, &LayerInfo)>
+{
+    // Groups are named by their openers, which come after their contents, so match them up first.
+    let (opener_of, has_closer) = match_groups(layers);
+    let mut path = vec!();
+    layers.iter().enumerate().filter_map(move |(i, layer)|
+    {
+        if layer.group_closer
+        {
+            if let Some(opener) = opener_of[i]
+            {
+                path.push(layers[opener].name.as_str());
+            }
+            return None;
+        }
+        if layer.group_opener && has_closer[i]
+        {
+            path.pop();
+        }
+        Some((path.clone(), layer))
+    })
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// A layer's visibility and opacity after taking its groups and clipping base into account. Returned from [compute_effective_visibility].
+pub struct EffectiveVisibility {
+    /// Whether the layer, every group it's in, and its clipping base (if it's clipped) are all visible.
+    pub visible : bool,
+    /// The layer's opacity, times the opacity of every group it's in, times its clipping base's opacity (if it's clipped). From 0 to 1.
+    pub opacity : f32,
+    /// If the layer is clipped, the index of the layer it's clipped to, in the same list.
+    pub clip_base : Option<usize>,
+}
+
+/// Works out every layer's effective visibility and opacity in a bottom-to-top layer list, as returned by [parse_layer_records], by walking up through the groups each layer is in.
+///
+/// Returns a list with one entry per layer, in the same order. Group openers get the group's own effective values, which its contents build on. Group closers get the same values as their openers.
+///
+/// Clipped layers take on their clipping base's visibility and opacity, like in Photoshop: if the base is hidden, so is everything clipped to it. A clipping base is the closest layer or group below that isn't clipped itself, in the same group. A clipped layer with nothing to clip to, like one at the bottom of a group, is treated as not clipped, and gets no [EffectiveVisibility::clip_base].
+///
+/// This is only plain inheritance. It doesn't know about blend modes, so pass-through groups are treated like any other group, and it doesn't fold in [LayerInfo::fill_opacity], which only applies to the layer's own pixels and not its effects. How to handle those is up to the renderer.
+///
+/// Closers without openers and openers without closers don't count as groups, like in [iter_with_paths].
+pub fn compute_effective_visibility(layers : &[LayerInfo]) -> Vec<EffectiveVisibility>
+{
+    let (opener_of, has_closer) = match_groups(layers);
+    let mut ret = vec![EffectiveVisibility::default(); layers.len()];
+    // The enclosing group's effective values, and the clipping base so far at its level.
+    let mut group = EffectiveVisibility { visible : true, opacity : 1.0, clip_base : None };
+    let mut stack = vec!();
+    
+    // The effective values of a layer at the current level, given the clipping base so far.
+    let effective = |layer : &LayerInfo, group : &EffectiveVisibility, ret : &[EffectiveVisibility]|
+    {
+        match group.clip_base.filter(|_| layer.is_clipped)
+        {
+            Some(base) => EffectiveVisibility { visible : layer.is_visible && ret[base].visible, opacity : layer.opacity * ret[base].opacity, clip_base : Some(base) },
+            None => EffectiveVisibility { visible : layer.is_visible && group.visible, opacity : layer.opacity * group.opacity, clip_base : None },
+        }
+    };
+    for (i, layer) in layers.iter().enumerate()
+    {
+        if let Some(opener) = opener_of[i]
+        {
+            // Entering a group from the bottom. Its opener's values are needed now, for its contents.
+            ret[i] = effective(&layers[opener], &group, &ret);
+            stack.push(group);
+            group = EffectiveVisibility { clip_base : None, ..ret[i] };
+            continue;
+        }
+        if layer.group_opener && has_closer[i]
+        {
+            group = stack.pop().unwrap_or_default();
+        }
+        ret[i] = effective(layer, &group, &ret);
+        if !layer.is_clipped && !layer.group_closer
+        {
+            group.clip_base = Some(i);
+        }
+    }
+    ret
+}
+
+#[non_exhaustive]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+/// File-wide PSD header metadata.
+///
+/// With the `serde_support` feature, the serde format has the same lack of stability guarantees as [LayerInfo]'s.
+///
+/// Returned from [parse_psd_metadata].
+pub struct PsdMetadata {
+    /// Canvas width in pixels.
+    pub width: u32,
+    /// Canvas height in pixels.
+    pub height: u32,
+    /// PSD-wide color mode constant. See <https://www.adobe.com/devnet-apps/photoshop/fileformatashtml/#50577409_pgfId-1055726>
+    pub color_mode: u16,
+    /// Color depth in bits. Only 8-bit images are currently supported, plus 16-bit images with [ParseOptions::convert_16_bit].
+    pub depth: u16,
+    /// Number of channels in the PSD file's colorspace, including alpha. Only Y/YA, RGB/RGBA, CMYK/CMYKA, and indexed images are currently supported.
+    pub channel_count: u16,
+    /// For indexed color PSDs (color mode 2), the 256-entry RGB palette from the color mode data section. `None` for everything else.
+    pub palette: Option<Vec<[u8; 3]>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Bare-bones layer record for synthesizing test files.
+    #[derive(Clone)]
+    struct TestLayer
+    {
+        /// top, left, bottom, right
+        rect : (i32, i32, i32, i32),
+        /// Channel id and the channel's full data, including the compression mode.
+        channels : Vec<(i16, Vec<u8>)>,
+        blend_mode : [u8; 4],
+        opacity : u8,
+        clipping : u8,
+        flags : u8,
+        /// Contents of the layer mask data section, without the length.
+        mask : Vec<u8>,
+        name : String,
+        /// Extra data blocks, without the signature or length.
+        extra : Vec<([u8; 4], Vec<u8>)>,
+    }
+    
+    impl Default for TestLayer
+    {
+        fn default() -> Self
+        {
+            Self {
+                rect : (0, 0, 0, 0),
+                channels : vec!(),
+                blend_mode : *b"norm",
+                opacity : 255,
+                clipping : 0,
+                flags : 0,
+                mask : vec!(),
+                name : "Layer".to_string(),
+                extra : vec!(),
+            }
+        }
+    }
+    
+    /// Bare-bones PSD document for synthesizing test files.
+    #[derive(Clone)]
+    struct TestPsd
+    {
+        width : u32,
+        height : u32,
+        channel_count : u16,
+        depth : u16,
+        color_mode : u16,
+        color_data : Vec<u8>,
+        resources : Vec<u8>,
+        layers : Vec<TestLayer>,
+        /// Contents of the global layer mask info section, without the length.
+        global_mask : Vec<u8>,
+        /// Raw bytes of the document-level tagged blocks after the global layer mask info.
+        tail : Vec<u8>,
+        /// Merged image data, including the compression mode. Defaults to all zeroes.
+        merged : Option<Vec<u8>>,
+    }
+    
+    impl Default for TestPsd
+    {
+        fn default() -> Self
+        {
+            Self {
+                width : 4,
+                height : 4,
+                channel_count : 3,
+                depth : 8,
+                color_mode : 3,
+                color_data : vec!(),
+                resources : vec!(),
+                layers : vec!(),
+                global_mask : vec!(),
+                tail : vec!(),
+                merged : None,
+            }
+        }
+    }
+    
+    fn push_u16(out : &mut Vec<u8>, n : u16) { out.extend_from_slice(&n.to_be_bytes()); }
+    fn push_u32(out : &mut Vec<u8>, n : u32) { out.extend_from_slice(&n.to_be_bytes()); }
+    fn push_i32(out : &mut Vec<u8>, n : i32) { out.extend_from_slice(&n.to_be_bytes()); }
+    
+    /// An unnamed 8BIM image resource block, including padding.
+    fn image_resource(id : u16, data : &[u8]) -> Vec<u8>
+    {
+        let mut out = b"8BIM".to_vec();
+        push_u16(&mut out, id);
+        out.extend_from_slice(&[0, 0]);
+        push_u32(&mut out, data.len() as u32);
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1
+        {
+            out.push(0);
+        }
+        out
+    }
+    
+    /// A 26-byte path record, as used by path resources and vector masks.
+    fn path_record(selector : u16, values : &[i32]) -> Vec<u8>
+    {
+        let mut out = vec!();
+        push_u16(&mut out, selector);
+        for v in values
+        {
+            push_i32(&mut out, *v);
+        }
+        out.resize(26, 0);
+        out
+    }
+    
+    /// Uncompressed channel data.
+    fn raw_channel(data : &[u8]) -> Vec<u8>
+    {
+        let mut out = vec![0, 0];
+        out.extend_from_slice(data);
+        out
+    }
+    
+    /// A 20-byte layer mask data section.
+    fn mask_record(rect : (i32, i32, i32, i32), default_color : u8, flags : u8) -> Vec<u8>
+    {
+        let mut out = vec!();
+        push_i32(&mut out, rect.0);
+        push_i32(&mut out, rect.1);
+        push_i32(&mut out, rect.2);
+        push_i32(&mut out, rect.3);
+        out.push(default_color);
+        out.push(flags);
+        out.extend_from_slice(&[0, 0]);
+        out
+    }
+    
+    /// A descriptor key or class ID.
+    fn desc_key(key : &str) -> Vec<u8>
+    {
+        let mut out = vec!();
+        push_u32(&mut out, if key.len() == 4 { 0 } else { key.len() as u32 });
+        out.extend_from_slice(key.as_bytes());
+        out
+    }
+    
+    /// A descriptor without its version header. Items are keys and their already-encoded values, including the type.
+    fn desc(class : &str, items : &[(&str, Vec<u8>)]) -> Vec<u8>
+    {
+        let mut out = vec!();
+        push_u32(&mut out, 1);
+        push_u16(&mut out, 0);
+        out.extend(desc_key(class));
+        push_u32(&mut out, items.len() as u32);
+        for (key, item) in items
+        {
+            out.extend(desc_key(key));
+            out.extend_from_slice(item);
+        }
+        out
+    }
+    
+    fn desc_objc(class : &str, items : &[(&str, Vec<u8>)]) -> Vec<u8>
+    {
+        let mut out = b"Objc".to_vec();
+        out.extend(desc(class, items));
+        out
+    }
+    
+    fn desc_list(items : &[Vec<u8>]) -> Vec<u8>
+    {
+        let mut out = b"VlLs".to_vec();
+        push_u32(&mut out, items.len() as u32);
+        for item in items
+        {
+            out.extend_from_slice(item);
+        }
+        out
+    }
+    
+    fn desc_text(text : &str) -> Vec<u8>
+    {
+        let mut out = b"TEXT".to_vec();
+        let text : Vec<u16> = text.encode_utf16().chain([0]).collect();
+        push_u32(&mut out, text.len() as u32);
+        for c in text
+        {
+            push_u16(&mut out, c);
+        }
+        out
+    }
+    
+    fn desc_bool(b : bool) -> Vec<u8>
+    {
+        let mut out = b"bool".to_vec();
+        out.push(b as u8);
+        out
+    }
+    
+    fn desc_doub(n : f64) -> Vec<u8>
+    {
+        let mut out = b"doub".to_vec();
+        out.extend_from_slice(&n.to_be_bytes());
+        out
+    }
+    
+    fn desc_long(n : i32) -> Vec<u8>
+    {
+        let mut out = b"long".to_vec();
+        out.extend_from_slice(&n.to_be_bytes());
+        out
+    }
+    
+    fn desc_untf(unit : &str, n : f64) -> Vec<u8>
+    {
+        let mut out = b"UntF".to_vec();
+        out.extend_from_slice(unit.as_bytes());
+        out.extend_from_slice(&n.to_be_bytes());
+        out
+    }
+    
+    fn desc_enum(kind : &str, value : &str) -> Vec<u8>
+    {
+        let mut out = b"enum".to_vec();
+        out.extend(desc_key(kind));
+        out.extend(desc_key(value));
+        out
+    }
+    
+    impl TestPsd
+    {
+        fn build(&self) -> Vec<u8>
+        {
+            let mut out = vec!();
+            out.extend_from_slice(b"8BPS");
+            push_u16(&mut out, 1);
+            out.extend_from_slice(&[0; 6]);
+            push_u16(&mut out, self.channel_count);
+            push_u32(&mut out, self.height);
+            push_u32(&mut out, self.width);
+            push_u16(&mut out, self.depth);
+            push_u16(&mut out, self.color_mode);
+            push_u32(&mut out, self.color_data.len() as u32);
+            out.extend_from_slice(&self.color_data);
+            push_u32(&mut out, self.resources.len() as u32);
+            out.extend_from_slice(&self.resources);
+            
+            let info = self.layer_info();
+            let mut section = vec!();
+            push_u32(&mut section, info.len() as u32);
+            section.extend_from_slice(&info);
+            push_u32(&mut section, self.global_mask.len() as u32);
+            section.extend_from_slice(&self.global_mask);
+            section.extend_from_slice(&self.tail);
+            
+            push_u32(&mut out, section.len() as u32);
+            out.extend_from_slice(&section);
+            
+            if let Some(merged) = &self.merged
+            {
+                out.extend_from_slice(merged);
+            }
+            else
+            {
+                push_u16(&mut out, 0);
+                out.extend(core::iter::repeat_n(0, (self.width * self.height * self.channel_count as u32) as usize));
+            }
+            out
+        }
+        
+        /// Contents of the layer info section, without the length. Empty if there are no layers.
+        fn layer_info(&self) -> Vec<u8>
+        {
+            let mut info = vec!();
+            if self.layers.is_empty()
+            {
+                return info;
+            }
+            push_u16(&mut info, self.layers.len() as u16);
+            for layer in &self.layers
+            {
+                push_i32(&mut info, layer.rect.0);
+                push_i32(&mut info, layer.rect.1);
+                push_i32(&mut info, layer.rect.2);
+                push_i32(&mut info, layer.rect.3);
+                push_u16(&mut info, layer.channels.len() as u16);
+                for (id, data) in &layer.channels
+                {
+                    push_u16(&mut info, *id as u16);
+                    push_u32(&mut info, data.len() as u32);
+                }
+                info.extend_from_slice(b"8BIM");
+                info.extend_from_slice(&layer.blend_mode);
+                info.push(layer.opacity);
+                info.push(layer.clipping);
+                info.push(layer.flags);
+                info.push(0);
+                
+                let mut exdat = vec!();
+                push_u32(&mut exdat, layer.mask.len() as u32);
+                exdat.extend_from_slice(&layer.mask);
+                push_u32(&mut exdat, 0);
+                let mut name = vec!(layer.name.len() as u8);
+                name.extend_from_slice(layer.name.as_bytes());
+                while name.len() % 4 != 0
+                {
+                    name.push(0);
+                }
+                exdat.extend_from_slice(&name);
+                for (key, data) in &layer.extra
+                {
+                    exdat.extend_from_slice(b"8BIM");
+                    exdat.extend_from_slice(key);
+                    push_u32(&mut exdat, data.len() as u32);
+                    exdat.extend_from_slice(data);
+                }
+                push_u32(&mut info, exdat.len() as u32);
+                info.extend_from_slice(&exdat);
+            }
+            for layer in &self.layers
+            {
+                for (_, data) in &layer.channels
+                {
+                    info.extend_from_slice(data);
+                }
+            }
+            while info.len() % 4 != 0
+            {
+                info.push(0);
+            }
+            info
+        }
+    }
+    
+    #[test]
+    fn test()
+    {
+        let data = std::fs::read("data/test.psd").expect("Failed to open test.psd");
+
+        if let Ok(layers) = parse_layer_records(&data)
+        {
+            for layer in layers
+            {
+                // Don't spew tons of image data bytes to stdout; we just want to see the metadata.
+                println!("{:?}", layer.metadata_view());
+            }
+        }
+        
+        println!("-----");
+        
+        let data = std::fs::read("data/test2.psd").expect("Failed to open test2.psd");
+
+        if let Ok(layers) = parse_layer_records(&data)
+        {
+            for layer in layers
+            {
+                println!("{:?}", layer.metadata_view());
+            }
+        }
+    }
+    
+    #[test]
+    #[cfg(feature = "serde_support")]
+    fn serde_round_trip()
+    {
+        for path in ["data/test.psd", "data/test2.psd"]
+        {
+            let data = std::fs::read(path).unwrap();
+            let metadata = parse_psd_metadata(&data).unwrap();
+            let json = serde_json::to_string(&metadata).unwrap();
+            assert_eq!(serde_json::from_str::<PsdMetadata>(&json).unwrap(), metadata);
+            
+            let layers = parse_layer_records(&data).unwrap();
+            let json = serde_json::to_string(&layers).unwrap();
+            let back : Vec<LayerInfo> = serde_json::from_str(&json).unwrap();
+            assert_eq!(format!("{:?}", back), format!("{:?}", layers));
+            
+            // Without the pixel data, only the metadata is left.
+            let options = ParseOptions { skip_image_data : true, skip_masks : true, ..Default::default() };
+            let layers = parse_layer_records_opts(&data, &options).unwrap();
+            let small = serde_json::to_string(&layers).unwrap();
+            assert!(small.len() < json.len());
+            let back : Vec<LayerInfo> = serde_json::from_str(&small).unwrap();
+            assert_eq!(format!("{:?}", back), format!("{:?}", layers));
+        }
+    }
+    
+    #[test]
+    #[cfg(feature = "json_support")]
+    fn descriptor_json()
+    {
+        use serde_json::json;
+        // Neither test file has layer effects, but test2.psd has a layer comp descriptor.
+        let data = std::fs::read("data/test2.psd").unwrap();
+        let layers = parse_layer_records(&data).unwrap();
+        let comps = layers[0].metadata_settings.iter().find(|x| x.key == "cmls").unwrap();
+        assert_eq!(descriptor_to_json(comps.desc.as_ref().unwrap()), json!({
+            "_class" : "null",
+            "LyrI" : 5,
+            "layerSettings" : [{
+                "_class" : "null",
+                "compList" : [0],
+                "enab" : true,
+                "Ofst" : { "_class" : "null", "Hrzn" : 0, "Vrtc" : 0 },
+                "FXRefPoint" : { "_class" : "null", "Hrzn" : 0, "Vrtc" : -12 },
+                "blendOptions" : {
+                    "_class" : "null",
+                    "Md  " : { "enum" : "BlnM", "value" : "Nrml" },
+                    "Opct" : { "unit" : "#Prc", "value" : 100.0 },
+                    "fillOpacity" : { "unit" : "#Prc", "value" : 50.19607843137255 },
+                },
+            }],
+        }));
+        
+        let desc = Descriptor("Lefx".to_string(), vec!(
+            ("Scl ".to_string(), DescItem::doub(f64::NAN)),
+            ("Dup ".to_string(), DescItem::long(1)),
+            ("Dup ".to_string(), DescItem::bool(false)),
+            ("Dup ".to_string(), DescItem::TEXT("x".to_string())),
+            ("raw ".to_string(), DescItem::tdta(vec!(1, 2))),
+            ("bad ".to_string(), DescItem::Err("Unknown OSType key".to_string())),
+            ("big ".to_string(), DescItem::comp(1 << 40)),
+            ("Clr ".to_string(), DescItem::GlbO(Box::new(Descriptor("Grsc".to_string(), vec!(("Gry ".to_string(), DescItem::doub(50.0))))))),
+            ("Type".to_string(), DescItem::_type("".to_string(), "RGBC".to_string())),
+            ("null".to_string(), DescItem::obj(vec!(DescRef::Enmr("Lyr ".to_string(), "Ordn".to_string(), "Trgt".to_string()), DescRef::indx(2)))),
+            ("file".to_string(), DescItem::alis(vec!(3))),
+            ("Hrzn".to_string(), DescItem::UnFl("#Pxl".to_string(), vec!(1.0, 2.5))),
+            ("mesh".to_string(), DescItem::ObAr(2, Box::new(Descriptor("rationalPoint".to_string(), vec!(("Vrtc".to_string(), DescItem::UnFl("#Pxl".to_string(), vec!(0.0, 4.0)))))))),
+        ));
+        assert_eq!(descriptor_to_json(&desc), json!({
+            "_class" : "Lefx",
+            "Scl " : null,
+            "Dup " : 1,
+            "Dup #2" : false,
+            "Dup #3" : "x",
+            "raw " : { "data" : [1, 2] },
+            "bad " : { "error" : "Unknown OSType key" },
+            "big " : 1u64 << 40,
+            "Clr " : { "_class" : "Grsc", "Gry " : 50.0 },
+            "Type" : { "class" : "RGBC", "name" : "" },
+            "null" : [{ "ref" : "Enmr", "class" : "Lyr ", "enum" : "Ordn", "value" : "Trgt" }, { "ref" : "indx", "index" : 2 }],
+            "file" : { "alias" : [3] },
+            "Hrzn" : { "unit" : "#Pxl", "values" : [1.0, 2.5] },
+            "mesh" : { "count" : 2, "fields" : { "_class" : "rationalPoint", "Vrtc" : { "unit" : "#Pxl", "values" : [0.0, 4.0] } } },
+        }));
+    }
+    
+    #[test]
+    fn descriptor_references()
+    {
+        // A unicode string, without desc_text's type tag.
+        let unicode = |text : &str| desc_text(text)[4..].to_vec();
+        let mut reference = b"obj ".to_vec();
+        push_u32(&mut reference, 7);
+        for (typ, rest) in [
+            (b"prop", [unicode(""), desc_key("Lyr "), desc_key("Nm  ")].concat()),
+            (b"Clss", [unicode(""), desc_key("Dcmn")].concat()),
+            (b"Enmr", [unicode(""), desc_key("Lyr "), desc_key("Ordn"), desc_key("Trgt")].concat()),
+            (b"rele", [unicode(""), desc_key("Lyr "), (-2i32).to_be_bytes().to_vec()].concat()),
+            (b"Idnt", 5i32.to_be_bytes().to_vec()),
+            (b"indx", 3i32.to_be_bytes().to_vec()),
+            (b"name", [unicode("Layer 1"), desc_key("Lyr ")].concat()),
+        ]
+        {
+            reference.extend_from_slice(typ);
+            reference.extend(rest);
+        }
+        let mut comp = b"comp".to_vec();
+        comp.extend_from_slice(&(-5_000_000_000i64).to_be_bytes());
+        let class = [b"type".to_vec(), unicode("Color"), desc_key("RGBC")].concat();
+        let global_class = [b"GlbC".to_vec(), unicode(""), desc_key("bookColor")].concat();
+        let mut alias = b"alis".to_vec();
+        push_u32(&mut alias, 3);
+        alias.extend_from_slice(&[1, 2, 3]);
+        let mut global = desc("Grsc", &[("Gry ", desc_doub(50.0))]);
+        global.splice(0..0, *b"GlbO");
+        let data = desc("null", &[
+            ("null", reference),
+            ("big ", comp),
+            ("Type", class),
+            ("Cls2", global_class),
+            ("file", alias),
+            ("Clr ", global),
+            ("Aftr", desc_long(1)),
+        ]);
+        
+        let mut cursor = SliceCursor::new(&data);
+        let desc = read_descriptor(&mut cursor).unwrap();
+        assert_eq!(cursor.position(), data.len() as u64);
+        assert_eq!(desc.get("null").and_then(DescItem::as_reference).unwrap(), [
+            DescRef::prop("Lyr ".to_string(), "Nm  ".to_string()),
+            DescRef::Clss("Dcmn".to_string()),
+            DescRef::Enmr("Lyr ".to_string(), "Ordn".to_string(), "Trgt".to_string()),
+            DescRef::rele("Lyr ".to_string(), -2),
+            DescRef::Idnt(5),
+            DescRef::indx(3),
+            DescRef::name("Lyr ".to_string(), "Layer 1".to_string()),
+        ]);
+        assert_eq!(desc.get("big ").and_then(DescItem::as_f64), Some(-5_000_000_000.0));
+        assert!(matches!(desc.get("Type"), Some(DescItem::_type(name, id)) if name == "Color" && id == "RGBC"));
+        assert!(matches!(desc.get("Cls2"), Some(DescItem::_type(name, id)) if name.is_empty() && id == "bookColor"));
+        assert!(matches!(desc.get("file"), Some(DescItem::alis(x)) if x == &[1, 2, 3]));
+        assert_eq!(desc.get_path(&["Clr ", "Gry "]).and_then(DescItem::as_f64), Some(50.0));
+        // Everything after the new item types is still in sync.
+        assert_eq!(desc.get("Aftr").and_then(DescItem::as_long), Some(1));
+    }
+    
+    #[test]
+    fn descriptor_raw_data()
+    {
+        let tdta = |bytes : &[u8]|
+        {
+            let mut out = b"tdta".to_vec();
+            push_u32(&mut out, bytes.len() as u32);
+            out.extend_from_slice(bytes);
+            out
+        };
+        let data = desc("TxLr", &[
+            ("Txt ", desc_text("Hi")),
+            ("EngineData", tdta(b"\n\n<<\n\t/EngineDict\n>>")),
+            ("Empt", tdta(b"")),
+            ("Aftr", desc_long(7)),
+        ]);
+        let mut truncated = desc("TxLr", &[("EngineData", tdta(b"abcdef"))]);
+        truncated.truncate(truncated.len() - 3);
+        
+        let mut cursor = SliceCursor::new(&data);
+        let desc = read_descriptor(&mut cursor).unwrap();
+        assert_eq!(cursor.position(), data.len() as u64);
+        assert_eq!(desc.get("EngineData").and_then(DescItem::as_tdta), Some(&b"\n\n<<\n\t/EngineDict\n>>"[..]));
+        assert_eq!(desc.get("Empt").and_then(DescItem::as_tdta), Some(&[][..]));
+        assert_eq!(desc.get("Aftr").and_then(DescItem::as_long), Some(7));
+        
+        // A payload that runs past the end of the data is an error, not a short read.
+        assert!(read_descriptor(&mut SliceCursor::new(&truncated)).is_err());
+    }
+    
+    #[test]
+    fn descriptor_paths()
+    {
+        let data = desc("null", &[
+            ("Scl ", desc_objc("Pnt ", &[("Hrzn", desc_untf("#Prc", 50.0)), ("Vrtc", desc_untf("#Prc", 25.0))])),
+            ("Lst ", desc_list(&[desc_long(1), desc_objc("null", &[("Nm  ", desc_text("deep"))])])),
+            ("Dup ", desc_long(1)),
+            ("Dup ", desc_long(2)),
+        ]);
+        let mut cursor = SliceCursor::new(&data);
+        let desc = read_descriptor(&mut cursor).unwrap();
+        assert_eq!(desc.class(), "null");
+        assert_eq!(desc.items().len(), 4);
+        assert_eq!(desc.get_path(&["Scl ", "Hrzn"]).and_then(DescItem::as_f64), Some(50.0));
+        assert_eq!(desc.get_path(&["Lst ", "1", "Nm  "]).and_then(DescItem::as_text), Some("deep"));
+        assert_eq!(desc.get_path(&["Lst ", "0"]).and_then(DescItem::as_long), Some(1));
+        assert!(desc.get_path(&["Lst ", "2"]).is_none());
+        assert!(desc.get_path(&["Lst ", "x"]).is_none());
+        assert!(desc.get_path(&["Dup ", "0"]).is_none());
+        assert!(desc.get_path(&[]).is_none());
+        assert_eq!(desc.get("Dup ").and_then(DescItem::as_long), Some(1));
+        assert_eq!(desc.get_all("Dup ").filter_map(DescItem::as_long).collect::<Vec<_>>(), [1, 2]);
+        
+        let tuple : (String, Vec<(String, DescItem)>) = desc.clone().into();
+        assert_eq!(Descriptor::from(tuple).get_path(&["Scl ", "Vrtc"]).and_then(DescItem::as_f64), Some(25.0));
+    }
+    
+    #[test]
+    fn desc_accessors()
+    {
+        let list = DescItem::VlLs(vec!(DescItem::long(3), DescItem::doub(0.5), DescItem::UntF("#Pxl".to_string(), 2.0), DescItem::TEXT("hi".to_string())));
+        let item = DescItem::Objc(Box::new(Descriptor("null".to_string(), vec!(("list".to_string(), list), ("mode".to_string(), DescItem::_enum("BlnM".to_string(), "Nrml".to_string()))))));
+        
+        let list = item.get("list").and_then(DescItem::as_list).unwrap();
+        assert_eq!(list.iter().map(DescItem::as_f64).collect::<Vec<_>>(), [Some(3.0), Some(0.5), Some(2.0), None]);
+        assert_eq!(list[0].as_long(), Some(3));
+        assert_eq!(list[1].as_long(), None);
+        assert_eq!(list[2].as_untf(), Some(("#Pxl", 2.0)));
+        assert_eq!(list[3].as_text(), Some("hi"));
+        assert_eq!(item.get("mode").and_then(DescItem::as_enum), Some(("BlnM", "Nrml")));
+        assert_eq!(item.as_objc().map(|x| x.1.len()), Some(2));
+        assert!(item.get("missing").is_none());
+        assert!(list[0].get("list").is_none());
+        assert!(item.as_tdta().is_none() && item.as_bool().is_none() && item.as_doub().is_none());
+    }
+    
+    #[test]
+    fn nonstandard_mask_default_color()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 2, 2),
+            channels : vec!((0, raw_channel(&[1, 2, 3, 4])), (-2, raw_channel(&[9, 9, 9, 9]))),
+            mask : mask_record((0, 0, 2, 2), 128, 0),
+            ..Default::default()
+        });
+        let data = psd.build();
+        
+        let layers = parse_layer_records(&data).unwrap();
+        assert_eq!(layers[0].mask_info.default_color, 128);
+        assert_eq!(layers[0].mask_info.effective_default_color(), 255);
+        assert_eq!(layers[0].warnings.len(), 1);
+        
+        let mut options = ParseOptions::default();
+        options.strict = true;
+        assert!(parse_layer_records_opts(&data, &options).is_err());
+    }
+    
+    #[test]
+    fn uncompressed_channel_size()
+    {
+        // The size includes the two bytes of compression mode, so only three bytes of data are read.
+        let mut out = vec!();
+        append_img_data(&[0, 0, 1, 2, 3, 4], &mut out, 5, 1).unwrap();
+        assert_eq!(out, [1, 2, 3]);
+        // Not enough data is an error, not a panic.
+        assert!(append_img_data(&[0, 0, 1], &mut out, 5, 1).is_err());
+    }
+    
+    #[test]
+    fn filter_mask_rect()
+    {
+        let mut item = vec!();
+        for _ in 0..4
+        {
+            push_i32(&mut item, 0);
+        }
+        push_u32(&mut item, 8);
+        push_u32(&mut item, 0);
+        push_u32(&mut item, 0);
+        push_u32(&mut item, 0);
+        // A filter mask whose edges are too far apart for an i32.
+        item.push(1);
+        for n in [0, i32::MIN, 0, i32::MAX]
+        {
+            push_i32(&mut item, n);
+        }
+        push_u16(&mut item, 0);
+        push_u32(&mut item, 0);
+        push_u32(&mut item, 0);
+        
+        let mut effects = vec!();
+        effects.push(1);
+        effects.push(b'a');
+        push_u32(&mut effects, 1);
+        push_u32(&mut effects, 0);
+        push_u32(&mut effects, item.len() as u32);
+        effects.extend(item);
+        let mut block = vec!();
+        push_u32(&mut block, 3);
+        push_u32(&mut block, 0);
+        push_u32(&mut block, effects.len() as u32);
+        block.extend(effects);
+        
+        let mut bytes_left = u64::MAX;
+        let masks = parse_filter_effects(&block, &ParseOptions::default(), &mut bytes_left).unwrap();
+        assert_eq!((masks[0].1.w, masks[0].1.h), (u32::MAX, 0));
+    }
+    
+    #[test]
+    fn packbits_literal_runs()
+    {
+        // A literal run of two bytes, then a repeat run. The repeat run's header comes right after the literal bytes.
+        let mut out = vec!();
+        append_img_data(&[0, 1, 0, 5, 1, 10, 20, 0xFE, 30], &mut out, 9, 1).unwrap();
+        assert_eq!(out, [10, 20, 30, 30, 30]);
+    }
+    
+    #[test]
+    fn no_mask_record()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 1),
+            channels : vec!((0, raw_channel(&[1]))),
+            name : "\u{FF}\u{FF}\u{FF}\u{FF}".to_string(),
+            ..Default::default()
+        });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!((layers[0].mask_info.x, layers[0].mask_info.y, layers[0].mask_info.w, layers[0].mask_info.h), (0, 0, 0, 0));
+        assert_eq!(layers[0].mask_info.default_color, 0);
+        assert!(layers[0].warnings.is_empty());
+    }
+    
+    #[test]
+    fn inside_out_rectangles()
+    {
+        let mut psd = TestPsd::default();
+        // Right of the layer is left of its left edge, and the mask's bottom is above its top.
+        psd.layers.push(TestLayer {
+            rect : (0, 5, 2, 1),
+            channels : vec!((0, raw_channel(&[1, 2])), (-2, raw_channel(&[3, 4]))),
+            mask : mask_record((4, 0, 3, 2), 0, 0),
+            ..Default::default()
+        });
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, raw_channel(&[9]))), name : "after".to_string(), ..Default::default() });
+        let data = psd.build();
+        
+        let layers = parse_layer_records(&data).unwrap();
+        assert_eq!((layers[0].x, layers[0].y, layers[0].w, layers[0].h), (5, 0, 0, 2));
+        assert_eq!(layers[0].rect_raw, (0, 5, 2, 1));
+        assert!(layers[0].image_data_rgba.is_empty());
+        assert_eq!((layers[0].mask_info.w, layers[0].mask_info.h), (2, 0));
+        assert_eq!(layers[0].mask_info.rect_raw, (4, 0, 3, 2));
+        assert!(layers[0].image_data_mask.is_empty());
+        assert_eq!(layers[0].warnings.len(), 2);
+        assert_eq!(layers[1].name, "after");
+        assert_eq!(layers[1].image_data_rgba, [9, 255, 255, 255]);
+        assert_eq!(layers[1].rect_raw, (0, 0, 1, 1));
+        
+        let mut options = ParseOptions::default();
+        options.strict = true;
+        assert!(matches!(parse_layer_records_opts(&data, &options), Err((_, PsdError::Strict(_)))));
+    }
+    
+    #[test]
+    fn zero_size_layers()
+    {
+        // Packbits with no rows, then padded out with a zero-length row that isn't there.
+        let empty_rle = vec![0, 1];
+        let padded_rle = vec![0, 1, 0, 0];
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { name : "</Layer group>".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Empty raster".to_string(), channels : vec!((0, empty_rle.clone()), (-1, padded_rle.clone())), ..Default::default() });
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 1),
+            channels : vec!((0, raw_channel(&[5])), (-2, padded_rle.clone()), (1, raw_channel(&[6]))),
+            mask : mask_record((0, 0, 0, 2), 0, 0),
+            ..Default::default()
+        });
+        psd.layers.push(TestLayer { name : "Group".to_string(), channels : vec!((0, empty_rle.clone()), (-1, empty_rle.clone())), extra : vec!((*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers.len(), 4);
+        assert!(layers[1].image_data_rgba.is_empty());
+        assert_eq!((layers[2].mask_info.w, layers[2].mask_info.h), (2, 0));
+        assert!(layers[2].image_data_mask.is_empty());
+        assert_eq!(layers[2].image_data_rgba, [5, 6, 255, 255]);
+        assert!(layers[3].group_opener);
+        
+        // The decoders all agree on zero rows and on zero-length rows.
+        let mut out = vec!();
+        assert_eq!(append_img_data(&padded_rle, &mut out, 4, 0), Ok(4));
+        assert!(out.is_empty());
+        assert_eq!(copy_img_data(&padded_rle, &mut [], 1, 4, 0), Ok(4));
+        assert_eq!(copy_img_data_rect(&padded_rle, &mut [], 4, 0, &CopyRect::default()), Ok(4));
+        let rows = [0, 1, 0, 0, 0, 2, 0, 7];
+        assert_eq!(append_img_data(&rows, &mut out, 8, 2), Ok(8));
+        assert_eq!(out, [7]);
+        let mut out = [0];
+        assert_eq!(copy_img_data(&rows, &mut out, 1, 8, 2), Ok(8));
+        assert_eq!(out, [7]);
+    }
+    
+    #[test]
+    fn uneven_packbits_rows()
+    {
+        // Three pixels wide. The first row is a literal that's one byte short, and the second is a run that's one byte long.
+        let rle = vec![0, 1, 0, 3, 0, 2, 1, 10, 20, 0xFD, 30];
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 2, 3),
+            channels : vec!((0, rle.clone()), (1, raw_channel(&[1, 2, 3, 4, 5, 6])), (-2, rle.clone())),
+            mask : mask_record((0, 0, 2, 3), 0, 0),
+            ..Default::default()
+        });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let red : Vec<u8> = layers[0].image_data_rgba.chunks(4).map(|x| x[0]).collect();
+        let green : Vec<u8> = layers[0].image_data_rgba.chunks(4).map(|x| x[1]).collect();
+        assert_eq!(red, [10, 20, 0, 30, 30, 30]);
+        assert_eq!(green, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(layers[0].image_data_mask, [10, 20, 0, 30, 30, 30]);
+        
+        let mut out = vec!();
+        assert_eq!(append_img_data_impl(&rle, &mut out, rle.len() as u64, 2, DecodeOpts { row_len : Some(3), ..Default::default() }), Ok(rle.len()));
+        assert_eq!(out, [10, 20, 0, 30, 30, 30]);
+        // Without knowing the width, rows are decoded as-is.
+        out.clear();
+        append_img_data(&rle, &mut out, rle.len() as u64, 2).unwrap();
+        assert_eq!(out, [10, 20, 30, 30, 30, 30]);
+    }
+    
+    #[test]
+    fn skip_image_data()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 2),
+            channels : vec!((0, raw_channel(&[1, 2])), (-2, vec![0, 1, 0, 2, 1, 3])),
+            mask : mask_record((0, 0, 1, 1), 0, 0),
+            name : "First".to_string(),
+            ..Default::default()
+        });
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, raw_channel(&[5])), (-1, raw_channel(&[6]))), name : "Second".to_string(), ..Default::default() });
+        let data = psd.build();
+        
+        let mut options = ParseOptions::default();
+        options.skip_image_data = true;
+        let layers = parse_layer_records_opts(&data, &options).unwrap();
+        assert_eq!((layers[0].w, layers[0].h, layers[0].image_channel_count), (2, 1, 2));
+        assert!(layers[0].image_data_rgba.is_empty());
+        assert_eq!(layers[0].image_data_mask, [3]);
+        assert_eq!(layers[1].name, "Second");
+        assert!(layers[1].image_data_rgba.is_empty());
+        assert!(layers[1].image_data_has_a);
+        
+        options.skip_masks = true;
+        let layers = parse_layer_records_opts(&data, &options).unwrap();
+        assert!(layers[0].image_data_mask.is_empty());
+        assert_eq!((layers[0].mask_info.w, layers[0].mask_info.h), (1, 1));
+        
+        options.skip_image_data = false;
+        let layers = parse_layer_records_opts(&data, &options).unwrap();
+        assert_eq!(layers[0].image_data_rgba, [1, 255, 255, 255, 2, 255, 255, 255]);
+        assert!(layers[0].image_data_mask.is_empty());
+        assert_eq!(layers[1].image_data_rgba, [5, 255, 255, 6]);
+    }
+    
+    #[test]
+    fn planar_channels()
+    {
+        let mut psd = TestPsd::default();
+        psd.color_mode = 4;
+        psd.channel_count = 4;
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 2),
+            channels : vec!((-1, raw_channel(&[7, 8])), (0, vec![0, 1, 0, 2, 0xFF, 9]), (3, raw_channel(&[3, 4])), (-2, raw_channel(&[5]))),
+            mask : mask_record((0, 0, 1, 1), 0, 0),
+            ..Default::default()
+        });
+        // A channel with no data is left out.
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, raw_channel(&[1])), (1, vec![0, 0])), ..Default::default() });
+        let data = psd.build();
+        
+        let options = ParseOptions { planar_channels : true, ..Default::default() };
+        let layers = parse_layer_records_opts(&data, &options).unwrap();
+        assert_eq!(layers[0].image_data_channels, [(-1, vec![7, 8]), (0, vec![9, 9]), (3, vec![3, 4])]);
+        assert!(layers[0].image_data_rgba.is_empty());
+        assert!(layers[0].image_data_k.is_empty());
+        assert_eq!(layers[0].image_data_mask, [5]);
+        assert_eq!(layers[1].image_data_channels, [(0, vec![1])]);
+        
+        let layers = parse_layer_records(&data).unwrap();
+        assert!(layers[0].image_data_channels.is_empty());
+        assert_eq!(layers[0].image_data_rgba, [9, 255, 255, 7, 9, 255, 255, 8]);
+    }
+    
+    #[test]
+    fn grayscale_planes()
+    {
+        let mut psd = TestPsd::default();
+        psd.color_mode = 1;
+        psd.channel_count = 2;
+        psd.layers.push(TestLayer { rect : (0, 0, 2, 2), channels : vec!((-1, raw_channel(&[255, 128, 0, 9])), (0, vec![0, 1, 0, 2, 0, 3, 0xFF, 40, 1, 40, 50])), ..Default::default() });
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 3), channels : vec!((0, raw_channel(&[1, 2, 3]))), ..Default::default() });
+        let data = psd.build();
+        
+        let rgba = parse_layer_records(&data).unwrap();
+        let planar = parse_layer_records_opts(&data, &ParseOptions { planar_channels : true, ..Default::default() }).unwrap();
+        for (rgba, planar) in rgba.iter().zip(&planar)
+        {
+            assert!(planar.image_data_rgba.is_empty());
+            let gray : Vec<u8> = rgba.image_data_rgba.chunks(4).map(|x| x[0]).collect();
+            assert_eq!(planar.plane(0), Some(&gray[..]));
+            let alpha : Vec<u8> = rgba.image_data_rgba.chunks(4).map(|x| x[3]).collect();
+            assert_eq!(planar.plane(-1), Some(&alpha[..]).filter(|_| rgba.image_data_has_a));
+        }
+        assert_eq!(planar[0].plane(0), Some(&[40, 40, 40, 50][..]));
+        assert_eq!(planar[0].plane(-1), Some(&[255, 128, 0, 9][..]));
+        assert_eq!(planar[1].plane(-1), None);
+        assert_eq!(planar[1].plane(1), None);
+    }
+    
+    #[test]
+    fn cmyk_conversion()
+    {
+        let mut psd = TestPsd::default();
+        psd.color_mode = 4;
+        psd.channel_count = 4;
+        // Two pixels: pure cyan at half opacity, then 50% black. Stored inverted, so 0 is full ink.
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 2),
+            channels : vec!((0, raw_channel(&[0, 255])), (1, raw_channel(&[255, 255])), (2, raw_channel(&[255, 255])), (3, raw_channel(&[255, 127])), (-1, raw_channel(&[128, 255]))),
+            ..Default::default()
+        });
+        // No K and no alpha.
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((1, raw_channel(&[55]))), ..Default::default() });
+        let data = psd.build();
+        
+        for options in [
+            ParseOptions::default(),
+            ParseOptions { shrink_channels : true, ..Default::default() },
+            ParseOptions { planar_channels : true, ..Default::default() },
+        ]
+        {
+            let layers = parse_layer_records_opts(&data, &options).unwrap();
+            assert_eq!(cmyk_layer_to_cmyka8(&layers[0]), [255, 0, 0, 0, 128, 0, 0, 0, 128, 255]);
+            assert_eq!(cmyk_layer_to_rgba8(&layers[0]), [0, 255, 255, 128, 127, 127, 127, 255]);
+            assert_eq!(cmyk_layer_to_cmyka8(&layers[1]), [0, 200, 0, 0, 255]);
+            assert_eq!(cmyk_layer_to_rgba8(&layers[1]), [255, 55, 255, 255]);
+        }
+        
+        let layers = parse_layer_records_opts(&data, &ParseOptions { skip_image_data : true, ..Default::default() }).unwrap();
+        assert!(cmyk_layer_to_rgba8(&layers[0]).is_empty());
+    }
+    
+    #[test]
+    fn extra_channels()
+    {
+        let mut psd = TestPsd::default();
+        psd.color_mode = 4;
+        psd.channel_count = 6;
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 2),
+            channels : vec!(
+                (0, raw_channel(&[1, 2])),
+                (4, vec![0, 1, 0, 2, 0xFF, 9]),
+                (-2, raw_channel(&[5])),
+                (5, raw_channel(&[6, 7])),
+                (6, vec![0, 0]),
+            ),
+            mask : mask_record((0, 0, 1, 1), 0, 0),
+            ..Default::default()
+        });
+        let data = psd.build();
+        
+        let layers = parse_layer_records(&data).unwrap();
+        assert!(layers[0].extra_channels.is_empty());
+        assert_eq!(layers[0].image_data_mask, [5]);
+        
+        let mut options = ParseOptions { extra_channels : true, ..Default::default() };
+        let layers = parse_layer_records_opts(&data, &options).unwrap();
+        // The channel with no data is left out.
+        assert_eq!(layers[0].extra_channels, [(4, vec![9, 9]), (5, vec![6, 7])]);
+        assert_eq!(layers[0].image_data_mask, [5]);
+        assert_eq!(layers[0].image_data_rgba, [1, 255, 255, 255, 2, 255, 255, 255]);
+        assert_eq!(layers[0].mask_channel_count, 4);
+        
+        options.skip_image_data = true;
+        let layers = parse_layer_records_opts(&data, &options).unwrap();
+        assert!(layers[0].extra_channels.is_empty());
+        assert_eq!(layers[0].image_data_mask, [5]);
+    }
+    
+    #[test]
+    fn shrink_channels()
+    {
+        let mut psd = TestPsd::default();
+        psd.color_mode = 1;
+        psd.channel_count = 1;
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 2), channels : vec!((-1, raw_channel(&[7, 8])), (0, raw_channel(&[1, 2]))), ..Default::default() });
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 2), channels : vec!((-1, raw_channel(&[5, 6]))), ..Default::default() });
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, raw_channel(&[1])), (1, raw_channel(&[2])), (2, raw_channel(&[3])), (-1, raw_channel(&[4]))), ..Default::default() });
+        let data = psd.build();
+
+        let mut layers = parse_layer_records(&data).unwrap();
+        assert_eq!(layers[0].pixel_stride(), 4);
+        assert!(layers[0].image_data_has_r);
+        assert!(!layers[1].image_data_has_r);
+        layers[0].shrink_channels();
+        assert!(layers[0].image_data_shrunk);
+        assert_eq!(layers[0].pixel_stride(), 2);
+        assert_eq!(layers[0].image_data_rgba, [1, 7, 2, 8]);
+        // Shrinking twice doesn't drop anything else.
+        layers[0].shrink_channels();
+        assert_eq!(layers[0].image_data_rgba, [1, 7, 2, 8]);
+
+        let options = ParseOptions { shrink_channels : true, ..Default::default() };
+        let layers = parse_layer_records_opts(&data, &options).unwrap();
+        assert_eq!((layers[0].pixel_stride(), &layers[0].image_data_rgba[..]), (2, &[1, 7, 2, 8][..]));
+        assert_eq!((layers[1].pixel_stride(), &layers[1].image_data_rgba[..]), (1, &[5, 6][..]));
+        assert_eq!((layers[2].pixel_stride(), &layers[2].image_data_rgba[..]), (4, &[1, 2, 3, 4][..]));
+    }
+
+    #[test]
+    fn reused_buffers()
+    {
+        for path in ["data/test.psd", "data/test2.psd"]
+        {
+            let data = std::fs::read(path).unwrap();
+            let fresh = parse_layer_records(&data).unwrap();
+            let mut layers = vec!();
+            let mut scratch = ScratchBuffers::default();
+            parse_layer_records_into(&data, &ParseOptions::default(), &mut layers, &mut scratch).unwrap();
+            let mut old : Vec<_> = layers.iter().map(|x| x.image_data_rgba.as_ptr()).collect();
+            parse_layer_records_into(&data, &ParseOptions::default(), &mut layers, &mut scratch).unwrap();
+            assert_eq!(format!("{:?}", layers), format!("{:?}", fresh));
+            // The same file needs the same buffers, so every one of them gets reused.
+            let mut new : Vec<_> = layers.iter().map(|x| x.image_data_rgba.as_ptr()).collect();
+            old.sort();
+            new.sort();
+            assert_eq!(old, new);
+        }
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 2), channels : vec!((0, raw_channel(&[1, 2]))), ..Default::default() });
+        let mut layers = vec!();
+        let mut scratch = ScratchBuffers::default();
+        parse_layer_records_into(&psd.build(), &ParseOptions::default(), &mut layers, &mut scratch).unwrap();
+        // Errors leave the layers that came before them, like parse_layer_records_opts.
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, vec![0, 9, 1])), ..Default::default() });
+        let err = parse_layer_records_into(&psd.build(), &ParseOptions::default(), &mut layers, &mut scratch).unwrap_err();
+        assert_eq!(err, parse_layer_records(&psd.build()).unwrap_err().1);
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].image_data_rgba, [1, 255, 255, 255, 2, 255, 255, 255]);
+    }
+    
+    #[test]
+            fn layer_iter()
+    {
+        let mut psd = TestPsd::default();
+        for i in 0..3
+        {
+            psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, raw_channel(&[i]))), name : format!("Layer {}", i), ..Default::default() });
+        }
+        let data = psd.build();
+        let iter = parse_layer_records_iter(&data).unwrap();
+        assert_eq!(iter.size_hint(), (1, Some(3)));
+        let layers : Vec<LayerInfo> = iter.collect::<Result<_, _>>().unwrap();
+        let collected = parse_layer_records(&data).unwrap();
+        assert_eq!(layers.len(), 3);
+        for (a, b) in layers.iter().zip(&collected)
+        {
+            assert_eq!((&a.name, &a.image_data_rgba), (&b.name, &b.image_data_rgba));
+        }
+        assert_eq!(layers[2].image_data_rgba, [2, 255, 255, 255]);
+        
+        // Cut off in the middle of the last layer's image data: the first two layers come out fine, then an error, then nothing.
+        let cut = &data[..layers[2].channel_spans[0].offset as usize + 1];
+        let mut iter = parse_layer_records_iter(cut).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap().name, "Layer 0");
+        assert_eq!(iter.next().unwrap().unwrap().name, "Layer 1");
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+        let (partial, _) = parse_layer_records(cut).unwrap_err();
+        assert_eq!(partial.len(), 2);
+        
+        assert!(parse_layer_records_iter(b"8BPS").is_err());
+    }
+    
+    #[test]
+    fn lazy_channels()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 2),
+            channels : vec!((0, raw_channel(&[1, 2])), (1, vec![0, 1, 0, 2, 0xFF, 7]), (2, vec![0, 0]), (-2, raw_channel(&[9]))),
+            mask : mask_record((0, 0, 1, 1), 0, 0),
+            ..Default::default()
+        });
+        let data = psd.build();
+        let eager = parse_layer_records(&data).unwrap();
+        let mut options = ParseOptions::default();
+        options.skip_image_data = true;
+        options.skip_masks = true;
+        let lazy = parse_layer_records_opts(&data, &options).unwrap();
+        assert_eq!(eager[0].channel_spans, lazy[0].channel_spans);
+        
+        let spans = &lazy[0].channel_spans;
+        assert_eq!(spans.iter().map(|x| (x.id, x.compression, x.length, x.w, x.h)).collect::<Vec<_>>(), [(0, 0, 4, 2, 1), (1, 1, 6, 2, 1), (2, 0, 2, 2, 1), (-2, 0, 3, 1, 1)]);
+        assert_eq!(&data[spans[1].offset as usize..][..spans[1].length as usize], [0, 1, 0, 2, 0xFF, 7]);
+        let decoded : Vec<Vec<u8>> = spans.iter().map(|x| decode_channel(&data, x).unwrap()).collect();
+        assert_eq!(decoded, [vec!(1, 2), vec!(7, 7), vec!(), vec!(9)]);
+        for (i, channel) in decoded[..2].iter().enumerate()
+        {
+            assert_eq!(eager[0].image_data_rgba.chunks(4).map(|x| x[i]).collect::<Vec<_>>(), *channel);
+        }
+        assert_eq!(eager[0].image_data_mask, decoded[3]);
+        
+        let mut bad = spans[0];
+        bad.length = data.len() as u64;
+        assert!(decode_channel(&data, &bad).is_err());
+    }
+    
+    #[test]
+    fn channel_table()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!(
+            (-1, raw_channel(&[200])),
+            (0, raw_channel(&[1])),
+            (1, raw_channel(&[2])),
+            (1, raw_channel(&[3])),
+            (2, raw_channel(&[4])),
+            (5, raw_channel(&[5])),
+        ), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        
+        // Spot channels and repeated IDs are all listed, in file order.
+        let table : Vec<_> = layers[0].channel_spans.iter().map(|x| (x.id, x.length)).collect();
+        assert_eq!(table, [(-1, 3), (0, 3), (1, 3), (1, 3), (2, 3), (5, 3)]);
+        assert_eq!(layers[0].image_channel_count, 6);
+        assert_eq!(layers[0].image_data_rgba, [1, 3, 4, 200]);
+    }
+    
+    #[test]
+    fn decode_rows()
+    {
+        // Three rows of three: one short, one long, and one missing its data entirely.
+        let rle = vec![0, 1, 0, 3, 0, 2, 0, 0, 1, 10, 20, 0xFD, 30];
+        let mut rows = vec!();
+        let ret = decode_img_data_rows(&rle, rle.len() as u64, 3, 3, |i, row| -> Result<(), PsdError>
+        {
+            rows.push((i, row.to_vec()));
+            Ok(())
+        });
+        assert_eq!(ret, Ok(rle.len()));
+        assert_eq!(rows, [(0, vec!(10, 20, 0)), (1, vec!(30, 30, 30)), (2, vec!(0, 0, 0))]);
+        
+        // Uncompressed data is cut into rows of the given width, and padded out at the end.
+        let raw = raw_channel(&[1, 2, 3, 4, 5]);
+        let mut rows = vec!();
+        decode_img_data_rows(&raw, raw.len() as u64, 2, 3, |_, row| -> Result<(), PsdError>
+        {
+            rows.push(row.to_vec());
+            Ok(())
+        }).unwrap();
+        assert_eq!(rows, [[1, 2], [3, 4], [5, 0]]);
+        
+        // Errors from the callback stop decoding.
+        let mut count = 0;
+        let ret = decode_img_data_rows(&rle, rle.len() as u64, 3, 3, |i, _| -> Result<(), String>
+        {
+            count += 1;
+            if i == 1 { Err("stop".to_string()) } else { Ok(()) }
+        });
+        assert_eq!(ret, Err("stop".to_string()));
+        assert_eq!(count, 2);
+        assert!(decode_img_data_rows(&[0, 9], 2, 1, 1, |_, _| -> Result<(), PsdError> { Ok(()) }).is_err());
+    }
+    
+    #[test]
+    fn copy_rect()
+    {
+        // A 4x3 channel where each sample is 10 * row + column.
+        let samples : Vec<u8> = (0..3).flat_map(|row| (0..4).map(move |col| row * 10 + col)).collect();
+        let mut rle = vec![0, 1, 0, 5, 0, 5, 0, 5];
+        for row in samples.chunks(4)
+        {
+            rle.push(3);
+            rle.extend_from_slice(row);
+        }
+        let raw = raw_channel(&samples);
+        
+        // Rows 1 and 2, columns 1 and 2, into the middle of a 5-wide destination.
+        let rect = CopyRect { offset : 6, pitch : 5, first_row : 1, row_count : Some(2), first_col : 1, col_count : Some(2), ..Default::default() };
+        let expected = [0, 0, 0, 0, 0, 0, 11, 12, 0, 0, 0, 21, 22, 0, 0];
+        for data in [&rle, &raw]
+        {
+            let mut out = [0; 15];
+            assert_eq!(copy_img_data_rect(data, &mut out, data.len() as u64, 3, &rect), Ok(data.len()));
+            assert_eq!(out, expected);
+        }
+        #[cfg(feature = "zip_support")]
+        {
+            let zip = zip_channel(2, &samples);
+            let mut out = [0; 15];
+            copy_img_data_rect(&zip, &mut out, zip.len() as u64, 3, &rect).unwrap();
+            assert_eq!(out, expected);
+        }
+        
+        // Leading rows are skipped without being decoded, so garbage there doesn't matter.
+        let mut broken = rle.clone();
+        broken[8] = 100;
+        let mut out = [0; 15];
+        copy_img_data_rect(&broken, &mut out, broken.len() as u64, 3, &rect).unwrap();
+        assert_eq!(out, expected);
+        
+        // Everything, interleaved into the second byte of each pixel.
+        let mut out = [0; 48];
+        copy_img_data_rect(&rle, &mut out, rle.len() as u64, 3, &CopyRect { offset : 1, stride : 4, pitch : 16, ..Default::default() }).unwrap();
+        assert_eq!(out.chunks(4).map(|x| x[1]).collect::<Vec<_>>(), samples);
+    }
+    
+    #[test]
+    fn real_user_mask()
+    {
+        // Rasterized vector mask, then the real user mask.
+        let mut mask = mask_record((0, 0, 1, 2), 0, 0);
+        mask.truncate(18);
+        mask.extend_from_slice(&[4, 255]);
+        for x in [0, 0, 2, 2]
+        {
+            push_i32(&mut mask, x);
+        }
+        mask.extend_from_slice(&[0, 0]);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 2, 2),
+            channels : vec!((0, raw_channel(&[1, 2, 3, 4])), (-3, raw_channel(&[5, 6, 7, 8])), (-2, raw_channel(&[9, 10]))),
+            mask,
+            ..Default::default()
+        });
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 1),
+            channels : vec!((-2, raw_channel(&[11]))),
+            mask : mask_record((0, 0, 1, 1), 0, 0),
+            ..Default::default()
+        });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        
+        assert_eq!((layers[0].mask_info.w, layers[0].mask_info.h), (2, 1));
+        assert_eq!(layers[0].image_data_mask, [9, 10]);
+        let real = layers[0].real_mask_info.as_ref().unwrap();
+        assert_eq!((real.w, real.h, real.default_color, real.invert), (2, 2, 255, true));
+        assert_eq!(layers[0].image_data_real_mask, [5, 6, 7, 8]);
+        assert_eq!(layers[0].mask_channel_count, 2);
+        
+        assert!(layers[1].real_mask_info.is_none());
+        assert_eq!(layers[1].image_data_mask, [11]);
+        assert!(layers[1].image_data_real_mask.is_empty());
+    }
+    
+    #[test]
+    fn mask_parameters()
+    {
+        let mut mask = mask_record((0, 0, 1, 1), 255, 16);
+        mask.truncate(18);
+        mask.push(0b0110);
+        mask.extend_from_slice(&f64::to_be_bytes(2.5));
+        mask.push(128);
+        
+        // With a real user mask, the parameters come before it.
+        let mut with_real = mask_record((0, 0, 1, 1), 255, 16);
+        with_real.truncate(18);
+        with_real.push(0b1001);
+        with_real.push(64);
+        with_real.extend_from_slice(&f64::to_be_bytes(8.0));
+        with_real.extend_from_slice(&[0, 255]);
+        for x in [0, 0, 1, 1]
+        {
+            push_i32(&mut with_real, x);
+        }
+        
+        // All four parameters and nothing after them.
+        let mut all = mask_record((0, 0, 1, 1), 255, 16);
+        all.truncate(18);
+        all.push(0b1111);
+        all.push(200);
+        all.extend_from_slice(&f64::to_be_bytes(1.5));
+        all.push(100);
+        all.extend_from_slice(&f64::to_be_bytes(4.0));
+        
+        // The same, padded out to 40 bytes, like Photoshop writes it.
+        let mut padded = all.clone();
+        padded.resize(40, 0);
+        
+        let mut psd = TestPsd::default();
+        for mask in [mask, with_real, mask_record((0, 0, 1, 1), 255, 0), all, padded]
+        {
+            psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((-2, raw_channel(&[1]))), mask, ..Default::default() });
+        }
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let params : Vec<_> = layers.iter().map(|x| (x.mask_info.user_density, x.mask_info.user_feather, x.mask_info.vector_density, x.mask_info.vector_feather)).collect();
+        assert_eq!(params, [(None, Some(2.5), Some(128), None), (Some(64), None, None, Some(8.0)), (None, None, None, None), (Some(200), Some(1.5), Some(100), Some(4.0)), (Some(200), Some(1.5), Some(100), Some(4.0))]);
+        assert_eq!(layers[0].image_data_mask, [1]);
+        assert_eq!(layers[1].real_mask_info.as_ref().map(|x| (x.w, x.h, x.default_color)), Some((1, 1, 255)));
+        assert!(layers[0].real_mask_info.is_none());
+        for layer in &layers[3..]
+        {
+            assert!(layer.real_mask_info.is_none());
+            assert_eq!(layer.image_data_mask, [1]);
+        }
+    }
+    
+    #[test]
+    fn raw_flags()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 1),
+            channels : vec!((-2, raw_channel(&[1]))),
+            flags : 0b11010,
+            mask : mask_record((0, 0, 1, 1), 0, 0b1001),
+            ..Default::default()
+        });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].flags_raw, 0b11010);
+        assert!(!layers[0].is_visible);
+        assert_eq!(layers[0].mask_info.flags_raw, 0b1001);
+        assert!(layers[0].mask_info.from_render && layers[0].mask_info.relative && !layers[0].mask_info.invert);
+    }
+    
+    #[test]
+    fn layer_section_flags()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer::default());
+        let mut data = psd.build();
+        assert_eq!(parse_layer_section_flags(&data).unwrap(), Some(LayerSectionFlags { raw_layer_count : 2, merged_alpha : false }));
+        
+        let start = find_sections(&data).unwrap().layer_and_mask_info as usize + 4;
+        data[start..start + 2].copy_from_slice(&(-2i16).to_be_bytes());
+        assert_eq!(parse_layer_section_flags(&data).unwrap(), Some(LayerSectionFlags { raw_layer_count : -2, merged_alpha : true }));
+        assert_eq!(parse_layer_records(&data).unwrap().len(), 2);
+        
+        assert_eq!(parse_layer_section_flags(&TestPsd::default().build()).unwrap(), None);
+    }
+    
+    #[test]
+    fn brightness_contrast()
+    {
+        let mut brit = vec!();
+        for x in [10, 20, 127]
+        {
+            push_u16(&mut brit, x);
+        }
+        brit.extend_from_slice(&[0, 0]);
+        let mut cged = vec!();
+        push_u32(&mut cged, 16);
+        cged.extend(desc("null", &[
+            ("Vrsn", desc_long(1)),
+            ("Brgh", desc_long(-30)),
+            ("Cntr", desc_long(40)),
+            ("means", desc_long(127)),
+            ("Lab ", desc_bool(false)),
+            ("useLegacy", desc_bool(false)),
+            ("Auto", desc_bool(true)),
+        ]));
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"brit", brit.clone())), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"brit", brit.clone()), (*b"CgEd", cged.clone())), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"CgEd", cged), (*b"brit", brit)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].adjustment_type, "brit");
+        assert_eq!(layers[0].adjustment_info, [10.0, 20.0, 127.0, 0.0, 1.0, 0.0]);
+        for layer in &layers[1..]
+        {
+            assert_eq!(layer.adjustment_type, "brit");
+            assert_eq!(layer.adjustment_info, [-30.0, 40.0, 127.0, 0.0, 0.0, 1.0]);
+        }
+    }
+    
+    #[test]
+    fn document_blocks()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, raw_channel(&[7]))), ..Default::default() });
+        
+        let mut tail = vec!();
+        // Odd length, padded out to 4 bytes.
+        tail.extend_from_slice(b"8BIMPatt");
+        push_u32(&mut tail, 5);
+        tail.extend_from_slice(&[1, 2, 3, 4, 5, 0, 0, 0]);
+        // Already aligned, no padding.
+        tail.extend_from_slice(b"8BIMTxt2");
+        push_u32(&mut tail, 4);
+        tail.extend_from_slice(&[6, 7, 8, 9]);
+        // 8-byte length.
+        tail.extend_from_slice(b"8B64lnk2");
+        push_u32(&mut tail, 0);
+        push_u32(&mut tail, 2);
+        tail.extend_from_slice(&[10, 11, 0, 0]);
+        // Trailing padding.
+        tail.extend_from_slice(&[0; 4]);
+        psd.tail = tail;
+        
+        let data = psd.build();
+        let blocks = parse_document_blocks(&data).unwrap();
+        assert_eq!(blocks, vec!(
+            ("Patt".to_string(), vec!(1, 2, 3, 4, 5)),
+            ("Txt2".to_string(), vec!(6, 7, 8, 9)),
+            ("lnk2".to_string(), vec!(10, 11)),
+        ));
+        assert_eq!(parse_layer_records(&data).unwrap().len(), 1);
+        
+        // Length runs past the end of the section.
+        let mut bad = psd.clone();
+        bad.tail = b"8BIMTxt2".to_vec();
+        push_u32(&mut bad.tail, 100);
+        bad.tail.extend_from_slice(&[0; 4]);
+        let err = parse_document_blocks(&bad.build()).unwrap_err();
+        assert!(err.to_string().contains("'Txt2'"));
+        
+        // Garbage instead of a signature.
+        bad.tail = b"XXXXPatt".to_vec();
+        push_u32(&mut bad.tail, 0);
+        let err = parse_document_blocks(&bad.build()).unwrap_err();
+        assert!(matches!(err, PsdError::BadSignature { found, .. } if &found == b"XXXX"));
+        
+        let real = std::fs::read("data/test.psd").expect("Failed to open test.psd");
+        assert!(parse_document_blocks(&real).is_ok());
+    }
+    
+    #[test]
+    fn patterns()
+    {
+        fn vma_channel(mode : u8, rect : (u32, u32, u32, u32), data : &[u8]) -> Vec<u8>
+        {
+            let mut out = vec!();
+            push_u32(&mut out, 1);
+            push_u32(&mut out, 23 + data.len() as u32);
+            push_u32(&mut out, 8);
+            for x in [rect.0, rect.1, rect.2, rect.3]
+            {
+                push_u32(&mut out, x);
+            }
+            push_u16(&mut out, 8);
+            out.push(mode);
+            out.extend_from_slice(data);
+            out
+        }
+        fn pattern(color_mode : u32, name : &str, id : &str, channels : &[Vec<u8>]) -> Vec<u8>
+        {
+            let mut out = vec!();
+            push_u32(&mut out, 1);
+            push_u32(&mut out, color_mode);
+            push_u16(&mut out, 0);
+            push_u16(&mut out, 0);
+            let name : Vec<u16> = name.encode_utf16().chain([0]).collect();
+            push_u32(&mut out, name.len() as u32);
+            for c in name
+            {
+                push_u16(&mut out, c);
+            }
+            out.push(id.len() as u8);
+            out.extend_from_slice(id.as_bytes());
+            if color_mode == 2
+            {
+                out.extend((0..768).map(|x| x as u8));
+            }
+            let mut vma = vec!();
+            for x in [0, 0, 1, 2]
+            {
+                push_u32(&mut vma, x);
+            }
+            push_u32(&mut vma, channels.len() as u32);
+            for c in channels
+            {
+                vma.extend_from_slice(c);
+            }
+            // Unwritten user mask and sheet mask.
+            vma.extend_from_slice(&[0; 8]);
+            push_u32(&mut out, 3);
+            push_u32(&mut out, vma.len() as u32);
+            out.extend(vma);
+            out
+        }
+        
+        let rect = (0, 0, 1, 2);
+        let rgb = pattern(3, "Dots", "abcd-1234", &[
+            vma_channel(0, rect, &[1, 2]),
+            // RLE: one row, one run.
+            vma_channel(1, rect, &[0, 2, 0xFF, 3]),
+            vma_channel(0, rect, &[4, 5]),
+        ]);
+        let indexed = pattern(2, "Idx", "efgh", &[vma_channel(0, rect, &[7, 9])]);
+        
+        let mut block = vec!();
+        for p in [&rgb, &indexed]
+        {
+            push_u32(&mut block, p.len() as u32);
+            block.extend_from_slice(p);
+            block.resize(block.len().div_ceil(4) * 4, 0);
+        }
+        let mut psd = TestPsd::default();
+        psd.tail = b"8BIMPatt".to_vec();
+        push_u32(&mut psd.tail, block.len() as u32);
+        psd.tail.extend(block);
+        
+        let patterns = parse_patterns(&psd.build()).unwrap();
+        assert_eq!(patterns.len(), 2);
+        assert_eq!((patterns[0].name.as_str(), patterns[0].id.as_str(), patterns[0].color_mode), ("Dots", "abcd-1234", 3));
+        assert_eq!((patterns[0].width(), patterns[0].height()), (2, 1));
+        assert_eq!(patterns[0].palette, None);
+        assert_eq!(patterns[0].channels.len(), 5);
+        let data : Vec<_> = patterns[0].channels[..3].iter().map(|x| x.as_ref().unwrap().data.clone()).collect();
+        assert_eq!(data, vec!(vec!(1, 2), vec!(3, 3), vec!(4, 5)));
+        assert_eq!(patterns[0].channels[3], None);
+        assert_eq!(patterns[1].palette.as_ref().unwrap()[1], [3, 4, 5]);
+        assert_eq!(patterns[1].channels[0].as_ref().unwrap().data, [7, 9]);
+        // 2 pixels per channel, and 8 bytes across all four channels.
+        let data = psd.build();
+        let parse = |limits : ParseLimits| parse_patterns_opts(&data, &ParseOptions { limits, ..Default::default() });
+        assert!(parse(ParseLimits { max_layer_pixels : Some(2), max_total_bytes : Some(8), ..Default::default() }).is_ok());
+        assert_eq!(parse(ParseLimits { max_layer_pixels : Some(1), ..Default::default() }).unwrap_err(), PsdError::LimitExceeded("max_layer_pixels"));
+        assert_eq!(parse(ParseLimits { max_total_bytes : Some(7), ..Default::default() }).unwrap_err(), PsdError::LimitExceeded("max_total_bytes"));
+        
+        let real = std::fs::read("data/test.psd").expect("Failed to open test.psd");
+        assert!(parse_patterns(&real).is_ok());
+    }
+    
+    #[test]
+    fn linked_files()
+    {
+        fn linked_file(kind : &[u8; 4], version : u32, id : &str, filename : &str, data : &[u8]) -> Vec<u8>
+        {
+            let mut out = kind.to_vec();
+            push_u32(&mut out, version);
+            out.push(id.len() as u8);
+            out.extend_from_slice(id.as_bytes());
+            // Same layout as a descriptor text item, minus the type key.
+            out.extend_from_slice(&desc_text(filename)[4..]);
+            out.extend_from_slice(b"png 8BIM");
+            // External files have their own length for the cached copy, so make this one different from it.
+            push_u32(&mut out, 0);
+            push_u32(&mut out, if kind == b"liFE" { 0 } else { data.len() as u32 });
+            out.push(0);
+            if kind == b"liFE"
+            {
+                push_u32(&mut out, 16);
+                out.extend(desc("ExternalFileLink", &[("fullPath", desc_text("/tmp/b.png"))]));
+                push_u32(&mut out, 2024);
+                out.extend_from_slice(&[1, 2, 3, 4]);
+                out.extend_from_slice(&f64::to_be_bytes(5.0));
+                push_u32(&mut out, 0);
+                push_u32(&mut out, data.len() as u32);
+            }
+            out.extend_from_slice(data);
+            out
+        }
+        fn block(sig : &[u8; 4], key : &[u8; 4], entries : &[Vec<u8>]) -> Vec<u8>
+        {
+            let mut body = vec!();
+            for entry in entries
+            {
+                push_u32(&mut body, 0);
+                push_u32(&mut body, entry.len() as u32);
+                body.extend_from_slice(entry);
+                body.resize(body.len().div_ceil(4) * 4, 0);
+            }
+            let mut out = sig.to_vec();
+            out.extend_from_slice(key);
+            if sig == b"8B64"
+            {
+                push_u32(&mut out, 0);
+            }
+            push_u32(&mut out, body.len() as u32);
+            out.extend(body);
+            out
+        }
+        
+        let mut psd = TestPsd::default();
+        psd.tail = block(b"8BIM", b"lnk2", &[
+            linked_file(b"liFD", 7, "1234-abcd", "a.png", b"\x89PNG!"),
+            linked_file(b"liFE", 7, "5678-efgh", "b.png", &[]),
+            linked_file(b"liFE", 7, "cached", "d.png", &[7, 8, 9]),
+        ]);
+        psd.tail.extend(block(b"8B64", b"lnk3", &[linked_file(b"liFD", 2, "9999", "c.psb", &[1, 2, 3])]));
+        
+        let files = parse_linked_files(&psd.build()).unwrap();
+        assert_eq!(files.len(), 4);
+        assert_eq!((files[0].kind.as_str(), files[0].id.as_str(), files[0].filename.as_str()), ("liFD", "1234-abcd", "a.png"));
+        assert_eq!((files[0].file_type.as_str(), files[0].creator.as_str()), ("png ", "8BIM"));
+        assert_eq!(files[0].data.as_deref(), Some(&b"\x89PNG!"[..]));
+        assert_eq!((files[1].kind.as_str(), files[1].id.as_str()), ("liFE", "5678-efgh"));
+        assert_eq!(files[1].link_desc.as_ref().unwrap().get("fullPath").unwrap().TEXT(), "/tmp/b.png");
+        assert_eq!(files[1].data, None);
+        assert_eq!((files[2].id.as_str(), files[2].data.as_deref()), ("cached", Some(&[7, 8, 9][..])));
+        assert_eq!((files[3].id.as_str(), files[3].data.as_deref()), ("9999", Some(&[1, 2, 3][..])));
+        
+        let real = std::fs::read("data/test.psd").expect("Failed to open test.psd");
+        assert!(parse_linked_files(&real).is_ok());
+    }
+    
+    #[test]
+    fn annotations()
+    {
+        fn annotation(kind : &[u8; 4], author : &str, data : &[u8]) -> Vec<u8>
+        {
+            let mut out = kind.to_vec();
+            out.extend_from_slice(&[1, 0, 0, 0]);
+            for x in [1, 2, 3, 4, 5, 6, 7, 8]
+            {
+                push_i32(&mut out, x);
+            }
+            for x in [0, 65535, 0, 0, 0]
+            {
+                push_u16(&mut out, x);
+            }
+            for s in [author, "Note", ""]
+            {
+                out.push(s.len() as u8);
+                out.extend_from_slice(s.as_bytes());
+                if s.len() % 2 == 0
+                {
+                    out.push(0);
+                }
+            }
+            push_u32(&mut out, 12 + data.len() as u32);
+            out.extend_from_slice(if kind == b"txtA" { b"txtC" } else { b"sndM" });
+            push_u32(&mut out, data.len() as u32);
+            out.extend_from_slice(data);
+            let mut ret = vec!();
+            push_u32(&mut ret, 4 + out.len() as u32);
+            ret.extend(out);
+            ret
+        }
+        
+        let mut block = vec!();
+        push_u16(&mut block, 2);
+        push_u16(&mut block, 1);
+        push_u32(&mut block, 3);
+        block.extend(annotation(b"txtA", "Me", &[0xFE, 0xFF, 0, b'h', 0, b'i', 0x30, 0x42]));
+        block.extend(annotation(b"txtA", "You", b"caf\xE9"));
+        block.extend(annotation(b"sndA", "Me", &[1, 2, 3]));
+        let mut psd = TestPsd::default();
+        psd.tail = b"8BIMAnno".to_vec();
+        push_u32(&mut psd.tail, block.len() as u32);
+        psd.tail.extend(block);
+        psd.tail.resize(psd.tail.len().div_ceil(4) * 4, 0);
+        
+        let notes = parse_annotations(&psd.build()).unwrap();
+        assert_eq!(notes.len(), 3);
+        assert_eq!((notes[0].kind.as_str(), notes[0].author.as_str(), notes[0].name.as_str()), ("txtA", "Me", "Note"));
+        assert!(notes[0].open);
+        assert_eq!((notes[0].icon_rect, notes[0].popup_rect), ((1, 2, 3, 4), (5, 6, 7, 8)));
+        assert_eq!(notes[0].color.color, [65535, 0, 0, 0]);
+        assert_eq!(notes[0].text.as_deref(), Some("hi\u{3042}"));
+        assert_eq!(notes[1].text.as_deref(), Some("caf\u{E9}"));
+        assert_eq!((notes[2].kind.as_str(), notes[2].text.as_ref(), &notes[2].data[..]), ("sndA", None, &[1, 2, 3][..]));
+    }
+    
+    #[test]
+    fn gradient_map()
+    {
+        let mut grdm = vec!();
+        push_u16(&mut grdm, 1);
+        grdm.extend_from_slice(&[1, 0]);
+        grdm.extend_from_slice(&desc_text("Custom")[4..]);
+        push_u16(&mut grdm, 2);
+        for (location, color) in [(0, 0), (4096, 65535)]
+        {
+            push_u32(&mut grdm, location);
+            push_u32(&mut grdm, 50);
+            push_u16(&mut grdm, 0);
+            for x in [color, color, color, 0, 0]
+            {
+                push_u16(&mut grdm, x);
+            }
+        }
+        push_u16(&mut grdm, 1);
+        push_u32(&mut grdm, 2048);
+        push_u32(&mut grdm, 50);
+        push_u16(&mut grdm, 255);
+        for x in [2, 4096, 32, 1]
+        {
+            push_u16(&mut grdm, x);
+        }
+        push_u32(&mut grdm, 7);
+        push_u16(&mut grdm, 1);
+        push_u16(&mut grdm, 0);
+        push_u32(&mut grdm, 2048);
+        push_u16(&mut grdm, 3);
+        for x in [0, 0, 0, 0, 100, 100, 100, 100, 0]
+        {
+            push_u16(&mut grdm, x);
+        }
+        
+        let mut newer = vec!();
+        push_u16(&mut newer, 2);
+        newer.resize(12, 0xFF);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"grdm", grdm)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"grdm", newer), (*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() });
+        let data = psd.build();
+        let layers = parse_layer_records(&data).unwrap();
+        assert_eq!(layers[0].adjustment_type, "grdm");
+        assert_eq!(layers[0].adjustment_info, [
+            1.0, 0.0, 1.0,
+            2.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.5, 0.0, 1.0, 1.0, 1.0, 0.0,
+            1.0, 0.5, 0.5, 1.0,
+            1.0, 7.0, 1.0, 0.0, 0.5, 3.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0,
+        ]);
+        assert_eq!(layers[0].adjustment().unwrap().to_adjustment_info(), layers[0].adjustment_info);
+        let Some(Adjustment::GradientMap(gradient)) = layers[0].adjustment() else { panic!() };
+        assert!(gradient.noise && gradient.reverse);
+        assert_eq!(gradient.color_stops[1].color, [1.0, 1.0, 1.0, 0.0]);
+        
+        // Unknown versions are skipped over, and the blocks after them still get read.
+        assert_eq!((layers[1].adjustment_type.as_str(), layers[1].adjustment_info.len()), ("grdm", 0));
+        assert_eq!(layers[1].warnings.len(), 1);
+        assert!(layers[1].group_opener);
+        let mut options = ParseOptions::default();
+        options.strict = true;
+        assert!(matches!(parse_layer_records_opts(&data, &options), Err((_, PsdError::Strict(_)))));
+    }
+    
+    #[test]
+    fn selective_color()
+    {
+        let mut selc = vec!();
+        push_u16(&mut selc, 1);
+        push_u16(&mut selc, 1);
+        for i in 0..40
+        {
+            push_u16(&mut selc, (i * 5 - 100) as u16);
+        }
+        
+        let mut newer = vec!();
+        push_u16(&mut newer, 2);
+        newer.resize(84, 0xFF);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"selc", selc)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"selc", newer), (*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() });
+        let data = psd.build();
+        let layers = parse_layer_records(&data).unwrap();
+        let info = &layers[0].adjustment_info;
+        assert_eq!(layers[0].adjustment_type, "selc");
+        assert_eq!(info.len(), 41);
+        assert_eq!(info[0], 1.0);
+        // Reds: cyan, magenta, yellow, black.
+        assert_eq!(info[5..9], [-0.8, -0.75, -0.7, -0.65]);
+        assert_eq!(info[40], 0.95);
+        assert_eq!(layers[0].adjustment().unwrap().to_adjustment_info(), layers[0].adjustment_info);
+        
+        assert_eq!((layers[1].adjustment_type.as_str(), layers[1].adjustment_info.len()), ("selc", 0));
+        assert_eq!(layers[1].warnings.len(), 1);
+        assert!(layers[1].group_opener);
+        let mut options = ParseOptions::default();
+        options.strict = true;
+        assert!(matches!(parse_layer_records_opts(&data, &options), Err((_, PsdError::Strict(_)))));
+    }
+    
+    #[test]
+    fn channel_mixer()
+    {
+        fn mixr(rows : &[[i16; 5]]) -> Vec<u8>
+        {
+            let mut out = vec!();
+            push_u16(&mut out, 1);
+            push_u16(&mut out, 0);
+            for row in rows
+            {
+                for x in row
+                {
+                    push_u16(&mut out, *x as u16);
+                }
+            }
+            out
+        }
+        
+        let rgb = mixr(&[[100, 0, 0, 0, 0], [0, 100, 0, 0, 0], [0, 0, 100, 0, -50], [40, 40, 20, 0, 0]]);
+        let cmyk = mixr(&[[100, 0, 0, 0, 0], [0, 100, 0, 0, 0], [0, 0, 100, 0, 0], [0, 0, 0, 100, 10], [25, 25, 25, 25, 0]]);
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"mixr", rgb)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"mixr", cmyk)), ..Default::default() });
+        let mut newer = vec!();
+        push_u16(&mut newer, 2);
+        newer.resize(44, 0xFF);
+        psd.layers.push(TestLayer { extra : vec!((*b"mixr", newer), (*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() });
+        let data = psd.build();
+        let layers = parse_layer_records(&data).unwrap();
+        assert_eq!(layers[0].adjustment_type, "mixr");
+        assert_eq!(layers[0].adjustment_info.len(), 1 + 4 * 5);
+        assert_eq!(layers[0].adjustment_info[11..16], [0.0, 0.0, 1.0, 0.0, -0.5]);
+        assert_eq!(layers[1].adjustment_info.len(), 1 + 5 * 5);
+        assert_eq!(layers[1].adjustment_info[16..21], [0.0, 0.0, 0.0, 1.0, 0.1]);
+        assert_eq!(layers[0].adjustment().unwrap().to_adjustment_info(), layers[0].adjustment_info);
+        assert_eq!(layers[1].adjustment().unwrap().to_adjustment_info(), layers[1].adjustment_info);
+        
+        assert_eq!((layers[2].adjustment_type.as_str(), layers[2].adjustment_info.len()), ("mixr", 0));
+        assert_eq!(layers[2].warnings.len(), 1);
+        assert!(layers[2].group_opener);
+        let mut options = ParseOptions::default();
+        options.strict = true;
+        assert!(matches!(parse_layer_records_opts(&data, &options), Err((_, PsdError::Strict(_)))));
+    }
+    
+    #[test]
+    fn photo_filter()
+    {
+        let mut v2 = vec!();
+        push_u16(&mut v2, 2);
+        for x in [0, 65535, 0, 0, 0]
+        {
+            push_u16(&mut v2, x);
+        }
+        push_u32(&mut v2, 25);
+        v2.push(1);
+        
+        let mut v3 = vec!();
+        push_u16(&mut v3, 3);
+        for x in [95, 100, 108]
+        {
+            push_i32(&mut v3, x);
+        }
+        push_u32(&mut v3, 50);
+        v3.push(0);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"phfl", v2)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"phfl", v3)), ..Default::default() });
+        let mut newer = vec!();
+        push_u16(&mut newer, 4);
+        newer.resize(20, 0xFF);
+        psd.layers.push(TestLayer { extra : vec!((*b"phfl", newer), (*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() });
+        let data = psd.build();
+        let layers = parse_layer_records(&data).unwrap();
+        assert_eq!(layers[0].adjustment_type, "phfl");
+        assert_eq!(layers[0].adjustment_info, [0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.25, 1.0]);
+        assert_eq!(layers[1].adjustment_info, [1.0, 0.0, 95.0, 100.0, 108.0, 0.0, 0.5, 0.0]);
+        assert_eq!(layers[0].adjustment().unwrap().to_adjustment_info(), layers[0].adjustment_info);
+        assert_eq!(layers[1].adjustment().unwrap().to_adjustment_info(), layers[1].adjustment_info);
+        let Some(Adjustment::PhotoFilter { color, .. }) = layers[1].adjustment() else { panic!() };
+        assert_eq!(color, PhotoFilterColor::Xyz([95.0, 100.0, 108.0]));
+        
+        assert_eq!((layers[2].adjustment_type.as_str(), layers[2].adjustment_info.len()), ("phfl", 0));
+        assert_eq!(layers[2].warnings.len(), 1);
+        assert!(layers[2].group_opener);
+        let mut options = ParseOptions::default();
+        options.strict = true;
+        assert!(matches!(parse_layer_records_opts(&data, &options), Err((_, PsdError::Strict(_)))));
+    }
+    
+    #[test]
+    fn color_balance()
+    {
+        let mut blnc = vec!();
+        for x in [-10, 0, 20, 0, 0, 0, 100, -100, 50]
+        {
+            push_u16(&mut blnc, x as i16 as u16);
+        }
+        blnc.extend_from_slice(&[1, 0]);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"blnc", blnc)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].adjustment_type, "blnc");
+        assert_eq!(layers[0].adjustment_info, [-0.1, 0.0, 0.2, 0.0, 0.0, 0.0, 1.0, -1.0, 0.5, 1.0]);
+        assert_eq!(layers[0].adjustment().unwrap().to_adjustment_info(), layers[0].adjustment_info);
+    }
+    
+    #[test]
+    fn hue_saturation_ranges()
+    {
+        let mut hue2 = vec!();
+        push_u16(&mut hue2, 2);
+        hue2.extend_from_slice(&[0, 0]);
+        for _ in 0..6
+        {
+            push_u16(&mut hue2, 0);
+        }
+        // Reds only.
+        for x in [315, 345, 15, 45, 10, -20, 5]
+        {
+            push_u16(&mut hue2, x as i16 as u16);
+        }
+        for i in 1..6
+        {
+            for x in [i * 60 - 45, i * 60 - 15, i * 60 + 15, i * 60 + 45, 0, 0, 0]
+            {
+                push_u16(&mut hue2, x as u16);
+            }
+        }
+        let mut old = vec!();
+        push_u16(&mut old, 1);
+        old.resize(40, 0);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"hue2", hue2)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"hue2", old.clone())), ..Default::default() });
+        let data = psd.build();
+        let layers = parse_layer_records(&data).unwrap();
+        let info = &layers[0].adjustment_info;
+        assert_eq!(layers[0].adjustment_type, "hue2");
+        assert_eq!(info.len(), 7 + 6 * 7);
+        assert_eq!(info[..7], [0.0; 7]);
+        assert_eq!(info[7..14], [315.0, 345.0, 15.0, 45.0, 10.0, -20.0, 5.0]);
+        assert_eq!(info[14..21], [15.0, 45.0, 75.0, 105.0, 0.0, 0.0, 0.0]);
+        assert_eq!(layers[0].adjustment().unwrap().to_adjustment_info(), layers[0].adjustment_info);
+        let Some(Adjustment::HueSaturation(hue_sat)) = layers[0].adjustment() else { panic!() };
+        assert_eq!((hue_sat.ranges[0].shift.hue, hue_sat.ranges[0].shift.saturation), (10.0, -20.0));
+        
+        assert_eq!((layers[1].adjustment_type.as_str(), layers[1].adjustment_info.len()), ("hue2", 0));
+        assert_eq!(layers[1].warnings.len(), 1);
+        let mut options = ParseOptions::default();
+        options.strict = true;
+        assert!(parse_layer_records_opts(&data, &options).is_err());
+    }
+    
+    #[test]
+    fn curves()
+    {
+        fn points(out : &mut Vec<u8>, points : &[(u16, u16)])
+        {
+            push_u16(out, points.len() as u16);
+            for (x, y) in points
+            {
+                push_u16(out, *y);
+                push_u16(out, *x);
+            }
+        }
+        
+        let mut legacy = vec!(0);
+        push_u16(&mut legacy, 1);
+        push_u32(&mut legacy, 0b101);
+        points(&mut legacy, &[(0, 0), (255, 255)]);
+        points(&mut legacy, &[(0, 51), (255, 255)]);
+        
+        let mut extended = legacy.clone();
+        extended.extend_from_slice(b"Crv ");
+        push_u16(&mut extended, 4);
+        push_u32(&mut extended, 2);
+        push_u16(&mut extended, 2);
+        points(&mut extended, &[(0, 102), (255, 255)]);
+        push_u16(&mut extended, 33);
+        points(&mut extended, &[(0, 0), (255, 0)]);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"curv", legacy)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"curv", extended)), ..Default::default() });
+        let mut newer = vec!(0);
+        push_u16(&mut newer, 2);
+        newer.resize(16, 0xFF);
+        psd.layers.push(TestLayer { extra : vec!((*b"curv", newer), (*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() });
+        let data = psd.build();
+        let layers = parse_layer_records(&data).unwrap();
+        
+        let info = &layers[0].adjustment_info;
+        assert_eq!(layers[0].adjustment_type, "curv");
+        assert_eq!(info.len(), 32 + 8);
+        assert_eq!(info[..6], [2.0, 0.0, 0.0, 1.0, 1.0, 0.0]);
+        assert_eq!(info[6..11], [2.0, 0.0, 0.2, 1.0, 1.0]);
+        
+        let info = &layers[1].adjustment_info;
+        assert_eq!(info.len(), 34 + 12);
+        assert_eq!(info[6..11], [2.0, 0.0, 0.4, 1.0, 1.0]);
+        assert_eq!(info[info.len() - 5..], [2.0, 0.0, 0.0, 1.0, 0.0]);
+        assert_eq!(layers[0].adjustment().unwrap().to_adjustment_info(), layers[0].adjustment_info);
+        assert_eq!(layers[1].adjustment().unwrap().to_adjustment_info(), layers[1].adjustment_info);
+        let Some(Adjustment::Curves(curves)) = layers[1].adjustment() else { panic!() };
+        assert_eq!((curves.len(), &curves[2]), (34, &vec!((0.0, 0.4), (1.0, 1.0))));
+        
+        assert_eq!((layers[2].adjustment_type.as_str(), layers[2].adjustment_info.len()), ("curv", 0));
+        assert_eq!(layers[2].warnings.len(), 1);
+        assert!(layers[2].group_opener);
+        let mut options = ParseOptions::default();
+        options.strict = true;
+        assert!(matches!(parse_layer_records_opts(&data, &options), Err((_, PsdError::Strict(_)))));
+    }
+    
+    #[test]
+    fn levels()
+    {
+        fn record(out : &mut Vec<u8>, record : [u16; 5])
+        {
+            for x in record
+            {
+                push_u16(out, x);
+            }
+        }
+        
+        let mut legacy = vec!();
+        push_u16(&mut legacy, 2);
+        for i in 0..29
+        {
+            // Black channel of a CMYK document.
+            record(&mut legacy, if i == 4 { [51, 204, 0, 255, 150] } else { [0, 255, 0, 255, 100] });
+        }
+        let mut extended = legacy.clone();
+        extended.extend_from_slice(b"Lvls");
+        push_u16(&mut extended, 3);
+        push_u16(&mut extended, 31);
+        record(&mut extended, [0, 255, 0, 255, 100]);
+        record(&mut extended, [0, 255, 51, 255, 50]);
+        let mut old = vec!();
+        push_u16(&mut old, 1);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"levl", legacy)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"levl", extended)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"levl", old)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        
+        assert_eq!(layers[0].adjustment_type, "levl");
+        assert_eq!(layers[0].adjustment_info.len(), 28 * 5);
+        assert_eq!(layers[0].adjustment_info[20..25], [0.2, 0.8, 0.0, 1.0, 1.5]);
+        assert_eq!(layers[1].adjustment_info.len(), 31 * 5);
+        assert_eq!(layers[1].adjustment_info[20..25], [0.2, 0.8, 0.0, 1.0, 1.5]);
+        assert_eq!(layers[1].adjustment_info[150..], [0.0, 1.0, 0.2, 1.0, 0.5]);
+        assert_eq!(layers[0].adjustment().unwrap().to_adjustment_info(), layers[0].adjustment_info);
+        assert_eq!(layers[1].adjustment().unwrap().to_adjustment_info(), layers[1].adjustment_info);
+        assert_eq!((layers[2].adjustment_type.as_str(), layers[2].adjustment_info.len()), ("levl", 0));
+        assert_eq!(layers[2].warnings.len(), 1);
+    }
+    
+    #[test]
+    fn typed_adjustments()
+    {
+        let mut post = vec!();
+        push_u16(&mut post, 4);
+        let mut brit = vec!();
+        for x in [20, 30, 127]
+        {
+            push_u16(&mut brit, x);
+        }
+        brit.extend_from_slice(&[0, 0]);
+        let mut thrs = vec!();
+        push_u16(&mut thrs, 128);
+        let mut ptfl = vec!();
+        push_u32(&mut ptfl, 16);
+        ptfl.extend(desc("null", &[("Ptrn", desc_objc("Ptrn", &[("Idnt", desc_text("abcd"))]))]));
+        
+        let mut psd = TestPsd::default();
+        for (key, data) in [(*b"post", post), (*b"nvrt", vec!()), (*b"brit", brit), (*b"thrs", thrs), (*b"PtFl", ptfl)]
+        {
+            psd.layers.push(TestLayer { extra : vec!((key, data)), ..Default::default() });
+        }
+        psd.layers.push(TestLayer::default());
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        for layer in &layers[..5]
+        {
+            assert_eq!(layer.adjustment().unwrap().to_adjustment_info(), layer.adjustment_info);
+        }
+        assert!(matches!(layers[0].adjustment(), Some(Adjustment::Posterize { levels : 4 })));
+        assert!(matches!(layers[1].adjustment(), Some(Adjustment::Invert)));
+        let Some(Adjustment::BrightnessContrast { brightness, contrast, legacy, .. }) = layers[2].adjustment() else { panic!() };
+        assert_eq!((brightness, contrast, legacy), (20.0, 30.0, true));
+        assert!(matches!(layers[3].adjustment(), Some(Adjustment::Threshold { level : 128 })));
+        let Some(Adjustment::PatternFill { pattern_id, desc }) = layers[4].adjustment() else { panic!() };
+        assert_eq!((pattern_id.as_deref(), desc.1.len()), (Some("abcd"), 1));
+        assert!(layers[5].adjustment().is_none());
+        
+        // Truncated data shouldn't panic.
+        let mut layer = layers[3].clone();
+        layer.adjustment_type = "hue2".to_string();
+        assert!(layer.adjustment().is_none());
+    }
+    
+    #[test]
+    fn image_resources()
+    {
+        let mut psd = TestPsd::default();
+        let mut res = vec!();
+        // Empty name, odd-length data.
+        res.extend_from_slice(b"8BIM");
+        push_u16(&mut res, 1005);
+        res.extend_from_slice(&[0, 0]);
+        push_u32(&mut res, 3);
+        res.extend_from_slice(&[1, 2, 3, 0]);
+        // Odd-length name, so no name padding; zero-length data.
+        res.extend_from_slice(b"8BIM");
+        push_u16(&mut res, 1000);
+        res.extend_from_slice(&[3, b'a', b'b', b'c']);
+        push_u32(&mut res, 0);
+        // Even-length name with padding, and a third-party signature.
+        res.extend_from_slice(b"MeSa");
+        push_u16(&mut res, 4000);
+        res.extend_from_slice(&[2, b'h', b'i', 0]);
+        push_u32(&mut res, 2);
+        res.extend_from_slice(&[4, 5]);
+        psd.resources = res;
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, raw_channel(&[7]))), ..Default::default() });
+        
+        let data = psd.build();
+        let resources = parse_image_resources(&data).unwrap();
+        assert_eq!(resources, vec!(
+            ImageResource { signature : "8BIM".to_string(), id : 1005, name : "".to_string(), data : vec!(1, 2, 3) },
+            ImageResource { signature : "8BIM".to_string(), id : 1000, name : "abc".to_string(), data : vec!() },
+            ImageResource { signature : "MeSa".to_string(), id : 4000, name : "hi".to_string(), data : vec!(4, 5) },
+        ));
+        assert_eq!(get_image_resource(&data, 4000).unwrap(), Some(&[4u8, 5][..]));
+        assert_eq!(get_image_resource(&data, 1234).unwrap(), None);
+        assert_eq!(parse_layer_records(&data).unwrap().len(), 1);
+        
+        let real = std::fs::read("data/test.psd").expect("Failed to open test.psd");
+        assert!(!parse_image_resources(&real).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn resolution_info()
+    {
+        let mut psd = TestPsd::default();
+        assert_eq!(parse_resolution_info(&psd.build()).unwrap(), None);
+        
+        let mut res = b"8BIM".to_vec();
+        push_u16(&mut res, 1005);
+        res.extend_from_slice(&[0, 0]);
+        push_u32(&mut res, 16);
+        push_u32(&mut res, 300 << 16);
+        push_u16(&mut res, 1);
+        push_u16(&mut res, 2);
+        push_u32(&mut res, (72 << 16) | 0x8000);
+        push_u16(&mut res, 2);
+        push_u16(&mut res, 1);
+        psd.resources = res;
+        let info = parse_resolution_info(&psd.build()).unwrap().unwrap();
+        assert_eq!(info, ResolutionInfo { h_res : 300.0, h_res_unit : 1, width_unit : 2, v_res : 72.5, v_res_unit : 2, height_unit : 1 });
+        
+        let real = std::fs::read("data/test.psd").expect("Failed to open test.psd");
+        assert!(parse_resolution_info(&real).unwrap().unwrap().h_res > 0.0);
+    }
+    
+    #[test]
+    fn thumbnail()
+    {
+        fn thumbnail_resource(id : u16, format : u32, payload : &[u8]) -> Vec<u8>
+        {
+            let mut res = b"8BIM".to_vec();
+            push_u16(&mut res, id);
+            res.extend_from_slice(&[0, 0]);
+            push_u32(&mut res, 28 + payload.len() as u32);
+            push_u32(&mut res, format);
+            push_u32(&mut res, 2);
+            push_u32(&mut res, 1);
+            push_u32(&mut res, 8);
+            push_u32(&mut res, 8);
+            push_u32(&mut res, if format == 1 { 3 } else { 0 });
+            push_u16(&mut res, 24);
+            push_u16(&mut res, 1);
+            res.extend_from_slice(payload);
+            if payload.len() % 2 == 1
+            {
+                res.push(0);
+            }
+            res
+        }
+        
+        let mut psd = TestPsd::default();
+        assert_eq!(parse_thumbnail(&psd.build()).unwrap(), None);
+        
+        psd.resources = thumbnail_resource(1033, 0, &[1, 2, 3, 4, 5, 6, 0, 0]);
+        let thumb = parse_thumbnail(&psd.build()).unwrap().unwrap();
+        assert_eq!(thumb, Thumbnail { resource_id : 1033, format : 0, width : 2, height : 1, width_bytes : 8, bits_per_pixel : 24, data : vec!(1, 2, 3, 4, 5, 6, 0, 0) });
+        
+        psd.resources.extend(thumbnail_resource(1036, 1, &[0xFF, 0xD8, 0xFF]));
+        let thumb = parse_thumbnail(&psd.build()).unwrap().unwrap();
+        assert_eq!((thumb.resource_id, thumb.format), (1036, 1));
+        assert_eq!(thumb.data, [0xFF, 0xD8, 0xFF]);
+        
+        let real = std::fs::read("data/test.psd").expect("Failed to open test.psd");
+        if let Some(thumb) = parse_thumbnail(&real).unwrap()
+        {
+            assert!(thumb.format != 1 || thumb.data.starts_with(&[0xFF, 0xD8]));
+        }
+    }
+    
+    #[test]
+    fn color_profile()
+    {
+        let mut psd = TestPsd::default();
+        psd.color_mode = 4;
+        psd.channel_count = 4;
+        assert_eq!(parse_color_profile(&psd.build()).unwrap(), ColorProfile { icc : None, untagged : false });
+        
+        // Just the start of a CMYK ICC profile header; rawpsd doesn't look inside.
+        let mut icc = vec!();
+        push_u32(&mut icc, 13);
+        icc.extend_from_slice(b"ADBE");
+        push_u32(&mut icc, 0x02100000);
+        icc.push(0);
+        let mut res = b"8BIM".to_vec();
+        push_u16(&mut res, 1039);
+        res.extend_from_slice(&[0, 0]);
+        push_u32(&mut res, icc.len() as u32);
+        res.extend_from_slice(&icc);
+        res.push(0);
+        res.extend_from_slice(b"8BIM");
+        push_u16(&mut res, 1041);
+        res.extend_from_slice(&[0, 0]);
+        push_u32(&mut res, 1);
+        res.extend_from_slice(&[1, 0]);
+        psd.resources = res;
+        assert_eq!(parse_color_profile(&psd.build()).unwrap(), ColorProfile { icc : Some(icc), untagged : true });
+        
+        let real = std::fs::read("data/test.psd").expect("Failed to open test.psd");
+        if let Some(icc) = parse_color_profile(&real).unwrap().icc
+        {
+            assert!(icc.len() >= 128);
+        }
+    }
+    
+    #[test]
+    fn exif()
+    {
+        let mut psd = TestPsd::default();
+        assert_eq!(parse_exif(&psd.build()).unwrap(), None);
+        
+        // Little-endian TIFF with two IFD entries, orientation second.
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        for (tag, value) in [(0x010Fu16, 0u16), (0x0112, 6)]
+        {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&3u16.to_le_bytes());
+            tiff.extend_from_slice(&1u32.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+            tiff.extend_from_slice(&[0, 0]);
+        }
+        tiff.extend_from_slice(&[0; 4]);
+        
+        psd.resources = image_resource(1059, b"junk!");
+        let exif = parse_exif(&psd.build()).unwrap().unwrap();
+        assert_eq!(exif, ExifData { resource_id : 1059, data : b"junk!".to_vec(), valid_tiff_header : false });
+        assert_eq!(exif.orientation(), None);
+        
+        psd.resources.extend(image_resource(1058, &tiff));
+        let exif = parse_exif(&psd.build()).unwrap().unwrap();
+        assert_eq!((exif.resource_id, exif.valid_tiff_header), (1058, true));
+        assert_eq!(exif.data, tiff);
+        assert_eq!(exif.orientation(), Some(6));
+        
+        // Big-endian, no orientation tag.
+        let mut tiff = b"MM\0*".to_vec();
+        push_u32(&mut tiff, 8);
+        push_u16(&mut tiff, 0);
+        psd.resources = image_resource(1058, &tiff);
+        assert_eq!(parse_exif(&psd.build()).unwrap().unwrap().orientation(), None);
+    }
+    
+    #[test]
+    fn guides()
+    {
+        let mut psd = TestPsd::default();
+        assert_eq!(parse_guides(&psd.build()).unwrap(), None);
+        
+        let mut res = vec!();
+        push_u32(&mut res, 1);
+        push_u32(&mut res, 576);
+        push_u32(&mut res, 576);
+        push_u32(&mut res, 0);
+        psd.resources = image_resource(1032, &res);
+        assert_eq!(parse_guides(&psd.build()).unwrap(), Some(GuidesInfo { version : 1, grid_h : 18.0, grid_v : 18.0, guides : vec!() }));
+        
+        res[15] = 2;
+        push_i32(&mut res, 100 * 32);
+        res.push(0);
+        push_i32(&mut res, -16);
+        res.push(1);
+        psd.resources = image_resource(1032, &res);
+        assert_eq!(parse_guides(&psd.build()).unwrap().unwrap().guides, vec!(
+            Guide { position : 100.0, horizontal : false },
+            Guide { position : -0.5, horizontal : true },
+        ));
+    }
+    
+    #[test]
+    fn alpha_channels()
+    {
+        let mut psd = TestPsd::default();
+        psd.color_mode = 4;
+        psd.channel_count = 6;
+        assert_eq!(parse_alpha_channels(&psd.build()).unwrap(), vec!());
+        
+        let mut res = vec!();
+        res.extend(image_resource(1006, b"\x0cPANTONE 185C\x05Alpha"));
+        let mut names = vec!();
+        for name in ["PANTONE 185 C", "Alpha 1", "Extra"]
+        {
+            let name : Vec<u16> = name.encode_utf16().collect();
+            push_u32(&mut names, name.len() as u32);
+            for c in name
+            {
+                push_u16(&mut names, c);
+            }
+        }
+        res.extend(image_resource(1045, &names));
+        res.extend(image_resource(1053, &[0, 0, 0, 10, 0, 0, 0, 11]));
+        let mut display = vec!();
+        push_u32(&mut display, 1);
+        for (color_space, color, opacity, kind) in [(0u16, [65535u16, 0, 0, 0], 100u16, 2u8), (0, [0, 65535, 0, 0], 50, 0)]
+        {
+            push_u16(&mut display, color_space);
+            for c in color
+            {
+                push_u16(&mut display, c);
+            }
+            push_u16(&mut display, opacity);
+            display.push(kind);
+        }
+        res.extend(image_resource(1077, &display));
+        psd.resources = res;
+        
+        let channels = parse_alpha_channels(&psd.build()).unwrap();
+        assert_eq!(channels.len(), 3);
+        assert_eq!(channels[0], AlphaChannelInfo
+        {
+            pascal_name : Some("PANTONE 185C".to_string()),
+            unicode_name : Some("PANTONE 185 C".to_string()),
+            identifier : Some(10),
+            display : Some(ChannelDisplayInfo { color_space : 0, color : [65535, 0, 0, 0], opacity : 100, kind : 2 }),
+        });
+        assert_eq!(channels[1].pascal_name.as_deref(), Some("Alpha"));
+        assert_eq!(channels[1].display.as_ref().map(|x| x.kind), Some(0));
+        assert_eq!(channels[2], AlphaChannelInfo { unicode_name : Some("Extra".to_string()), ..Default::default() });
+    }
+    
+    #[test]
+    fn layer_state()
+    {
+        let mut psd = TestPsd::default();
+        // Group closer, nested layer, group opener, top layer.
+        psd.layers.push(TestLayer { name : "</Layer group>".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Nested".to_string(), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Group".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Top".to_string(), ..Default::default() });
+        assert_eq!(parse_layer_state(&psd.build()).unwrap(), LayerStateInfo::default());
+        
+        let mut res = image_resource(1024, &[0, 1]);
+        res.extend(image_resource(1069, &[0, 1, 0, 0, 0, 7]));
+        res.extend(image_resource(1026, &[0, 0, 0, 2, 0, 0, 0, 2]));
+        res.extend(image_resource(1072, &[0, 1, 0, 1]));
+        psd.resources = res;
+        
+        let data = psd.build();
+        let state = parse_layer_state(&data).unwrap();
+        assert_eq!(state, LayerStateInfo
+        {
+            target_layer : Some(1),
+            selected_ids : Some(vec!(7)),
+            group_ids : Some(vec!(0, 2, 0, 2)),
+            group_enabled_ids : Some(vec!(0, 1, 0, 1)),
+        });
+        let layers = parse_layer_records(&data).unwrap();
+        assert_eq!(layers[state.target_layer.unwrap() as usize].name, "Nested");
+    }
+    
+    #[test]
+    fn layer_id()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { name : "Old".to_string(), ..Default::default() });
+        psd.layers.push(TestLayer { name : "New".to_string(), extra : vec!((*b"lyid", vec!(0, 0, 1, 2))), ..Default::default() });
+        psd.resources = image_resource(1069, &[0, 1, 0, 0, 1, 2]);
+        let data = psd.build();
+        let layers = parse_layer_records(&data).unwrap();
+        assert_eq!(layers[0].layer_id, None);
+        assert_eq!(layers[1].layer_id, Some(0x102));
+        assert_eq!(parse_layer_state(&data).unwrap().selected_ids, Some(vec!(layers[1].layer_id.unwrap())));
+    }
+    
+    #[test]
+    fn sheet_color()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer { extra : vec!((*b"lclr", vec!(0, 6, 0xDE, 0xAD, 0xBE, 0xEF, 1, 2))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].sheet_color, 0);
+        assert_eq!(layers[1].sheet_color, 6);
+    }
+    
+    #[test]
+    fn protection_flags()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { flags : 1, ..Default::default() });
+        // The flags byte says alpha locked, 'lspf' says otherwise.
+        psd.layers.push(TestLayer { flags : 1, extra : vec!((*b"lspf", vec!(0, 0, 0, 6))), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"lspf", vec!(0x80, 0, 0, 1))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let locks : Vec<_> = layers.iter().map(|x| (x.is_alpha_locked, x.lock_composite, x.lock_position, x.lock_all)).collect();
+        assert_eq!(locks, vec!(
+            (true, false, false, false),
+            (false, true, true, false),
+            (true, false, false, true),
+        ));
+    }
+    
+    #[test]
+    fn name_source()
+    {
+        let mut psd = TestPsd::default();
+        let luni : Vec<u16> = "Ébauche".encode_utf16().collect();
+        let mut luni_data = vec!();
+        push_u32(&mut luni_data, luni.len() as u32);
+        for c in luni
+        {
+            push_u16(&mut luni_data, c);
+        }
+        psd.layers.push(TestLayer { name : "Background".to_string(), extra : vec!((*b"lnsr", b"bgnd".to_vec())), ..Default::default() });
+        psd.layers.push(TestLayer { name : "?bauche".to_string(), extra : vec!((*b"luni", luni_data), (*b"lnsr", b"user".to_vec())), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!((layers[0].name.as_str(), layers[0].name_ascii.as_str(), layers[0].name_source.as_str()), ("Background", "Background", "bgnd"));
+        assert_eq!((layers[1].name.as_str(), layers[1].name_ascii.as_str(), layers[1].name_source.as_str()), ("Ébauche", "?bauche", "user"));
+    }
+    
+    #[test]
+    fn metadata_settings()
+    {
+        let mut mlst = vec!();
+        push_u32(&mut mlst, 16);
+        mlst.extend(desc("null", &[("LaID", desc_long(5))]));
+        
+        let mut shmd = vec!();
+        push_u32(&mut shmd, 2);
+        for (key, copy, data) in [(b"mlst", 1u8, mlst.clone()), (b"cust", 0, vec!(1, 2, 3))]
+        {
+            shmd.extend_from_slice(b"8BIM");
+            shmd.extend_from_slice(key);
+            shmd.extend_from_slice(&[copy, 0, 0, 0]);
+            push_u32(&mut shmd, data.len() as u32);
+            shmd.extend(data);
+        }
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"shmd", shmd)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let settings = &layers[0].metadata_settings;
+        assert_eq!(settings.len(), 2);
+        assert_eq!((settings[0].key.as_str(), settings[0].copy_on_duplicate), ("mlst", true));
+        assert_eq!(settings[0].data, mlst);
+        let desc = settings[0].desc.as_ref().unwrap();
+        assert_eq!(desc.1[0].0, "LaID");
+        assert_eq!(desc.1[0].1.long(), 5);
+        assert_eq!((settings[1].key.as_str(), settings[1].copy_on_duplicate), ("cust", false));
+        assert_eq!(settings[1].data, [1, 2, 3]);
+        assert!(settings[1].desc.is_none());
+        
+        // A bad signature on the second entry keeps the first one and the rest of the layer.
+        let second = 8 + 12 + mlst.len();
+        psd.layers[0].extra[0].1[second..second + 4].copy_from_slice(b"8BIX");
+        psd.layers[0].extra.push((*b"lyid", vec!(0, 0, 0, 9)));
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].metadata_settings.len(), 1);
+        assert_eq!(layers[0].metadata_settings[0].key, "mlst");
+        assert_eq!((layers[0].layer_id, layers[0].warnings.len()), (Some(9), 1));
+        let options = ParseOptions { strict : true, ..Default::default() };
+        assert!(matches!(parse_layer_records_opts(&psd.build(), &options), Err((_, PsdError::Strict(_)))));
+    }
+    
+    #[test]
+    fn extra_blocks()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!(
+            (*b"zzzz", vec!(1, 2, 3, 4)),
+            (*b"iOpa", vec!(51, 0, 0, 0)),
+            (*b"yyyy", vec!()),
+        ), ..Default::default() });
+        let data = psd.build();
+        let blocks = |options : &ParseOptions|
+        {
+            let mut layers = parse_layer_records_opts(&data, options).unwrap();
+            assert_eq!(layers[0].fill_opacity, 0.2);
+            layers.swap_remove(0).extra_blocks
+        };
+        
+        assert!(blocks(&ParseOptions::default()).is_empty());
+        assert_eq!(blocks(&ParseOptions { keep_unknown_blocks : true, ..Default::default() }), [
+            ("zzzz".to_string(), vec!(1, 2, 3, 4)),
+            ("yyyy".to_string(), vec!()),
+        ]);
+        assert_eq!(blocks(&ParseOptions { keep_known_blocks : true, ..Default::default() }), [("iOpa".to_string(), vec!(51, 0, 0, 0))]);
+        assert_eq!(blocks(&ParseOptions { keep_unknown_blocks : true, keep_known_blocks : true, ..Default::default() }).len(), 3);
+    }
+    
+    #[test]
+    fn reference_point()
+    {
+        let pattern_overlay = desc("null", &[
+            ("masterFXSwitch", desc_bool(true)),
+            ("patternFill", desc_objc("patternFill", &[("enab", desc_bool(true))])),
+        ]);
+        let mut lfx2 = vec!();
+        push_u32(&mut lfx2, 0);
+        push_u32(&mut lfx2, 16);
+        lfx2.extend(pattern_overlay);
+        let mut fxrp = vec!();
+        fxrp.extend_from_slice(&12.5f64.to_be_bytes());
+        fxrp.extend_from_slice(&(-3.0f64).to_be_bytes());
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        // Moved after the pattern overlay was applied, so the reference point isn't the layer's position.
+        psd.layers.push(TestLayer { rect : (1, 2, 2, 3), channels : vec!((0, raw_channel(&[7]))), extra : vec!((*b"lfx2", lfx2), (*b"fxrp", fxrp)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].reference_point, None);
+        assert_eq!(layers[1].reference_point, Some((12.5, -3.0)));
+        assert_eq!((layers[1].x, layers[1].y), (2, 1));
+        assert_eq!(layers[1].effects_enabled, Some(true));
+    }
+    
+    #[test]
+    fn knockout()
+    {
+        let mut psd = TestPsd::default();
+        let closer = TestLayer { name : "</Layer group>".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() };
+        psd.layers.push(TestLayer { name : "Background".to_string(), ..Default::default() });
+        psd.layers.push(closer.clone());
+        psd.layers.push(closer);
+        psd.layers.push(TestLayer { name : "Deep".to_string(), extra : vec!((*b"knko", vec!(2, 0, 0, 0))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Inner".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 1)), (*b"knko", vec!(1, 0, 0, 0))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Outer".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let knockout : Vec<_> = layers.iter().map(|x| (x.name.as_str(), x.knockout)).collect();
+        assert_eq!(knockout, vec!(("Background", 0), ("</Layer group>", 0), ("</Layer group>", 0), ("Deep", 2), ("Inner", 1), ("Outer", 0)));
+    }
+    
+    #[test]
+    fn blend_interior_effects()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer { extra : vec!((*b"infx", vec!(0, 0, 0, 0))), ..Default::default() });
+        psd.layers.push(TestLayer { blend_mode : *b"mul ", extra : vec!((*b"infx", vec!(1, 0, 0, 0))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let flags : Vec<_> = layers.iter().map(|x| x.blend_interior_effects).collect();
+        assert_eq!(flags, vec!(false, false, true));
+    }
+    
+    #[test]
+    fn blend_clipped_elements()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { name : "Other".to_string(), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Base".to_string(), blend_mode : *b"scrn", extra : vec!((*b"clbl", vec!(0, 0, 0, 0))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Clipped".to_string(), clipping : 1, extra : vec!((*b"clbl", vec!(1, 0, 0, 0))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let flags : Vec<_> = layers.iter().map(|x| (x.is_clipped, x.blend_clipped_elements)).collect();
+        assert_eq!(flags, vec!((false, true), (false, false), (true, true)));
+    }
+    
+    #[test]
+    fn blend_restricted_channels()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer { extra : vec!((*b"brst", vec!())), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"brst", vec!(0, 0, 0, 0, 0, 0, 0, 2))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let channels : Vec<_> = layers.iter().map(|x| x.blend_restricted_channels.clone()).collect();
+        assert_eq!(channels, vec!(vec!(), vec!(), vec!(0, 2)));
+    }
+    
+    #[test]
+    fn group_blend_mode()
+    {
+        let mut psd = TestPsd::default();
+        let mut lsct = vec!(0, 0, 0, 1);
+        lsct.extend_from_slice(b"8BIMmul ");
+        psd.layers.push(TestLayer { name : "</Layer group>".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Scene".to_string(), blend_mode : *b"pass", extra : vec!((*b"lsct", [&lsct[..], &[0, 0, 0, 1]].concat())), ..Default::default() });
+        psd.layers.push(TestLayer { name : "</Layer group>".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Multiply".to_string(), extra : vec!((*b"lsct", lsct)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let groups : Vec<_> = layers.iter().map(|x| (x.blend_mode.as_str(), x.group_blend_mode.as_str(), x.group_sub_type)).collect();
+        assert_eq!(groups, vec!(("norm", "", 0), ("pass", "mul ", 1), ("norm", "", 0), ("norm", "mul ", 0)));
+    }
+    
+    #[test]
+    fn lsdk()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { name : "</Layer group>".to_string(), extra : vec!((*b"lsdk", vec!(0, 0, 0, 3))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Inside".to_string(), ..Default::default() });
+        let mut lsdk = vec!(0, 0, 0, 2);
+        lsdk.extend_from_slice(b"8BIMdiff");
+        // Both, in both orders: 'lsct' wins.
+        psd.layers.push(TestLayer { name : "Group".to_string(), extra : vec!((*b"lsdk", lsdk.clone()), (*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() });
+        psd.layers.push(TestLayer { name : "Both".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 0)), (*b"lsdk", lsdk)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert!(layers[0].group_closer);
+        assert!(!layers[1].group_opener && !layers[1].group_closer);
+        assert!(layers[2].group_opener && layers[2].group_expanded);
+        assert_eq!(layers[2].group_blend_mode, "");
+        assert!(!layers[3].group_opener && !layers[3].group_closer);
+        assert_eq!(layers[3].group_blend_mode, "");
+    }
+    
+    #[test]
+    fn vector_mask()
+    {
+        const ONE : i32 = 1 << 24;
+        let mut vmsk = vec!();
+        push_u32(&mut vmsk, 3);
+        push_u32(&mut vmsk, 5);
+        vmsk.extend(path_record(6, &[]));
+        vmsk.extend(path_record(8, &[0]));
+        vmsk.extend(path_record(0, &[2 << 16]));
+        vmsk.extend(path_record(2, &[0, 0, 0, 0, 0, 0]));
+        vmsk.extend(path_record(1, &[ONE / 4, ONE / 2, ONE / 4, ONE / 2, ONE / 4, ONE]));
+        vmsk.extend(path_record(3, &[1 << 16]));
+        vmsk.extend(path_record(5, &[ONE, ONE, ONE, ONE, ONE, ONE]));
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer { extra : vec!((*b"vmsk", vmsk.clone())), ..Default::default() });
+        vmsk[7] = 2;
+        psd.layers.push(TestLayer { extra : vec!((*b"vsms", vmsk)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].vector_mask, None);
+        
+        let mask = layers[1].vector_mask.as_ref().unwrap();
+        assert_eq!((mask.invert, mask.not_linked, mask.disabled, mask.initial_fill), (true, false, true, Some(false)));
+        assert_eq!(mask.subpaths.len(), 2);
+        assert!(mask.subpaths[0].closed);
+        assert_eq!(mask.subpaths[0].knots[1], PathKnot { control_in : (0.5, 0.25), anchor : (0.5, 0.25), control_out : (1.0, 0.25), linked : true });
+        assert!(!mask.subpaths[1].closed);
+        assert_eq!(mask.subpaths[1].knots[0].anchor, (1.0, 1.0));
+        
+        let mask = layers[2].vector_mask.as_ref().unwrap();
+        assert_eq!((mask.invert, mask.not_linked, mask.disabled), (false, true, false));
+        assert_eq!(mask.subpaths, layers[1].vector_mask.as_ref().unwrap().subpaths);
+    }
+    
+    #[test]
+    fn vector_fill_and_stroke()
+    {
+        let mut vscg = b"SoCo".to_vec();
+        push_u32(&mut vscg, 16);
+        vscg.extend(desc("null", &[("Clr ", desc_objc("RGBC", &[("Rd  ", desc_doub(255.0)), ("Grn ", desc_doub(0.0)), ("Bl  ", desc_doub(0.0))]))]));
+        let mut vstk = vec!();
+        push_u32(&mut vstk, 16);
+        vstk.extend(desc("strokeStyle", &[("strokeEnabled", desc_bool(true)), ("fillEnabled", desc_bool(false))]));
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer { extra : vec!((*b"vscg", vscg), (*b"vstk", vstk)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert!(layers[0].vector_fill_desc.is_none() && layers[0].vector_stroke_desc.is_none());
+        
+        let (key, fill) = layers[1].vector_fill_desc.as_ref().unwrap();
+        assert_eq!(key, "SoCo");
+        let color = fill.1[0].1.Objc();
+        assert_eq!(color.0, "RGBC");
+        assert_eq!(color.1[0].1.doub(), 255.0);
+        let stroke = layers[1].vector_stroke_desc.as_ref().unwrap();
+        assert_eq!(stroke.0, "strokeStyle");
+        assert!(stroke.1[0].1.bool());
+        assert!(!stroke.1[1].1.bool());
+    }
+    
+    #[test]
+    fn text_layer()
+    {
+        let mut engine_data = b"tdta".to_vec();
+        push_u32(&mut engine_data, 5);
+        engine_data.extend_from_slice(b"<< >>");
+        
+        let mut tysh = vec!();
+        push_u16(&mut tysh, 1);
+        for x in [1.0f64, 0.0, 0.0, 1.0, 10.0, 20.0]
+        {
+            tysh.extend_from_slice(&x.to_be_bytes());
+        }
+        push_u16(&mut tysh, 50);
+        push_u32(&mut tysh, 16);
+        tysh.extend(desc("TxLr", &[("Txt ", desc_text("Hello")), ("EngineData", engine_data)]));
+        push_u16(&mut tysh, 1);
+        push_u32(&mut tysh, 16);
+        tysh.extend(desc("warp", &[("warpStyle", desc_text("warpNone"))]));
+        let mut tysh_int = tysh.clone();
+        for x in [-1.0f64, -2.0, 30.0, 4.5]
+        {
+            tysh.extend_from_slice(&x.to_be_bytes());
+        }
+        for x in [-1i32, -2, 30, 4]
+        {
+            push_i32(&mut tysh_int, x);
+        }
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer { extra : vec!((*b"TySh", tysh)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"TySh", tysh_int)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert!(layers[0].text_info.is_none());
+        
+        let text = layers[1].text_info.as_ref().unwrap();
+        assert_eq!(text.transform, [1.0, 0.0, 0.0, 1.0, 10.0, 20.0]);
+        assert_eq!(text.text_desc.1[0].1.TEXT(), "Hello");
+        assert_eq!(text.text_desc.1[1].0, "EngineData");
+        assert_eq!(text.text_desc.1[1].1.tdta(), b"<< >>");
+        assert_eq!(text.warp_desc.1[0].1.TEXT(), "warpNone");
+        assert_eq!(text.bounds, [-1.0, -2.0, 30.0, 4.5]);
+        assert_eq!(layers[2].text_info.as_ref().unwrap().bounds, [-1.0, -2.0, 30.0, 4.0]);
+    }
+    
+    #[test]
+    fn warped_text_layer()
+    {
+        let unfl = |values : &[f64]|
+        {
+            let mut out = b"UnFl#Pxl".to_vec();
+            push_u32(&mut out, values.len() as u32);
+            for x in values
+            {
+                out.extend_from_slice(&x.to_be_bytes());
+            }
+            out
+        };
+        let horizontal : Vec<f64> = (0..16).map(|i| (i % 4) as f64 * 10.0).collect();
+        let vertical : Vec<f64> = (0..16).map(|i| (i / 4) as f64 * 5.0 + 0.5).collect();
+        let mut mesh = b"ObAr".to_vec();
+        push_u32(&mut mesh, 16);
+        mesh.extend(desc("rationalPoint", &[("Hrzn", unfl(&horizontal)), ("Vrtc", unfl(&vertical))]));
+        
+        let mut tysh = vec!();
+        push_u16(&mut tysh, 1);
+        for x in [1.0f64, 0.0, 0.0, 1.0, 0.0, 0.0]
+        {
+            tysh.extend_from_slice(&x.to_be_bytes());
+        }
+        push_u16(&mut tysh, 50);
+        push_u32(&mut tysh, 16);
+        tysh.extend(desc("TxLr", &[("Txt ", desc_text("Bent"))]));
+        push_u16(&mut tysh, 1);
+        push_u32(&mut tysh, 16);
+        tysh.extend(desc("warp", &[
+            ("warpStyle", desc_enum("warpStyle", "warpCustom")),
+            ("warpValue", desc_doub(0.0)),
+            ("customEnvelopeWarp", desc_objc("customEnvelopeWarp", &[("meshPoints", mesh)])),
+            ("warpRotate", desc_enum("Ornt", "Hrzn")),
+        ]));
+        for x in [0.0f64, 0.0, 15.0, 30.0]
+        {
+            tysh.extend_from_slice(&x.to_be_bytes());
+        }
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"TySh", tysh)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let text = layers[0].text_info.as_ref().unwrap();
+        
+        let warp = &text.warp_desc;
+        assert_eq!(warp.get("warpStyle").and_then(DescItem::as_enum), Some(("warpStyle", "warpCustom")));
+        let (count, points) = warp.get_path(&["customEnvelopeWarp", "meshPoints"]).and_then(DescItem::as_object_array).unwrap();
+        assert_eq!(count, 16);
+        assert_eq!(points.class(), "rationalPoint");
+        assert_eq!(points.get("Hrzn").and_then(DescItem::as_unfl), Some(("#Pxl", &horizontal[..])));
+        assert_eq!(points.get("Vrtc").and_then(DescItem::as_unfl), Some(("#Pxl", &vertical[..])));
+        // The items after the mesh, and the bounds after the descriptor, are still in sync.
+        assert_eq!(warp.get("warpRotate").and_then(DescItem::as_enum), Some(("Ornt", "Hrzn")));
+        assert_eq!(text.bounds, [0.0, 0.0, 15.0, 30.0]);
+    }
+    
+    #[test]
+    fn engine_data()
+    {
+        let mut data = b"\n\n<<\n\t/EngineDict\n\t<<\n\t\t/Editor\n\t\t<<\n\t\t\t/Text (\xFE\xFF".to_vec();
+        // "a(b)" plus a carriage return, with the parentheses escaped.
+        data.extend_from_slice(b"\x00a\x00\\(\x00b\x00\\)\x00\r)\n\t\t>>\n");
+        data.extend_from_slice(b"\t\t/StyleRun << /RunArray [ << /StyleSheet << /Font 0 /FontSize 12.5 /Tracking -50 /FauxBold false >> >> << >> ] /Leading .5 >>\n");
+        data.extend_from_slice(b"\t\t/Justification /Left /Raw (plain) /Weird 1.2.3\n\t>>\n>>\x00\x00garbage");
+        
+        let value = parse_engine_data(&data).unwrap();
+        let dict = value.get("EngineDict").unwrap();
+        assert_eq!(dict.get("Editor").unwrap().get("Text"), Some(&EngineValue::String("a(b)\r".to_string())));
+        let style_run = dict.get("StyleRun").unwrap();
+        assert_eq!(style_run.get("Leading"), Some(&EngineValue::Number(0.5)));
+        let EngineValue::Array(runs) = style_run.get("RunArray").unwrap() else { panic!() };
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[1], EngineValue::Dict(vec!()));
+        let style = runs[0].get("StyleSheet").unwrap();
+        assert_eq!(style.get("Font"), Some(&EngineValue::Integer(0)));
+        assert_eq!(style.get("FontSize"), Some(&EngineValue::Number(12.5)));
+        assert_eq!(style.get("Tracking"), Some(&EngineValue::Integer(-50)));
+        assert_eq!(style.get("FauxBold"), Some(&EngineValue::Bool(false)));
+        assert_eq!(dict.get("Justification"), Some(&EngineValue::Name("Left".to_string())));
+        assert_eq!(dict.get("Raw"), Some(&EngineValue::String("plain".to_string())));
+        assert_eq!(dict.get("Weird"), Some(&EngineValue::Null));
+        
+        assert!(parse_engine_data(b"<< /Text (unterminated").is_err());
+        assert!(parse_engine_data(b"garbage").is_err());
+    }
+    
+    #[test]
+    fn legacy_effects()
+    {
+        fn color(c : [u16; 4]) -> Vec<u8>
+        {
+            let mut out = vec!(0, 0);
+            for x in c
+            {
+                push_u16(&mut out, x);
+            }
+            out
+        }
+        
+        let mut records : Vec<(&[u8; 4], Vec<u8>)> = vec!();
+        records.push((b"cmnS", vec!(0, 0, 0, 0, 1, 0, 0)));
+        let mut dsdw = vec!();
+        for x in [0u32, 5, 75, 120, 3]
+        {
+            push_u32(&mut dsdw, x);
+        }
+        dsdw.extend(color([0, 0, 0, 0]));
+        dsdw.extend_from_slice(b"8BIMmul ");
+        dsdw.extend_from_slice(&[1, 1, 75]);
+        records.push((b"dsdw", dsdw));
+        let mut iglw = vec!();
+        for x in [2u32, 10, 0]
+        {
+            push_u32(&mut iglw, x);
+        }
+        iglw.extend(color([65535, 65535, 0, 0]));
+        iglw.extend_from_slice(b"8BIMscrn");
+        iglw.extend_from_slice(&[0, 50, 1]);
+        iglw.extend(color([65535, 65535, 1, 0]));
+        records.push((b"iglw", iglw));
+        let mut bevl = vec!();
+        push_u32(&mut bevl, 0);
+        push_i32(&mut bevl, -30);
+        push_u32(&mut bevl, 4);
+        push_u32(&mut bevl, 2);
+        bevl.extend_from_slice(b"8BIMscrn8BIMmul ");
+        bevl.extend(color([65535, 65535, 65535, 0]));
+        bevl.extend(color([0, 0, 0, 0]));
+        bevl.extend_from_slice(&[2, 75, 60, 1, 0, 1]);
+        records.push((b"bevl", bevl));
+        let mut sofi = vec!();
+        push_u32(&mut sofi, 2);
+        sofi.extend_from_slice(b"8BIMnorm");
+        sofi.extend(color([0, 65535, 0, 0]));
+        sofi.extend_from_slice(&[100, 1]);
+        sofi.extend(color([0, 65535, 0, 0]));
+        records.push((b"sofi", sofi));
+        
+        let mut lrfx = vec!();
+        push_u16(&mut lrfx, 0);
+        push_u16(&mut lrfx, records.len() as u16);
+        for (key, data) in records
+        {
+            lrfx.extend_from_slice(b"8BIM");
+            lrfx.extend_from_slice(key);
+            push_u32(&mut lrfx, data.len() as u32);
+            lrfx.extend(data);
+        }
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer { extra : vec!((*b"lrFX", lrfx.clone())), ..Default::default() });
+        // 'lfx2' turns the effects off, and wins whether it's before or after 'lrFX'.
+        let mut lfx2 = vec!();
+        push_u32(&mut lfx2, 0);
+        push_u32(&mut lfx2, 16);
+        lfx2.extend(desc("null", &[("masterFXSwitch", desc_bool(false))]));
+        psd.layers.push(TestLayer { extra : vec!((*b"lrFX", lrfx.clone()), (*b"lfx2", lfx2.clone())), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"lfx2", lfx2), (*b"lrFX", lrfx)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!((layers[0].legacy_effects.as_ref(), layers[0].effects_enabled), (None, None));
+        assert_eq!((layers[2].effects_enabled, layers[3].effects_enabled), (Some(false), Some(false)));
+        
+        let fx = layers[1].legacy_effects.as_ref().unwrap();
+        assert!(fx.visible);
+        assert_eq!(layers[1].effects_enabled, Some(true));
+        assert_eq!(fx.drop_shadow, Some(LegacyShadow
+        {
+            version : 0, blur : 5, intensity : 75, angle : 120, distance : 3, color : EffectColor::default(), blend_mode : "mul ".to_string(),
+            enabled : true, use_global_angle : true, opacity : 75, native_color : None,
+        }));
+        assert_eq!(fx.inner_shadow, None);
+        assert_eq!(fx.outer_glow, None);
+        let glow = fx.inner_glow.as_ref().unwrap();
+        assert_eq!((glow.version, glow.blur, glow.blend_mode.as_str(), glow.enabled, glow.opacity, glow.invert), (2, 10, "scrn", false, 50, true));
+        assert_eq!(glow.native_color, Some(EffectColor { color_space : 0, color : [65535, 65535, 1, 0] }));
+        let bevel = fx.bevel.as_ref().unwrap();
+        assert_eq!((bevel.angle, bevel.strength, bevel.blur, bevel.style, bevel.highlight_opacity, bevel.shadow_opacity), (-30, 4, 2, 2, 75, 60));
+        assert_eq!((bevel.highlight_blend_mode.as_str(), bevel.shadow_blend_mode.as_str(), bevel.direction), ("scrn", "mul ", 1));
+        assert_eq!(bevel.highlight_color.color, [65535, 65535, 65535, 0]);
+        assert_eq!(bevel.real_highlight_color, None);
+        let fill = fx.solid_fill.as_ref().unwrap();
+        assert_eq!((fill.blend_mode.as_str(), fill.color.color, fill.opacity, fill.enabled), ("norm", [0, 65535, 0, 0], 100, true));
+    }
+    
+    #[test]
+    fn legacy_effects_to_descriptor()
+    {
+        // Everything the conversion makes has to match what's in 'lfx2', but 'lfx2' has settings that legacy effects don't, so it can have more.
+        fn check(converted : &Descriptor, saved : &Descriptor)
+        {
+            assert_eq!(converted.0, saved.0);
+            for (key, item) in &converted.1
+            {
+                match (item, saved.get(key))
+                {
+                    (DescItem::Objc(a), Some(DescItem::Objc(b))) => check(a, b),
+                    (a, b) => assert_eq!(format!("{:?}", Some(a)), format!("{:?}", b), "{}", key),
+                }
+            }
+        }
+        
+        // The same drop shadow and bevel, saved both ways.
+        let mut dsdw = vec!();
+        for x in [2u32, 5, 20, 120, 3]
+        {
+            push_u32(&mut dsdw, x);
+        }
+        dsdw.extend_from_slice(&[0, 0, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0]);
+        dsdw.extend_from_slice(b"8BIMmul ");
+        dsdw.extend_from_slice(&[1, 0, 75]);
+        dsdw.extend_from_slice(&[0; 10]);
+        let mut bevl = vec!();
+        for x in [0u32, 30, 100, 5]
+        {
+            push_u32(&mut bevl, x);
+        }
+        bevl.extend_from_slice(b"8BIMscrn8BIMmul ");
+        bevl.extend_from_slice(&[0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0]);
+        bevl.extend_from_slice(&[0, 8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        bevl.extend_from_slice(&[2, 75, 40, 1, 1, 0]);
+        let mut lrfx = vec!();
+        push_u16(&mut lrfx, 0);
+        push_u16(&mut lrfx, 3);
+        for (key, data) in [(b"cmnS", vec!(0, 0, 0, 0, 1, 0, 0)), (b"dsdw", dsdw), (b"bevl", bevl)]
+        {
+            lrfx.extend_from_slice(b"8BIM");
+            lrfx.extend_from_slice(key);
+            push_u32(&mut lrfx, data.len() as u32);
+            lrfx.extend(data);
+        }
+        
+        // Laid out like Photoshop writes it, with the settings that legacy effects don't have.
+        let lfx2 = desc("null", &[
+            ("Scl ", desc_untf("#Prc", 100.0)),
+            ("masterFXSwitch", desc_bool(true)),
+            ("DrSh", desc_objc("DrSh", &[
+                ("enab", desc_bool(true)),
+                ("present", desc_bool(true)),
+                ("showInDialog", desc_bool(true)),
+                ("Md  ", desc_enum("BlnM", "Mltp")),
+                ("Clr ", desc_objc("RGBC", &[("Rd  ", desc_doub(255.0)), ("Grn ", desc_doub(0.0)), ("Bl  ", desc_doub(0.0))])),
+                ("Opct", desc_untf("#Prc", 75.0)),
+                ("uglg", desc_bool(false)),
+                ("lagl", desc_untf("#Ang", 120.0)),
+                ("Dstn", desc_untf("#Pxl", 3.0)),
+                ("Ckmt", desc_untf("#Pxl", 20.0)),
+                ("blur", desc_untf("#Pxl", 5.0)),
+                ("Nose", desc_untf("#Prc", 0.0)),
+                ("AntA", desc_bool(false)),
+                ("TrnS", desc_objc("ShpC", &[("Nm  ", desc_text("Linear"))])),
+                ("layerConceals", desc_bool(true)),
+            ])),
+            ("ebbl", desc_objc("ebbl", &[
+                ("enab", desc_bool(true)),
+                ("hglM", desc_enum("BlnM", "Scrn")),
+                ("hglC", desc_objc("RGBC", &[("Rd  ", desc_doub(255.0)), ("Grn ", desc_doub(255.0)), ("Bl  ", desc_doub(255.0))])),
+                ("hglO", desc_untf("#Prc", 75.0)),
+                ("sdwM", desc_enum("BlnM", "Mltp")),
+                ("sdwC", desc_objc("Grsc", &[("Gry ", desc_doub(0.0))])),
+                ("sdwO", desc_untf("#Prc", 40.0)),
+                ("bvlT", desc_enum("bvlT", "SfBL")),
+                ("bvlS", desc_enum("BESl", "InrB")),
+                ("uglg", desc_bool(true)),
+                ("lagl", desc_untf("#Ang", 30.0)),
+                ("Lald", desc_untf("#Ang", 30.0)),
+                ("srgR", desc_untf("#Prc", 100.0)),
+                ("blur", desc_untf("#Pxl", 5.0)),
+                ("bvlD", desc_enum("BESs", "In  ")),
+                ("Sftn", desc_untf("#Pxl", 0.0)),
+            ])),
+        ]);
+        let mut lfx2_block = vec!();
+        push_u32(&mut lfx2_block, 0);
+        push_u32(&mut lfx2_block, 16);
+        lfx2_block.extend(lfx2);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"lrFX", lrfx), (*b"lfx2", lfx2_block)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let mut legacy = layers[0].legacy_effects.clone().unwrap();
+        check(&lrfx_to_lfx2(&legacy), layers[0].effects_desc.as_ref().unwrap());
+        
+        // 'Ckmt' only goes up to 100.
+        legacy.drop_shadow.as_mut().unwrap().intensity = 250;
+        let converted = lrfx_to_lfx2(&legacy);
+        let Some(DescItem::Objc(shadow)) = converted.get("DrSh") else { panic!() };
+        assert!(matches!(shadow.get("Ckmt"), Some(DescItem::UntF(unit, x)) if unit == "#Pxl" && *x == 100.0));
+    }
+    
+    #[test]
+    fn solid_color_fill()
+    {
+        let mut soco = vec!();
+        push_u32(&mut soco, 16);
+        soco.extend(desc("null", &[("Clr ", desc_objc("RGBC", &[("Rd  ", desc_doub(10.0)), ("Grn ", desc_doub(20.0)), ("Bl  ", desc_doub(30.0))]))]));
+        let mut vmsk = vec!();
+        push_u32(&mut vmsk, 3);
+        push_u32(&mut vmsk, 0);
+        vmsk.extend(path_record(6, &[]));
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"SoCo", soco.clone()), (*b"vmsk", vmsk)), ..Default::default() });
+        psd.layers.push(TestLayer {
+            channels : vec!((-2, raw_channel(&[0, 255]))),
+            mask : mask_record((0, 0, 1, 2), 0, 0),
+            extra : vec!((*b"SoCo", soco)),
+            ..Default::default()
+        });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        for layer in &layers
+        {
+            assert_eq!(layer.adjustment_type, "SoCo");
+            let color = layer.adjustment_desc.as_ref().unwrap().get("Clr ").unwrap().Objc();
+            assert_eq!(color.1[2].1.doub(), 30.0);
+        }
+        assert!(layers[0].vector_mask.is_some());
+        assert_eq!(layers[1].image_data_mask, [0, 255]);
+    }
+    
+    #[test]
+    fn gradient_fill()
+    {
+        fn stop(location : i32, color : Vec<u8>) -> Vec<u8>
+        {
+            desc_objc("Clrt", &[("Clr ", color), ("Type", desc_enum("Clry", "UsrS")), ("Lctn", desc_long(location)), ("Mdpn", desc_long(50))])
+        }
+        fn rgb(r : f64, g : f64, b : f64) -> Vec<u8>
+        {
+            desc_objc("RGBC", &[("Rd  ", desc_doub(r)), ("Grn ", desc_doub(g)), ("Bl  ", desc_doub(b))])
+        }
+        let gradient = desc_objc("Grdn", &[
+            ("Nm  ", desc_text("Black, White")),
+            ("GrdF", desc_enum("GrdF", "CstS")),
+            ("Intr", desc_doub(4096.0)),
+            ("Clrs", desc_list(&[stop(0, rgb(0.0, 0.0, 0.0)), stop(4096, rgb(255.0, 255.0, 255.0))])),
+            ("Trns", desc_list(&[
+                desc_objc("TrnS", &[("Opct", desc_untf("#Prc", 100.0)), ("Lctn", desc_long(0)), ("Mdpn", desc_long(50))]),
+                desc_objc("TrnS", &[("Opct", desc_untf("#Prc", 0.0)), ("Lctn", desc_long(4096)), ("Mdpn", desc_long(50))]),
+            ])),
+        ]);
+        let mut gdfl = vec!();
+        push_u32(&mut gdfl, 16);
+        gdfl.extend(desc("null", &[
+            ("Dthr", desc_bool(true)),
+            ("Rvrs", desc_bool(false)),
+            ("Angl", desc_untf("#Ang", 90.0)),
+            ("Type", desc_enum("GrdT", "Lnr ")),
+            ("Algn", desc_bool(true)),
+            ("Scl ", desc_untf("#Prc", 100.0)),
+            ("Grad", gradient),
+        ]));
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"GdFl", gdfl)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].adjustment_type, "GdFl");
+        let desc = layers[0].adjustment_desc.as_ref().unwrap();
+        assert_eq!(desc.get("Angl").unwrap().UntF(), ("#Ang".to_string(), 90.0));
+        assert_eq!(desc.get("Type").unwrap()._enum(), ("GrdT".to_string(), "Lnr ".to_string()));
+        let gradient = desc.get("Grad").unwrap().Objc();
+        let stops = gradient.get("Clrs").unwrap().VlLs();
+        let locations : Vec<_> = stops.iter().map(|x| x.Objc().get("Lctn").unwrap().long()).collect();
+        assert_eq!(locations, vec!(0, 4096));
+        assert_eq!(stops[1].Objc().get("Clr ").unwrap().Objc().get("Grn ").unwrap().doub(), 255.0);
+        let transparency = gradient.get("Trns").unwrap().VlLs();
+        assert_eq!(transparency[1].Objc().get("Opct").unwrap().UntF().1, 0.0);
+    }
+    
+    #[test]
+    fn pattern_fill()
+    {
+        let mut ptfl = vec!();
+        push_u32(&mut ptfl, 16);
+        ptfl.extend(desc("null", &[
+            ("phase", desc_objc("Pnt ", &[("Hrzn", desc_doub(3.0)), ("Vrtc", desc_doub(-4.0))])),
+            ("Scl ", desc_untf("#Prc", 50.0)),
+            ("Ptrn", desc_objc("Ptrn", &[("Nm  ", desc_text("Dots")), ("Idnt", desc_text("abcd-1234"))])),
+        ]));
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer { extra : vec!((*b"PtFl", ptfl)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!((layers[0].adjustment_type.as_str(), &layers[0].fill_pattern_id), ("", &None));
+        assert_eq!(layers[1].adjustment_type, "PtFl");
+        assert_eq!(layers[1].fill_pattern_id.as_deref(), Some("abcd-1234"));
+        assert_eq!(layers[1].adjustment_desc.as_ref().unwrap().get("Scl ").unwrap().UntF(), ("#Prc".to_string(), 50.0));
+    }
+    
+    #[test]
+    fn placed_layers()
+    {
+        let mut plld = b"plcL".to_vec();
+        push_u32(&mut plld, 3);
+        plld.push(9);
+        plld.extend_from_slice(b"1234-abcd");
+        for n in [1, 2, 2, 2]
+        {
+            push_u32(&mut plld, n);
+        }
+        for n in [0.0, 0.0, 10.0, 0.0, 10.0, 5.0, 0.0, 5.0]
+        {
+            plld.extend_from_slice(&f64::to_be_bytes(n));
+        }
+        push_u32(&mut plld, 0);
+        push_u32(&mut plld, 16);
+        plld.extend(desc("warp", &[("warpStyle", desc_enum("warpStyle", "warpNone"))]));
+        
+        let mut sold = b"soLD".to_vec();
+        push_u32(&mut sold, 4);
+        push_u32(&mut sold, 16);
+        let corners = [1.0, 2.0, 11.0, 2.0, 11.0, 7.0, 1.0, 7.0].map(desc_doub);
+        sold.extend(desc("null", &[
+            ("Idnt", desc_text("1234-abcd")),
+            ("PgNm", desc_long(1)),
+            ("totalPages", desc_long(3)),
+            ("Annt", desc_long(2)),
+            ("Type", desc_long(1)),
+            ("Trnf", desc_list(&corners)),
+            ("warp", desc_objc("warp", &[("warpStyle", desc_enum("warpStyle", "warpNone"))])),
+        ]));
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"PlLd", plld.clone())), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"PlLd", plld.clone()), (*b"SoLd", sold.clone())), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"SoLd", sold), (*b"PlLd", plld)), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        
+        let placed = layers[0].placed_layer.as_ref().unwrap();
+        assert_eq!(placed.unique_id, "1234-abcd");
+        assert_eq!((placed.page_number, placed.total_pages, placed.anti_alias, placed.placed_type), (1, 2, 2, 2));
+        assert_eq!(placed.transform, [0.0, 0.0, 10.0, 0.0, 10.0, 5.0, 0.0, 5.0]);
+        assert_eq!(placed.plld_transform, Some(placed.transform));
+        assert_eq!(placed.warp_desc.as_ref().unwrap().get("warpStyle").unwrap()._enum().1, "warpNone");
+        assert!(placed.desc.is_none());
+        
+        for layer in &layers[1..]
+        {
+            let placed = layer.placed_layer.as_ref().unwrap();
+            assert_eq!(placed.unique_id, "1234-abcd");
+            assert_eq!((placed.page_number, placed.total_pages, placed.anti_alias, placed.placed_type), (1, 3, 2, 1));
+            assert_eq!(placed.transform, [1.0, 2.0, 11.0, 2.0, 11.0, 7.0, 1.0, 7.0]);
+            assert_eq!(placed.plld_transform, Some([0.0, 0.0, 10.0, 0.0, 10.0, 5.0, 0.0, 5.0]));
+            assert!(placed.warp_desc.is_some() && placed.desc.is_some());
+        }
+    }
+    
+    #[test]
+    fn paths()
+    {
+        const ONE : i32 = 1 << 24;
+        const HALF : i32 = 1 << 23;
+        
+        let mut path = vec!();
+        path.extend(path_record(6, &[]));
+        path.extend(path_record(8, &[1 << 16]));
+        path.extend(path_record(7, &[0, 0, ONE, ONE, 72 * ONE / 128]));
+        // Closed triangle.
+        path.extend(path_record(0, &[3 << 16]));
+        path.extend(path_record(1, &[0, 0, 0, 0, 0, 0]));
+        path.extend(path_record(2, &[0, ONE, 0, ONE, 0, ONE]));
+        path.extend(path_record(2, &[ONE, HALF, ONE, HALF, ONE, -HALF]));
+        // Open line.
+        path.extend(path_record(3, &[2 << 16]));
+        path.extend(path_record(4, &[HALF, 0, HALF, 0, HALF, 0]));
+        path.extend(path_record(5, &[HALF, ONE, HALF, ONE, HALF, ONE]));
+        
+        let mut psd = TestPsd::default();
+        assert_eq!(parse_paths(&psd.build()).unwrap(), vec!());
+        assert_eq!(parse_clipping_path_name(&psd.build()).unwrap(), None);
+        
+        let mut res = b"8BIM".to_vec();
+        push_u16(&mut res, 2000);
+        res.extend_from_slice(&[4, b'C', b'l', b'i', b'p', 0]);
+        push_u32(&mut res, path.len() as u32);
+        res.extend_from_slice(&path);
+        res.extend(image_resource(2999, b"\x04Clip"));
+        res.extend(image_resource(1005, &[0; 16]));
+        psd.resources = res;
+        
+        let data = psd.build();
+        let paths = parse_paths(&data).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].resource_id, 2000);
+        assert_eq!(paths[0].initial_fill, Some(true));
+        assert_eq!(paths[0].clipboard, Some([0.0, 0.0, 1.0, 1.0, 72.0 / 128.0]));
+        assert_eq!(paths[0].subpaths.len(), 2);
+        assert!(paths[0].subpaths[0].closed);
+        assert_eq!(paths[0].subpaths[0].knots.len(), 3);
+        assert_eq!(paths[0].subpaths[0].knots[2], PathKnot { control_in : (0.5, 1.0), anchor : (0.5, 1.0), control_out : (-0.5, 1.0), linked : false });
+        assert!(paths[0].subpaths[0].knots[0].linked);
+        assert!(!paths[0].subpaths[1].closed);
+        assert_eq!(paths[0].subpaths[1].knots[1].anchor, (1.0, 0.5));
+        assert!(paths[0].subpaths[1].knots[0].linked);
+        assert_eq!(parse_clipping_path_name(&data).unwrap(), Some(paths[0].name.clone()));
+    }
+    
+    #[test]
+    fn iptc()
+    {
+        let mut psd = TestPsd::default();
+        assert_eq!(parse_iptc(&psd.build()).unwrap(), None);
+        
+        let mut iim = vec!(0x1C, 2, 0);
+        push_u16(&mut iim, 2);
+        iim.extend_from_slice(&[0, 4]);
+        iim.extend_from_slice(&[0x1C, 2, 120]);
+        push_u16(&mut iim, 7);
+        iim.extend_from_slice(b"Caption");
+        // Extended dataset with a 4-byte length.
+        iim.extend_from_slice(&[0x1C, 2, 25, 0x80, 4]);
+        push_u32(&mut iim, 3);
+        iim.extend_from_slice(b"kw1");
+        // Truncated dataset, then padding.
+        iim.extend_from_slice(&[0x1C, 2, 80, 0, 50, b'x', 0, 0]);
+        psd.resources = image_resource(1028, &iim);
+        
+        let iptc = parse_iptc(&psd.build()).unwrap().unwrap();
+        assert_eq!(iptc.data, iim);
+        assert_eq!(iptc.datasets, vec!(
+            (2, 0, vec!(0, 4)),
+            (2, 120, b"Caption".to_vec()),
+            (2, 25, b"kw1".to_vec()),
+        ));
+    }
+    
+    #[test]
+    fn print_info()
+    {
+        let mut psd = TestPsd::default();
+        psd.color_mode = 4;
+        psd.channel_count = 4;
+        let info = parse_print_info(&psd.build()).unwrap();
+        assert!(info.flags.is_none() && info.scale.is_none() && info.print_info.is_none() && info.print_style.is_none());
+        
+        let mut res = image_resource(1011, &[0, 1, 0, 1, 0, 0, 1, 0, 1]);
+        let mut scale = vec!();
+        push_u16(&mut scale, 2);
+        push_u32(&mut scale, 1.5f32.to_bits());
+        push_u32(&mut scale, (-2.0f32).to_bits());
+        push_u32(&mut scale, 0.5f32.to_bits());
+        res.extend(image_resource(1062, &scale));
+        let mut print_info = vec!();
+        push_u32(&mut print_info, 16);
+        print_info.extend(desc("printOutput", &[
+            ("PstS", desc_bool(true)),
+            ("Bleed", desc_doub(3.0)),
+        ]));
+        res.extend(image_resource(1082, &print_info));
+        psd.resources = res;
+        
+        let info = parse_print_info(&psd.build()).unwrap();
+        assert_eq!(info.flags, Some(PrintFlags
+        {
+            labels : false, crop_marks : true, color_bars : false, registration_marks : true, negative : false,
+            flip : false, interpolate : true, caption : false, print_flags : true,
+        }));
+        assert_eq!(info.scale, Some(PrintScale { style : 2, x : 1.5, y : -2.0, scale : 0.5 }));
+        let print_info = info.print_info.unwrap();
+        assert_eq!(print_info.0, "printOutput");
+        assert_eq!(print_info.1[0].0, "PstS");
+        assert!(print_info.1[0].1.bool());
+        assert_eq!(print_info.1[1].0, "Bleed");
+        assert_eq!(print_info.1[1].1.doub(), 3.0);
+        assert!(info.print_style.is_none());
+    }
+    
+    #[test]
+    fn pixel_aspect_ratio_and_urls()
+    {
+        let mut psd = TestPsd::default();
+        assert_eq!(parse_pixel_aspect_ratio(&psd.build()).unwrap(), None);
+        assert_eq!(parse_url_list(&psd.build()).unwrap(), vec!());
+        
+        let mut par = vec!();
+        push_u32(&mut par, 2);
+        par.extend_from_slice(&0.9f64.to_be_bytes());
+        let mut res = image_resource(1064, &par);
+        let mut urls = vec!();
+        push_u32(&mut urls, 2);
+        for (id, url) in [(1, "http://a.example/"), (2, "b")]
+        {
+            urls.extend_from_slice(b"slic");
+            push_u32(&mut urls, id);
+            let url : Vec<u16> = url.encode_utf16().collect();
+            push_u32(&mut urls, url.len() as u32);
+            for c in url
+            {
+                push_u16(&mut urls, c);
+            }
+        }
+        res.extend(image_resource(1054, &urls));
+        psd.resources = res;
+        
+        let data = psd.build();
+        assert_eq!(parse_pixel_aspect_ratio(&data).unwrap(), Some(0.9));
+        assert_eq!(parse_url_list(&data).unwrap(), vec!(
+            UrlEntry { tag : u32::from_be_bytes(*b"slic"), id : 1, url : "http://a.example/".to_string() },
+            UrlEntry { tag : u32::from_be_bytes(*b"slic"), id : 2, url : "b".to_string() },
+        ));
+    }
+    
+    #[test]
+    fn smart_filters()
+    {
+        let filter = desc_objc("filterFX", &[
+            ("Nm  ", desc_text("Gaussian Blur")),
+            ("enab", desc_bool(false)),
+            ("Fltr", desc_objc("GsnB", &[("Rds ", desc_doub(4.0))])),
+        ]);
+        let mut sold = b"soLD".to_vec();
+        push_u32(&mut sold, 4);
+        push_u32(&mut sold, 16);
+        sold.extend(desc("null", &[
+            ("Idnt", desc_text("1234-abcd")),
+            ("filterFX", desc_objc("filterFXStyle", &[("enab", desc_bool(true)), ("filterFXList", desc_list(&[filter]))])),
+        ]));
+        
+        let mut fmsk = vec!();
+        for x in [0, 65535, 0, 0, 0, 50]
+        {
+            push_u16(&mut fmsk, x);
+        }
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 1),
+            channels : vec!((0, raw_channel(&[1]))),
+            extra : vec!((*b"FMsk", fmsk), (*b"SoLd", sold)),
+            ..Default::default()
+        });
+        
+        let mut item = vec!();
+        for _ in 0..4
+        {
+            push_i32(&mut item, 0);
+        }
+        push_u32(&mut item, 8);
+        push_u32(&mut item, 0);
+        // Two unwritten mask channels.
+        push_u32(&mut item, 0);
+        push_u32(&mut item, 0);
+        // Filter mask.
+        item.push(1);
+        for n in [1, 2, 3, 4]
+        {
+            push_i32(&mut item, n);
+        }
+        push_u16(&mut item, 0);
+        push_u32(&mut item, 0);
+        push_u32(&mut item, 4);
+        item.extend_from_slice(&[5, 6, 7, 8]);
+        
+        let mut effects = vec!();
+        effects.push(9);
+        effects.extend_from_slice(b"1234-abcd");
+        push_u32(&mut effects, 1);
+        push_u32(&mut effects, 0);
+        push_u32(&mut effects, item.len() as u32);
+        effects.extend(item);
+        
+        let mut block = vec!();
+        push_u32(&mut block, 3);
+        push_u32(&mut block, 0);
+        push_u32(&mut block, effects.len() as u32);
+        block.extend(effects);
+        
+        psd.tail.extend_from_slice(b"8BIMFEid");
+        push_u32(&mut psd.tail, block.len() as u32);
+        psd.tail.extend(block);
+        while psd.tail.len() % 4 != 0
+        {
+            psd.tail.push(0);
+        }
+        
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let filters = layers[0].smart_filters.as_ref().unwrap();
+        assert!(filters.enabled);
+        assert_eq!(filters.filters.len(), 1);
+        assert_eq!(filters.filters[0].key, "GsnB");
+        assert_eq!(filters.filters[0].name, "Gaussian Blur");
+        assert!(!filters.filters[0].enabled);
+        assert_eq!(filters.filters[0].params.as_ref().unwrap().1[0].1.doub(), 4.0);
+        let mask_info = filters.mask_info.as_ref().unwrap();
+        assert_eq!((mask_info.x, mask_info.y, mask_info.w, mask_info.h), (2, 1, 2, 2));
+        assert_eq!(filters.image_data_mask[..4], [5, 6, 7, 8]);
+        assert_eq!(filters.mask_color, Some(EffectColor { color_space : 0, color : [65535, 0, 0, 0] }));
+        assert_eq!(filters.mask_opacity, 50);
+        
+        // The metadata view leaves the mask's pixels out.
+        let view = layers[0].metadata_view().smart_filters.unwrap();
+        assert_eq!((view.filters.len(), view.mask_opacity), (1, 50));
+        assert!(!format!("{:?}", view).contains("5, 6, 7, 8"));
+    }
+    
+    #[test]
+    fn global_mask_flags()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 1),
+            channels : vec!((0, raw_channel(&[1]))),
+            extra : vec!((*b"lmgm", vec!(1, 0, 0, 0))),
+            ..Default::default()
+        });
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 1),
+            channels : vec!((0, raw_channel(&[1]))),
+            extra : vec!((*b"lumm", vec!(1, 0, 0, 0))),
+            ..Default::default()
+        });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert!(layers[0].layer_mask_as_global_mask);
+        assert!(!layers[0].user_mask_as_global_mask);
+        assert!(!layers[1].layer_mask_as_global_mask);
+        assert!(layers[1].user_mask_as_global_mask);
+    }
+    
+    #[test]
+    fn effects_enabled()
+    {
+        let mut lfx2 = vec!();
+        push_u32(&mut lfx2, 0);
+        push_u32(&mut lfx2, 16);
+        lfx2.extend(desc("null", &[("masterFXSwitch", desc_bool(false))]));
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, raw_channel(&[1]))), extra : vec!((*b"lfx2", lfx2)), ..Default::default() });
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 1), channels : vec!((0, raw_channel(&[1]))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].effects_enabled, Some(false));
+        assert!(layers[0].effects_desc.is_some());
+        assert_eq!(layers[1].effects_enabled, None);
+    }
+    
+    #[test]
+    fn unknown_descriptor_items()
+    {
+        // An unknown item type, followed by bytes that would be nonsense if read as more items.
+        let mut unknown = b"zzzz".to_vec();
+        unknown.extend_from_slice(&[0xFF; 12]);
+        let mut bad_ref = b"obj ".to_vec();
+        push_u32(&mut bad_ref, 2);
+        bad_ref.extend_from_slice(b"indx");
+        push_i32(&mut bad_ref, 1);
+        bad_ref.extend_from_slice(b"zzzz");
+        bad_ref.extend_from_slice(&[0xFF; 8]);
+        
+        let mut lfx2 = vec!();
+        push_u32(&mut lfx2, 0);
+        push_u32(&mut lfx2, 16);
+        lfx2.extend(desc("null", &[
+            ("masterFXSwitch", desc_bool(true)),
+            ("DrSh", desc_objc("DrSh", &[("enab", desc_bool(true)), ("Lst ", desc_list(&[desc_long(1), unknown.clone(), desc_long(2)])), ("Opct", desc_untf("#Prc", 50.0))])),
+            ("Scl ", desc_untf("#Prc", 100.0)),
+        ]));
+        let mut fxrp = vec!();
+        fxrp.extend_from_slice(&1.5f64.to_be_bytes());
+        fxrp.extend_from_slice(&2.5f64.to_be_bytes());
+        
+        let mut tysh = vec!();
+        push_u16(&mut tysh, 1);
+        for x in [1.0f64, 0.0, 0.0, 1.0, 0.0, 0.0]
+        {
+            tysh.extend_from_slice(&x.to_be_bytes());
+        }
+        push_u16(&mut tysh, 50);
+        push_u32(&mut tysh, 16);
+        tysh.extend(desc("TxLr", &[("Txt ", desc_text("Hi")), ("Ref ", bad_ref), ("Aftr", desc_long(1))]));
+        tysh.extend_from_slice(&[0xFF; 40]);
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"lfx2", lfx2), (*b"fxrp", fxrp)), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"TySh", tysh), (*b"iOpa", vec!(51, 0, 0, 0))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        
+        // The descriptor stops at the unknown item, at every level, and the blocks after it are still read.
+        let fx = layers[0].effects_desc.as_ref().unwrap();
+        assert!(fx.is_truncated());
+        assert_eq!(layers[0].effects_enabled, Some(true));
+        assert_eq!(fx.items().len(), 2);
+        let list = fx.get_path(&["DrSh", "Lst "]).and_then(DescItem::as_list).unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(matches!(&list[1], DescItem::Err(x) if x.starts_with("Unknown descriptor item type 'zzzz'")));
+        assert!(fx.get_path(&["DrSh", "Opct"]).is_none());
+        assert!(fx.get("Scl ").is_none());
+        assert_eq!(layers[0].reference_point, Some((1.5, 2.5)));
+        
+        let text = layers[1].text_info.as_ref().unwrap();
+        assert!(text.text_desc.is_truncated());
+        assert_eq!(text.text_desc.get("Txt ").and_then(DescItem::as_text), Some("Hi"));
+        assert!(matches!(text.text_desc.get("Ref "), Some(DescItem::Err(x)) if x.starts_with("Unknown descriptor reference type 'zzzz'")));
+        assert!(text.text_desc.get("Aftr").is_none());
+        assert!(text.warp_desc.items().is_empty());
+        assert_eq!(text.bounds, [0.0; 4]);
+        assert_eq!(layers[1].fill_opacity, 0.2);
+        
+        let data = desc("null", &[("Good", desc_long(1)), ("Bad ", unknown)]);
+        let desc = read_descriptor(&mut SliceCursor::new(&data)).unwrap();
+        assert!(desc.is_truncated());
+        assert_eq!(desc.get("Good").and_then(DescItem::as_long), Some(1));
+    }
+    
+    #[test]
+    fn descriptor_versions()
+    {
+        let versioned = |prefix : &[u8], version : u32, data : Vec<u8>|
+        {
+            let mut out = prefix.to_vec();
+            push_u32(&mut out, version);
+            out.extend(data);
+            out
         };
+        let adjustment = desc("null", &[("Rd  ", desc_long(40))]);
+        let brightness = desc("null", &[("Brgh", desc_long(9)), ("Cntr", desc_long(30)), ("means", desc_long(127)), ("Lab ", desc_bool(false)), ("useLegacy", desc_bool(true))]);
+        let mut brit = vec!();
+        for x in [5u16, 6, 127, 0]
+        {
+            push_u16(&mut brit, x);
+        }
+        
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!(
+            (*b"blwh", versioned(&[], 17, adjustment.clone())),
+            (*b"lfx2", versioned(&[0; 4], 15, desc("null", &[("masterFXSwitch", desc_bool(false))]))),
+            (*b"vstk", versioned(&[], 0, desc("strokeStyle", &[]))),
+            (*b"iOpa", vec!(51, 0, 0, 0)),
+        ), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"brit", brit), (*b"CgEd", versioned(&[], 1, brightness.clone()))), ..Default::default() });
+        psd.layers.push(TestLayer { extra : vec!((*b"blwh", versioned(&[], 16, adjustment)), (*b"CgEd", versioned(&[], 16, brightness))), ..Default::default() });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        
+        // Blocks with unknown descriptor versions are skipped, without taking the layer down with them.
+        assert!(layers[0].adjustment_desc.is_none());
+        assert!(layers[0].effects_desc.is_none() && layers[0].effects_enabled.is_none());
+        assert!(layers[0].vector_stroke_desc.is_none());
+        assert_eq!(layers[0].fill_opacity, 0.2);
+        // An unreadable 'CgEd' falls back to 'brit'.
+        assert_eq!(layers[1].adjustment_type, "brit");
+        assert_eq!(layers[1].adjustment_info, vec!(5.0, 6.0, 127.0, 0.0, 1.0, 0.0));
+        
+        assert_eq!(layers[2].adjustment_desc.as_ref().and_then(|x| x.get("Rd  ")).and_then(DescItem::as_long), Some(40));
+        assert_eq!(layers[2].adjustment_info, vec!(9.0, 30.0, 127.0, 0.0, 1.0, 0.0));
+    }
+    
+    #[test]
+    fn group_bounds()
+    {
+        let closer = TestLayer { extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() };
+        let opener = TestLayer { extra : vec!((*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() };
+        let mut psd = TestPsd::default();
+        psd.layers = vec!(
+            closer.clone(),
+            TestLayer { rect : (-10, -20, -5, -15), channels : vec!((0, raw_channel(&[1; 25]))), ..Default::default() },
+            closer.clone(),
+            TestLayer { rect : (-4, -8, -2, -6), channels : vec!((0, raw_channel(&[1; 4]))), ..Default::default() },
+            TestLayer { rect : (-3, -30, -1, -28), channels : vec!((0, raw_channel(&[1; 4]))), clipping : 1, ..Default::default() },
+            opener.clone(),
+            closer.clone(),
+            opener.clone(),
+            opener.clone(),
+        );
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let metadata = parse_psd_metadata(&psd.build()).unwrap();
+        
+        let bounds = compute_layer_bounds(&layers, false, None);
+        assert_eq!(bounds[0], None);
+        assert_eq!(bounds[1], Some((-20, -10, 5, 5)));
+        assert_eq!(bounds[5], Some((-30, -4, 24, 3)));
+        // Empty nested group.
+        assert_eq!(bounds[7], None);
+        assert_eq!(bounds[8], Some((-30, -10, 24, 9)));
+        
+        let bounds = compute_layer_bounds(&layers, false, Some(&metadata));
+        assert!(bounds.iter().all(|x| x.is_none()));
+    }
+    
+    #[test]
+    fn crop_to_canvas()
+    {
+        let mut psd = TestPsd::default();
+        psd.width = 3;
+        psd.height = 2;
+        // 4x3, hanging off the top left and the right of the canvas.
+        psd.layers.push(TestLayer {
+            rect : (-1, -1, 2, 3),
+            channels : vec!((0, raw_channel(&(0..12).collect::<Vec<u8>>())), (-2, raw_channel(&[1, 2, 3, 4, 5, 6]))),
+            mask : mask_record((1, 2, 3, 5), 0, 0),
+            ..Default::default()
+        });
+        psd.layers.push(TestLayer { rect : (5, 5, 6, 6), channels : vec!((0, raw_channel(&[1]))), ..Default::default() });
+        let data = psd.build();
+        let metadata = parse_psd_metadata(&data).unwrap();
+        
+        let mut layers = parse_layer_records(&data).unwrap();
+        let (x, y, w, h, pixels) = layers[0].crop_to_canvas(&metadata);
+        assert_eq!((x, y, w, h), (0, 0, 3, 2));
+        assert_eq!(pixels.chunks(4).map(|x| x[0]).collect::<Vec<_>>(), [5, 6, 7, 9, 10, 11]);
+        assert!(pixels.chunks(4).all(|x| x[3] == 255));
+        assert_eq!(layers[0].crop_mask_to_canvas(&metadata), (2, 1, 1, 1, vec!(1)));
+        assert_eq!(layers[1].crop_to_canvas(&metadata), (0, 0, 0, 0, vec!()));
+        
+        layers[0].shrink_channels();
+        assert_eq!(layers[0].crop_to_canvas(&metadata).4, [5, 6, 7, 9, 10, 11]);
+        
+        let layers = parse_layer_records_opts(&data, &ParseOptions { skip_image_data : true, ..Default::default() }).unwrap();
+        assert_eq!(layers[0].crop_to_canvas(&metadata), (0, 0, 3, 2, vec!()));
+    }
+    
+    #[test]
+    fn layer_tree()
+    {
+        let closer = TestLayer { name : "</Layer group>".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() };
+        let opener = |name : &str| TestLayer { name : name.to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() };
+        let layer = |name : &str| TestLayer { name : name.to_string(), ..Default::default() };
+        let mut psd = TestPsd::default();
+        psd.layers = vec!(
+            layer("Background"),
+            closer.clone(),
+            layer("Bottom"),
+            closer.clone(),
+            opener("Empty"),
+            layer("Top"),
+            opener("Outer"),
+            layer("Above"),
+        );
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        
+        fn names(nodes : &[LayerNode]) -> Vec<String>
+        {
+            nodes.iter().map(|node| match node
+            {
+                LayerNode::Layer(info) => info.name.clone(),
+                LayerNode::Group { info, children } => format!("{}({})", info.name, names(children).join(", ")),
+            }).collect()
+        }
+        let tree = build_layer_tree(layers.clone()).unwrap();
+        assert_eq!(names(&tree), ["Above", "Outer(Top, Empty(), Bottom)", "Background"]);
+        assert_eq!(tree[1].info().name, "Outer");
+        assert!(build_layer_tree(vec!()).unwrap().is_empty());
+        
+        // Unbalanced lists say which record is at fault.
+        let err = build_layer_tree(layers[..6].to_vec()).unwrap_err();
+        assert_eq!(err.to_string(), "Layer record 1 closes a group that was never opened");
+        let err = build_layer_tree(layers[2..].to_vec()).unwrap_err();
+        assert_eq!(err.to_string(), "Layer record 4 opens a group that was never closed");
+    }
+    
+    #[test]
+    fn layer_paths()
+    {
+        let closer = TestLayer { name : "</Layer group>".to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() };
+        let opener = |name : &str| TestLayer { name : name.to_string(), extra : vec!((*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() };
+        let layer = |name : &str| TestLayer { name : name.to_string(), ..Default::default() };
+        let mut psd = TestPsd::default();
+        psd.layers = vec!(
+            layer("Background"),
+            closer.clone(),
+            closer.clone(),
+            layer("Blush"),
+            opener("Face"),
+            layer("Hair"),
+            opener("Character"),
+            layer("Above"),
+        );
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let paths = |layers : &[LayerInfo]| iter_with_paths(layers).map(|(path, layer)| format!("{}/{}", path.join("/"), layer.name)).collect::<Vec<_>>();
+        assert_eq!(paths(&layers), ["/Background", "Character/Face/Blush", "Character/Face", "Character/Hair", "/Character", "/Above"]);
+        
+        // Unmatched closers and openers don't count as groups.
+        assert_eq!(paths(&layers[2..]), ["Face/Blush", "/Face", "/Hair", "/Character", "/Above"]);
+        assert_eq!(paths(&layers[..4]), ["/Background", "/Blush"]);
+    }
+    
+    #[test]
+    fn effective_visibility()
+    {
+        let closer = TestLayer { extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() };
+        let opener = |opacity : u8, flags : u8| TestLayer { opacity, flags, extra : vec!((*b"lsct", vec!(0, 0, 0, 1))), ..Default::default() };
+        let layer = |opacity : u8, flags : u8, clipping : u8| TestLayer { opacity, flags, clipping, ..Default::default() };
+        let mut psd = TestPsd::default();
+        psd.layers = vec!(
+            layer(255, 0, 0), // 0: background
+            closer.clone(), // 1: closes "outer"
+            layer(255, 0, 1), // 2: clipped, but there's nothing to clip to in this group
+            layer(255, 2, 0), // 3: hidden base
+            layer(255, 0, 1), // 4: clipped to the hidden base
+            closer.clone(), // 5: closes "inner"
+            layer(102, 0, 0), // 6
+            opener(51, 0), // 7: "inner", at 20%
+            layer(255, 0, 1), // 8: clipped to "inner"
+            opener(102, 0), // 9: "outer", at 40%
+            closer.clone(), // 10: closes "hidden"
+            layer(255, 0, 0), // 11
+            opener(255, 2), // 12: "hidden"
+            layer(255, 0, 1), // 13: clipped to "hidden"
+            layer(51, 0, 0), // 14
+        );
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        let ret = compute_effective_visibility(&layers);
+        
+        let visible : Vec<bool> = ret.iter().map(|x| x.visible).collect();
+        assert_eq!(visible, [true, true, true, false, false, true, true, true, true, true, false, false, false, false, true]);
+        let opacity : Vec<u8> = ret.iter().map(|x| (x.opacity * 100.0).round() as u8).collect();
+        assert_eq!(opacity, [100, 40, 40, 40, 40, 8, 3, 8, 8, 40, 100, 100, 100, 100, 20]);
+        let clip_base : Vec<Option<usize>> = ret.iter().map(|x| x.clip_base).collect();
+        assert_eq!(clip_base, [None, None, None, None, Some(3), None, None, None, Some(7), None, None, None, None, Some(12), None]);
+        assert!(compute_effective_visibility(&[]).is_empty());
+    }
+    
+    #[test]
+    fn presized_planar_channels()
+    {
+        // Two rows of packbits: a literal run of 3, then a repeat run of 3.
+        let mut rle = vec!(0, 1);
+        push_u16(&mut rle, 4);
+        push_u16(&mut rle, 2);
+        rle.extend_from_slice(&[2, 10, 20, 30]);
+        rle.extend_from_slice(&[0xFE, 40]);
+        
+        let mut psd = TestPsd::default();
+        psd.color_mode = 4;
+        psd.channel_count = 4;
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 2, 3),
+            channels : vec!((3, rle.clone()), (-2, rle.clone())),
+            mask : mask_record((0, 0, 2, 3), 0, 0),
+            ..Default::default()
+        });
+        // Declared mask rectangle is smaller than the data. The rest is cut off.
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 2, 3),
+            channels : vec!((-2, raw_channel(&[1, 2, 3, 4, 5, 6]))),
+            mask : mask_record((0, 0, 1, 3), 0, 0),
+            ..Default::default()
+        });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].image_data_k, [10, 20, 30, 40, 40, 40]);
+        assert_eq!(layers[0].image_data_mask, [10, 20, 30, 40, 40, 40]);
+        assert_eq!(layers[1].image_data_mask, [1, 2, 3]);
+    }
+    
+    #[test]
+    fn take_pixels()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 2),
+            channels : vec!((0, raw_channel(&[1, 2])), (-2, raw_channel(&[3, 4]))),
+            mask : mask_record((0, 0, 1, 2), 255, 0),
+            name : "Pixels".to_string(),
+            ..Default::default()
+        });
+        let mut layers = parse_layer_records(&psd.build()).unwrap();
+        let before = format!("{:?}", layers[0].metadata_view());
+        
+        let pixels = layers[0].take_pixels();
+        assert_eq!(pixels.image_data_rgba, [1, 255, 255, 255, 2, 255, 255, 255]);
+        assert_eq!(pixels.image_data_mask, [3, 4]);
+        assert!(layers[0].image_data_rgba.is_empty());
+        assert!(layers[0].image_data_mask.is_empty());
+        assert_eq!(layers[0].name, "Pixels");
+        assert_eq!((layers[0].w, layers[0].h, layers[0].mask_info.default_color), (2, 1, 255));
+        assert_eq!(format!("{:?}", layers[0].metadata_view()), before);
+    }
+    
+    #[test]
+    fn sixteen_bit()
+    {
+        // One row, two samples: 0x80FF and 0xFFFF, with the repeat run splitting the first sample.
+        let mut rle = vec!(0, 1);
+        push_u16(&mut rle, 4);
+        rle.extend_from_slice(&[0, 0x80, 0xFE, 0xFF]);
+        
+        let mut layers = TestPsd::default();
+        layers.depth = 16;
+        layers.layers.push(TestLayer {
+            rect : (0, 0, 1, 2),
+            channels : vec!((0, rle), (-2, raw_channel(&[0, 0, 0xFF, 0xFF]))),
+            mask : mask_record((0, 0, 1, 2), 0, 0),
+            ..Default::default()
+        });
+        
+        let mut psd = layers.clone();
+        psd.layers = vec!();
+        psd.tail.extend_from_slice(b"8BIMLr16");
+        let info = layers.layer_info();
+        push_u32(&mut psd.tail, info.len() as u32);
+        psd.tail.extend(info);
+        
+        for data in [layers.build(), psd.build()]
+        {
+            assert!(parse_layer_records(&data).is_err());
+            let mut options = ParseOptions::default();
+            options.convert_16_bit = true;
+            let layers = parse_layer_records_opts(&data, &options).unwrap();
+            assert_eq!(layers.len(), 1);
+            assert_eq!(layers[0].image_data_rgba, [128, 255, 255, 255, 255, 255, 255, 255]);
+            assert_eq!(layers[0].image_data_mask, [0, 255]);
+        }
+    }
+    
+    #[cfg(feature = "zip_support")]
+    fn zip_channel(mode : u16, data : &[u8]) -> Vec<u8>
+    {
+        let mut out = vec!();
+        push_u16(&mut out, mode);
+        out.extend(miniz_oxide::deflate::compress_to_vec_zlib(data, 6));
+        out
+    }
+    
+    #[test]
+    #[cfg(feature = "zip_support")]
+    fn zip_compression()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 2, 2),
+            // Rows of deltas: [10, +5], [200, +100 (wraps)]
+            channels : vec!((0, zip_channel(3, &[10, 5, 200, 100])), (1, zip_channel(2, &[1, 2, 3, 4])), (-2, zip_channel(2, &[7, 8, 9, 10]))),
+            mask : mask_record((0, 0, 2, 2), 0, 0),
+            ..Default::default()
+        });
+        let layers = parse_layer_records(&psd.build()).unwrap();
+        assert_eq!(layers[0].image_data_rgba, [10, 1, 255, 255, 15, 2, 255, 255, 200, 3, 255, 255, 44, 4, 255, 255]);
+        assert_eq!(layers[0].image_data_mask, [7, 8, 9, 10]);
+        
+        let mut psd = TestPsd::default();
+        psd.depth = 16;
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 2),
+            // Deltas of whole 16-bit samples: 0x00FF, then +0x0001 carries into the high byte.
+            channels : vec!((0, zip_channel(3, &[0x00, 0xFF, 0x00, 0x01]))),
+            ..Default::default()
+        });
+        let mut options = ParseOptions::default();
+        options.convert_16_bit = true;
+        let layers = parse_layer_records_opts(&psd.build(), &options).unwrap();
+        assert_eq!(layers[0].image_data_rgba[0], 1);
+        assert_eq!(layers[0].image_data_rgba[4], 1);
+    }
+    
+    #[test]
+    fn indexed_color()
+    {
+        let mut psd = TestPsd::default();
+        psd.color_mode = 2;
+        psd.channel_count = 1;
+        psd.color_data = vec![0; 768];
+        psd.color_data[1] = 10;
+        psd.color_data[256 + 1] = 20;
+        psd.color_data[512 + 1] = 30;
+        psd.layers.push(TestLayer { rect : (0, 0, 1, 2), channels : vec!((0, raw_channel(&[1, 0]))), ..Default::default() });
+        let data = psd.build();
+        
+        let metadata = parse_psd_metadata(&data).unwrap();
+        let palette = metadata.palette.unwrap();
+        assert_eq!(palette.len(), 256);
+        assert_eq!(palette[1], [10, 20, 30]);
+        
+        let layers = parse_layer_records(&data).unwrap();
+        assert_eq!(layers[0].image_data_rgba[..], [1, 255, 255, 255, 0, 255, 255, 255]);
+        
+        let mut options = ParseOptions::default();
+        options.expand_indexed = true;
+        let layers = parse_layer_records_opts(&data, &options).unwrap();
+        assert_eq!(layers[0].image_data_rgba[..], [10, 20, 30, 255, 0, 0, 0, 255]);
+        assert!(layers[0].image_data_has_g && layers[0].image_data_has_b);
+    }
+    
+    #[test]
+    fn header_only_metadata()
+    {
+        let data = TestPsd::default().build();
+        let metadata = parse_psd_metadata(&data[..26]).unwrap();
+        assert_eq!((metadata.width, metadata.height, metadata.color_mode, metadata.palette), (4, 4, 3, None));
+        // Indexed color documents need their palette.
+        let data = TestPsd { color_mode : 2, color_data : vec![0; 768], ..Default::default() }.build();
+        assert!(parse_psd_metadata(&data[..26]).is_err());
+        assert!(parse_psd_metadata(&data[..26 + 4 + 768]).unwrap().palette.is_some());
+    }
+    
+    #[test]
+    fn merged_image()
+    {
+        let mut psd = TestPsd::default();
+        psd.width = 2;
+        psd.height = 1;
+        psd.channel_count = 4;
+        // RLE, one row per channel.
+        let mut merged = vec!(0, 1);
+        for len in [2, 2, 2, 3]
+        {
+            push_u16(&mut merged, len);
+        }
+        merged.extend_from_slice(&[0xFF, 1, 0xFF, 2, 0xFF, 3, 1, 4, 5]);
+        psd.merged = Some(merged);
+        let data = psd.build();
+        let metadata = parse_psd_metadata(&data).unwrap();
+        assert_eq!(parse_merged_image(&data, &metadata).unwrap(), [1, 2, 3, 4, 1, 2, 3, 5]);
+        // 8 bytes of channel data, then 8 bytes of RGBA.
+        let parse = |limits : ParseLimits| parse_merged_image_opts(&data, &metadata, &ParseOptions { limits, ..Default::default() });
+        assert!(parse(ParseLimits { max_layer_pixels : Some(2), max_total_bytes : Some(16), ..Default::default() }).is_ok());
+        assert_eq!(parse(ParseLimits { max_layer_pixels : Some(1), ..Default::default() }), Err(PsdError::LimitExceeded("max_layer_pixels")));
+        assert_eq!(parse(ParseLimits { max_total_bytes : Some(15), ..Default::default() }), Err(PsdError::LimitExceeded("max_total_bytes")));
         
-        //println!("--- {:X}", cursor.position());
+        let mut psd = TestPsd::default();
+        psd.width = 1;
+        psd.height = 2;
+        psd.color_mode = 4;
+        psd.channel_count = 4;
+        psd.merged = Some(raw_channel(&[1, 2, 3, 4, 5, 6, 7, 8]));
+        let data = psd.build();
+        let metadata = parse_psd_metadata(&data).unwrap();
+        assert_eq!(parse_merged_image(&data, &metadata).unwrap(), [1, 3, 5, 255, 2, 4, 6, 255, 7, 8]);
         
-        while cursor.position() < exdat_start + exdat_len
+        let real = std::fs::read("data/test.psd").expect("Failed to open test.psd");
+        let metadata = parse_psd_metadata(&real).unwrap();
+        let merged = parse_merged_image(&real, &metadata).unwrap();
+        assert_eq!(merged.len(), (metadata.width * metadata.height * 4) as usize);
+    }
+    
+    #[test]
+    fn structured_errors()
+    {
+        let mut psd = TestPsd::default();
+        let mut channel = raw_channel(&[1; 16]);
+        channel[1] = 7;
+        psd.layers.push(TestLayer { rect : (0, 0, 4, 4), channels : vec!((0, channel)), ..Default::default() });
+        let data = psd.build();
+        
+        let Err((layers, err)) = parse_layer_records(&data) else { panic!() };
+        assert!(layers.is_empty());
+        let PsdError::UnsupportedCompression { mode : 7, offset } = err else { panic!("{:?}", err) };
+        assert_eq!(data[offset as usize..offset as usize + 2], [0, 7]);
+        assert_eq!(err.offset(), Some(offset));
+        
+        assert_eq!(parse_psd_metadata(&data[..10]).unwrap_err(), PsdError::UnexpectedEof { offset : 12 });
+        
+        let mut bad = data.clone();
+        bad[0] = b'X';
+        let err = parse_psd_metadata(&bad).unwrap_err();
+        assert_eq!(err, PsdError::BadSignature { what : "PSD", found : *b"XBPS", offset : 0 });
+        let text : String = err.into();
+        assert!(text.contains("XBPS"));
+    }
+    
+    #[test]
+    fn fuzz_regressions()
+    {
+        // Inputs that the fuzz target found crashes or runaway allocations with. They only need to not panic.
+        for entry in std::fs::read_dir("fuzz/regressions/parse").expect("Failed to open fuzz regressions")
         {
-            let sig = read_b4(&mut cursor)?;
-            if sig != [0x38, 0x42, 0x49, 0x4D]
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+            // Same limits as the fuzz target.
+            let limits = ParseLimits { max_layer_pixels : Some(1 << 24), max_total_bytes : Some(1 << 26), ..Default::default() };
+            let limited = ParseOptions { limits, ..Default::default() };
+            if let Ok(metadata) = parse_psd_metadata(&data)
             {
-                return Err("Invalid extended data magic signature".to_string());
+                let _ = parse_merged_image_opts(&data, &metadata, &limited);
             }
-            
-            let name = read_b4(&mut cursor)?;
-            let name = String::from_utf8_lossy(&name).to_string();
-            
-            let len = read_u32(&mut cursor)? as u64;
-            //println!("?? {}", len);
-            let start = cursor.position();
-            
-            #[cfg(feature = "debug_spew")]
-            println!("reading metadata.... {}", name.as_str());
-            
-            fn read_descriptor(c : &mut SliceCursor) -> Result<Descriptor, String>
+            let _ = parse_layer_records_opts(&data, &limited);
+            let mut options = limited.clone();
+            options.convert_16_bit = true;
+            options.expand_indexed = true;
+            if let Ok(layers) = parse_layer_records_opts(&data, &options)
             {
-                // skip name. usually/often blank
-                let n = read_u32(c)? as u64;
-                c.set_position(c.position() + n * 2);
-                
-                let mut idlen = read_u32(c)?;
-                if idlen == 0 { idlen = 4; }
-                let mut id = vec![0; idlen as usize];
-                c.read_exact(&mut id).map_err(|x| x.to_string())?;
-                let id = String::from_utf8_lossy(&id).to_string();
-                
-                let mut data = vec!();
-                
-                let itemcount = read_u32(c)?;
-                
-                for _ in 0..itemcount
+                let _ = compute_layer_bounds(&layers, true, None);
+                for layer in &layers
                 {
-                    let mut namelen = read_u32(c)?;
-                    if namelen == 0 { namelen = 4; }
-                    let mut name = vec![0; namelen as usize];
-                    c.read_exact(&mut name).map_err(|x| x.to_string())?;
-                    let name = String::from_utf8_lossy(&name).to_string();
-                    
-                    fn read_key(c : &mut SliceCursor) -> Result<DescItem, String>
-                    {
-                        let id = read_b4(c)?;
-                        let id = String::from_utf8_lossy(&id).to_string();
-                        
-                        Ok(match id.as_str()
-                        {
-                            "long" => DescItem::long(read_i32(c)?),
-                            "doub" => DescItem::doub(read_f64(c)?),
-                            "Objc" => DescItem::Objc(Box::new(read_descriptor(c)?)),
-                            "bool" => DescItem::bool(read_u8(c)? != 0),
-                            "TEXT" =>
-                            {
-                                let len = read_u32(c)? as u64;
-                                let mut text = vec![0; len as usize];
-                                for i in 0..len
-                                {
-                                    text[i as usize] = read_u16(c)?;
-                                }
-                                let text = String::from_utf16_lossy(&text).trim_end_matches('\0').to_string();
-                                DescItem::TEXT(text)
-                            }
-                            "UntF" =>
-                            {
-                                let typ = read_b4(c)?;
-                                let typ = String::from_utf8_lossy(&typ).to_string();
-                                
-                                DescItem::UntF(typ, read_f64(c)?)
-                            }
-                            "enum" =>
-                            {
-                                let mut len = read_u32(c)?;
-                                if len == 0 { len = 4; }
-                                let mut name1 = vec![0; len as usize];
-                                c.read_exact(&mut name1).map_err(|x| x.to_string())?;
-                                let name1 = String::from_utf8_lossy(&name1).to_string();
-                                
-                                let mut len = read_u32(c)?;
-                                if len == 0 { len = 4; }
-                                let mut name2 = vec![0; len as usize];
-                                c.read_exact(&mut name2).map_err(|x| x.to_string())?;
-                                let name2 = String::from_utf8_lossy(&name2).to_string();
-                                
-                                DescItem::_enum(name1, name2)
-                            }
-                            "VlLs" =>
-                            {
-                                let len = read_u32(c)?;
-                                let mut ret = vec!();
-                                for _ in 0..len
-                                {
-                                    ret.push(read_key(c)?);
-                                }
-                                DescItem::VlLs(ret)
-                            }
-                            _ =>
-                            {
-                                #[cfg(feature = "debug_spew")]
-                                println!("!!! errant descriptor subobject type... {}", id);
-                                DescItem::Err(format!("!!! errant descriptor subobject type... {}", id))
-                            }
-                        })
-                    }
-                    
-                    data.push((name, read_key(c)?));
+                    let _ = layer.adjustment();
                 }
-                
-                Ok((id, data))
             }
-            
-            // This comment must stay here: it is a ctrl+f anchor.
-            // Read adjustment data.
-            match name.as_str()
+            let _ = parse_layer_section_flags(&data);
+            let _ = parse_document_blocks(&data);
+            let _ = parse_image_resources(&data);
+            let _ = parse_patterns_opts(&data, &limited);
+            let _ = parse_linked_files(&data);
+            let _ = parse_annotations(&data);
+            let _ = parse_engine_data(&data);
+        }
+        
+        // Absurd dimensions fail instead of overflowing.
+        assert!(pixel_buffer(u32::MAX as u64, u32::MAX as u64, 4, 0).is_err());
+        assert_eq!(rect_size(0, 10, 0, 0), (0, 0));
+    }
+    
+    #[test]
+    fn parse_limits()
+    {
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer::default());
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 2, 2),
+            channels : vec!((0, raw_channel(&[1, 2, 3, 4])), (-2, raw_channel(&[5, 6, 7, 8]))),
+            mask : mask_record((0, 0, 2, 2), 0, 0),
+            ..Default::default()
+        });
+        let data = psd.build();
+        let parse = |limits : ParseLimits| parse_layer_records_opts(&data, &ParseOptions { limits, ..Default::default() });
+        assert_eq!(parse(ParseLimits::default()).unwrap().len(), 2);
+        
+        assert!(parse(ParseLimits { max_layer_count : Some(2), ..Default::default() }).is_ok());
+        assert_eq!(parse(ParseLimits { max_layer_count : Some(1), ..Default::default() }).unwrap_err().1, PsdError::LimitExceeded("max_layer_count"));
+        assert!(parse(ParseLimits { max_layer_pixels : Some(4), ..Default::default() }).is_ok());
+        assert_eq!(parse(ParseLimits { max_layer_pixels : Some(3), ..Default::default() }).unwrap_err().1, PsdError::LimitExceeded("max_layer_pixels"));
+        // 16 bytes of RGBA and 4 bytes of mask.
+        assert!(parse(ParseLimits { max_total_bytes : Some(20), ..Default::default() }).is_ok());
+        assert_eq!(parse(ParseLimits { max_total_bytes : Some(19), ..Default::default() }).unwrap_err().1, PsdError::LimitExceeded("max_total_bytes"));
+        
+        let mut lfx2 = vec!();
+        push_u32(&mut lfx2, 0);
+        push_u32(&mut lfx2, 16);
+        lfx2.extend(desc("null", &[("a", desc_objc("null", &[("b", desc_objc("null", &[]))]))]));
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { extra : vec!((*b"lfx2", lfx2)), ..Default::default() });
+        let data = psd.build();
+        let parse = |limits : ParseLimits| parse_layer_records_opts(&data, &ParseOptions { limits, ..Default::default() });
+        assert!(parse(ParseLimits { max_descriptor_depth : Some(2), ..Default::default() }).is_ok());
+        assert_eq!(parse(ParseLimits { max_descriptor_depth : Some(1), ..Default::default() }).unwrap_err().1, PsdError::LimitExceeded("max_descriptor_depth"));
+    }
+    
+    #[test]
+    #[cfg(feature = "zip_support")]
+    fn zip_bomb_limit()
+    {
+        // A megabyte of zeroes compresses down to about a kilobyte, but the mask is declared as a single pixel.
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer {
+            rect : (0, 0, 1, 1),
+            channels : vec!((0, raw_channel(&[1])), (-2, zip_channel(2, &vec![0; 1 << 20]))),
+            mask : mask_record((0, 0, 1, 1), 0, 0),
+            ..Default::default()
+        });
+        let data = psd.build();
+        assert_eq!(parse_layer_records(&data).unwrap()[0].image_data_mask, [0]);
+        let options = ParseOptions { limits : ParseLimits { max_total_bytes : Some(1 << 16), ..Default::default() }, ..Default::default() };
+        assert_eq!(parse_layer_records_opts(&data, &options).unwrap_err().1, PsdError::LimitExceeded("max_total_bytes"));
+    }
+    
+    #[test]
+    fn write_round_trip()
+    {
+        // The parts of a layer that aren't expected to survive writing: where its channels were, and the blocks that get written from its fields.
+        fn normalize(layers : &mut [LayerInfo])
+        {
+            for layer in layers
             {
-                "lsct" =>
-                {
-                    let kind = read_u32(&mut cursor)? as u64;
-                    layer.group_expanded = kind == 1;
-                    layer.group_opener = kind == 1 || kind == 2;
-                    layer.group_closer = kind == 3;
-                    if kind == 1 || kind == 2
-                    {
-                        #[cfg(feature = "debug_spew")]
-                        println!("group opener!");
-                    }
-                    if kind == 3
-                    {
-                        #[cfg(feature = "debug_spew")]
-                        println!("group closer!");
-                    }
-                }
-                "luni" =>
-                {
-                    let len = read_u32(&mut cursor)? as u64;
-                    let mut name = vec![0; len as usize];
-                    for i in 0..len
-                    {
-                        name[i as usize] = read_u16(&mut cursor)?;
-                    }
-                    layer.name = String::from_utf16_lossy(&name).to_string();
-                }
-                "tsly" =>
-                {
-                    let thing = read_u8(&mut cursor)?;
-                    layer.funny_flag = thing == 0;
-                    #[cfg(feature = "debug_spew")]
-                    println!("{}", layer.funny_flag);
-                }
-                "iOpa" =>
-                {
-                    layer.fill_opacity = read_u8(&mut cursor)? as f32 / 255.0;
-                }
-                "lfx2" =>
-                {
-                    if read_u32(&mut cursor)? == 0 && read_u32(&mut cursor)? == 16
-                    {
-                        layer.effects_desc = Some(read_descriptor(&mut cursor)?);
-                    }
-                    else
-                    {
-                        read_descriptor(&mut cursor)?;
-                    }
-                }
-                // adjustment layers
-                "post" =>
-                {
-                    let mut data = vec!();
-                    data.push(read_u16(&mut cursor)? as f32); // number of levels
-                    layer.adjustment_type = name.clone();
-                    layer.adjustment_info = data;
-                }
-                "nvrt" =>
-                {
-                    layer.adjustment_type = name.clone();
-                    layer.adjustment_info = vec!();
-                }
-                "brit" =>
-                {
-                    let mut data = vec!();
-                    data.push(read_u16(&mut cursor)? as f32); // brightness
-                    data.push(read_u16(&mut cursor)? as f32); // contrast
-                    data.push(read_u16(&mut cursor)? as f32); // "Mean value for brightness and contrast"
-                    data.push(read_u8(&mut cursor)? as f32); // "Lab color only"
-                    data.push(1.0); // legacy mode
-                    layer.adjustment_type = name.clone();
-                    layer.adjustment_info = data;
-                }
-                "thrs" =>
-                {
-                    let mut data = vec!();
-                    data.push(read_u16(&mut cursor)? as f32);
-                    layer.adjustment_type = name.clone();
-                    layer.adjustment_info = data;
-                }
-                "hue2" =>
-                {
-                    let mut data = vec!();
-                    
-                    read_u16(&mut cursor)?; // version
-                    data.push(read_u8(&mut cursor)? as f32); // if 1, is absolute/colorization (rather than relative)
-                    read_u8(&mut cursor)?;
-                    
-                    // "colorization"
-                    data.push(read_u16(&mut cursor)? as i16 as f32); // hue
-                    data.push(read_u16(&mut cursor)? as i16 as f32); // sat
-                    data.push(read_u16(&mut cursor)? as i16 as f32); // lightness (-1 to +1)
-                    
-                    // "master"
-                    data.push(read_u16(&mut cursor)? as i16 as f32); // hue
-                    data.push(read_u16(&mut cursor)? as i16 as f32); // sat
-                    data.push(read_u16(&mut cursor)? as i16 as f32); // lightness (-1 to +1)
-                    
-                    // todo: read hextant values?
-                    
-                    layer.adjustment_type = name.clone();
-                    layer.adjustment_info = data;
-                }
-                "levl" =>
-                {
-                    let mut data = vec!();
-                    
-                    if read_u16(&mut cursor)? != 2
-                    {
-                        return Err("Ran into an unsupported subdata version".to_string());
-                    }
-                    for _ in 0..28
-                    {
-                        data.push(read_u16(&mut cursor)? as f32 / 255.0); // in floor
-                        data.push(read_u16(&mut cursor)? as f32 / 255.0); // in ceil
-                        data.push(read_u16(&mut cursor)? as f32 / 255.0); // out floor
-                        data.push(read_u16(&mut cursor)? as f32 / 255.0); // out ceil
-                        data.push(read_u16(&mut cursor)? as f32 / 100.0); // gamma
-                    }
-                    layer.adjustment_type = name.clone();
-                    layer.adjustment_info = data;
-                }
-                "curv" =>
-                {
-                    let mut data = vec!();
-                    
-                    read_u8(&mut cursor)?;
-                    if read_u16(&mut cursor)? != 1
-                    {
-                        return Err("Ran into an unsupported subdata version".to_string());
-                    }
-                    let enabled = read_u32(&mut cursor)?;
-                    
-                    for i in 0..32
-                    {
-                        if (enabled & (1u32 << i)) != 0
-                        {
-                            let n = read_u16(&mut cursor)?;
-                            data.push(n as f32); // number of points
-                            for _ in 0..n
-                            {
-                                let y = read_u16(&mut cursor)? as f32 / 255.0;
-                                data.push(read_u16(&mut cursor)? as f32 / 255.0); // x
-                                data.push(y); // y
-                            }
-                        }
-                        else
-                        {
-                            data.push(0.0); // number of points
-                        }
-                    }
-                    layer.adjustment_type = name.clone();
-                    layer.adjustment_info = data;
-                }
-                "blwh" =>
-                {
-                    if read_u32(&mut cursor)? != 16
-                    {
-                        return Err("Ran into an unsupported subdata version".to_string());
-                    }
-                    layer.adjustment_type = name.clone();
-                    layer.adjustment_desc = Some(read_descriptor(&mut cursor)?);
-                }
-                "CgEd" =>
-                {
-                    if read_u32(&mut cursor)? != 16
-                    {
-                        return Err("Ran into an unsupported subdata version".to_string());
-                    }
-                    //layer.adjustment_type = name.clone();
-                    //layer.adjustment_type = "brit".to_string();
-                    let temp = read_descriptor(&mut cursor)?.1;
-                    #[cfg(feature = "debug_spew")]
-                    println!("{:?}", temp);
-                    let mut n = BTreeMap::new();
-                    for t in temp
-                    {
-                        n.insert(t.0, t.1);
-                    }
-                    #[cfg(feature = "debug_spew")]
-                    println!("{:?}", n);
-                    //("null", [("Vrsn", long(1)), ("Brgh", long(9)), ("Cntr", long(30)), ("means", long(127)), ("Lab ", bool(false)), ("useLegacy", bool(true)), ("Auto", bool(true))])
-                    let mut data = vec!();
-                    data.push(n.get("Brgh").ok_or("Malformed data structure".to_string())?.long() as f32);
-                    data.push(n.get("Cntr").ok_or("Malformed data structure".to_string())?.long() as f32);
-                    data.push(n.get("means").ok_or("Malformed data structure".to_string())?.long() as f32);
-                    data.push(n.get("Lab ").ok_or("Malformed data structure".to_string())?.bool() as u8 as f32);
-                    data.push(n.get("useLegacy").ok_or("Malformed data structure".to_string())?.bool() as u8 as f32);
-                    #[cfg(feature = "debug_spew")]
-                    println!("??????????? {:?}", data);
-                    layer.adjustment_info = data;
-                }
-                _ => {}
+                layer.channel_spans.clear();
+                layer.extra_blocks.retain(|(key, _)| !write::GENERATED_LAYER_BLOCKS.contains(&key.as_str()));
             }
-            cursor.set_position(start + len);
         }
-        //println!("{:X} {:X}", cursor.position(), exdat_start + exdat_len);
-        if cursor.position() != exdat_start + exdat_len
+        let options = ParseOptions { keep_unknown_blocks : true, keep_known_blocks : true, ..Default::default() };
+        for path in ["data/test.psd", "data/test2.psd"]
         {
-            return Err("Desynchronized while reading or skipping extra data".to_string());
+            let data = std::fs::read(path).unwrap();
+            let metadata = parse_psd_metadata(&data).unwrap();
+            let mut layers = parse_layer_records_opts(&data, &options).unwrap();
+            let mut out = vec!();
+            write_psd(&metadata, &layers, &mut out).unwrap();
+            
+            assert_eq!(parse_psd_metadata(&out).unwrap(), PsdMetadata { depth : 8, ..metadata });
+            let mut back = parse_layer_records_opts(&out, &options).unwrap();
+            normalize(&mut layers);
+            normalize(&mut back);
+            assert_eq!(format!("{:?}", back), format!("{:?}", layers));
         }
-        
-        #[cfg(feature = "debug_spew")]
-        println!("added layer with name {}", layer.name);
-        layers.push(layer);
     }
     
-    Ok(())
-}
-
-#[non_exhaustive]
-#[derive(Debug, PartialEq)]
-/// File-wide PSD header metadata.
-///
-/// Returned from [parse_psd_metadata].
-pub struct PsdMetadata {
-    /// Canvas width in pixels.
-    pub width: u32,
-    /// Canvas height in pixels.
-    pub height: u32,
-    /// PSD-wide color mode constant. See <https://www.adobe.com/devnet-apps/photoshop/fileformatashtml/#50577409_pgfId-1055726>
-    pub color_mode: u16,
-    /// Color depth in bytes. Only 8-bit (1-byte) images are currently supported.
-    pub depth: u16,
-    /// Number of channels in the PSD file's colorspace, including alpha. Only Y/YA, RGB/RGBA, and CMYK/CMYKA images are currently supported.
-    pub channel_count: u16,
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
     #[test]
-    fn test()
+    fn write_layers()
     {
-        let data = std::fs::read("data/test.psd").expect("Failed to open test.psd");
-
-        if let Ok(layers) = parse_layer_records(&data)
+        let channels = |ids : &[i16], len : u8| ids.iter().map(|id| (*id, raw_channel(&(0..len).map(|x| (x * 16).wrapping_add(*id as u8)).collect::<Vec<_>>()))).collect::<Vec<_>>();
+        // A user mask with a feather parameter, then a real user mask.
+        let mut mask = mask_record((1, 1, 2, 3), 0, 16 | 1);
+        mask.truncate(18);
+        mask.push(0b0010);
+        mask.extend_from_slice(&f64::to_be_bytes(2.5));
+        mask.extend_from_slice(&[4, 255]);
+        for x in [0, 0, 2, 2]
         {
-            for mut layer in layers
-            {
-                // Don't spew tons of image data bytes to stdout; we just want to see the metadata.
-                layer.image_data_rgba = vec!();
-                layer.image_data_k = vec!();
-                layer.image_data_mask = vec!();
-                println!("{:?}", layer);
-            }
+            push_i32(&mut mask, x);
         }
+        let mut lsct = vec!(0, 0, 0, 2);
+        lsct.extend_from_slice(b"8BIMmul ");
+        push_u32(&mut lsct, 1);
         
-        println!("-----");
+        let mut psd = TestPsd::default();
+        psd.layers.push(TestLayer { name : "</Layer group>".to_string(), channels : channels(&[-1, 0, 1, 2], 0), extra : vec!((*b"lsct", vec!(0, 0, 0, 3))), ..Default::default() });
+        psd.layers.push(TestLayer {
+            name : "Masked".to_string(),
+            rect : (1, 1, 3, 3),
+            channels : [channels(&[-1, 0, 1, 2], 4), vec!((-3, raw_channel(&[5, 6, 7, 8])), (-2, raw_channel(&[9, 10])))].concat(),
+            opacity : 128,
+            clipping : 1,
+            // Hidden and alpha locked, like 'lspf' says.
+            flags : 3,
+            mask,
+            extra : vec!(
+                (*b"lyid", vec!(0, 0, 0, 7)),
+                (*b"iOpa", vec!(51, 0, 0, 0)),
+                (*b"lclr", vec!(0, 2, 0, 0, 0, 0, 0, 0)),
+                (*b"lspf", vec!(0x80, 0, 0, 5)),
+                (*b"zzzz", vec!(1, 2, 3, 4)),
+            ),
+            ..Default::default()
+        });
+        psd.layers.push(TestLayer { name : "Group".to_string(), channels : channels(&[-1, 0, 1, 2], 0), blend_mode : *b"pass", extra : vec!((*b"lsct", lsct)), ..Default::default() });
+        let data = psd.build();
         
-        let data = std::fs::read("data/test2.psd").expect("Failed to open test2.psd");
-
-        if let Ok(layers) = parse_layer_records(&data)
+        let options = ParseOptions { keep_unknown_blocks : true, ..Default::default() };
+        let metadata = parse_psd_metadata(&data).unwrap();
+        let mut layers = parse_layer_records_opts(&data, &options).unwrap();
+        let mut out = vec!();
+        write_psd(&metadata, &layers, &mut out).unwrap();
+        let mut back = parse_layer_records_opts(&out, &options).unwrap();
+        for layer in layers.iter_mut().chain(back.iter_mut())
         {
-            for mut layer in layers
-            {
-                layer.image_data_rgba = vec!();
-                layer.image_data_k = vec!();
-                layer.image_data_mask = vec!();
-                println!("{:?}", layer);
-            }
+            layer.channel_spans.clear();
+        }
+        assert_eq!(format!("{:?}", back), format!("{:?}", layers));
+        assert_eq!((back[1].mask_info.user_feather, back[1].image_data_real_mask.as_slice()), (Some(2.5), &[5, 6, 7, 8][..]));
+        assert_eq!((back[1].layer_id, back[1].sheet_color, back[1].lock_all, back[1].is_visible), (Some(7), 2, true, false));
+        assert_eq!((back[2].group_blend_mode.as_str(), back[2].group_sub_type, back[2].group_expanded), ("mul ", 1, false));
+        assert_eq!(back[1].extra_blocks, [("zzzz".to_string(), vec!(1, 2, 3, 4))]);
+        // The merged image is blank, and flagged as not being real.
+        assert_eq!(parse_merged_image(&out, &metadata).unwrap(), vec![255; 4 * 4 * 4]);
+        assert_eq!(get_image_resource(&out, 1057).unwrap().unwrap()[4], 0);
+        
+        // A feathered mask with all four parameters and no real user mask gets padded out to 40 bytes, and still reads back without one.
+        let mut feathered = layers[1].clone();
+        feathered.real_mask_info = None;
+        feathered.image_data_real_mask.clear();
+        (feathered.image_channel_count, feathered.mask_channel_count) = (5, 1);
+        feathered.mask_info.user_density = Some(200);
+        feathered.mask_info.vector_density = Some(100);
+        feathered.mask_info.vector_feather = Some(4.0);
+        let mut out = vec!();
+        write_psd(&metadata, &[feathered.clone()], &mut out).unwrap();
+        let mut back = parse_layer_records_opts(&out, &options).unwrap();
+        back[0].channel_spans.clear();
+        assert_eq!(format!("{:?}", back[0]), format!("{:?}", feathered));
+        
+        // Every pixel layout writes the same file, K channel included.
+        let psd = TestPsd { color_mode : 4, channel_count : 4, layers : vec!(TestLayer { rect : (0, 0, 2, 2), channels : channels(&[-1, 0, 1, 2, 3], 4), ..Default::default() }), ..Default::default() };
+        let data = psd.build();
+        let metadata = parse_psd_metadata(&data).unwrap();
+        let write = |options : &ParseOptions|
+        {
+            let layers = parse_layer_records_opts(&data, options).unwrap();
+            let mut out = vec!();
+            write_psd(&metadata, &layers, &mut out).unwrap();
+            out
+        };
+        let out = write(&ParseOptions::default());
+        assert_eq!(write(&ParseOptions { planar_channels : true, ..Default::default() }), out);
+        assert_eq!(write(&ParseOptions { shrink_channels : true, ..Default::default() }), out);
+        assert_eq!(parse_layer_records(&out).unwrap()[0].image_data_k, [3, 19, 35, 51]);
+        
+        // Layers without their pixels can't be written, and neither can color modes that rawpsd doesn't support.
+        let layers = parse_layer_records_opts(&data, &ParseOptions { skip_image_data : true, ..Default::default() }).unwrap();
+        assert!(matches!(write_psd(&metadata, &layers, &mut vec!()), Err(PsdError::Malformed { .. })));
+        let lab = parse_psd_metadata(&TestPsd { color_mode : 9, ..Default::default() }.build()).unwrap();
+        assert_eq!(write_psd(&lab, &[], &mut vec!()), Err(PsdError::UnsupportedColorMode(9)));
+        // Neither can documents too big for a PSD file.
+        let huge = PsdMetadata { width : u32::MAX, ..parse_psd_metadata(&data).unwrap() };
+        assert!(matches!(write_psd(&huge, &[], &mut vec!()), Err(PsdError::Malformed { .. })));
+        
+        // The blank merged image decodes right at widths that aren't a multiple of 128 too.
+        for width in [1, 2, 127, 128, 300]
+        {
+            let metadata = PsdMetadata { width, height : 2, ..parse_psd_metadata(&data).unwrap() };
+            let mut out = vec!();
+            write_psd(&metadata, &[], &mut out).unwrap();
+            // CMYK, so the K channel comes after the RGBA pixels.
+            assert_eq!(parse_merged_image(&out, &metadata).unwrap(), vec![255; width as usize * 2 * 5]);
         }
     }
 }
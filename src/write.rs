@@ -0,0 +1,403 @@
+//! Writing layers back out to PSD files. See [write_psd].
+
+use super::*;
+
+/// PackBits-compresses one row of image data, the format that [append_img_data] decompresses.
+fn packbits_row(row : &[u8], out : &mut Vec<u8>)
+{
+    let mut i = 0;
+    while i < row.len()
+    {
+        let run = row[i..].iter().take(128).take_while(|x| **x == row[i]).count();
+        if run >= 3
+        {
+            out.push((1 - run as i32) as u8);
+            out.push(row[i]);
+            i += run;
+        }
+        else
+        {
+            // Literals go until the next run that's worth encoding as a run.
+            let mut end = i;
+            while end < row.len() && end - i < 128 && !(end + 2 < row.len() && row[end] == row[end + 1] && row[end] == row[end + 2])
+            {
+                end += 1;
+            }
+            out.push((end - i - 1) as u8);
+            out.extend_from_slice(&row[i..end]);
+            i = end;
+        }
+    }
+}
+
+fn too_big(what : &str) -> PsdError
+{
+    PsdError::Malformed { message : format!("{} is too big to write to a PSD file", what), offset : None }
+}
+
+/// Writes one channel of a layer's image data, PackBits-compressed, starting with its compression mode. Empty channels are written as uncompressed, with no data, like Photoshop does.
+fn write_channel(out : &mut Vec<u8>, w : u32, h : u32, mut sample : impl FnMut(usize) -> u8) -> Result<(), PsdError>
+{
+    if w == 0 || h == 0
+    {
+        out.extend_from_slice(&0u16.to_be_bytes());
+        return Ok(());
+    }
+    out.extend_from_slice(&1u16.to_be_bytes());
+    // The row lengths come first, so they're filled in as the rows get compressed.
+    let counts = out.len();
+    out.resize(counts + h as usize * 2, 0);
+    let mut row = vec![0; w as usize];
+    for y in 0..h as usize
+    {
+        for (x, c) in row.iter_mut().enumerate()
+        {
+            *c = sample(y * w as usize + x);
+        }
+        let start = out.len();
+        packbits_row(&row, out);
+        let len = u16::try_from(out.len() - start).map_err(|_| too_big("A row of image data"))?;
+        out[counts + y * 2..counts + y * 2 + 2].copy_from_slice(&len.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Fills in a section length that was written as a placeholder at `at`, counting everything written after it.
+fn patch_len(out : &mut [u8], at : usize, what : &str) -> Result<(), PsdError>
+{
+    let len = u32::try_from(out.len() - at - 4).map_err(|_| too_big(what))?;
+    out[at..at + 4].copy_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+/// Writes a layer's additional info block, padding its data out to an even length.
+fn write_layer_block(out : &mut Vec<u8>, key : &[u8], data : &[u8]) -> Result<(), PsdError>
+{
+    out.extend_from_slice(b"8BIM");
+    out.extend_from_slice(key);
+    let len = u32::try_from(data.len().next_multiple_of(2)).map_err(|_| too_big("A layer's additional info block"))?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(data);
+    if !data.len().is_multiple_of(2)
+    {
+        out.push(0);
+    }
+    Ok(())
+}
+
+/// Blocks that [write_psd] makes from a layer's fields, instead of writing kept copies from [LayerInfo::extra_blocks].
+pub (crate) const GENERATED_LAYER_BLOCKS : [&str; 7] = ["luni", "lsct", "lsdk", "lyid", "iOpa", "lclr", "lspf"];
+
+/// Writes one layer's record to `out` and its channels' image data to `channel_data`. `index` is only for error messages.
+fn write_layer_record(out : &mut Vec<u8>, channel_data : &mut Vec<u8>, layer : &LayerInfo, index : usize, color_channels : i16) -> Result<(), PsdError>
+{
+    let malformed = |what : &str| PsdError::Malformed { message : format!("Layer {} {}", index, what), offset : None };
+    let rect = |x : i32, y : i32, w : u32, h : u32|
+    {
+        let bottom = i32::try_from(y as i64 + h as i64).map_err(|_| malformed("is too big to write"))?;
+        let right = i32::try_from(x as i64 + w as i64).map_err(|_| malformed("is too big to write"))?;
+        Ok::<_, PsdError>([y, x, bottom, right])
+    };
+    if layer.w as u64 * layer.h as u64 > 0 && layer.image_data_rgba.is_empty() && layer.image_data_channels.is_empty()
+    {
+        return Err(malformed("has no pixel data to write. It was skipped or taken"));
+    }
+    
+    // Every channel, as its ID and its data, starting with the compression mode.
+    let mut channels = vec!();
+    for id in layer.image_data_has_a.then_some(-1).into_iter().chain(0..color_channels)
+    {
+        let mut data = vec!();
+        write_channel(&mut data, layer.w, layer.h, |i| layer_sample(layer, id, i).unwrap_or(255))?;
+        channels.push((id, data));
+    }
+    
+    let mask = &layer.mask_info;
+    let has_mask = mask.w as u64 * mask.h as u64 > 0 || mask.default_color != 0 || mask.flags_raw != 0 || layer.real_mask_info.is_some();
+    let masks = [(-2, Some(mask), &layer.image_data_mask), (-3, layer.real_mask_info.as_ref(), &layer.image_data_real_mask)];
+    for (id, info, image) in masks.into_iter().filter(|_| has_mask)
+    {
+        let Some(info) = info else { continue };
+        if (image.len() as u64) < info.w as u64 * info.h as u64
+        {
+            return Err(malformed("has no mask data to write. It was skipped or taken"));
+        }
+        let mut data = vec!();
+        write_channel(&mut data, info.w, info.h, |i| image[i])?;
+        channels.push((id, data));
+    }
+    for (id, plane) in &layer.extra_channels
+    {
+        let mut data = vec!();
+        write_channel(&mut data, layer.w, layer.h, |i| plane.get(i).copied().unwrap_or(0))?;
+        channels.push((*id, data));
+    }
+    
+    for x in rect(layer.x, layer.y, layer.w, layer.h)?
+    {
+        out.extend_from_slice(&x.to_be_bytes());
+    }
+    out.extend_from_slice(&(channels.len() as u16).to_be_bytes());
+    for (id, data) in &channels
+    {
+        out.extend_from_slice(&id.to_be_bytes());
+        out.extend_from_slice(&u32::try_from(data.len()).map_err(|_| malformed("has a channel that's too big to write"))?.to_be_bytes());
+        channel_data.extend_from_slice(data);
+    }
+    
+    out.extend_from_slice(b"8BIM");
+    match layer.blend_mode.as_bytes()
+    {
+        [] => out.extend_from_slice(b"norm"),
+        key @ [_, _, _, _] => out.extend_from_slice(key),
+        _ => return Err(malformed(&format!("has a blend mode, '{}', that isn't four bytes long", layer.blend_mode))),
+    }
+    out.push((layer.opacity.clamp(0.0, 1.0) * 255.0 + 0.5) as u8);
+    out.push(layer.is_clipped as u8);
+    out.push((layer.flags_raw & !3) | layer.is_alpha_locked as u8 | ((!layer.is_visible as u8) << 1));
+    out.push(0);
+    
+    let extra_start = out.len();
+    out.extend_from_slice(&0u32.to_be_bytes());
+    
+    // Mask data: the user mask, then the mask parameters, then the real user mask.
+    let mask_start = out.len();
+    out.extend_from_slice(&0u32.to_be_bytes());
+    if has_mask
+    {
+        let params = [mask.user_density.is_some(), mask.user_feather.is_some(), mask.vector_density.is_some(), mask.vector_feather.is_some()];
+        let params = params.iter().enumerate().fold(0u8, |acc, (i, x)| acc | ((*x as u8) << i));
+        let flags = |info : &MaskInfo, params : bool| (info.flags_raw & !0x1F) | info.relative as u8 | ((info.disabled as u8) << 1) | ((info.invert as u8) << 2) | ((info.from_render as u8) << 3) | ((params as u8) << 4);
+        for x in rect(mask.x, mask.y, mask.w, mask.h)?
+        {
+            out.extend_from_slice(&x.to_be_bytes());
+        }
+        out.push(mask.default_color);
+        out.push(flags(mask, params != 0));
+        if params != 0
+        {
+            out.push(params);
+            out.extend(mask.user_density);
+            out.extend(mask.user_feather.map(f64::to_be_bytes).into_iter().flatten());
+            out.extend(mask.vector_density);
+            out.extend(mask.vector_feather.map(f64::to_be_bytes).into_iter().flatten());
+        }
+        if let Some(real) = &layer.real_mask_info
+        {
+            out.push(flags(real, false));
+            out.push(real.default_color);
+            for x in rect(real.x, real.y, real.w, real.h)?
+            {
+                out.extend_from_slice(&x.to_be_bytes());
+            }
+        }
+        while out.len() - mask_start - 4 < 20 || !(out.len() - mask_start).is_multiple_of(4)
+        {
+            out.push(0);
+        }
+    }
+    patch_len(out, mask_start, "A layer's mask data")?;
+    
+    // No blending ranges.
+    out.extend_from_slice(&0u32.to_be_bytes());
+    
+    // Pascal string name, padded so that the length byte and the string together are a multiple of 4 bytes.
+    // The legacy name is written back byte for byte, so that it parses the same. Without one, it's made from the unicode name, with anything that isn't ASCII turned into question marks.
+    let name : Vec<u8> = if layer.name_ascii.is_empty()
+    {
+        layer.name.chars().map(|c| if c.is_ascii() { c as u8 } else { b'?' }).take(255).collect()
+    }
+    else
+    {
+        let mut len = layer.name_ascii.len().min(255);
+        while !layer.name_ascii.is_char_boundary(len)
+        {
+            len -= 1;
+        }
+        layer.name_ascii.as_bytes()[..len].to_vec()
+    };
+    out.push(name.len() as u8);
+    out.extend_from_slice(&name);
+    out.resize(out.len() + (name.len() + 1).next_multiple_of(4) - (name.len() + 1), 0);
+    
+    let mut data = vec!();
+    let name : Vec<u16> = layer.name.encode_utf16().collect();
+    data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    data.extend(name.iter().flat_map(|x| x.to_be_bytes()));
+    write_layer_block(out, b"luni", &data)?;
+    if layer.group_opener || layer.group_closer
+    {
+        let kind : u32 = if layer.group_closer { 3 } else if layer.group_expanded { 1 } else { 2 };
+        let mut data = kind.to_be_bytes().to_vec();
+        if !layer.group_blend_mode.is_empty() || layer.group_sub_type != 0
+        {
+            let key = if layer.group_blend_mode.is_empty() { &layer.blend_mode } else { &layer.group_blend_mode };
+            let key : [u8; 4] = key.as_bytes().try_into().map_err(|_| malformed(&format!("has a group blend mode, '{}', that isn't four bytes long", key)))?;
+            data.extend_from_slice(b"8BIM");
+            data.extend_from_slice(&key);
+            if layer.group_sub_type != 0
+            {
+                data.extend_from_slice(&layer.group_sub_type.to_be_bytes());
+            }
+        }
+        write_layer_block(out, b"lsct", &data)?;
+    }
+    if let Some(id) = layer.layer_id
+    {
+        write_layer_block(out, b"lyid", &id.to_be_bytes())?;
+    }
+    if layer.fill_opacity != 1.0
+    {
+        write_layer_block(out, b"iOpa", &[(layer.fill_opacity.clamp(0.0, 1.0) * 255.0 + 0.5) as u8, 0, 0, 0])?;
+    }
+    if layer.sheet_color != 0
+    {
+        let mut data = layer.sheet_color.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 6]);
+        write_layer_block(out, b"lclr", &data)?;
+    }
+    let locks = layer.is_alpha_locked as u32 | ((layer.lock_composite as u32) << 1) | ((layer.lock_position as u32) << 2) | ((layer.lock_all as u32) << 31);
+    if locks != 0
+    {
+        write_layer_block(out, b"lspf", &locks.to_be_bytes())?;
+    }
+    for (key, data) in layer.extra_blocks.iter().filter(|(key, _)| !GENERATED_LAYER_BLOCKS.contains(&key.as_str()))
+    {
+        if key.len() != 4
+        {
+            return Err(malformed(&format!("has an additional info block key, '{}', that isn't four bytes long", key)));
+        }
+        write_layer_block(out, key.as_bytes(), data)?;
+    }
+    
+    patch_len(out, extra_start, "A layer record")
+}
+
+/// Writes a PSD file with the given header and layers to the end of `out`. The layers go bottom to top, with groups marked by opener and closer records, the same way [parse_layer_records] returns them, so parsing a file and writing it back out gives a file that parses into the same layers.
+///
+/// Each layer's rectangle, name, blend mode, opacity, clipping, flags, color and alpha channels, user mask and real user mask, and [extra channels][LayerInfo::extra_channels] are written, along with these blocks, made from the layer's fields: 'luni' for its name, 'lsct' for group openers and closers, 'lyid' for its ID, 'iOpa' for its fill opacity, 'lclr' for its sheet color, and 'lspf' for its lock flags. Anything else, like effects, text, and adjustments, is only written if it's in [LayerInfo::extra_blocks], which is written back as-is; parse with [ParseOptions::keep_unknown_blocks] and [ParseOptions::keep_known_blocks] to carry everything over. Kept copies of the blocks listed above are left out, so that the layer's fields win.
+///
+/// The pixel data can be in any layout: the default, [shrunk][LayerInfo::shrink_channels], or [planar][ParseOptions::planar_channels]. Every layer gets all of the document's color channels, with missing ones filled with 255, plus an alpha channel if [LayerInfo::image_data_has_a] is set. Everything is written as 8-bit and PackBits-compressed, so 16-bit documents loaded with `ParseOptions::convert_16_bit` are written as 8-bit ones. For indexed color documents, the layers need to have been parsed without [ParseOptions::expand_indexed], so that they still hold palette indexes.
+///
+/// The file has no image resources other than version info (resource 1057) saying that it doesn't have a real merged image, and the merged image is blank white, so readers that respect the flag know to build the image from the layers instead. Readers that only look at the merged image will see a blank document.
+///
+/// Layers whose pixel data or mask data was skipped or taken can't be written, and are an error. So are color modes other than grayscale, indexed, RGB, and CMYK, and documents more than 30000 pixels wide or tall, which only fit in PSB files.
+pub fn write_psd(metadata : &PsdMetadata, layers : &[LayerInfo], out : &mut Vec<u8>) -> Result<(), PsdError>
+{
+    let color_channels : i16 = match metadata.color_mode
+    {
+        1 | 2 => 1,
+        3 => 3,
+        4 => 4,
+        _ => return Err(PsdError::UnsupportedColorMode(metadata.color_mode)),
+    };
+    if metadata.depth != 8 && metadata.depth != 16
+    {
+        return Err(PsdError::UnsupportedDepth(metadata.depth));
+    }
+    // PSB files can be bigger than this, but only PSD files get written.
+    if metadata.width > 30000 || metadata.height > 30000
+    {
+        return Err(too_big("The document"));
+    }
+    let layer_count = i16::try_from(layers.len()).map_err(|_| too_big("The layer list"))?;
+    let channel_count = metadata.channel_count.max(color_channels as u16);
+    
+    out.extend_from_slice(b"8BPS");
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&[0; 6]);
+    out.extend_from_slice(&channel_count.to_be_bytes());
+    out.extend_from_slice(&metadata.height.to_be_bytes());
+    out.extend_from_slice(&metadata.width.to_be_bytes());
+    out.extend_from_slice(&8u16.to_be_bytes());
+    out.extend_from_slice(&metadata.color_mode.to_be_bytes());
+    
+    // Color mode data. Only indexed color documents have any: the palette, stored planar.
+    match &metadata.palette
+    {
+        Some(palette) if metadata.color_mode == 2 =>
+        {
+            out.extend_from_slice(&768u32.to_be_bytes());
+            for i in 0..3
+            {
+                out.extend((0..256).map(|j| palette.get(j).map_or(0, |x| x[i])));
+            }
+        }
+        _ => out.extend_from_slice(&0u32.to_be_bytes()),
+    }
+    
+    // Image resources: just version info, with "has real merged data" turned off.
+    let mut version_info = vec!();
+    version_info.extend_from_slice(&1u32.to_be_bytes());
+    version_info.push(0);
+    for name in ["rawpsd", "rawpsd"]
+    {
+        let name : Vec<u16> = name.encode_utf16().collect();
+        version_info.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        version_info.extend(name.iter().flat_map(|x| x.to_be_bytes()));
+    }
+    version_info.extend_from_slice(&1u32.to_be_bytes());
+    let resources_start = out.len();
+    out.extend_from_slice(&0u32.to_be_bytes());
+    out.extend_from_slice(b"8BIM");
+    out.extend_from_slice(&1057u16.to_be_bytes());
+    // Empty, padded name.
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(&(version_info.len() as u32).to_be_bytes());
+    out.extend_from_slice(&version_info);
+    if !version_info.len().is_multiple_of(2)
+    {
+        out.push(0);
+    }
+    patch_len(out, resources_start, "The image resources section")?;
+    
+    // Layer and mask info. The layer records all come first, then all of their image data.
+    let section_start = out.len();
+    out.extend_from_slice(&0u32.to_be_bytes());
+    let info_start = out.len();
+    out.extend_from_slice(&0u32.to_be_bytes());
+    if !layers.is_empty()
+    {
+        out.extend_from_slice(&layer_count.to_be_bytes());
+        let mut channel_data = vec!();
+        for (i, layer) in layers.iter().enumerate()
+        {
+            write_layer_record(out, &mut channel_data, layer, i, color_channels)?;
+        }
+        out.extend_from_slice(&channel_data);
+        if !(out.len() - info_start).is_multiple_of(2)
+        {
+            out.push(0);
+        }
+    }
+    patch_len(out, info_start, "The layer info section")?;
+    // No global layer mask info.
+    out.extend_from_slice(&0u32.to_be_bytes());
+    patch_len(out, section_start, "The layer and mask info section")?;
+    
+    // The merged image, blank white: 255 in every channel, which is also no ink for CMYK. All of its channels share one compression mode and one row length table.
+    let rows = metadata.height as usize * channel_count as usize;
+    if metadata.width == 0 || rows == 0
+    {
+        out.extend_from_slice(&0u16.to_be_bytes());
+        return Ok(());
+    }
+    // Runs of 128, then one for whatever's left.
+    let mut row = [0x81, 0xFF].repeat(metadata.width as usize / 128);
+    let rest = metadata.width % 128;
+    if rest != 0
+    {
+        row.extend_from_slice(&[(1 - rest as i32) as u8, 0xFF]);
+    }
+    let row_len = row.len() as u16;
+    out.extend_from_slice(&1u16.to_be_bytes());
+    for _ in 0..rows
+    {
+        out.extend_from_slice(&row_len.to_be_bytes());
+    }
+    for _ in 0..rows
+    {
+        out.extend_from_slice(&row);
+    }
+    Ok(())
+}